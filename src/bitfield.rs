@@ -0,0 +1,247 @@
+// Bit-level helpers for BITFIELD, SETBIT and GETBIT. Offsets count from the most significant bit
+// of the first byte, like Redis, and bytes past the end of a value read as 0.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BitFieldType {
+    pub signed: bool,
+    pub bits: u32,
+}
+
+// The single-bit field SETBIT and GETBIT work on.
+pub const BIT: BitFieldType = BitFieldType {
+    signed: false,
+    bits: 1,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Overflow {
+    Wrap,
+    Sat,
+    Fail,
+}
+
+impl BitFieldType {
+    // `i1`..`i64` or `u1`..`u63`; u64 is out because replies are signed.
+    pub fn parse(encoding: &str) -> Option<Self> {
+        let (signed, bits) = match encoding.as_bytes().first()? {
+            b'i' | b'I' => (true, &encoding[1..]),
+            b'u' | b'U' => (false, &encoding[1..]),
+            _ => return None,
+        };
+        let bits = bits.parse::<u32>().ok()?;
+        let max_bits = if signed { 64 } else { 63 };
+        (1..=max_bits)
+            .contains(&bits)
+            .then_some(Self { signed, bits })
+    }
+
+    fn min(&self) -> i128 {
+        if self.signed {
+            -(1i128 << (self.bits - 1))
+        } else {
+            0
+        }
+    }
+
+    fn max(&self) -> i128 {
+        if self.signed {
+            (1i128 << (self.bits - 1)) - 1
+        } else {
+            (1i128 << self.bits) - 1
+        }
+    }
+
+    // Fits `value` into this type per the overflow policy; `None` means FAIL.
+    pub fn fit(&self, value: i128, overflow: Overflow) -> Option<i64> {
+        if (self.min()..=self.max()).contains(&value) {
+            return Some(value as i64);
+        }
+        match overflow {
+            Overflow::Fail => None,
+            Overflow::Sat => Some(value.clamp(self.min(), self.max()) as i64),
+            Overflow::Wrap => {
+                let wrapped = value.rem_euclid(1i128 << self.bits);
+                if self.signed && wrapped > self.max() {
+                    Some((wrapped - (1i128 << self.bits)) as i64)
+                } else {
+                    Some(wrapped as i64)
+                }
+            }
+        }
+    }
+
+    pub fn get(&self, bytes: &[u8], offset: u64) -> i64 {
+        let mut value = 0u64;
+        for bit in offset..offset + self.bits as u64 {
+            let byte = bytes.get((bit / 8) as usize).copied().unwrap_or(0);
+            value = (value << 1) | ((byte >> (7 - bit % 8)) & 1) as u64;
+        }
+        if self.signed && self.bits < 64 && value >> (self.bits - 1) & 1 == 1 {
+            // Sign-extend from the field's top bit.
+            (value | (u64::MAX << self.bits)) as i64
+        } else {
+            value as i64
+        }
+    }
+
+    // Writes the low `bits` bits of `value`, growing `bytes` with zeros.
+    pub fn set(&self, bytes: &mut Vec<u8>, offset: u64, value: i64) {
+        let end = offset + self.bits as u64;
+        let needed = end.div_ceil(8) as usize;
+        if bytes.len() < needed {
+            bytes.resize(needed, 0);
+        }
+        for (i, bit) in (offset..end).enumerate() {
+            let set = (value as u64 >> (self.bits as usize - 1 - i)) & 1 == 1;
+            let mask = 1 << (7 - bit % 8);
+            let byte = &mut bytes[(bit / 8) as usize];
+            if set {
+                *byte |= mask;
+            } else {
+                *byte &= !mask;
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BitFieldOp {
+    Get {
+        field: BitFieldType,
+        offset: u64,
+    },
+    Set {
+        field: BitFieldType,
+        offset: u64,
+        value: i64,
+        overflow: Overflow,
+    },
+    IncrBy {
+        field: BitFieldType,
+        offset: u64,
+        increment: i64,
+        overflow: Overflow,
+    },
+}
+
+// Offsets are bits, or with a `#` prefix a multiple of the field width.
+// Values can't be longer than 512MB, so neither can offsets.
+pub fn parse_offset(offset: &str, field: BitFieldType) -> Option<u64> {
+    let offset = match offset.strip_prefix('#') {
+        Some(index) => index.parse::<u64>().ok()?.checked_mul(field.bits as u64)?,
+        None => offset.parse::<u64>().ok()?,
+    };
+    (offset < 512 * 1024 * 1024 * 8).then_some(offset)
+}
+
+// A SETBIT or GETBIT offset: a plain bit number under the same 512MB limit.
+pub fn parse_bit_offset(offset: &str) -> Option<u64> {
+    offset
+        .parse::<u64>()
+        .ok()
+        .filter(|offset| *offset < 512 * 1024 * 1024 * 8)
+}
+
+impl BitFieldOp {
+    // The op as BITFIELD arguments, for propagating to replicas.
+    pub fn to_args(&self) -> Vec<String> {
+        let encoding = |field: &BitFieldType| {
+            format!("{}{}", if field.signed { 'i' } else { 'u' }, field.bits)
+        };
+        let overflow_args = |overflow: &Overflow| {
+            let mode = match overflow {
+                Overflow::Wrap => "WRAP",
+                Overflow::Sat => "SAT",
+                Overflow::Fail => "FAIL",
+            };
+            vec!["OVERFLOW".to_string(), mode.to_string()]
+        };
+        match self {
+            BitFieldOp::Get { field, offset } => {
+                vec!["GET".to_string(), encoding(field), offset.to_string()]
+            }
+            BitFieldOp::Set {
+                field,
+                offset,
+                value,
+                overflow,
+            } => {
+                let mut args = overflow_args(overflow);
+                args.extend(["SET".to_string(), encoding(field), offset.to_string()]);
+                args.push(value.to_string());
+                args
+            }
+            BitFieldOp::IncrBy {
+                field,
+                offset,
+                increment,
+                overflow,
+            } => {
+                let mut args = overflow_args(overflow);
+                args.extend(["INCRBY".to_string(), encoding(field), offset.to_string()]);
+                args.push(increment.to_string());
+                args
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn field(encoding: &str) -> BitFieldType {
+        BitFieldType::parse(encoding).unwrap()
+    }
+
+    #[test]
+    fn parses_signed_and_unsigned_widths() {
+        assert_eq!(
+            field("i64"),
+            BitFieldType {
+                signed: true,
+                bits: 64
+            }
+        );
+        assert_eq!(
+            field("U8"),
+            BitFieldType {
+                signed: false,
+                bits: 8
+            }
+        );
+        assert!(BitFieldType::parse("u64").is_none());
+        assert!(BitFieldType::parse("i0").is_none());
+        assert!(BitFieldType::parse("x8").is_none());
+    }
+
+    #[test]
+    fn overflow_wraps_saturates_or_fails() {
+        let u8 = field("u8");
+        assert_eq!(u8.fit(256, Overflow::Wrap), Some(0));
+        assert_eq!(u8.fit(256, Overflow::Sat), Some(255));
+        assert_eq!(u8.fit(256, Overflow::Fail), None);
+        let i8 = field("i8");
+        assert_eq!(i8.fit(128, Overflow::Wrap), Some(-128));
+        assert_eq!(i8.fit(-129, Overflow::Sat), Some(-128));
+    }
+
+    #[test]
+    fn reads_back_fields_written_across_byte_boundaries() {
+        let mut bytes = Vec::new();
+        field("u5").set(&mut bytes, 6, 0b10111);
+        assert_eq!(bytes, [0b0000_0010, 0b1110_0000]);
+        assert_eq!(field("u5").get(&bytes, 6), 0b10111);
+        // The top bit of a signed field is its sign.
+        assert_eq!(field("i5").get(&bytes, 6), -9);
+        // Past the end reads as zeros.
+        assert_eq!(field("u8").get(&bytes, 100), 0);
+    }
+
+    #[test]
+    fn hash_offsets_count_in_field_widths() {
+        assert_eq!(parse_offset("#2", field("u8")), Some(16));
+        assert_eq!(parse_offset("3", field("u8")), Some(3));
+        assert_eq!(parse_offset("4294967296", field("u8")), None);
+    }
+}
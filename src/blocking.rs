@@ -0,0 +1,74 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use tokio::sync::Notify;
+
+// A database index and a key in it.
+type BlockedKey = (usize, Vec<u8>);
+
+// Connections parked in BLPOP, BRPOP or XREAD BLOCK, by database index and
+// key, so a push or XADD onto one of their keys can wake them.
+#[derive(Debug, Default)]
+pub struct BlockingRegistry {
+    waiters: Mutex<HashMap<BlockedKey, Vec<Arc<Notify>>>>,
+}
+
+// Keeps a connection registered on its keys until dropped.
+pub struct Registration<'a> {
+    registry: &'a BlockingRegistry,
+    db: usize,
+    keys: &'a [Vec<u8>],
+    waiter: Arc<Notify>,
+}
+
+impl BlockingRegistry {
+    // Registers `waiter` on every key. Notify keeps a permit when nobody is
+    // waiting yet, so a push between the caller's last attempt and its next
+    // wait isn't missed.
+    pub fn register<'a>(
+        &'a self,
+        db: usize,
+        keys: &'a [Vec<u8>],
+        waiter: &Arc<Notify>,
+    ) -> Registration<'a> {
+        let mut waiters = self.waiters.lock().unwrap();
+        for key in keys {
+            waiters
+                .entry((db, key.clone()))
+                .or_default()
+                .push(Arc::clone(waiter));
+        }
+        Registration {
+            registry: self,
+            db,
+            keys,
+            waiter: Arc::clone(waiter),
+        }
+    }
+
+    // Wakes every connection waiting on `key`. Each retries its pop or read,
+    // so those that lose the race for the new elements go back to waiting.
+    pub fn notify(&self, db: usize, key: &[u8]) {
+        let waiters = self.waiters.lock().unwrap();
+        for waiter in waiters.get(&(db, key.to_vec())).into_iter().flatten() {
+            waiter.notify_one();
+        }
+    }
+}
+
+impl Drop for Registration<'_> {
+    fn drop(&mut self) {
+        let mut waiters = self.registry.waiters.lock().unwrap();
+        for key in self.keys {
+            let entry = (self.db, key.clone());
+            if let Some(list) = waiters.get_mut(&entry) {
+                list.retain(|waiter| !Arc::ptr_eq(waiter, &self.waiter));
+                if list.is_empty() {
+                    waiters.remove(&entry);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,293 @@
+use std::{
+    collections::{BTreeMap, HashSet},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Instant,
+};
+
+use tokio::sync::watch;
+
+use crate::{command::Command, pubsub::Subscriber, Config};
+
+// Per-connection state owned by `handle_client` and handed to every command.
+#[derive(Debug)]
+pub struct ClientState {
+    pub id: u64,
+    pub addr: String,
+    pub laddr: String,
+    // Set with CLIENT SETNAME.
+    pub name: Option<String>,
+    pub connected_at: Instant,
+    pub lib_name: Option<String>,
+    pub lib_ver: Option<String>,
+    // RESP version negotiated with HELLO: 2 (default) or 3.
+    pub protocol: u8,
+    // Index of the database chosen with SELECT.
+    pub db: usize,
+    // Name of the most recent command, shown as `cmd=` in CLIENT INFO.
+    pub last_command: &'static str,
+    pub subscriptions: HashSet<String>,
+    pub pattern_subscriptions: HashSet<String>,
+    pub shard_subscriptions: HashSet<String>,
+    // Commands queued since MULTI; `None` outside a transaction.
+    pub transaction: Option<Vec<Command>>,
+    // Set when a command failed to queue, so EXEC must abort.
+    pub transaction_failed: bool,
+    // Keys passed to WATCH, with their database and the version they had at
+    // that moment.
+    pub watched: Vec<(usize, Vec<u8>, u64)>,
+    // Set once PSYNC turns this connection into a replica: the replication
+    // offset it has been sent everything up to.
+    pub replica_offset: Option<usize>,
+    // Registered with the pub/sub channels this connection subscribes to;
+    // `handle_client` forwards whatever arrives on it to the socket.
+    pub pubsub_sender: Subscriber,
+    // Flipped to true by CLIENT KILL; `handle_client` closes the connection
+    // once it sees that.
+    pub killed: watch::Receiver<bool>,
+    kill: Arc<watch::Sender<bool>>,
+}
+
+impl ClientState {
+    pub fn new(id: u64, addr: String, laddr: String, pubsub_sender: Subscriber) -> Self {
+        let (kill, killed) = watch::channel(false);
+        Self {
+            id,
+            addr,
+            laddr,
+            name: None,
+            connected_at: Instant::now(),
+            lib_name: None,
+            lib_ver: None,
+            protocol: 2,
+            db: 0,
+            last_command: "NULL",
+            subscriptions: HashSet::new(),
+            pattern_subscriptions: HashSet::new(),
+            shard_subscriptions: HashSet::new(),
+            transaction: None,
+            transaction_failed: false,
+            watched: Vec::new(),
+            replica_offset: None,
+            pubsub_sender,
+            killed,
+            kill: Arc::new(kill),
+        }
+    }
+
+    pub fn is_killed(&self) -> bool {
+        *self.killed.borrow()
+    }
+
+    // In RESP2 a subscribed connection can only run pub/sub commands.
+    pub fn in_pubsub_mode(&self) -> bool {
+        self.protocol == 2
+            && (!self.subscriptions.is_empty()
+                || !self.pattern_subscriptions.is_empty()
+                || !self.shard_subscriptions.is_empty())
+    }
+
+    pub fn subscriptions_mut(&mut self, kind: SubscriptionKind) -> &mut HashSet<String> {
+        match kind {
+            SubscriptionKind::Channel => &mut self.subscriptions,
+            SubscriptionKind::Pattern => &mut self.pattern_subscriptions,
+            SubscriptionKind::Shard => &mut self.shard_subscriptions,
+        }
+    }
+
+    // The count (UN)SUBSCRIBE replies report. Like Redis, channels and
+    // patterns are counted together and shard channels on their own.
+    pub fn subscription_count(&self, kind: SubscriptionKind) -> usize {
+        match kind {
+            SubscriptionKind::Channel | SubscriptionKind::Pattern => {
+                self.subscriptions.len() + self.pattern_subscriptions.len()
+            }
+            SubscriptionKind::Shard => self.shard_subscriptions.len(),
+        }
+    }
+
+    pub async fn unsubscribe_all(&mut self, config: &Config) {
+        for channel in self.subscriptions.drain() {
+            config
+                .pubsub
+                .unsubscribe(&channel, &self.pubsub_sender)
+                .await;
+        }
+        for pattern in self.pattern_subscriptions.drain() {
+            config
+                .pubsub
+                .punsubscribe(&pattern, &self.pubsub_sender)
+                .await;
+        }
+        for channel in self.shard_subscriptions.drain() {
+            config
+                .shard_pubsub
+                .unsubscribe(&channel, &self.pubsub_sender)
+                .await;
+        }
+    }
+
+    // Drops every WATCH this connection holds.
+    pub fn unwatch_all(&mut self, config: &Config) {
+        for (index, key, _) in self.watched.drain(..) {
+            config.databases[index].write().unwrap().unwatch(&key);
+        }
+    }
+
+    // RESET: back to the state of a freshly accepted connection.
+    pub async fn reset(&mut self, config: &Config) {
+        self.unsubscribe_all(config).await;
+        config.monitors.remove(self.id);
+        self.transaction = None;
+        self.transaction_failed = false;
+        self.unwatch_all(config);
+        self.protocol = 2;
+        self.db = 0;
+    }
+
+    // One line in the `CLIENT INFO` format.
+    pub fn info(&self) -> String {
+        ClientEntry::new(self).info()
+    }
+}
+
+// What a connection subscribes to: channels (SUBSCRIBE), glob patterns
+// (PSUBSCRIBE) or shard channels (SSUBSCRIBE).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SubscriptionKind {
+    Channel,
+    Pattern,
+    Shard,
+}
+
+// Which connections CLIENT KILL closes: those matching every filter given.
+// With `skip_me` the connection running the command is spared.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KillFilter {
+    pub id: Option<u64>,
+    pub addr: Option<String>,
+    pub laddr: Option<String>,
+    pub skip_me: bool,
+}
+
+// A connection as CLIENT LIST shows it, refreshed after each of its
+// commands, along with the handle CLIENT KILL closes it with. Its age is
+// worked out when the line is printed, so idle connections don't go stale.
+#[derive(Debug)]
+struct ClientEntry {
+    id: u64,
+    addr: String,
+    laddr: String,
+    name: String,
+    connected_at: Instant,
+    // The fields after `age=`.
+    rest: String,
+    kill: Arc<watch::Sender<bool>>,
+}
+
+impl ClientEntry {
+    fn new(client: &ClientState) -> Self {
+        Self {
+            id: client.id,
+            addr: client.addr.clone(),
+            laddr: client.laddr.clone(),
+            name: client.name.clone().unwrap_or_default(),
+            connected_at: client.connected_at,
+            rest: format!(
+                "db={} sub={} psub={} ssub={} multi={} watch={} cmd={} lib-name={} lib-ver={} resp={}",
+                client.db,
+                client.subscriptions.len(),
+                client.pattern_subscriptions.len(),
+                client.shard_subscriptions.len(),
+                client
+                    .transaction
+                    .as_ref()
+                    .map_or(-1, |queued| queued.len() as i64),
+                client.watched.len(),
+                client.last_command,
+                client.lib_name.as_deref().unwrap_or_default(),
+                client.lib_ver.as_deref().unwrap_or_default(),
+                client.protocol,
+            ),
+            kill: Arc::clone(&client.kill),
+        }
+    }
+
+    fn info(&self) -> String {
+        format!(
+            "id={} addr={} laddr={} name={} age={} {}",
+            self.id,
+            self.addr,
+            self.laddr,
+            self.name,
+            self.connected_at.elapsed().as_secs(),
+            self.rest
+        )
+    }
+
+    fn matches(&self, filter: &KillFilter, caller: u64) -> bool {
+        !(filter.skip_me && self.id == caller)
+            && filter.id.is_none_or(|id| id == self.id)
+            && filter.addr.as_ref().is_none_or(|addr| *addr == self.addr)
+            && filter
+                .laddr
+                .as_ref()
+                .is_none_or(|laddr| *laddr == self.laddr)
+    }
+}
+
+// Every open connection, so CLIENT LIST can report on connections other
+// than the caller and CLIENT KILL can close them.
+#[derive(Debug, Default)]
+pub struct ClientRegistry {
+    last_id: AtomicU64,
+    clients: Mutex<BTreeMap<u64, ClientEntry>>,
+}
+
+impl ClientRegistry {
+    pub fn next_id(&self) -> u64 {
+        self.last_id.fetch_add(1, Ordering::SeqCst) + 1
+    }
+
+    pub fn update(&self, client: &ClientState) {
+        self.clients
+            .lock()
+            .unwrap()
+            .insert(client.id, ClientEntry::new(client));
+    }
+
+    pub fn remove(&self, id: u64) {
+        self.clients.lock().unwrap().remove(&id);
+    }
+
+    // How many connections are open, as INFO's `connected_clients`.
+    pub fn count(&self) -> usize {
+        self.clients.lock().unwrap().len()
+    }
+
+    // One line per connection, oldest first.
+    pub fn list(&self) -> String {
+        self.clients
+            .lock()
+            .unwrap()
+            .values()
+            .map(|entry| format!("{}\n", entry.info()))
+            .collect()
+    }
+
+    // Tells every matching connection to close, returning how many there
+    // were. Connections already told to close aren't counted again.
+    pub fn kill(&self, filter: &KillFilter, caller: u64) -> usize {
+        let clients = self.clients.lock().unwrap();
+        let mut killed = 0;
+        for entry in clients.values() {
+            if !*entry.kill.borrow() && entry.matches(filter, caller) {
+                entry.kill.send_replace(true);
+                killed += 1;
+            }
+        }
+        killed
+    }
+}
@@ -1,119 +1,1221 @@
 use std::{
-    ops::Deref,
-    sync::{Arc, Mutex},
+    collections::{HashMap, VecDeque},
+    ops::{Bound, RangeInclusive},
+    path::Path,
+    sync::{atomic::Ordering, Arc, RwLock},
+    time::Instant,
 };
 
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
-    net::{TcpListener, TcpStream},
-    time::{sleep, Duration},
+    io::{AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt},
+    sync::Notify,
+    time::Duration,
 };
 
 use crate::{
-    database::{Database, Item},
-    resp::RespType,
-    Config, Role,
+    bitfield::{parse_bit_offset, parse_offset, BitFieldOp, BitFieldType, Overflow, BIT},
+    client::{ClientState, KillFilter, SubscriptionKind},
+    database::{unix_time_ms, Database, Item, Value},
+    error::RedisError,
+    glob::glob_match,
+    pubsub::Subscriber,
+    rdb::{dump_value, encode_rdb, restore_value, serialized_length},
+    replication::wait_for_replicas,
+    resp::{format_double, RespType},
+    stream::{Fields, Stream, StreamId, XAddId},
+    zset::{parse_score, LexBound, Score, ScoreBound, SortedSet},
+    Config, Role, REDIS_VERSION,
 };
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Command {
-    Ping,
-    Echo(String),
+    // The optional message to echo back instead of PONG.
+    Ping(Option<String>),
+    Echo(Vec<u8>),
     Set {
-        key: String,
+        key: Vec<u8>,
+        value: Vec<u8>,
+        expiry: Option<SetExpiry>,
+        condition: Option<SetCondition>,
+        // Reply with the previous value instead of OK.
+        get: bool,
+    },
+    Get(Vec<u8>),
+    GetDel(Vec<u8>),
+    SetRange {
+        key: Vec<u8>,
+        offset: usize,
+        value: Vec<u8>,
+    },
+    GetRange {
+        key: Vec<u8>,
+        start: i64,
+        end: i64,
+    },
+    SetBit {
+        key: Vec<u8>,
+        offset: u64,
+        bit: bool,
+    },
+    GetBit {
+        key: Vec<u8>,
+        offset: u64,
+    },
+    BitCount {
+        key: Vec<u8>,
+        // Inclusive byte indexes, negative ones counting from the end.
+        range: Option<(i64, i64)>,
+    },
+    GetEx {
+        key: Vec<u8>,
+        option: Option<GetExOption>,
+    },
+    Del(Vec<Vec<u8>>),
+    Touch(Vec<Vec<u8>>),
+    ConfigGet(Vec<String>),
+    ConfigSet {
+        param: String,
         value: String,
-        ttl: Option<u64>,
     },
-    Get(String),
-    ConfigGet(String),
-    Keys(String),
-    Info,
-    Unknown,
+    Keys(Vec<u8>),
+    // The sections asked for, lowercased; none for the default ones.
+    Info(Vec<String>),
     ReplConf(String),
-    PSync,
+    PSync {
+        replid: String,
+        offset: i64,
+    },
+    FlushDb,
+    FlushAll,
+    ClientSetInfo {
+        attr: String,
+        value: String,
+    },
+    ClientInfo,
+    ClientList,
+    ClientSetName(String),
+    ClientGetName,
+    ClientId,
+    ClientKill {
+        filter: KillFilter,
+        // The old `CLIENT KILL addr:port` form, answered with OK or an
+        // error rather than a count.
+        legacy: bool,
+    },
+    Scan {
+        cursor: usize,
+        pattern: Option<Vec<u8>>,
+        count: usize,
+    },
+    Subscribe(Vec<String>),
+    Unsubscribe(Vec<String>),
+    Publish {
+        channel: String,
+        message: String,
+    },
+    PSubscribe(Vec<String>),
+    PUnsubscribe(Vec<String>),
+    SSubscribe(Vec<String>),
+    SUnsubscribe(Vec<String>),
+    // PUBSUB CHANNELS, or SHARDCHANNELS with `shard`.
+    PubSubChannels {
+        pattern: Option<String>,
+        shard: bool,
+    },
+    // PUBSUB NUMSUB, or SHARDNUMSUB with `shard`.
+    PubSubNumSub {
+        channels: Vec<String>,
+        shard: bool,
+    },
+    PubSubNumPat,
+    SPublish {
+        channel: String,
+        message: String,
+    },
+    Wait {
+        numreplicas: usize,
+        timeout: u64,
+    },
+    Multi,
+    Exec,
+    Discard,
+    Watch(Vec<Vec<u8>>),
+    Unwatch,
+    // COMMAND COUNT and COMMAND DOCS.
+    Count,
+    Docs,
+    Quit,
+    Hello(Option<u8>),
+    Cluster(Vec<String>),
+    Reset,
+    MemoryPurge,
+    // SLOWLOG GET with how many entries to return; `None` for all of them.
+    SlowLogGet(Option<usize>),
+    SlowLogLen,
+    SlowLogReset,
+    Monitor,
+    DebugSleep(Duration),
+    DebugObject(Vec<u8>),
+    // Any other DEBUG subcommand, accepted so test harnesses don't fail.
+    Debug(String),
+    BitField {
+        key: Vec<u8>,
+        ops: Vec<BitFieldOp>,
+    },
+    DbSize,
+    RandomKey,
+    Copy {
+        src: Vec<u8>,
+        dst: Vec<u8>,
+        // Target database, when not the connection's own.
+        db: Option<usize>,
+        replace: bool,
+    },
+    Dump(Vec<u8>),
+    Restore {
+        key: Vec<u8>,
+        // Milliseconds to live, or the Unix time in milliseconds to expire at
+        // with ABSTTL; zero means no expiry.
+        ttl: u64,
+        payload: Vec<u8>,
+        replace: bool,
+        absttl: bool,
+    },
+    Append {
+        key: Vec<u8>,
+        value: Vec<u8>,
+    },
+    Object {
+        subcommand: String,
+        key: Vec<u8>,
+    },
+    XInfo {
+        subcommand: String,
+        key: Vec<u8>,
+    },
+    Select(usize),
+    SwapDb(usize, usize),
+    // EXPIRE, PEXPIRE, EXPIREAT and PEXPIREAT, with the time converted to
+    // milliseconds from now.
+    Expire {
+        key: Vec<u8>,
+        millis: i64,
+        condition: Option<ExpireCondition>,
+    },
+    // TTL and PTTL.
+    Ttl {
+        key: Vec<u8>,
+        millis: bool,
+    },
+    // EXPIRETIME and PEXPIRETIME.
+    ExpireTime {
+        key: Vec<u8>,
+        millis: bool,
+    },
+    Type(Vec<u8>),
+    // LPUSH and RPUSH.
+    Push {
+        key: Vec<u8>,
+        elements: Vec<Vec<u8>>,
+        left: bool,
+    },
+    // LPOP and RPOP. Without a count the reply is a single element.
+    Pop {
+        key: Vec<u8>,
+        count: Option<usize>,
+        left: bool,
+    },
+    // BLPOP and BRPOP. A zero timeout waits forever.
+    BPop {
+        keys: Vec<Vec<u8>>,
+        timeout: Duration,
+        left: bool,
+    },
+    LRange {
+        key: Vec<u8>,
+        start: i64,
+        stop: i64,
+    },
+    LLen(Vec<u8>),
+    LPos {
+        key: Vec<u8>,
+        element: Vec<u8>,
+        // Which match to start from: 1 for the first, -1 for the last.
+        rank: i64,
+        // How many matches to return, zero meaning all of them.
+        count: Option<usize>,
+    },
+    LInsert {
+        key: Vec<u8>,
+        before: bool,
+        pivot: Vec<u8>,
+        element: Vec<u8>,
+    },
+    LSet {
+        key: Vec<u8>,
+        index: i64,
+        element: Vec<u8>,
+    },
+    RPopLPush {
+        src: Vec<u8>,
+        dst: Vec<u8>,
+    },
+    LMove {
+        src: Vec<u8>,
+        dst: Vec<u8>,
+        from: ListEnd,
+        to: ListEnd,
+    },
+    ZAdd {
+        key: Vec<u8>,
+        members: Vec<(Score, Vec<u8>)>,
+        // NX only adds new members, XX only updates existing ones.
+        condition: Option<SetCondition>,
+        score_condition: Option<ScoreCondition>,
+        // Count updated members in the reply as well as added ones.
+        ch: bool,
+    },
+    ZScore {
+        key: Vec<u8>,
+        member: Vec<u8>,
+    },
+    ZRange {
+        key: Vec<u8>,
+        range: ZRange,
+        withscores: bool,
+    },
+    ZRangeStore {
+        dst: Vec<u8>,
+        src: Vec<u8>,
+        range: Box<ZRange>,
+    },
+    ZRangeByScore {
+        key: Vec<u8>,
+        min: ScoreBound,
+        max: ScoreBound,
+        withscores: bool,
+        // Offset and count; a negative count means no limit.
+        limit: Option<(i64, i64)>,
+    },
+    ZRangeByLex {
+        key: Vec<u8>,
+        min: LexBound,
+        max: LexBound,
+        // Offset and count; a negative count means no limit.
+        limit: Option<(i64, i64)>,
+    },
+    ZLexCount {
+        key: Vec<u8>,
+        min: LexBound,
+        max: LexBound,
+    },
+    ZRank {
+        key: Vec<u8>,
+        member: Vec<u8>,
+    },
+    ZRem {
+        key: Vec<u8>,
+        members: Vec<Vec<u8>>,
+    },
+    ZCard(Vec<u8>),
+    HSet {
+        key: Vec<u8>,
+        fields: Fields,
+    },
+    HGet {
+        key: Vec<u8>,
+        field: Vec<u8>,
+    },
+    HGetAll(Vec<u8>),
+    HDel {
+        key: Vec<u8>,
+        fields: Vec<Vec<u8>>,
+    },
+    ZRandMember {
+        key: Vec<u8>,
+        // Without a count the reply is one member rather than an array. A
+        // negative count may repeat members.
+        count: Option<i64>,
+        withscores: bool,
+    },
+    XAdd {
+        key: Vec<u8>,
+        id: XAddId,
+        fields: Fields,
+    },
+    XRange {
+        key: Vec<u8>,
+        start: StreamId,
+        end: StreamId,
+        count: Option<usize>,
+    },
+    XLen(Vec<u8>),
+    XRead {
+        // Each key with the ID to read after; `None` for `$`, the stream's
+        // last ID.
+        streams: Vec<(Vec<u8>, Option<StreamId>)>,
+        count: Option<usize>,
+        // How long BLOCK waits for new entries; zero waits forever.
+        block: Option<Duration>,
+    },
+    LRem {
+        key: Vec<u8>,
+        // How many matches to remove, from the tail when negative and all of
+        // them when zero.
+        count: i64,
+        element: Vec<u8>,
+    },
+    Error(RedisError),
+    // The command as received, when it couldn't be parsed.
+    Unknown {
+        name: String,
+        args: Vec<String>,
+    },
+}
+
+// Whether `name` is a command this server knows, for validating
+// --rename-command.
+pub fn is_command(name: &str) -> bool {
+    COMMAND_TABLE
+        .iter()
+        .any(|(command, _)| command.eq_ignore_ascii_case(name))
+}
+
+// Every command `from_resp` understands with its arity in Redis's
+// convention: the argument count including the command name, or at least
+// that many when negative. Also what COMMAND COUNT reports.
+const COMMAND_TABLE: &[(&str, i64)] = &[
+    ("ping", -1),
+    ("echo", 2),
+    ("set", -3),
+    ("get", 2),
+    ("getex", -2),
+    ("getdel", 2),
+    ("setrange", 4),
+    ("getrange", 4),
+    ("setbit", 4),
+    ("getbit", 3),
+    ("bitcount", -2),
+    ("del", -2),
+    ("touch", -2),
+    ("config", -2),
+    ("keys", 2),
+    ("info", -1),
+    ("replconf", -1),
+    ("psync", -3),
+    ("client", -2),
+    ("scan", -2),
+    ("wait", 3),
+    ("multi", 1),
+    ("exec", 1),
+    ("discard", 1),
+    ("watch", -2),
+    ("unwatch", 1),
+    ("subscribe", -2),
+    ("psubscribe", -2),
+    ("ssubscribe", -2),
+    ("unsubscribe", -1),
+    ("punsubscribe", -1),
+    ("sunsubscribe", -1),
+    ("publish", 3),
+    ("spublish", 3),
+    ("flushdb", -1),
+    ("flushall", -1),
+    ("command", -1),
+    ("quit", -1),
+    ("hello", -1),
+    ("debug", -2),
+    ("cluster", -2),
+    ("reset", 1),
+    ("memory", -2),
+    ("slowlog", -2),
+    ("monitor", 1),
+    ("bitfield", -2),
+    ("dbsize", 1),
+    ("randomkey", 1),
+    ("copy", -3),
+    ("append", 3),
+    ("dump", 2),
+    ("restore", -4),
+    ("object", -2),
+    ("select", 2),
+    ("swapdb", 3),
+    ("setex", 4),
+    ("psetex", 4),
+    ("expire", -3),
+    ("pexpire", -3),
+    ("expireat", -3),
+    ("pexpireat", -3),
+    ("ttl", 2),
+    ("pttl", 2),
+    ("expiretime", 2),
+    ("pexpiretime", 2),
+    ("type", 2),
+    ("lpush", -3),
+    ("rpush", -3),
+    ("lpop", -2),
+    ("rpop", -2),
+    ("lrange", 4),
+    ("llen", 2),
+    ("lpos", -3),
+    ("linsert", 5),
+    ("lset", 4),
+    ("lrem", 4),
+    ("rpoplpush", 3),
+    ("lmove", 5),
+    ("blpop", -3),
+    ("brpop", -3),
+    ("zadd", -4),
+    ("zscore", 3),
+    ("zrange", -4),
+    ("zrangestore", -5),
+    ("zrank", 3),
+    ("zcard", 2),
+    ("zrangebyscore", -4),
+    ("zrangebylex", -4),
+    ("zlexcount", 4),
+    ("zrem", -3),
+    ("zrandmember", -2),
+    ("hset", -4),
+    ("hget", 3),
+    ("hgetall", 2),
+    ("hdel", -3),
+    ("xadd", -5),
+    ("xrange", -4),
+    ("xlen", 2),
+    ("xread", -4),
+    ("xinfo", -2),
+    ("pubsub", -2),
+];
+
+// Subcommands of the container commands above, with arities counted the
+// same way (so `CONFIG GET pattern` is 3).
+const SUBCOMMAND_TABLE: &[(&str, &str, i64)] = &[
+    ("config", "get", -3),
+    ("config", "set", 4),
+    ("client", "setinfo", 4),
+    ("client", "info", 2),
+    ("client", "list", 2),
+    ("client", "setname", 3),
+    ("client", "getname", 2),
+    ("client", "id", 2),
+    ("client", "kill", -3),
+    ("command", "count", 2),
+    ("command", "docs", -2),
+    ("cluster", "info", 2),
+    ("cluster", "myid", 2),
+    ("cluster", "slots", 2),
+    ("cluster", "shards", 2),
+    ("memory", "purge", 2),
+    ("slowlog", "get", -2),
+    ("slowlog", "len", 2),
+    ("slowlog", "reset", 2),
+    ("object", "encoding", 3),
+    ("object", "refcount", 3),
+    ("xinfo", "stream", 3),
+    ("xinfo", "groups", 3),
+    ("pubsub", "channels", -2),
+    ("pubsub", "numsub", -2),
+    ("pubsub", "numpat", 2),
+    ("pubsub", "shardchannels", -2),
+    ("pubsub", "shardnumsub", -2),
+];
+
+// Rejects a known command called with the wrong number of arguments, or a
+// container command called with an unknown subcommand.
+fn check_arity(name: &str, args: &[RespType]) -> Option<Command> {
+    let &(_, arity) = COMMAND_TABLE.iter().find(|(command, _)| *command == name)?;
+    if !arity_matches(arity, args.len()) {
+        return Some(Command::Error(RedisError::Err(format!(
+            "wrong number of arguments for '{}' command",
+            name
+        ))));
+    }
+
+    let Some(RespType::BulkString(subcommand)) = args.get(1) else {
+        return None;
+    };
+    if !SUBCOMMAND_TABLE
+        .iter()
+        .any(|(command, _, _)| *command == name)
+    {
+        return None;
+    }
+    let Some(&(_, sub, arity)) = SUBCOMMAND_TABLE
+        .iter()
+        .find(|(command, sub, _)| *command == name && sub.eq_ignore_ascii_case(subcommand))
+    else {
+        return Some(Command::Error(RedisError::Err(format!(
+            "unknown subcommand '{}'. Try {} HELP.",
+            subcommand,
+            name.to_uppercase()
+        ))));
+    };
+    if !arity_matches(arity, args.len()) {
+        return Some(Command::Error(RedisError::Err(format!(
+            "wrong number of arguments for '{}|{}' command",
+            name, sub
+        ))));
+    }
+    None
+}
+
+fn arity_matches(arity: i64, argc: usize) -> bool {
+    if arity < 0 {
+        argc as i64 >= -arity
+    } else {
+        argc as i64 == arity
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SetExpiry {
+    Ex(u64),
+    Px(u64),
+    ExAt(u64),
+    PxAt(u64),
+    KeepTtl,
+}
+
+// What GETEX does to the key's TTL besides reading it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum GetExOption {
+    Expire(SetExpiry),
+    Persist,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SetCondition {
+    Nx,
+    Xx,
+}
+
+// Which members ZRANGE and ZRANGESTORE select: a span of ranks, of scores
+// or, for equally scored members, of the members themselves.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ZRangeBy {
+    Index(i64, i64),
+    Score(ScoreBound, ScoreBound),
+    Lex(LexBound, LexBound),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ZRange {
+    pub by: ZRangeBy,
+    // Highest first. Ranks then count from the highest member too.
+    pub rev: bool,
+    // Offset and count; a negative count means no limit.
+    pub limit: Option<(i64, i64)>,
+}
+
+// ZADD's GT and LT: only move existing members to a higher or lower score.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScoreCondition {
+    Gt,
+    Lt,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExpireCondition {
+    Nx,
+    Xx,
+    Gt,
+    Lt,
+}
+
+// Which end of a list LMOVE pops from or pushes to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ListEnd {
+    Left,
+    Right,
+}
+
+impl ListEnd {
+    fn parse(word: &[u8]) -> Option<ListEnd> {
+        match String::from_utf8_lossy(word).to_uppercase().as_str() {
+            "LEFT" => Some(ListEnd::Left),
+            "RIGHT" => Some(ListEnd::Right),
+            _ => None,
+        }
+    }
+
+    fn as_bytes(self) -> &'static [u8] {
+        match self {
+            ListEnd::Left => b"LEFT",
+            ListEnd::Right => b"RIGHT",
+        }
+    }
 }
 
 impl Command {
-    pub fn from_resp(resp: Vec<RespType>) -> Command {
+    // The command's name as a client would type it, for error messages.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Command::Ping(_) => "ping",
+            Command::Echo(_) => "echo",
+            Command::Set { .. } => "set",
+            Command::Get(_) => "get",
+            Command::GetEx { .. } => "getex",
+            Command::GetDel(_) => "getdel",
+            Command::SetRange { .. } => "setrange",
+            Command::GetRange { .. } => "getrange",
+            Command::SetBit { .. } => "setbit",
+            Command::GetBit { .. } => "getbit",
+            Command::BitCount { .. } => "bitcount",
+            Command::Del(_) => "del",
+            Command::Touch(_) => "touch",
+            Command::ConfigGet(_) | Command::ConfigSet { .. } => "config",
+            Command::Keys(_) => "keys",
+            Command::Info(_) => "info",
+            Command::ReplConf(_) => "replconf",
+            Command::PSync { .. } => "psync",
+            Command::FlushDb => "flushdb",
+            Command::FlushAll => "flushall",
+            Command::ClientSetInfo { .. }
+            | Command::ClientInfo
+            | Command::ClientList
+            | Command::ClientSetName(_)
+            | Command::ClientGetName
+            | Command::ClientId
+            | Command::ClientKill { .. } => "client",
+            Command::Scan { .. } => "scan",
+            Command::Subscribe(_) => "subscribe",
+            Command::Unsubscribe(_) => "unsubscribe",
+            Command::Publish { .. } => "publish",
+            Command::PSubscribe(_) => "psubscribe",
+            Command::PUnsubscribe(_) => "punsubscribe",
+            Command::SSubscribe(_) => "ssubscribe",
+            Command::PubSubChannels { .. }
+            | Command::PubSubNumSub { .. }
+            | Command::PubSubNumPat => "pubsub",
+            Command::SUnsubscribe(_) => "sunsubscribe",
+            Command::SPublish { .. } => "spublish",
+            Command::Wait { .. } => "wait",
+            Command::Multi => "multi",
+            Command::Exec => "exec",
+            Command::Discard => "discard",
+            Command::Watch(_) => "watch",
+            Command::Unwatch => "unwatch",
+            Command::Count | Command::Docs => "command",
+            Command::Quit => "quit",
+            Command::Hello(_) => "hello",
+            Command::Cluster(_) => "cluster",
+            Command::Reset => "reset",
+            Command::MemoryPurge => "memory",
+            Command::SlowLogGet(_) | Command::SlowLogLen | Command::SlowLogReset => "slowlog",
+            Command::Monitor => "monitor",
+            Command::DebugSleep(_) | Command::DebugObject(_) | Command::Debug(_) => "debug",
+            Command::BitField { .. } => "bitfield",
+            Command::DbSize => "dbsize",
+            Command::RandomKey => "randomkey",
+            Command::Copy { .. } => "copy",
+            Command::Append { .. } => "append",
+            Command::Dump(_) => "dump",
+            Command::Restore { .. } => "restore",
+            Command::Object { .. } => "object",
+            Command::XInfo { .. } => "xinfo",
+            Command::Select(_) => "select",
+            Command::SwapDb(..) => "swapdb",
+            Command::Expire { .. } => "expire",
+            Command::Ttl { millis: false, .. } => "ttl",
+            Command::Ttl { millis: true, .. } => "pttl",
+            Command::ExpireTime { millis: false, .. } => "expiretime",
+            Command::ExpireTime { millis: true, .. } => "pexpiretime",
+            Command::Type(_) => "type",
+            Command::Push { left: true, .. } => "lpush",
+            Command::Push { left: false, .. } => "rpush",
+            Command::Pop { left: true, .. } => "lpop",
+            Command::Pop { left: false, .. } => "rpop",
+            Command::BPop { left: true, .. } => "blpop",
+            Command::BPop { left: false, .. } => "brpop",
+            Command::LRange { .. } => "lrange",
+            Command::LLen(_) => "llen",
+            Command::LPos { .. } => "lpos",
+            Command::LInsert { .. } => "linsert",
+            Command::LSet { .. } => "lset",
+            Command::LRem { .. } => "lrem",
+            Command::ZAdd { .. } => "zadd",
+            Command::ZScore { .. } => "zscore",
+            Command::ZRange { .. } => "zrange",
+            Command::ZRangeStore { .. } => "zrangestore",
+            Command::ZRangeByScore { .. } => "zrangebyscore",
+            Command::ZRangeByLex { .. } => "zrangebylex",
+            Command::ZLexCount { .. } => "zlexcount",
+            Command::ZRank { .. } => "zrank",
+            Command::ZRem { .. } => "zrem",
+            Command::ZCard(_) => "zcard",
+            Command::ZRandMember { .. } => "zrandmember",
+            Command::HSet { .. } => "hset",
+            Command::HGet { .. } => "hget",
+            Command::HGetAll(_) => "hgetall",
+            Command::HDel { .. } => "hdel",
+            Command::XAdd { .. } => "xadd",
+            Command::XRange { .. } => "xrange",
+            Command::XLen(_) => "xlen",
+            Command::XRead { .. } => "xread",
+            Command::RPopLPush { .. } => "rpoplpush",
+            Command::LMove { .. } => "lmove",
+            Command::Error(_) | Command::Unknown { .. } => "",
+        }
+    }
+
+    // `renamed` maps the lowercase names set up with --rename-command to the
+    // command they run, or to `None` for names that no longer work.
+    pub fn from_resp(resp: Vec<RespType>, renamed: &HashMap<String, Option<String>>) -> Command {
         if let Some(RespType::Array(inner_resp)) = resp.first() {
             if inner_resp.is_empty() {
-                return Command::Unknown;
+                return Command::unknown("", &[]);
             }
 
             if let RespType::BulkString(command) = &inner_resp[0] {
-                match command.to_lowercase().as_str() {
-                    "ping" => Command::Ping,
-                    "echo" => {
-                        if let Some(RespType::BulkString(arg)) = inner_resp.get(1) {
-                            Command::Echo(arg.clone())
-                        } else {
-                            Command::Unknown
+                let unknown = || Command::unknown(command, &inner_resp[1..]);
+                let name = match renamed.get(&command.to_lowercase()) {
+                    Some(Some(original)) => original.clone(),
+                    Some(None) => return unknown(),
+                    None => command.to_lowercase(),
+                };
+                if let Some(error) = check_arity(&name, inner_resp) {
+                    return error;
+                }
+                match name.as_str() {
+                    "ping" => match bytes_args(&inner_resp[1..])[..] {
+                        [] => Command::Ping(None),
+                        [message] => {
+                            Command::Ping(Some(String::from_utf8_lossy(message).into_owned()))
                         }
-                    }
-                    "set" => {
-                        let key = inner_resp
-                            .get(1)
-                            .and_then(|x| {
-                                if let RespType::BulkString(s) = x {
-                                    Some(s.clone())
-                                } else {
-                                    None
-                                }
-                            })
-                            .unwrap_or_default();
-
-                        let value = inner_resp
-                            .get(2)
-                            .and_then(|x| {
-                                if let RespType::BulkString(s) = x {
-                                    Some(s.clone())
-                                } else {
-                                    None
-                                }
-                            })
-                            .unwrap_or_default();
-
-                        let ttl = inner_resp.get(4).and_then(|x| {
-                            if let RespType::BulkString(s) = x {
-                                s.parse::<u64>().ok()
-                            } else {
-                                None
+                        _ => Command::Error(RedisError::Err(
+                            "wrong number of arguments for 'ping' command".to_string(),
+                        )),
+                    },
+                    "echo" => match bytes_args(&inner_resp[1..])[..] {
+                        [message] => Command::Echo(message.to_vec()),
+                        _ => unknown(),
+                    },
+                    "set" => parse_set(&inner_resp[1..]),
+                    "setex" => parse_setex(&inner_resp[1..], 1000, "setex"),
+                    "psetex" => parse_setex(&inner_resp[1..], 1, "psetex"),
+                    "expire" => parse_expire(&inner_resp[1..], 1000, false, "expire"),
+                    "pexpire" => parse_expire(&inner_resp[1..], 1, false, "pexpire"),
+                    "expireat" => parse_expire(&inner_resp[1..], 1000, true, "expireat"),
+                    "pexpireat" => parse_expire(&inner_resp[1..], 1, true, "pexpireat"),
+                    "ttl" | "pttl" => match bytes_args(&inner_resp[1..])[..] {
+                        [key] => Command::Ttl {
+                            key: key.to_vec(),
+                            millis: name == "pttl",
+                        },
+                        _ => unknown(),
+                    },
+                    "expiretime" | "pexpiretime" => match bytes_args(&inner_resp[1..])[..] {
+                        [key] => Command::ExpireTime {
+                            key: key.to_vec(),
+                            millis: name == "pexpiretime",
+                        },
+                        _ => unknown(),
+                    },
+                    "get" => match bytes_args(&inner_resp[1..])[..] {
+                        [key] => Command::Get(key.to_vec()),
+                        _ => unknown(),
+                    },
+                    "type" => match bytes_args(&inner_resp[1..])[..] {
+                        [key] => Command::Type(key.to_vec()),
+                        _ => unknown(),
+                    },
+                    "lpush" | "rpush" => match &bytes_args(&inner_resp[1..])[..] {
+                        [key, elements @ ..] => Command::Push {
+                            key: key.to_vec(),
+                            elements: elements.iter().map(|element| element.to_vec()).collect(),
+                            left: name == "lpush",
+                        },
+                        [] => unknown(),
+                    },
+                    "lpop" | "rpop" => match bytes_args(&inner_resp[1..])[..] {
+                        [key] => Command::Pop {
+                            key: key.to_vec(),
+                            count: None,
+                            left: name == "lpop",
+                        },
+                        [key, count] => match String::from_utf8_lossy(count).parse::<usize>() {
+                            Ok(count) => Command::Pop {
+                                key: key.to_vec(),
+                                count: Some(count),
+                                left: name == "lpop",
+                            },
+                            Err(_) => Command::Error(RedisError::Err(
+                                "value is out of range, must be positive".to_string(),
+                            )),
+                        },
+                        _ => Command::Error(RedisError::Err(format!(
+                            "wrong number of arguments for '{}' command",
+                            name
+                        ))),
+                    },
+                    "blpop" | "brpop" => match &bytes_args(&inner_resp[1..])[..] {
+                        [keys @ .., timeout] if !keys.is_empty() => {
+                            match String::from_utf8_lossy(timeout).parse::<f64>() {
+                                Ok(timeout) if timeout < 0.0 => Command::Error(RedisError::Err(
+                                    "timeout is negative".to_string(),
+                                )),
+                                Ok(timeout) if timeout.is_finite() => Command::BPop {
+                                    keys: keys.iter().map(|key| key.to_vec()).collect(),
+                                    timeout: Duration::from_secs_f64(timeout),
+                                    left: name == "blpop",
+                                },
+                                _ => Command::Error(RedisError::Err(
+                                    "timeout is not a float or out of range".to_string(),
+                                )),
                             }
-                        });
-
-                        Command::Set { key, value, ttl }
-                    }
-                    "get" => {
-                        if let Some(RespType::BulkString(key)) = inner_resp.get(1) {
-                            Command::Get(key.clone())
+                        }
+                        _ => unknown(),
+                    },
+                    "lrange" => match bytes_args(&inner_resp[1..])[..] {
+                        [key, start, stop] => match (
+                            String::from_utf8_lossy(start).parse::<i64>(),
+                            String::from_utf8_lossy(stop).parse::<i64>(),
+                        ) {
+                            (Ok(start), Ok(stop)) => Command::LRange {
+                                key: key.to_vec(),
+                                start,
+                                stop,
+                            },
+                            _ => Command::Error(RedisError::Err(
+                                "value is not an integer or out of range".to_string(),
+                            )),
+                        },
+                        _ => unknown(),
+                    },
+                    "llen" => match bytes_args(&inner_resp[1..])[..] {
+                        [key] => Command::LLen(key.to_vec()),
+                        _ => unknown(),
+                    },
+                    "lpos" => parse_lpos(&inner_resp[1..]),
+                    "linsert" => match bytes_args(&inner_resp[1..])[..] {
+                        [key, place, pivot, element] => {
+                            match String::from_utf8_lossy(place).to_uppercase().as_str() {
+                                place @ ("BEFORE" | "AFTER") => Command::LInsert {
+                                    key: key.to_vec(),
+                                    before: place == "BEFORE",
+                                    pivot: pivot.to_vec(),
+                                    element: element.to_vec(),
+                                },
+                                _ => Command::Error(RedisError::Err("syntax error".to_string())),
+                            }
+                        }
+                        _ => unknown(),
+                    },
+                    "rpoplpush" => match bytes_args(&inner_resp[1..])[..] {
+                        [src, dst] => Command::RPopLPush {
+                            src: src.to_vec(),
+                            dst: dst.to_vec(),
+                        },
+                        _ => unknown(),
+                    },
+                    "lmove" => match bytes_args(&inner_resp[1..])[..] {
+                        [src, dst, from, to] => match (ListEnd::parse(from), ListEnd::parse(to)) {
+                            (Some(from), Some(to)) => Command::LMove {
+                                src: src.to_vec(),
+                                dst: dst.to_vec(),
+                                from,
+                                to,
+                            },
+                            _ => Command::Error(RedisError::Err("syntax error".to_string())),
+                        },
+                        _ => unknown(),
+                    },
+                    "zadd" => parse_zadd(&inner_resp[1..]),
+                    "zscore" | "zrank" => match bytes_args(&inner_resp[1..])[..] {
+                        [key, member] if name == "zscore" => Command::ZScore {
+                            key: key.to_vec(),
+                            member: member.to_vec(),
+                        },
+                        [key, member] => Command::ZRank {
+                            key: key.to_vec(),
+                            member: member.to_vec(),
+                        },
+                        _ => unknown(),
+                    },
+                    "zrange" => parse_zrange(&inner_resp[1..]),
+                    "zrangestore" => parse_zrangestore(&inner_resp[1..]),
+                    "zrangebyscore" => parse_zrangebyscore(&inner_resp[1..]),
+                    "zrangebylex" | "zlexcount" => parse_zrangebylex(&name, &inner_resp[1..]),
+                    "zrem" => match &bytes_args(&inner_resp[1..])[..] {
+                        [key, members @ ..] if !members.is_empty() => Command::ZRem {
+                            key: key.to_vec(),
+                            members: members.iter().map(|member| member.to_vec()).collect(),
+                        },
+                        _ => unknown(),
+                    },
+                    "zcard" => match bytes_args(&inner_resp[1..])[..] {
+                        [key] => Command::ZCard(key.to_vec()),
+                        _ => unknown(),
+                    },
+                    "zrandmember" => parse_zrandmember(&inner_resp[1..]),
+                    "hset" => match &bytes_args(&inner_resp[1..])[..] {
+                        [key, pairs @ ..] if pairs.len().is_multiple_of(2) => Command::HSet {
+                            key: key.to_vec(),
+                            fields: pairs
+                                .chunks(2)
+                                .map(|pair| (pair[0].to_vec(), pair[1].to_vec()))
+                                .collect(),
+                        },
+                        _ => Command::Error(RedisError::Err(
+                            "wrong number of arguments for 'hset' command".to_string(),
+                        )),
+                    },
+                    "hget" => match bytes_args(&inner_resp[1..])[..] {
+                        [key, field] => Command::HGet {
+                            key: key.to_vec(),
+                            field: field.to_vec(),
+                        },
+                        _ => unknown(),
+                    },
+                    "hgetall" => match bytes_args(&inner_resp[1..])[..] {
+                        [key] => Command::HGetAll(key.to_vec()),
+                        _ => unknown(),
+                    },
+                    "hdel" => match &bytes_args(&inner_resp[1..])[..] {
+                        [key, fields @ ..] if !fields.is_empty() => Command::HDel {
+                            key: key.to_vec(),
+                            fields: fields.iter().map(|field| field.to_vec()).collect(),
+                        },
+                        _ => unknown(),
+                    },
+                    "xadd" => parse_xadd(&inner_resp[1..]),
+                    "xrange" => parse_xrange(&inner_resp[1..]),
+                    "xlen" => match bytes_args(&inner_resp[1..])[..] {
+                        [key] => Command::XLen(key.to_vec()),
+                        _ => unknown(),
+                    },
+                    "xread" => parse_xread(&inner_resp[1..]),
+                    "lset" | "lrem" => match bytes_args(&inner_resp[1..])[..] {
+                        [key, number, element] => {
+                            match String::from_utf8_lossy(number).parse::<i64>() {
+                                Ok(index) if name == "lset" => Command::LSet {
+                                    key: key.to_vec(),
+                                    index,
+                                    element: element.to_vec(),
+                                },
+                                Ok(count) => Command::LRem {
+                                    key: key.to_vec(),
+                                    count,
+                                    element: element.to_vec(),
+                                },
+                                Err(_) => Command::Error(RedisError::Err(
+                                    "value is not an integer or out of range".to_string(),
+                                )),
+                            }
+                        }
+                        _ => unknown(),
+                    },
+                    "getex" => parse_getex(&inner_resp[1..]),
+                    "getdel" => match bytes_args(&inner_resp[1..])[..] {
+                        [key] => Command::GetDel(key.to_vec()),
+                        _ => unknown(),
+                    },
+                    "setrange" => match bytes_args(&inner_resp[1..])[..] {
+                        [key, offset, value] => {
+                            match String::from_utf8_lossy(offset).parse::<i64>() {
+                                Ok(offset) if offset >= 0 => Command::SetRange {
+                                    key: key.to_vec(),
+                                    offset: offset as usize,
+                                    value: value.to_vec(),
+                                },
+                                Ok(_) => Command::Error(RedisError::Err(
+                                    "offset is out of range".to_string(),
+                                )),
+                                Err(_) => Command::Error(RedisError::Err(
+                                    "value is not an integer or out of range".to_string(),
+                                )),
+                            }
+                        }
+                        _ => unknown(),
+                    },
+                    "setbit" => match &bytes_args(&inner_resp[1..])[..] {
+                        &[key, offset, bit] => {
+                            let offset = parse_bit_offset(&String::from_utf8_lossy(offset));
+                            match (offset, bit) {
+                                (None, _) => Command::Error(RedisError::Err(
+                                    "bit offset is not an integer or out of range".to_string(),
+                                )),
+                                (Some(offset), b"0" | b"1") => Command::SetBit {
+                                    key: key.to_vec(),
+                                    offset,
+                                    bit: bit == b"1",
+                                },
+                                _ => Command::Error(RedisError::Err(
+                                    "bit is not an integer or out of range".to_string(),
+                                )),
+                            }
+                        }
+                        _ => unknown(),
+                    },
+                    "getbit" => match &bytes_args(&inner_resp[1..])[..] {
+                        &[key, offset] => {
+                            match parse_bit_offset(&String::from_utf8_lossy(offset)) {
+                                Some(offset) => Command::GetBit {
+                                    key: key.to_vec(),
+                                    offset,
+                                },
+                                None => Command::Error(RedisError::Err(
+                                    "bit offset is not an integer or out of range".to_string(),
+                                )),
+                            }
+                        }
+                        _ => unknown(),
+                    },
+                    "bitcount" => match bytes_args(&inner_resp[1..])[..] {
+                        [key] => Command::BitCount {
+                            key: key.to_vec(),
+                            range: None,
+                        },
+                        [key, start, end] => match (
+                            String::from_utf8_lossy(start).parse::<i64>(),
+                            String::from_utf8_lossy(end).parse::<i64>(),
+                        ) {
+                            (Ok(start), Ok(end)) => Command::BitCount {
+                                key: key.to_vec(),
+                                range: Some((start, end)),
+                            },
+                            _ => Command::Error(RedisError::Err(
+                                "value is not an integer or out of range".to_string(),
+                            )),
+                        },
+                        _ => Command::Error(RedisError::Err("syntax error".to_string())),
+                    },
+                    "getrange" => match &bytes_args(&inner_resp[1..])[..] {
+                        &[key, start, end] => match (
+                            String::from_utf8_lossy(start).parse::<i64>(),
+                            String::from_utf8_lossy(end).parse::<i64>(),
+                        ) {
+                            (Ok(start), Ok(end)) => Command::GetRange {
+                                key: key.to_vec(),
+                                start,
+                                end,
+                            },
+                            _ => Command::Error(RedisError::Err(
+                                "value is not an integer or out of range".to_string(),
+                            )),
+                        },
+                        _ => unknown(),
+                    },
+                    "del" => {
+                        let keys = bytes_args(&inner_resp[1..])
+                            .into_iter()
+                            .map(<[u8]>::to_vec)
+                            .collect::<Vec<Vec<u8>>>();
+                        if keys.is_empty() {
+                            unknown()
                         } else {
-                            Command::Unknown
+                            Command::Del(keys)
                         }
                     }
+                    "touch" => Command::Touch(
+                        bytes_args(&inner_resp[1..])
+                            .into_iter()
+                            .map(<[u8]>::to_vec)
+                            .collect(),
+                    ),
                     "config" => {
                         if let Some(RespType::BulkString(subcommand)) = inner_resp.get(1) {
                             match subcommand.to_lowercase().as_str() {
                                 "get" => {
-                                    if let Some(RespType::BulkString(key)) = inner_resp.get(2) {
-                                        Command::ConfigGet(key.clone())
+                                    let patterns = string_args(&inner_resp[2..])
+                                        .into_iter()
+                                        .map(String::from)
+                                        .collect::<Vec<String>>();
+                                    if patterns.is_empty() {
+                                        unknown()
                                     } else {
-                                        Command::Unknown
+                                        Command::ConfigGet(patterns)
                                     }
                                 }
-                                _ => Command::Unknown,
+                                "set" => match (inner_resp.get(2), inner_resp.get(3)) {
+                                    (
+                                        Some(RespType::BulkString(param)),
+                                        Some(RespType::BulkString(value)),
+                                    ) => Command::ConfigSet {
+                                        param: param.clone(),
+                                        value: value.clone(),
+                                    },
+                                    _ => unknown(),
+                                },
+                                _ => unknown(),
                             }
                         } else {
-                            Command::Unknown
+                            unknown()
                         }
                     }
-                    "keys" => {
-                        if let Some(RespType::BulkString(key_value)) = inner_resp.get(0) {
-                            Command::Keys(key_value.clone())
-                        } else {
-                            Command::Unknown
+                    "keys" => match bytes_args(&inner_resp[1..])[..] {
+                        [pattern] => Command::Keys(pattern.to_vec()),
+                        _ => unknown(),
+                    },
+                    "info" => Command::Info(
+                        string_args(&inner_resp[1..])
+                            .iter()
+                            .map(|section| section.to_lowercase())
+                            .collect(),
+                    ),
+                    "dbsize" => Command::DbSize,
+                    "randomkey" => Command::RandomKey,
+                    "copy" => parse_copy(&inner_resp[1..]),
+                    "dump" => match bytes_args(&inner_resp[1..])[..] {
+                        [key] => Command::Dump(key.to_vec()),
+                        _ => unknown(),
+                    },
+                    "restore" => parse_restore(&inner_resp[1..]),
+                    "append" => match bytes_args(&inner_resp[1..])[..] {
+                        [key, value] => Command::Append {
+                            key: key.to_vec(),
+                            value: value.to_vec(),
+                        },
+                        _ => unknown(),
+                    },
+                    "select" => match string_args(&inner_resp[1..])[..] {
+                        [index] => match index.parse::<i64>() {
+                            Ok(index) if index >= 0 => Command::Select(index as usize),
+                            Ok(_) => Command::Error(RedisError::Err(
+                                "DB index is out of range".to_string(),
+                            )),
+                            Err(_) => Command::Error(RedisError::Err(
+                                "value is not an integer or out of range".to_string(),
+                            )),
+                        },
+                        _ => unknown(),
+                    },
+                    "swapdb" => {
+                        let indexes = string_args(&inner_resp[1..])
+                            .iter()
+                            .map(|index| index.parse::<i64>())
+                            .collect::<Vec<_>>();
+                        match indexes[..] {
+                            [Err(_), _] => Command::Error(RedisError::Err(
+                                "invalid first DB index".to_string(),
+                            )),
+                            [_, Err(_)] => Command::Error(RedisError::Err(
+                                "invalid second DB index".to_string(),
+                            )),
+                            [Ok(first), Ok(second)] if first >= 0 && second >= 0 => {
+                                Command::SwapDb(first as usize, second as usize)
+                            }
+                            [Ok(_), Ok(_)] => Command::Error(RedisError::Err(
+                                "DB index is out of range".to_string(),
+                            )),
+                            _ => unknown(),
                         }
                     }
-                    "info" => Command::Info,
+                    "object" => match (inner_resp.get(1), &bytes_args(&inner_resp[2..])[..]) {
+                        (Some(RespType::BulkString(subcommand)), &[key]) => Command::Object {
+                            subcommand: subcommand.to_lowercase(),
+                            key: key.to_vec(),
+                        },
+                        _ => unknown(),
+                    },
+                    "xinfo" => match (inner_resp.get(1), &bytes_args(&inner_resp[2..])[..]) {
+                        (Some(RespType::BulkString(subcommand)), &[key]) => Command::XInfo {
+                            subcommand: subcommand.to_lowercase(),
+                            key: key.to_vec(),
+                        },
+                        _ => unknown(),
+                    },
                     "replconf" => {
                         if let Some(RespType::BulkString(subcommand)) = inner_resp.get(1) {
                             match subcommand.to_lowercase().as_str() {
@@ -121,7 +1223,7 @@ impl Command {
                                     if let Some(RespType::BulkString(port)) = inner_resp.get(2) {
                                         Command::ReplConf(format!("listening-port {}", port))
                                     } else {
-                                        Command::Unknown
+                                        unknown()
                                     }
                                 }
                                 "capa" => {
@@ -131,170 +1233,6878 @@ impl Command {
                                         if capability.to_lowercase() == "psync2" {
                                             Command::ReplConf("capa psync2".to_string())
                                         } else {
-                                            Command::Unknown
+                                            unknown()
                                         }
                                     } else {
-                                        Command::Unknown
+                                        unknown()
                                     }
                                 }
                                 "getack" => Command::ReplConf("getack".to_string()),
-                                _ => Command::Unknown,
+                                _ => unknown(),
+                            }
+                        } else if inner_resp.len() == 1 {
+                            // Like Redis, a bare REPLCONF sets nothing and
+                            // replies OK.
+                            Command::ReplConf(String::new())
+                        } else {
+                            unknown()
+                        }
+                    }
+                    "psync" => match string_args(&inner_resp[1..])[..] {
+                        [replid, offset] => match offset.parse::<i64>() {
+                            Ok(offset) => Command::PSync {
+                                replid: replid.to_string(),
+                                offset,
+                            },
+                            Err(_) => Command::Error(RedisError::Err(
+                                "value is not an integer or out of range".to_string(),
+                            )),
+                        },
+                        _ => unknown(),
+                    },
+                    "client" => {
+                        if let Some(RespType::BulkString(subcommand)) = inner_resp.get(1) {
+                            match subcommand.to_lowercase().as_str() {
+                                "setinfo" => match (inner_resp.get(2), inner_resp.get(3)) {
+                                    (
+                                        Some(RespType::BulkString(attr)),
+                                        Some(RespType::BulkString(value)),
+                                    ) => Command::ClientSetInfo {
+                                        attr: attr.clone(),
+                                        value: value.clone(),
+                                    },
+                                    _ => unknown(),
+                                },
+                                "info" => Command::ClientInfo,
+                                "list" => Command::ClientList,
+                                "setname" => match inner_resp.get(2) {
+                                    Some(RespType::BulkString(name)) => {
+                                        Command::ClientSetName(name.clone())
+                                    }
+                                    _ => unknown(),
+                                },
+                                "getname" => Command::ClientGetName,
+                                "id" => Command::ClientId,
+                                "kill" => parse_client_kill(&inner_resp[2..]),
+                                _ => unknown(),
+                            }
+                        } else {
+                            unknown()
+                        }
+                    }
+                    "scan" => parse_scan(&inner_resp[1..]),
+                    "bitfield" => parse_bitfield(&inner_resp[1..]),
+                    // redis-cli asks for COMMAND DOCS on startup to drive its hints.
+                    "command" => match inner_resp.get(1) {
+                        Some(RespType::BulkString(subcommand)) => {
+                            match subcommand.to_lowercase().as_str() {
+                                "count" => Command::Count,
+                                "docs" => Command::Docs,
+                                _ => unknown(),
+                            }
+                        }
+                        _ => Command::Docs,
+                    },
+                    "wait" => match string_args(&inner_resp[1..])[..] {
+                        [numreplicas, timeout] => {
+                            match (numreplicas.parse::<usize>(), timeout.parse::<i64>()) {
+                                (Ok(_), Ok(timeout)) if timeout < 0 => Command::Error(
+                                    RedisError::Err("timeout is negative".to_string()),
+                                ),
+                                (Ok(numreplicas), Ok(timeout)) => Command::Wait {
+                                    numreplicas,
+                                    timeout: timeout as u64,
+                                },
+                                _ => Command::Error(RedisError::Err(
+                                    "value is not an integer or out of range".to_string(),
+                                )),
+                            }
+                        }
+                        _ => unknown(),
+                    },
+                    "quit" => Command::Quit,
+                    "monitor" => Command::Monitor,
+                    "reset" => Command::Reset,
+                    "memory" => match inner_resp.get(1) {
+                        Some(RespType::BulkString(subcommand))
+                            if subcommand.eq_ignore_ascii_case("purge") =>
+                        {
+                            Command::MemoryPurge
+                        }
+                        _ => unknown(),
+                    },
+                    "slowlog" => match &string_args(&inner_resp[1..])[..] {
+                        [subcommand] if subcommand.eq_ignore_ascii_case("get") => {
+                            Command::SlowLogGet(Some(10))
+                        }
+                        [subcommand, count] if subcommand.eq_ignore_ascii_case("get") => {
+                            match count.parse::<i64>() {
+                                Ok(-1) => Command::SlowLogGet(None),
+                                Ok(count) if count >= 0 => {
+                                    Command::SlowLogGet(Some(count as usize))
+                                }
+                                Ok(_) => Command::Error(RedisError::Err(
+                                    "count should be greater than or equal to -1".to_string(),
+                                )),
+                                Err(_) => Command::Error(RedisError::Err(
+                                    "value is not an integer or out of range".to_string(),
+                                )),
+                            }
+                        }
+                        [subcommand] if subcommand.eq_ignore_ascii_case("len") => {
+                            Command::SlowLogLen
+                        }
+                        [subcommand] if subcommand.eq_ignore_ascii_case("reset") => {
+                            Command::SlowLogReset
+                        }
+                        _ => unknown(),
+                    },
+                    "debug" => match &bytes_args(&inner_resp[1..])[..] {
+                        [subcommand, seconds] if subcommand.eq_ignore_ascii_case(b"sleep") => {
+                            match String::from_utf8_lossy(seconds)
+                                .parse::<f64>()
+                                .map(Duration::try_from_secs_f64)
+                            {
+                                Ok(Ok(duration)) => Command::DebugSleep(duration),
+                                _ => Command::Error(RedisError::Err(
+                                    "value is not a valid float".to_string(),
+                                )),
+                            }
+                        }
+                        [subcommand, key] if subcommand.eq_ignore_ascii_case(b"object") => {
+                            Command::DebugObject(key.to_vec())
+                        }
+                        [subcommand, ..] => {
+                            Command::Debug(String::from_utf8_lossy(subcommand).to_lowercase())
+                        }
+                        [] => unknown(),
+                    },
+                    "cluster" => {
+                        let args = string_args(&inner_resp[1..])
+                            .into_iter()
+                            .map(String::from)
+                            .collect::<Vec<String>>();
+                        if args.is_empty() {
+                            Command::Error(RedisError::Err(
+                                "wrong number of arguments for 'cluster' command".to_string(),
+                            ))
+                        } else {
+                            Command::Cluster(args)
+                        }
+                    }
+                    "hello" => match string_args(&inner_resp[1..])[..] {
+                        [] => Command::Hello(None),
+                        [protover] => match protover.parse::<i64>() {
+                            Ok(protover @ (2 | 3)) => Command::Hello(Some(protover as u8)),
+                            Ok(_) => Command::Error(RedisError::NoProto),
+                            Err(_) => Command::Error(RedisError::Err(
+                                "Protocol version is not an integer or out of range".to_string(),
+                            )),
+                        },
+                        _ => Command::Error(RedisError::Err("syntax error".to_string())),
+                    },
+                    "multi" => Command::Multi,
+                    "exec" => Command::Exec,
+                    "discard" => Command::Discard,
+                    "watch" => {
+                        let keys = bytes_args(&inner_resp[1..])
+                            .into_iter()
+                            .map(<[u8]>::to_vec)
+                            .collect::<Vec<Vec<u8>>>();
+                        if keys.is_empty() {
+                            unknown()
+                        } else {
+                            Command::Watch(keys)
+                        }
+                    }
+                    "unwatch" => Command::Unwatch,
+                    "subscribe" | "psubscribe" | "ssubscribe" => {
+                        let channels = string_args(&inner_resp[1..])
+                            .into_iter()
+                            .map(String::from)
+                            .collect::<Vec<String>>();
+                        if channels.is_empty() {
+                            unknown()
+                        } else if name == "subscribe" {
+                            Command::Subscribe(channels)
+                        } else if name == "psubscribe" {
+                            Command::PSubscribe(channels)
+                        } else {
+                            Command::SSubscribe(channels)
+                        }
+                    }
+                    "unsubscribe" | "punsubscribe" | "sunsubscribe" => {
+                        let channels = string_args(&inner_resp[1..])
+                            .into_iter()
+                            .map(String::from)
+                            .collect::<Vec<String>>();
+                        if name == "unsubscribe" {
+                            Command::Unsubscribe(channels)
+                        } else if name == "punsubscribe" {
+                            Command::PUnsubscribe(channels)
+                        } else {
+                            Command::SUnsubscribe(channels)
+                        }
+                    }
+                    "publish" | "spublish" => match (inner_resp.get(1), inner_resp.get(2)) {
+                        (
+                            Some(RespType::BulkString(channel)),
+                            Some(RespType::BulkString(message)),
+                        ) => {
+                            let (channel, message) = (channel.clone(), message.clone());
+                            if name == "publish" {
+                                Command::Publish { channel, message }
+                            } else {
+                                Command::SPublish { channel, message }
+                            }
+                        }
+                        _ => unknown(),
+                    },
+                    "pubsub" => {
+                        let args = string_args(&inner_resp[1..]);
+                        match args.split_first() {
+                            Some((subcommand, rest)) => {
+                                let subcommand = subcommand.to_lowercase();
+                                let shard = subcommand.starts_with("shard");
+                                match (subcommand.as_str(), rest) {
+                                    ("channels" | "shardchannels", [] | [_]) => {
+                                        Command::PubSubChannels {
+                                            pattern: rest.first().map(|p| p.to_string()),
+                                            shard,
+                                        }
+                                    }
+                                    ("numsub" | "shardnumsub", channels) => Command::PubSubNumSub {
+                                        channels: channels.iter().map(|c| c.to_string()).collect(),
+                                        shard,
+                                    },
+                                    ("numpat", []) => Command::PubSubNumPat,
+                                    _ => unknown(),
+                                }
+                            }
+                            None => unknown(),
+                        }
+                    }
+                    "flushdb" | "flushall" => {
+                        // ASYNC/SYNC only change how the memory is freed, so accept and ignore them.
+                        let valid_mode = match inner_resp.get(1) {
+                            Some(RespType::BulkString(mode)) => {
+                                matches!(mode.to_lowercase().as_str(), "async" | "sync")
                             }
+                            _ => true,
+                        };
+
+                        if !valid_mode || inner_resp.len() > 2 {
+                            unknown()
+                        } else if name == "flushdb" {
+                            Command::FlushDb
                         } else {
-                            Command::Unknown
+                            Command::FlushAll
                         }
                     }
-                    "psync" => Command::PSync,
-                    _ => Command::Unknown,
+                    _ => unknown(),
                 }
             } else {
-                Command::Unknown
+                Command::unknown("", &[])
             }
         } else {
-            Command::Unknown
+            Command::unknown("", &[])
         }
     }
-}
 
-pub async fn handle_command(
-    command: &Command,
-    stream: &mut TcpStream,
-    in_memory: &mut Arc<Mutex<Database>>,
-    config: &Arc<Config>,
-) -> Option<String> {
-    match command {
-        Command::Ping => Some(RespType::SimpleString("PONG".to_string()).serialize()),
-        Command::Echo(msg) => Some(RespType::BulkString(msg.clone()).serialize()),
-        Command::Set { key, value, ttl } => handle_set(key, value, ttl, in_memory, config).await,
-        Command::Get(key) => handle_get(key, in_memory),
-        Command::ConfigGet(key) => handle_config_get(key, in_memory),
-        Command::Keys(_) => handle_keys(in_memory),
-        Command::Info => handle_info(in_memory),
-        Command::ReplConf(message) => {
-            if message == "getack" {
-                let ack_response = RespType::Array(vec![
-                    RespType::BulkString("REPLCONF".to_string()),
-                    RespType::BulkString("ACK".to_string()),
-                    RespType::BulkString("0".to_string()),
-                ]);
-                Some(ack_response.serialize())
-            } else {
-                Some(RespType::SimpleString("OK".to_string()).serialize())
-            }
-        }
-        Command::PSync => {
-            handle_psync(stream, config).await;
-            None
-        }
-        Command::Unknown => {
-            Some(RespType::SimpleString("-ERR Unknown command".to_string()).serialize())
+    fn unknown(name: &str, args: &[RespType]) -> Command {
+        Command::Unknown {
+            name: name.to_string(),
+            args: bytes_args(args)
+                .into_iter()
+                .map(|arg| String::from_utf8_lossy(arg).into_owned())
+                .collect(),
         }
     }
 }
 
-async fn handle_set(
-    key: &String,
-    value: &String,
-    ttl: &Option<u64>,
-    in_memory: &mut Arc<Mutex<Database>>,
-    config: &Arc<Config>,
-) -> Option<String> {
-    let item = Item::new(value.clone(), ttl.map(Duration::from_millis));
-    in_memory.lock().unwrap().storage.insert(key.clone(), item);
-
-    if config.role == Role::Master {
-        let set_command = RespType::Array(vec![
-            RespType::BulkString("SET".to_string()),
-            RespType::BulkString(key.clone()),
-            RespType::BulkString(value.clone()),
-        ])
-        .serialize();
+fn string_args(args: &[RespType]) -> Vec<&str> {
+    args.iter()
+        .filter_map(|arg| match arg {
+            RespType::BulkString(s) => Some(s.as_str()),
+            _ => None,
+        })
+        .collect()
+}
 
-        println!(
-            "Replicas: {:?}",
-            config.replication_manager.replicas.read().await
-        );
-        for replica in &mut *config.replication_manager.replicas.write().await {
-            if let Err(e) = replica.write_all(set_command.as_bytes()).await {
-                eprintln!("Error propagating command to replica: {}", e);
+// Like `string_args`, but keeps arguments that aren't UTF-8. Used for keys
+// and values, which are binary-safe.
+fn bytes_args(args: &[RespType]) -> Vec<&[u8]> {
+    args.iter()
+        .filter_map(|arg| match arg {
+            RespType::BulkString(s) => Some(s.as_bytes()),
+            RespType::BulkBytes(bytes) => Some(bytes.as_slice()),
+            _ => None,
+        })
+        .collect()
+}
+
+fn parse_set(args: &[RespType]) -> Command {
+    let [key, value] = bytes_args(&args[..args.len().min(2)])[..] else {
+        return Command::Error(RedisError::Err(
+            "wrong number of arguments for 'set' command".to_string(),
+        ));
+    };
+    let options = string_args(&args[2..]);
+    if options.len() != args.len() - 2 {
+        return Command::Error(RedisError::Err("syntax error".to_string()));
+    }
+
+    let mut expiry = None;
+    let mut condition = None;
+    let mut get = false;
+    let mut options = options.iter();
+    while let Some(option) = options.next() {
+        let option = option.to_uppercase();
+        match option.as_str() {
+            "NX" | "XX" if condition.is_none() => {
+                condition = Some(if option == "NX" {
+                    SetCondition::Nx
+                } else {
+                    SetCondition::Xx
+                });
             }
-            println!("SET COMMAND REPLICAS: {:?}", set_command.as_bytes());
+            "KEEPTTL" if expiry.is_none() => expiry = Some(SetExpiry::KeepTtl),
+            "GET" => get = true,
+            "EX" | "PX" | "EXAT" | "PXAT" if expiry.is_none() => {
+                let Some(time) = options.next() else {
+                    return Command::Error(RedisError::Err("syntax error".to_string()));
+                };
+                let unit_ms = if option.starts_with('E') { 1000 } else { 1 };
+                let time = match parse_expire_time(time, unit_ms, "set") {
+                    Ok(time) => time,
+                    Err(error) => return error,
+                };
+                expiry = Some(match option.as_str() {
+                    "EX" => SetExpiry::Ex(time),
+                    "PX" => SetExpiry::Px(time),
+                    "EXAT" => SetExpiry::ExAt(time),
+                    _ => SetExpiry::PxAt(time),
+                });
+            }
+            _ => return Command::Error(RedisError::Err("syntax error".to_string())),
         }
     }
-    Some(RespType::SimpleString("OK".to_string()).serialize())
+
+    Command::Set {
+        key: key.to_vec(),
+        value: value.to_vec(),
+        expiry,
+        condition,
+        get,
+    }
 }
 
-fn handle_get(key: &String, in_memory: &mut Arc<Mutex<Database>>) -> Option<String> {
-    match in_memory.lock().unwrap().storage.get(key) {
-        Some(item) if !item.is_expired() => {
-            Some(RespType::SimpleString(item.value.to_string()).serialize())
+// GETEX key [EX seconds | PX milliseconds | EXAT timestamp |
+// PXAT milliseconds-timestamp | PERSIST]
+fn parse_getex(args: &[RespType]) -> Command {
+    let [key] = bytes_args(&args[..1])[..] else {
+        return Command::Error(RedisError::Err("syntax error".to_string()));
+    };
+    let option = match &string_args(&args[1..])[..] {
+        [] => None,
+        [persist] if persist.eq_ignore_ascii_case("persist") => Some(GetExOption::Persist),
+        [option, time] => {
+            let option = option.to_uppercase();
+            if !matches!(option.as_str(), "EX" | "PX" | "EXAT" | "PXAT") {
+                return Command::Error(RedisError::Err("syntax error".to_string()));
+            }
+            let unit_ms = if option.starts_with('E') { 1000 } else { 1 };
+            let time = match parse_expire_time(time, unit_ms, "getex") {
+                Ok(time) => time,
+                Err(error) => return error,
+            };
+            Some(GetExOption::Expire(match option.as_str() {
+                "EX" => SetExpiry::Ex(time),
+                "PX" => SetExpiry::Px(time),
+                "EXAT" => SetExpiry::ExAt(time),
+                _ => SetExpiry::PxAt(time),
+            }))
         }
-        _ => Some(RespType::NullBulkString.serialize()),
+        _ => return Command::Error(RedisError::Err("syntax error".to_string())),
+    };
+    Command::GetEx {
+        key: key.to_vec(),
+        option,
     }
 }
 
-fn handle_config_get(key: &String, in_memory: &mut Arc<Mutex<Database>>) -> Option<String> {
-    let db = in_memory.lock().unwrap();
-    match key.as_str() {
-        "dir" => db.config.dir.as_ref().map(|dir| {
-            RespType::Array(vec![
-                RespType::BulkString("dir".to_string()),
-                RespType::BulkString(dir.clone()),
-            ])
-            .serialize()
-        }),
-        "dbfilename" => db.config.dbfilename.as_ref().map(|dbfilename| {
-            RespType::Array(vec![
-                RespType::BulkString("dbfilename".to_string()),
-                RespType::BulkString(dbfilename.clone()),
-            ])
-            .serialize()
-        }),
-        _ => None,
+// Validates the expire time given to SET, SETEX, PSETEX or GETEX the way
+// Redis does: an integer above zero that doesn't overflow once turned into a
+// millisecond deadline. `unit_ms` is 1000 for seconds and 1 for milliseconds.
+fn parse_expire_time(time: &str, unit_ms: i64, command: &str) -> Result<u64, Command> {
+    let Ok(time) = time.parse::<i64>() else {
+        return Err(Command::Error(RedisError::Err(
+            "value is not an integer or out of range".to_string(),
+        )));
+    };
+    let deadline = time
+        .checked_mul(unit_ms)
+        .and_then(|ms| ms.checked_add(unix_time_ms() as i64));
+    if time <= 0 || deadline.is_none() {
+        return Err(Command::Error(RedisError::Err(format!(
+            "invalid expire time in '{}' command",
+            command
+        ))));
     }
+    Ok(time as u64)
 }
 
-fn handle_keys(in_memory: &mut Arc<Mutex<Database>>) -> Option<String> {
-    let db = in_memory.lock().unwrap();
-    let db_keys = db
-        .storage
-        .keys()
-        .map(|key| RespType::BulkString(key.clone()))
-        .collect::<Vec<RespType>>();
-    Some(RespType::Array(db_keys).serialize())
+// SETEX key seconds value and PSETEX key milliseconds value, as SET.
+fn parse_setex(args: &[RespType], unit_ms: i64, command: &str) -> Command {
+    let [key, time, value] = bytes_args(args)[..] else {
+        return Command::Error(RedisError::Err("syntax error".to_string()));
+    };
+    let Some(time) = std::str::from_utf8(time).ok() else {
+        return Command::Error(RedisError::Err(
+            "value is not an integer or out of range".to_string(),
+        ));
+    };
+    let time = match parse_expire_time(time, unit_ms, command) {
+        Ok(time) => time,
+        Err(error) => return error,
+    };
+    Command::Set {
+        key: key.to_vec(),
+        value: value.to_vec(),
+        expiry: Some(if unit_ms == 1000 {
+            SetExpiry::Ex(time)
+        } else {
+            SetExpiry::Px(time)
+        }),
+        condition: None,
+        get: false,
+    }
 }
 
-fn handle_info(in_memory: &mut Arc<Mutex<Database>>) -> Option<String> {
-    let db = in_memory.lock().unwrap();
-    let role = if db.config.role == Role::Master {
-        "master"
-    } else {
-        "slave"
+// EXPIRE key seconds and PEXPIRE key milliseconds, with an optional NX, XX,
+// GT or LT. Unlike SET, a time of zero or below is allowed and deletes the
+// key; only a deadline that overflows is rejected. With `absolute` the time
+// is a Unix timestamp instead, for EXPIREAT and PEXPIREAT, and one already
+// past deletes the key the same way.
+fn parse_expire(args: &[RespType], unit_ms: i64, absolute: bool, command: &str) -> Command {
+    let Some(&key) = bytes_args(args).first() else {
+        return Command::Error(RedisError::Err("syntax error".to_string()));
     };
-    let response = format!(
-        "role:{}\nmaster_replid:{}\nmaster_repl_offset:0",
-        role, db.config.repl_id
-    );
-    Some(RespType::BulkString(response).serialize())
+    let args = string_args(&args[1..]);
+    let Some(Ok(time)) = args.first().map(|time| time.parse::<i64>()) else {
+        return Command::Error(RedisError::Err(
+            "value is not an integer or out of range".to_string(),
+        ));
+    };
+    let now = unix_time_ms() as i64;
+    let Some(millis) = time
+        .checked_mul(unit_ms)
+        .and_then(|ms| {
+            if absolute {
+                ms.checked_sub(now)
+            } else {
+                Some(ms)
+            }
+        })
+        .filter(|ms| ms.checked_add(now).is_some())
+    else {
+        return Command::Error(RedisError::Err(format!(
+            "invalid expire time in '{}' command",
+            command
+        )));
+    };
+
+    let mut condition = None;
+    for option in &args[1..] {
+        let option = match option.to_uppercase().as_str() {
+            "NX" => ExpireCondition::Nx,
+            "XX" => ExpireCondition::Xx,
+            "GT" => ExpireCondition::Gt,
+            "LT" => ExpireCondition::Lt,
+            _ => return Command::Error(RedisError::Err(format!("Unsupported option {}", option))),
+        };
+        condition = match (condition, option) {
+            (None, option) => Some(option),
+            (Some(previous), option) if previous == option => Some(option),
+            (Some(ExpireCondition::Gt), ExpireCondition::Lt)
+            | (Some(ExpireCondition::Lt), ExpireCondition::Gt) => {
+                return Command::Error(RedisError::Err(
+                    "GT and LT options at the same time are not compatible".to_string(),
+                ))
+            }
+            _ => {
+                return Command::Error(RedisError::Err(
+                    "NX and XX, GT or LT options at the same time are not compatible".to_string(),
+                ))
+            }
+        };
+    }
+
+    Command::Expire {
+        key: key.to_vec(),
+        millis,
+        condition,
+    }
 }
 
-async fn handle_psync(
-    stream: &mut TcpStream,
-    config: &Arc<Config>,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let full_resync =
-        RespType::SimpleString(format!("FULLRESYNC {} 0", config.repl_id)).serialize();
-    stream.write_all(full_resync.as_bytes()).await?;
-    stream.flush().await?;
+// LPOS key element [RANK rank] [COUNT num-matches]
+fn parse_lpos(args: &[RespType]) -> Command {
+    let [key, element] = bytes_args(&args[..2])[..] else {
+        return Command::Error(RedisError::Err("syntax error".to_string()));
+    };
+    let options = string_args(&args[2..]);
+    if options.len() != args.len() - 2 || !options.len().is_multiple_of(2) {
+        return Command::Error(RedisError::Err("syntax error".to_string()));
+    }
 
-    let empty_file = hex::decode("524544495330303131fa0972656469732d76657205372e322e30fa0a72656469732d62697473c040fa056374696d65c26d08bc65fa08757365642d6d656dc2b0c41000fa08616f662d62617365c000fff06e3bfec0ff5aa2")?;
+    let mut rank = 1;
+    let mut count = None;
+    for option in options.chunks(2) {
+        let Ok(value) = option[1].parse::<i64>() else {
+            return Command::Error(RedisError::Err(
+                "value is not an integer or out of range".to_string(),
+            ));
+        };
+        match option[0].to_uppercase().as_str() {
+            // Negating i64::MIN to search from the tail would overflow.
+            "RANK" if value == i64::MIN => {
+                return Command::Error(RedisError::Err(format!(
+                    "value is out of range, value must between {} and {}",
+                    -i64::MAX,
+                    i64::MAX
+                )))
+            }
+            "RANK" if value == 0 => {
+                return Command::Error(RedisError::Err(
+                    "RANK can't be zero: use 1 to start from the first match, 2 from the second ... or use negative to start from the end of the list".to_string(),
+                ))
+            }
+            "RANK" => rank = value,
+            "COUNT" if value < 0 => {
+                return Command::Error(RedisError::Err("COUNT can't be negative".to_string()))
+            }
+            "COUNT" => count = Some(value as usize),
+            _ => return Command::Error(RedisError::Err("syntax error".to_string())),
+        }
+    }
 
-    let rdb_header = format!("${}\r\n", empty_file.len());
-    stream.write_all(rdb_header.as_bytes()).await?;
-    stream.flush().await?;
-    stream.write_all(&empty_file).await?;
-    stream.flush().await?;
+    Command::LPos {
+        key: key.to_vec(),
+        element: element.to_vec(),
+        rank,
+        count,
+    }
+}
 
-    println!("Successfully sent PSYNC response with RDB file");
-    Ok(())
+// ZADD key [NX | XX] [GT | LT] [CH] score member [score member ...]
+fn parse_zadd(args: &[RespType]) -> Command {
+    let [key] = bytes_args(&args[..1])[..] else {
+        return Command::Error(RedisError::Err("syntax error".to_string()));
+    };
+    let args = bytes_args(&args[1..]);
+
+    let mut condition = None;
+    let mut score_condition = None;
+    let mut ch = false;
+    let mut flags = 0;
+    for flag in &args {
+        match String::from_utf8_lossy(flag).to_uppercase().as_str() {
+            "NX" => condition = Some(SetCondition::Nx),
+            "XX" => condition = Some(SetCondition::Xx),
+            "GT" => score_condition = Some(ScoreCondition::Gt),
+            "LT" => score_condition = Some(ScoreCondition::Lt),
+            "CH" => ch = true,
+            _ => break,
+        }
+        flags += 1;
+    }
+    let flag_given = |flag: &str| {
+        args[..flags]
+            .iter()
+            .any(|arg| arg.eq_ignore_ascii_case(flag.as_bytes()))
+    };
+    if flag_given("NX") && flag_given("XX") {
+        return Command::Error(RedisError::Err(
+            "XX and NX options at the same time are not compatible".to_string(),
+        ));
+    }
+    if (flag_given("GT") && flag_given("LT")) || (flag_given("NX") && score_condition.is_some()) {
+        return Command::Error(RedisError::Err(
+            "GT, LT, and/or NX options at the same time are not compatible".to_string(),
+        ));
+    }
+
+    let pairs = &args[flags..];
+    if pairs.is_empty() || !pairs.len().is_multiple_of(2) {
+        return Command::Error(RedisError::Err("syntax error".to_string()));
+    }
+    let mut members = Vec::with_capacity(pairs.len() / 2);
+    for pair in pairs.chunks(2) {
+        let Some(score) = parse_score(&String::from_utf8_lossy(pair[0])) else {
+            return Command::Error(RedisError::Err("value is not a valid float".to_string()));
+        };
+        members.push((Score(score), pair[1].to_vec()));
+    }
+
+    Command::ZAdd {
+        key: key.to_vec(),
+        members,
+        condition,
+        score_condition,
+        ch,
+    }
+}
+
+// ZRANGE key start stop [BYSCORE | BYLEX] [REV] [LIMIT offset count]
+// [WITHSCORES]
+fn parse_zrange(args: &[RespType]) -> Command {
+    let key = bytes_args(&args[..1])[0].to_vec();
+    match parse_zrange_range(&args[1..]) {
+        Ok((range, withscores)) => Command::ZRange {
+            key,
+            range,
+            withscores,
+        },
+        Err(e) => Command::Error(e),
+    }
+}
+
+// ZRANGESTORE dst src start stop [BYSCORE | BYLEX] [REV] [LIMIT offset count]
+fn parse_zrangestore(args: &[RespType]) -> Command {
+    let [dst, src] = bytes_args(&args[..2])[..] else {
+        return Command::Error(RedisError::Err("syntax error".to_string()));
+    };
+    match parse_zrange_range(&args[2..]) {
+        Ok((_, true)) => Command::Error(RedisError::Err("syntax error".to_string())),
+        Ok((range, false)) => Command::ZRangeStore {
+            dst: dst.to_vec(),
+            src: src.to_vec(),
+            range: Box::new(range),
+        },
+        Err(e) => Command::Error(e),
+    }
+}
+
+// The `start stop` and options ZRANGE and ZRANGESTORE share, and whether
+// WITHSCORES was among them. With REV, score and lex ranges are given
+// highest bound first.
+fn parse_zrange_range(args: &[RespType]) -> Result<(ZRange, bool), RedisError> {
+    let syntax_error = || RedisError::Err("syntax error".to_string());
+    let [start, stop] = bytes_args(&args[..2])[..] else {
+        return Err(syntax_error());
+    };
+
+    let mut by_score = false;
+    let mut by_lex = false;
+    let mut rev = false;
+    let mut withscores = false;
+    let mut limit = None;
+    let mut options = string_args(&args[2..]).into_iter();
+    while let Some(option) = options.next() {
+        match option.to_uppercase().as_str() {
+            "BYSCORE" => by_score = true,
+            "BYLEX" => by_lex = true,
+            "REV" => rev = true,
+            "WITHSCORES" => withscores = true,
+            "LIMIT" => {
+                let (Some(offset), Some(count)) = (options.next(), options.next()) else {
+                    return Err(syntax_error());
+                };
+                let (Ok(offset), Ok(count)) = (offset.parse::<i64>(), count.parse::<i64>()) else {
+                    return Err(RedisError::Err(
+                        "value is not an integer or out of range".to_string(),
+                    ));
+                };
+                limit = Some((offset, count));
+            }
+            _ => return Err(syntax_error()),
+        }
+    }
+    if by_score && by_lex {
+        return Err(syntax_error());
+    }
+    if limit.is_some() && !by_score && !by_lex {
+        return Err(RedisError::Err(
+            "syntax error, LIMIT is only supported in combination with either BYSCORE or BYLEX"
+                .to_string(),
+        ));
+    }
+    if withscores && by_lex {
+        return Err(RedisError::Err(
+            "syntax error, WITHSCORES not supported in combination with BYLEX".to_string(),
+        ));
+    }
+
+    let (min, max) = if rev { (stop, start) } else { (start, stop) };
+    let by = if by_score {
+        let (Some(min), Some(max)) = (
+            ScoreBound::parse(&String::from_utf8_lossy(min)),
+            ScoreBound::parse(&String::from_utf8_lossy(max)),
+        ) else {
+            return Err(RedisError::Err("min or max is not a float".to_string()));
+        };
+        ZRangeBy::Score(min, max)
+    } else if by_lex {
+        let (Some(min), Some(max)) = (LexBound::parse(min), LexBound::parse(max)) else {
+            return Err(RedisError::Err(
+                "min or max not valid string range item".to_string(),
+            ));
+        };
+        ZRangeBy::Lex(min, max)
+    } else {
+        let (Ok(start), Ok(stop)) = (
+            String::from_utf8_lossy(start).parse::<i64>(),
+            String::from_utf8_lossy(stop).parse::<i64>(),
+        ) else {
+            return Err(RedisError::Err(
+                "value is not an integer or out of range".to_string(),
+            ));
+        };
+        ZRangeBy::Index(start, stop)
+    };
+
+    Ok((ZRange { by, rev, limit }, withscores))
+}
+
+// ZRANGEBYSCORE key min max [WITHSCORES] [LIMIT offset count]
+fn parse_zrangebyscore(args: &[RespType]) -> Command {
+    let [key, min, max] = bytes_args(&args[..3])[..] else {
+        return Command::Error(RedisError::Err("syntax error".to_string()));
+    };
+    let (Some(min), Some(max)) = (
+        ScoreBound::parse(&String::from_utf8_lossy(min)),
+        ScoreBound::parse(&String::from_utf8_lossy(max)),
+    ) else {
+        return Command::Error(RedisError::Err("min or max is not a float".to_string()));
+    };
+
+    let mut withscores = false;
+    let mut limit = None;
+    let mut options = string_args(&args[3..]).into_iter();
+    while let Some(option) = options.next() {
+        match option.to_uppercase().as_str() {
+            "WITHSCORES" => withscores = true,
+            "LIMIT" => {
+                let (Some(offset), Some(count)) = (options.next(), options.next()) else {
+                    return Command::Error(RedisError::Err("syntax error".to_string()));
+                };
+                let (Ok(offset), Ok(count)) = (offset.parse::<i64>(), count.parse::<i64>()) else {
+                    return Command::Error(RedisError::Err(
+                        "value is not an integer or out of range".to_string(),
+                    ));
+                };
+                limit = Some((offset, count));
+            }
+            _ => return Command::Error(RedisError::Err("syntax error".to_string())),
+        }
+    }
+
+    Command::ZRangeByScore {
+        key: key.to_vec(),
+        min,
+        max,
+        withscores,
+        limit,
+    }
+}
+
+// ZRANDMEMBER key [count [WITHSCORES]]
+fn parse_zrandmember(args: &[RespType]) -> Command {
+    let (key, count, withscores) = match &bytes_args(args)[..] {
+        [key] => (key.to_vec(), None, false),
+        [key, count] => (key.to_vec(), Some(*count), false),
+        [key, count, option] if option.eq_ignore_ascii_case(b"WITHSCORES") => {
+            (key.to_vec(), Some(*count), true)
+        }
+        _ => return Command::Error(RedisError::Err("syntax error".to_string())),
+    };
+    let count = match count.map(|count| String::from_utf8_lossy(count).parse::<i64>()) {
+        None => None,
+        Some(Ok(count)) => Some(count),
+        Some(Err(_)) => {
+            return Command::Error(RedisError::Err(
+                "value is not an integer or out of range".to_string(),
+            ))
+        }
+    };
+
+    Command::ZRandMember {
+        key,
+        count,
+        withscores,
+    }
+}
+
+// ZRANGEBYLEX key min max [LIMIT offset count], or ZLEXCOUNT key min max.
+fn parse_zrangebylex(name: &str, args: &[RespType]) -> Command {
+    let [key, min, max] = bytes_args(&args[..3])[..] else {
+        return Command::Error(RedisError::Err("syntax error".to_string()));
+    };
+    let (Some(min), Some(max)) = (LexBound::parse(min), LexBound::parse(max)) else {
+        return Command::Error(RedisError::Err(
+            "min or max not valid string range item".to_string(),
+        ));
+    };
+    if name == "zlexcount" {
+        return Command::ZLexCount {
+            key: key.to_vec(),
+            min,
+            max,
+        };
+    }
+
+    let limit = match &string_args(&args[3..])[..] {
+        [] => None,
+        [option, offset, count] if option.eq_ignore_ascii_case("LIMIT") => {
+            let (Ok(offset), Ok(count)) = (offset.parse::<i64>(), count.parse::<i64>()) else {
+                return Command::Error(RedisError::Err(
+                    "value is not an integer or out of range".to_string(),
+                ));
+            };
+            Some((offset, count))
+        }
+        _ => return Command::Error(RedisError::Err("syntax error".to_string())),
+    };
+
+    Command::ZRangeByLex {
+        key: key.to_vec(),
+        min,
+        max,
+        limit,
+    }
+}
+
+fn invalid_stream_id() -> Command {
+    Command::Error(RedisError::Err(
+        "Invalid stream ID specified as stream command argument".to_string(),
+    ))
+}
+
+fn parse_xadd(args: &[RespType]) -> Command {
+    let [key, id] = bytes_args(&args[..2])[..] else {
+        return Command::Error(RedisError::Err("syntax error".to_string()));
+    };
+    let Some(id) = XAddId::parse(&String::from_utf8_lossy(id)) else {
+        return invalid_stream_id();
+    };
+    let pairs = bytes_args(&args[2..]);
+    if !pairs.len().is_multiple_of(2) {
+        return Command::Error(RedisError::Err(
+            "wrong number of arguments for 'xadd' command".to_string(),
+        ));
+    }
+
+    Command::XAdd {
+        key: key.to_vec(),
+        id,
+        fields: pairs
+            .chunks(2)
+            .map(|pair| (pair[0].to_vec(), pair[1].to_vec()))
+            .collect(),
+    }
+}
+
+// `-` and `+` are the lowest and highest possible IDs. A bare `<ms>` covers
+// every sequence number in that millisecond, so it starts at `<ms>-0` and
+// ends at the largest sequence number.
+fn parse_xrange(args: &[RespType]) -> Command {
+    let [key, start, end] = bytes_args(&args[..3])[..] else {
+        return Command::Error(RedisError::Err("syntax error".to_string()));
+    };
+    let bound = |text: &[u8], missing_seq| match String::from_utf8_lossy(text).as_ref() {
+        "-" => Some(StreamId::MIN),
+        "+" => Some(StreamId::MAX),
+        text => StreamId::parse(text, missing_seq),
+    };
+    let (Some(start), Some(end)) = (bound(start, 0), bound(end, u64::MAX)) else {
+        return invalid_stream_id();
+    };
+
+    let count = match &string_args(&args[3..])[..] {
+        [] => None,
+        [option, count] if option.eq_ignore_ascii_case("COUNT") => match count.parse::<i64>() {
+            // A negative count returns nothing, like zero.
+            Ok(count) => Some(count.max(0) as usize),
+            Err(_) => {
+                return Command::Error(RedisError::Err(
+                    "value is not an integer or out of range".to_string(),
+                ))
+            }
+        },
+        _ => return Command::Error(RedisError::Err("syntax error".to_string())),
+    };
+
+    Command::XRange {
+        key: key.to_vec(),
+        start,
+        end,
+        count,
+    }
+}
+
+// `[COUNT n] STREAMS key [key ...] id [id ...]`. BLOCK isn't supported.
+fn parse_xread(args: &[RespType]) -> Command {
+    let mut count = None;
+    let mut block = None;
+    let mut index = 0;
+    loop {
+        let Some(RespType::BulkString(option)) = args.get(index) else {
+            return Command::Error(RedisError::Err("syntax error".to_string()));
+        };
+        match option.to_uppercase().as_str() {
+            "COUNT" => match args.get(index + 1) {
+                Some(RespType::BulkString(n)) => match n.parse::<i64>() {
+                    // Zero or less means no limit.
+                    Ok(n) => count = (n > 0).then_some(n as usize),
+                    Err(_) => {
+                        return Command::Error(RedisError::Err(
+                            "value is not an integer or out of range".to_string(),
+                        ))
+                    }
+                },
+                _ => return Command::Error(RedisError::Err("syntax error".to_string())),
+            },
+            "BLOCK" => match args.get(index + 1) {
+                Some(RespType::BulkString(ms)) => match ms.parse::<i64>() {
+                    Ok(ms) if ms < 0 => {
+                        return Command::Error(RedisError::Err("timeout is negative".to_string()))
+                    }
+                    Ok(ms) => block = Some(Duration::from_millis(ms as u64)),
+                    Err(_) => {
+                        return Command::Error(RedisError::Err(
+                            "timeout is not an integer or out of range".to_string(),
+                        ))
+                    }
+                },
+                _ => return Command::Error(RedisError::Err("syntax error".to_string())),
+            },
+            "STREAMS" => break,
+            _ => return Command::Error(RedisError::Err("syntax error".to_string())),
+        }
+        index += 2;
+    }
+
+    let rest = bytes_args(&args[index + 1..]);
+    if rest.is_empty() || !rest.len().is_multiple_of(2) {
+        return Command::Error(RedisError::Err(
+            "Unbalanced 'xread' list of streams: for each stream key an ID or '$' must be specified."
+                .to_string(),
+        ));
+    }
+    let (keys, ids) = rest.split_at(rest.len() / 2);
+    let mut streams = Vec::with_capacity(keys.len());
+    for (key, id) in keys.iter().zip(ids) {
+        let id = match String::from_utf8_lossy(id).as_ref() {
+            "$" => None,
+            id => match StreamId::parse(id, 0) {
+                Some(id) => Some(id),
+                None => return invalid_stream_id(),
+            },
+        };
+        streams.push((key.to_vec(), id));
+    }
+
+    Command::XRead {
+        streams,
+        count,
+        block,
+    }
+}
+
+// Either `CLIENT KILL addr:port`, or filters given as `ID id`, `ADDR
+// addr:port`, `LADDR addr:port` and `SKIPME yes|no` pairs. Filtered kills
+// spare the caller unless given `SKIPME no`.
+fn parse_client_kill(args: &[RespType]) -> Command {
+    let args = string_args(args);
+    if let [addr] = args[..] {
+        return Command::ClientKill {
+            filter: KillFilter {
+                id: None,
+                addr: Some(addr.to_string()),
+                laddr: None,
+                skip_me: false,
+            },
+            legacy: true,
+        };
+    }
+
+    let mut filter = KillFilter {
+        id: None,
+        addr: None,
+        laddr: None,
+        skip_me: true,
+    };
+    for pair in args.chunks(2) {
+        let [option, value] = pair else {
+            return Command::Error(RedisError::Err("syntax error".to_string()));
+        };
+        match option.to_uppercase().as_str() {
+            "ID" => match value.parse::<u64>() {
+                Ok(id) if id > 0 => filter.id = Some(id),
+                _ => {
+                    return Command::Error(RedisError::Err(
+                        "client-id should be greater than 0".to_string(),
+                    ))
+                }
+            },
+            "ADDR" => filter.addr = Some(value.to_string()),
+            "LADDR" => filter.laddr = Some(value.to_string()),
+            "SKIPME" => match value.to_lowercase().as_str() {
+                "yes" => filter.skip_me = true,
+                "no" => filter.skip_me = false,
+                _ => return Command::Error(RedisError::Err("syntax error".to_string())),
+            },
+            _ => return Command::Error(RedisError::Err("syntax error".to_string())),
+        }
+    }
+    Command::ClientKill {
+        filter,
+        legacy: false,
+    }
+}
+
+fn parse_copy(args: &[RespType]) -> Command {
+    let [src, dst] = bytes_args(&args[..2])[..] else {
+        return Command::Error(RedisError::Err("syntax error".to_string()));
+    };
+    let options = string_args(&args[2..]);
+    if options.len() != args.len() - 2 {
+        return Command::Error(RedisError::Err("syntax error".to_string()));
+    }
+
+    let mut db = None;
+    let mut replace = false;
+    let mut options = options.iter();
+    while let Some(option) = options.next() {
+        match option.to_uppercase().as_str() {
+            "REPLACE" => replace = true,
+            "DB" => match options.next().map(|db| db.parse::<i64>()) {
+                Some(Ok(index)) if index >= 0 => db = Some(index as usize),
+                Some(Ok(_)) => {
+                    return Command::Error(RedisError::Err("DB index is out of range".to_string()))
+                }
+                Some(Err(_)) => {
+                    return Command::Error(RedisError::Err(
+                        "value is not an integer or out of range".to_string(),
+                    ))
+                }
+                None => return Command::Error(RedisError::Err("syntax error".to_string())),
+            },
+            _ => return Command::Error(RedisError::Err("syntax error".to_string())),
+        }
+    }
+
+    Command::Copy {
+        src: src.to_vec(),
+        dst: dst.to_vec(),
+        db,
+        replace,
+    }
+}
+
+fn parse_restore(args: &[RespType]) -> Command {
+    let [key, ttl, payload] = bytes_args(&args[..3])[..] else {
+        return Command::Error(RedisError::Err("syntax error".to_string()));
+    };
+    let ttl = match String::from_utf8_lossy(ttl).parse::<i64>() {
+        Ok(ttl) if ttl >= 0 => ttl as u64,
+        Ok(_) => {
+            return Command::Error(RedisError::Err(
+                "Invalid TTL value, must be >= 0".to_string(),
+            ))
+        }
+        Err(_) => {
+            return Command::Error(RedisError::Err(
+                "value is not an integer or out of range".to_string(),
+            ))
+        }
+    };
+    let options = string_args(&args[3..]);
+    if options.len() != args.len() - 3 {
+        return Command::Error(RedisError::Err("syntax error".to_string()));
+    }
+
+    let mut replace = false;
+    let mut absttl = false;
+    let mut options = options.iter();
+    while let Some(option) = options.next() {
+        match option.to_uppercase().as_str() {
+            "REPLACE" => replace = true,
+            "ABSTTL" => absttl = true,
+            // There's no LRU or LFU eviction, so the access hints are only
+            // validated.
+            "IDLETIME" => match options.next().map(|idle| idle.parse::<i64>()) {
+                Some(Ok(idle)) if idle >= 0 => {}
+                Some(Ok(_)) => {
+                    return Command::Error(RedisError::Err(
+                        "Invalid IDLETIME value, must be >= 0".to_string(),
+                    ))
+                }
+                Some(Err(_)) => {
+                    return Command::Error(RedisError::Err(
+                        "value is not an integer or out of range".to_string(),
+                    ))
+                }
+                None => return Command::Error(RedisError::Err("syntax error".to_string())),
+            },
+            "FREQ" => match options.next().map(|freq| freq.parse::<i64>()) {
+                Some(Ok(0..=255)) => {}
+                Some(Ok(_)) => {
+                    return Command::Error(RedisError::Err(
+                        "Invalid FREQ value, must be >= 0 and <= 255".to_string(),
+                    ))
+                }
+                Some(Err(_)) => {
+                    return Command::Error(RedisError::Err(
+                        "value is not an integer or out of range".to_string(),
+                    ))
+                }
+                None => return Command::Error(RedisError::Err("syntax error".to_string())),
+            },
+            _ => return Command::Error(RedisError::Err("syntax error".to_string())),
+        }
+    }
+
+    Command::Restore {
+        key: key.to_vec(),
+        ttl,
+        payload: payload.to_vec(),
+        replace,
+        absttl,
+    }
+}
+
+fn parse_bitfield(args: &[RespType]) -> Command {
+    let Some(&key) = bytes_args(args).first() else {
+        return Command::Error(RedisError::Err(
+            "wrong number of arguments for 'bitfield' command".to_string(),
+        ));
+    };
+    let args = string_args(&args[1..]);
+
+    let mut ops = Vec::new();
+    let mut overflow = Overflow::Wrap;
+    let mut args = args.iter();
+    while let Some(op) = args.next() {
+        let op = op.to_uppercase();
+        if op == "OVERFLOW" {
+            overflow = match args.next().map(|mode| mode.to_uppercase()).as_deref() {
+                Some("WRAP") => Overflow::Wrap,
+                Some("SAT") => Overflow::Sat,
+                Some("FAIL") => Overflow::Fail,
+                Some(_) => {
+                    return Command::Error(RedisError::Err(
+                        "Invalid OVERFLOW type specified".to_string(),
+                    ))
+                }
+                None => return Command::Error(RedisError::Err("syntax error".to_string())),
+            };
+            continue;
+        }
+
+        let takes_value = match op.as_str() {
+            "GET" => false,
+            "SET" | "INCRBY" => true,
+            _ => return Command::Error(RedisError::Err("syntax error".to_string())),
+        };
+        let (Some(encoding), Some(offset)) = (args.next(), args.next()) else {
+            return Command::Error(RedisError::Err("syntax error".to_string()));
+        };
+        let Some(field) = BitFieldType::parse(encoding) else {
+            return Command::Error(RedisError::Err(
+                "Invalid bitfield type. Use something like i16 u8. Note that u64 is not supported but i64 is.".to_string(),
+            ));
+        };
+        let Some(offset) = parse_offset(offset, field) else {
+            return Command::Error(RedisError::Err(
+                "bit offset is not an integer or out of range".to_string(),
+            ));
+        };
+
+        if !takes_value {
+            ops.push(BitFieldOp::Get { field, offset });
+            continue;
+        }
+        let Some(value) = args.next() else {
+            return Command::Error(RedisError::Err("syntax error".to_string()));
+        };
+        let Ok(value) = value.parse::<i64>() else {
+            return Command::Error(RedisError::Err(
+                "value is not an integer or out of range".to_string(),
+            ));
+        };
+        ops.push(if op == "SET" {
+            BitFieldOp::Set {
+                field,
+                offset,
+                value,
+                overflow,
+            }
+        } else {
+            BitFieldOp::IncrBy {
+                field,
+                offset,
+                increment: value,
+                overflow,
+            }
+        });
+    }
+
+    Command::BitField {
+        key: key.to_vec(),
+        ops,
+    }
+}
+
+fn parse_scan(args: &[RespType]) -> Command {
+    let args = bytes_args(args);
+
+    let Some(cursor) = args.first() else {
+        return Command::Error(RedisError::Err(
+            "wrong number of arguments for 'scan' command".to_string(),
+        ));
+    };
+    let Ok(cursor) = String::from_utf8_lossy(cursor).parse::<usize>() else {
+        return Command::Error(RedisError::Err("invalid cursor".to_string()));
+    };
+
+    let mut pattern = None;
+    let mut count = 10;
+    for option in args[1..].chunks(2) {
+        match option {
+            [name, value] if name.eq_ignore_ascii_case(b"match") => {
+                pattern = Some(value.to_vec());
+            }
+            [name, value] if name.eq_ignore_ascii_case(b"count") => {
+                match String::from_utf8_lossy(value).parse::<usize>() {
+                    Ok(value) if value > 0 => count = value,
+                    Ok(_) => return Command::Error(RedisError::Err("syntax error".to_string())),
+                    Err(_) => {
+                        return Command::Error(RedisError::Err(
+                            "value is not an integer or out of range".to_string(),
+                        ))
+                    }
+                }
+            }
+            _ => return Command::Error(RedisError::Err("syntax error".to_string())),
+        }
+    }
+
+    Command::Scan {
+        cursor,
+        pattern,
+        count,
+    }
+}
+
+pub async fn handle_command<S: AsyncRead + AsyncWrite + Unpin>(
+    command: &Command,
+    stream: &mut S,
+    in_memory: &mut Arc<RwLock<Database>>,
+    config: &Arc<Config>,
+    client: &mut ClientState,
+) -> Option<Vec<u8>> {
+    // RESP2 connections with subscriptions only take pub/sub commands. RESP3
+    // can interleave pushed messages with ordinary replies.
+    if client.in_pubsub_mode()
+        && !matches!(
+            command,
+            Command::Subscribe(_)
+                | Command::Unsubscribe(_)
+                | Command::PSubscribe(_)
+                | Command::PUnsubscribe(_)
+                | Command::SSubscribe(_)
+                | Command::SUnsubscribe(_)
+                | Command::Ping(_)
+                | Command::Quit
+                | Command::Reset
+                | Command::Error(_)
+                | Command::Unknown { .. }
+        )
+    {
+        return Some(
+            RespType::Error(RedisError::Err(format!(
+                "Can't execute '{}': only (P|S)SUBSCRIBE / (P|S)UNSUBSCRIBE / PING / QUIT / RESET are allowed in this context",
+                command.name()
+            )))
+            .serialize(),
+        );
+    }
+
+    if client.transaction.is_some()
+        && !matches!(
+            command,
+            Command::Multi
+                | Command::Exec
+                | Command::Discard
+                | Command::Watch(_)
+                | Command::Quit
+                | Command::Reset
+        )
+    {
+        return queue_command(command, client);
+    }
+
+    match command {
+        // Subscribed connections get a ["pong", message] array instead.
+        Command::Ping(message) if client.in_pubsub_mode() => Some(
+            RespType::Array(vec![
+                RespType::BulkString("pong".to_string()),
+                RespType::BulkString(message.clone().unwrap_or_default()),
+            ])
+            .serialize(),
+        ),
+        Command::Ping(Some(message)) => Some(RespType::BulkString(message.clone()).serialize()),
+        Command::Ping(None) => Some(RespType::SimpleString("PONG".to_string()).serialize()),
+        Command::Echo(msg) => Some(RespType::BulkBytes(msg.clone()).serialize()),
+        Command::Set {
+            key,
+            value,
+            expiry,
+            condition,
+            get,
+        } => Some(
+            handle_set(key, value, expiry, condition, *get, in_memory, config)
+                .await
+                .serialize_for(client.protocol),
+        ),
+        Command::Get(key) => handle_get(key, client.protocol, in_memory),
+        Command::GetDel(key) => handle_getdel(key, client.protocol, in_memory, config).await,
+        Command::SetRange { key, offset, value } => {
+            handle_setrange(key, *offset, value, in_memory, config).await
+        }
+        Command::GetRange { key, start, end } => handle_getrange(key, *start, *end, in_memory),
+        Command::BitCount { key, range } => handle_bitcount(key, *range, in_memory),
+        Command::SetBit { key, offset, bit } => {
+            handle_setbit(key, *offset, *bit, in_memory, config).await
+        }
+        Command::GetBit { key, offset } => match in_memory.read().unwrap().get_string(key) {
+            Ok(value) => {
+                let value = value.map_or(&[][..], Vec::as_slice);
+                Some(RespType::Integer(BIT.get(value, *offset)).serialize())
+            }
+            Err(e) => Some(RespType::Error(e).serialize()),
+        },
+        Command::GetEx { key, option } => {
+            handle_getex(key, option, client.protocol, in_memory, config).await
+        }
+        Command::Del(keys) => handle_del(keys, in_memory, config).await,
+        // There's no LRU clock to update yet, so TOUCH only counts the keys.
+        Command::Touch(keys) => {
+            let db = in_memory.read().unwrap();
+            let touched = keys.iter().filter(|key| db.get(key).is_some()).count();
+            Some(RespType::Integer(touched as i64).serialize())
+        }
+        Command::ConfigGet(patterns) => handle_config_get(patterns, client.protocol, config),
+        Command::ConfigSet { param, value } => handle_config_set(param, value, config),
+        Command::Keys(pattern) => handle_keys(pattern, in_memory),
+        // Like Redis, expired keys count until they're actually removed.
+        Command::DbSize => {
+            let size = in_memory.read().unwrap().storage.len();
+            Some(RespType::Integer(size as i64).serialize())
+        }
+        Command::RandomKey => handle_randomkey(client.protocol, in_memory, config),
+        Command::Copy {
+            src,
+            dst,
+            db,
+            replace,
+        } => handle_copy(src, dst, *db, *replace, in_memory, config).await,
+        Command::Expire {
+            key,
+            millis,
+            condition,
+        } => handle_expire(key, *millis, *condition, in_memory, config).await,
+        Command::Ttl { key, millis } => {
+            let reply = match in_memory.read().unwrap().get(key) {
+                None => -2,
+                Some(item) => match item.ttl() {
+                    None => -1,
+                    Some(ttl) if *millis => ttl.as_millis() as i64,
+                    // Rounded to the nearest second, like Redis.
+                    Some(ttl) => (ttl.as_millis() as i64 + 500) / 1000,
+                },
+            };
+            Some(RespType::Integer(reply).serialize())
+        }
+        Command::ExpireTime { key, millis } => {
+            let reply = match in_memory.read().unwrap().get(key) {
+                None => -2,
+                Some(item) => match item.ttl() {
+                    None => -1,
+                    Some(ttl) => {
+                        let expires_at = (unix_time_ms() + ttl.as_millis()) as i64;
+                        if *millis {
+                            expires_at
+                        } else {
+                            expires_at / 1000
+                        }
+                    }
+                },
+            };
+            Some(RespType::Integer(reply).serialize())
+        }
+        Command::Type(key) => {
+            let type_name = in_memory
+                .read()
+                .unwrap()
+                .get(key)
+                .map_or("none", |item| item.value.type_name());
+            Some(RespType::SimpleString(type_name.to_string()).serialize())
+        }
+        Command::Push {
+            key,
+            elements,
+            left,
+        } => handle_push(key, elements, *left, in_memory, config).await,
+        Command::Pop { key, count, left } => {
+            handle_pop(key, *count, *left, client.protocol, in_memory, config).await
+        }
+        // Inside MULTI, or wherever else it can't wait, a blocking pop
+        // replies straight away as if it had timed out.
+        Command::BPop { keys, left, .. } => Some(
+            pop_first_list(keys, *left, in_memory, config)
+                .await
+                .unwrap_or(RespType::NullArray)
+                .serialize_for(client.protocol),
+        ),
+        Command::LRange { key, start, stop } => handle_lrange(key, *start, *stop, in_memory),
+        Command::LLen(key) => match in_memory.read().unwrap().get_list(key) {
+            Ok(list) => Some(RespType::Integer(list.map_or(0, VecDeque::len) as i64).serialize()),
+            Err(e) => Some(RespType::Error(e).serialize()),
+        },
+        Command::LPos {
+            key,
+            element,
+            rank,
+            count,
+        } => handle_lpos(key, element, *rank, *count, client.protocol, in_memory),
+        Command::LInsert {
+            key,
+            before,
+            pivot,
+            element,
+        } => handle_linsert(key, *before, pivot, element, in_memory, config).await,
+        Command::LSet {
+            key,
+            index,
+            element,
+        } => handle_lset(key, *index, element, in_memory, config).await,
+        Command::RPopLPush { src, dst } => {
+            handle_lmove(
+                src,
+                dst,
+                ListEnd::Right,
+                ListEnd::Left,
+                client.protocol,
+                in_memory,
+                config,
+            )
+            .await
+        }
+        Command::LMove { src, dst, from, to } => {
+            handle_lmove(src, dst, *from, *to, client.protocol, in_memory, config).await
+        }
+        Command::ZAdd {
+            key,
+            members,
+            condition,
+            score_condition,
+            ch,
+        } => {
+            handle_zadd(
+                key,
+                members,
+                condition,
+                *score_condition,
+                *ch,
+                in_memory,
+                config,
+            )
+            .await
+        }
+        Command::ZScore { key, member } => match in_memory.read().unwrap().get_zset(key) {
+            Ok(zset) => Some(
+                zset.and_then(|zset| zset.score(member))
+                    .map_or(RespType::NullBulkString, RespType::Double)
+                    .serialize_for(client.protocol),
+            ),
+            Err(e) => Some(RespType::Error(e).serialize()),
+        },
+        Command::ZRange {
+            key,
+            range,
+            withscores,
+        } => handle_zrange(key, range, *withscores, client.protocol, in_memory),
+        Command::ZRangeStore { dst, src, range } => {
+            handle_zrangestore(dst, src, range, in_memory, config).await
+        }
+        Command::ZRangeByScore {
+            key,
+            min,
+            max,
+            withscores,
+            limit,
+        } => {
+            let range = ZRange {
+                by: ZRangeBy::Score(*min, *max),
+                rev: false,
+                limit: *limit,
+            };
+            handle_zrange(key, &range, *withscores, client.protocol, in_memory)
+        }
+        Command::ZRangeByLex {
+            key,
+            min,
+            max,
+            limit,
+        } => {
+            let range = ZRange {
+                by: ZRangeBy::Lex(min.clone(), max.clone()),
+                rev: false,
+                limit: *limit,
+            };
+            handle_zrange(key, &range, false, client.protocol, in_memory)
+        }
+        Command::ZLexCount { key, min, max } => {
+            match in_memory.read().unwrap().get_zset(key) {
+                Ok(zset) => Some(
+                    RespType::Integer(
+                        zset.map_or(0, |zset| zset.range_by_lex(min, max).count()) as i64
+                    )
+                    .serialize(),
+                ),
+                Err(e) => Some(RespType::Error(e).serialize()),
+            }
+        }
+        Command::ZRem { key, members } => handle_zrem(key, members, in_memory, config).await,
+        Command::ZRank { key, member } => match in_memory.read().unwrap().get_zset(key) {
+            Ok(zset) => Some(
+                zset.and_then(|zset| zset.rank(member))
+                    .map_or(RespType::NullBulkString, |rank| {
+                        RespType::Integer(rank as i64)
+                    })
+                    .serialize_for(client.protocol),
+            ),
+            Err(e) => Some(RespType::Error(e).serialize()),
+        },
+        Command::ZCard(key) => match in_memory.read().unwrap().get_zset(key) {
+            Ok(zset) => Some(RespType::Integer(zset.map_or(0, SortedSet::len) as i64).serialize()),
+            Err(e) => Some(RespType::Error(e).serialize()),
+        },
+        Command::HSet { key, fields } => handle_hset(key, fields, in_memory, config).await,
+        Command::HGet { key, field } => match in_memory.read().unwrap().get_hash(key) {
+            Ok(hash) => Some(
+                hash.and_then(|hash| hash.get(field))
+                    .map_or(RespType::NullBulkString, |value| {
+                        RespType::BulkBytes(value.clone())
+                    })
+                    .serialize_for(client.protocol),
+            ),
+            Err(e) => Some(RespType::Error(e).serialize()),
+        },
+        Command::HGetAll(key) => match in_memory.read().unwrap().get_hash(key) {
+            Ok(hash) => Some(
+                RespType::Map(
+                    hash.into_iter()
+                        .flatten()
+                        .map(|(field, value)| {
+                            (
+                                RespType::BulkBytes(field.clone()),
+                                RespType::BulkBytes(value.clone()),
+                            )
+                        })
+                        .collect(),
+                )
+                .serialize_for(client.protocol),
+            ),
+            Err(e) => Some(RespType::Error(e).serialize()),
+        },
+        Command::HDel { key, fields } => handle_hdel(key, fields, in_memory, config).await,
+        Command::ZRandMember {
+            key,
+            count,
+            withscores,
+        } => handle_zrandmember(key, *count, *withscores, client.protocol, in_memory, config),
+        Command::XAdd { key, id, fields } => handle_xadd(key, *id, fields, in_memory, config).await,
+        Command::XRange {
+            key,
+            start,
+            end,
+            count,
+        } => handle_xrange(key, *start, *end, *count, in_memory),
+        Command::XLen(key) => match in_memory.read().unwrap().get_stream(key) {
+            Ok(stream) => Some(
+                RespType::Integer(stream.map_or(0, |stream| stream.entries.len()) as i64)
+                    .serialize(),
+            ),
+            Err(e) => Some(RespType::Error(e).serialize()),
+        },
+        // Like a blocking pop, XREAD BLOCK only waits outside MULTI.
+        Command::XRead { streams, count, .. } => Some(
+            read_streams(streams, *count, client.protocol, in_memory)
+                .unwrap_or(RespType::NullArray)
+                .serialize_for(client.protocol),
+        ),
+        Command::LRem {
+            key,
+            count,
+            element,
+        } => handle_lrem(key, *count, element, in_memory, config).await,
+        Command::SwapDb(first, second) => handle_swapdb(*first, *second, in_memory, config).await,
+        Command::Select(index) => match config.databases.get(*index) {
+            Some(selected) => {
+                *in_memory = Arc::clone(selected);
+                client.db = *index;
+                Some(RespType::SimpleString("OK".to_string()).serialize())
+            }
+            None => Some(
+                RespType::Error(RedisError::Err("DB index is out of range".to_string()))
+                    .serialize(),
+            ),
+        },
+        Command::Append { key, value } => handle_append(key, value, in_memory, config).await,
+        Command::Dump(key) => match in_memory.read().unwrap().get(key) {
+            Some(item) => Some(RespType::BulkBytes(dump_value(item)).serialize()),
+            None => Some(RespType::NullBulkString.serialize_for(client.protocol)),
+        },
+        Command::Restore {
+            key,
+            ttl,
+            payload,
+            replace,
+            absttl,
+        } => handle_restore(key, *ttl, payload, *replace, *absttl, in_memory, config).await,
+        Command::Object { subcommand, key } => handle_object(subcommand, key, in_memory, config),
+        Command::XInfo { subcommand, key } => {
+            handle_xinfo(subcommand, key, client.protocol, in_memory)
+        }
+        Command::Info(sections) => handle_info(sections, client.protocol, config).await,
+        Command::ReplConf(message) => {
+            if message == "getack" {
+                // On a replica the offset counts the bytes applied from the
+                // master before this GETACK.
+                let offset = config.replication_manager.offset.load(Ordering::SeqCst);
+                let ack_response = RespType::Array(vec![
+                    RespType::BulkString("REPLCONF".to_string()),
+                    RespType::BulkString("ACK".to_string()),
+                    RespType::BulkString(offset.to_string()),
+                ]);
+                Some(ack_response.serialize())
+            } else {
+                Some(RespType::SimpleString("OK".to_string()).serialize())
+            }
+        }
+        Command::PSync { replid, offset } => {
+            if let Err(e) = handle_psync(replid, *offset, stream, config, client).await {
+                eprintln!("Error handling PSYNC: {}", e);
+            }
+            None
+        }
+        Command::FlushDb => handle_flush(false, in_memory, config).await,
+        Command::FlushAll => handle_flush(true, in_memory, config).await,
+        Command::ClientSetInfo { attr, value } => handle_client_setinfo(attr, value, client),
+        Command::ClientSetName(name) => handle_client_setname(name, client),
+        Command::ClientGetName => Some(
+            client
+                .name
+                .clone()
+                .map_or(RespType::NullBulkString, RespType::BulkString)
+                .serialize_for(client.protocol),
+        ),
+        Command::ClientId => Some(RespType::Integer(client.id as i64).serialize()),
+        // Killed connections close once they finish their current command,
+        // so a client killing itself still gets this reply.
+        Command::ClientKill { filter, legacy } => {
+            let killed = config.clients.kill(filter, client.id);
+            let reply = match (legacy, killed) {
+                (false, killed) => RespType::Integer(killed as i64),
+                (true, 0) => RespType::Error(RedisError::Err("No such client".to_string())),
+                (true, _) => RespType::SimpleString("OK".to_string()),
+            };
+            Some(reply.serialize())
+        }
+        Command::ClientInfo => Some(
+            RespType::VerbatimString("txt", format!("{}\n", client.info()))
+                .serialize_for(client.protocol),
+        ),
+        Command::ClientList => {
+            // The registry only catches up after a command, so bring this
+            // connection's own line up to date first.
+            config.clients.update(client);
+            Some(
+                RespType::VerbatimString("txt", config.clients.list())
+                    .serialize_for(client.protocol),
+            )
+        }
+        Command::Scan {
+            cursor,
+            pattern,
+            count,
+        } => handle_scan(*cursor, pattern, *count, in_memory),
+        Command::Subscribe(channels) => {
+            handle_subscribe(channels, SubscriptionKind::Channel, config, client).await
+        }
+        Command::Unsubscribe(channels) => {
+            handle_unsubscribe(channels, SubscriptionKind::Channel, config, client).await
+        }
+        Command::PSubscribe(patterns) => {
+            handle_subscribe(patterns, SubscriptionKind::Pattern, config, client).await
+        }
+        Command::PUnsubscribe(patterns) => {
+            handle_unsubscribe(patterns, SubscriptionKind::Pattern, config, client).await
+        }
+        Command::Publish { channel, message } => {
+            let received = config.pubsub.publish(channel, message).await;
+            Some(RespType::Integer(received as i64).serialize())
+        }
+        Command::SSubscribe(channels) => {
+            handle_subscribe(channels, SubscriptionKind::Shard, config, client).await
+        }
+        Command::SUnsubscribe(channels) => {
+            handle_unsubscribe(channels, SubscriptionKind::Shard, config, client).await
+        }
+        Command::SPublish { channel, message } => {
+            let received = config.shard_pubsub.publish(channel, message).await;
+            Some(RespType::Integer(received as i64).serialize())
+        }
+        Command::PubSubChannels { pattern, shard } => {
+            let registry = if *shard {
+                &config.shard_pubsub
+            } else {
+                &config.pubsub
+            };
+            let channels = registry
+                .channels(pattern.as_deref())
+                .await
+                .into_iter()
+                .map(RespType::BulkString)
+                .collect();
+            Some(RespType::Array(channels).serialize())
+        }
+        Command::PubSubNumSub { channels, shard } => {
+            let registry = if *shard {
+                &config.shard_pubsub
+            } else {
+                &config.pubsub
+            };
+            let mut counts = Vec::with_capacity(channels.len() * 2);
+            for channel in channels {
+                counts.push(RespType::BulkString(channel.clone()));
+                counts.push(RespType::Integer(
+                    registry.subscriber_count(channel).await as i64,
+                ));
+            }
+            Some(RespType::Array(counts).serialize())
+        }
+        Command::PubSubNumPat => {
+            Some(RespType::Integer(config.pubsub.pattern_count().await as i64).serialize())
+        }
+        Command::Wait {
+            numreplicas,
+            timeout,
+        } => {
+            let acked = wait_for_replicas(
+                &config.replication_manager,
+                *numreplicas,
+                Duration::from_millis(*timeout),
+            )
+            .await;
+            Some(RespType::Integer(acked as i64).serialize())
+        }
+        Command::Multi => {
+            if client.transaction.is_some() {
+                return Some(
+                    RespType::Error(RedisError::Err("MULTI calls can not be nested".to_string()))
+                        .serialize(),
+                );
+            }
+            client.transaction = Some(Vec::new());
+            client.transaction_failed = false;
+            Some(RespType::SimpleString("OK".to_string()).serialize())
+        }
+        Command::Exec => handle_exec(stream, in_memory, config, client).await,
+        Command::Discard => {
+            if client.transaction.take().is_none() {
+                return Some(
+                    RespType::Error(RedisError::Err("DISCARD without MULTI".to_string()))
+                        .serialize(),
+                );
+            }
+            client.unwatch_all(config);
+            Some(RespType::SimpleString("OK".to_string()).serialize())
+        }
+        Command::Watch(keys) => {
+            // Like a command that fails to queue, this dooms the transaction.
+            if client.transaction.is_some() {
+                client.transaction_failed = true;
+                return Some(
+                    RespType::Error(RedisError::Err(
+                        "WATCH inside MULTI is not allowed".to_string(),
+                    ))
+                    .serialize(),
+                );
+            }
+            let mut db = in_memory.write().unwrap();
+            for key in keys {
+                let version = db.watch(key);
+                client.watched.push((client.db, key.clone(), version));
+            }
+            Some(RespType::SimpleString("OK".to_string()).serialize())
+        }
+        Command::Unwatch => {
+            client.unwatch_all(config);
+            Some(RespType::SimpleString("OK".to_string()).serialize())
+        }
+        Command::Count => Some(RespType::Integer(COMMAND_TABLE.len() as i64).serialize()),
+        Command::Docs => Some(RespType::Array(Vec::new()).serialize()),
+        // `handle_client` closes the connection once the reply is written.
+        Command::Quit => Some(RespType::SimpleString("OK".to_string()).serialize()),
+        Command::Hello(protover) => handle_hello(*protover, config, client),
+        Command::Cluster(args) => handle_cluster(args, client.protocol, config),
+        // Nothing to hand back to the OS; accepted for ops scripts.
+        Command::MemoryPurge => Some(RespType::SimpleString("OK".to_string()).serialize()),
+        // Only this connection waits; other clients keep being served.
+        Command::DebugSleep(duration) => {
+            tokio::time::sleep(*duration).await;
+            Some(RespType::SimpleString("OK".to_string()).serialize())
+        }
+        Command::DebugObject(key) => handle_debug_object(key, in_memory, config),
+        Command::Debug(_) => Some(RespType::SimpleString("OK".to_string()).serialize()),
+        Command::SlowLogGet(count) => Some(
+            RespType::Array(config.slowlog.newest(count.unwrap_or(usize::MAX)))
+                .serialize_for(client.protocol),
+        ),
+        Command::SlowLogLen => Some(RespType::Integer(config.slowlog.len() as i64).serialize()),
+        Command::SlowLogReset => {
+            config.slowlog.reset();
+            Some(RespType::SimpleString("OK".to_string()).serialize())
+        }
+        // Commands reach monitors before they run, so this one isn't echoed.
+        Command::Monitor => {
+            config.monitors.add(client.id, &client.pubsub_sender);
+            Some(RespType::SimpleString("OK".to_string()).serialize())
+        }
+        Command::BitField { key, ops } => {
+            handle_bitfield(key, ops, client.protocol, in_memory, config).await
+        }
+        Command::Reset => {
+            client.reset(config).await;
+            *in_memory = Arc::clone(&config.databases[0]);
+            Some(RespType::SimpleString("RESET".to_string()).serialize())
+        }
+        Command::Error(message) => Some(RespType::Error(message.clone()).serialize()),
+        Command::Unknown { name, args } => {
+            Some(RespType::Error(unknown_command(name, args)).serialize())
+        }
+    }
+}
+
+fn queue_command(command: &Command, client: &mut ClientState) -> Option<Vec<u8>> {
+    // Commands that fail to parse are rejected now and poison the transaction.
+    if let Command::Error(_) | Command::Unknown { .. } = command {
+        client.transaction_failed = true;
+        return match command {
+            Command::Error(message) => Some(RespType::Error(message.clone()).serialize()),
+            Command::Unknown { name, args } => {
+                Some(RespType::Error(unknown_command(name, args)).serialize())
+            }
+            _ => None,
+        };
+    }
+
+    if let Some(queued) = client.transaction.as_mut() {
+        queued.push(command.clone());
+    }
+    Some(RespType::SimpleString("QUEUED".to_string()).serialize())
+}
+
+// Redis's wording: "ERR unknown command 'foo', with args beginning with: 'a' 'b' ",
+// with the quoted arguments cut off at about 128 characters.
+fn unknown_command(name: &str, args: &[String]) -> RedisError {
+    let mut quoted = String::new();
+    for arg in args {
+        if quoted.len() >= 128 {
+            break;
+        }
+        let room = 128 - quoted.len();
+        quoted.push_str(&format!(
+            "'{}' ",
+            arg.chars().take(room).collect::<String>()
+        ));
+    }
+    RedisError::Err(format!(
+        "unknown command '{}', with args beginning with: {}",
+        name.chars().take(128).collect::<String>(),
+        quoted
+    ))
+}
+
+async fn handle_exec<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    in_memory: &mut Arc<RwLock<Database>>,
+    config: &Arc<Config>,
+    client: &mut ClientState,
+) -> Option<Vec<u8>> {
+    let Some(queued) = client.transaction.take() else {
+        return Some(
+            RespType::Error(RedisError::Err("EXEC without MULTI".to_string())).serialize(),
+        );
+    };
+
+    if client.transaction_failed {
+        client.transaction_failed = false;
+        client.unwatch_all(config);
+        return Some(RespType::Error(RedisError::ExecAbort).serialize());
+    }
+
+    // A watched key changed since WATCH, so the transaction must not run.
+    let modified = client.watched.iter().any(|(index, key, version)| {
+        config.databases[*index].read().unwrap().version(key) != *version
+    });
+    client.unwatch_all(config);
+    if modified {
+        return Some(RespType::NullArray.serialize_for(client.protocol));
+    }
+
+    let mut replies = Vec::with_capacity(queued.len());
+    for command in &queued {
+        if let Some(reply) =
+            Box::pin(handle_command(command, stream, in_memory, config, client)).await
+        {
+            replies.push(reply);
+        }
+    }
+
+    let mut response = format!("*{}\r\n", replies.len()).into_bytes();
+    response.extend(replies.concat());
+    Some(response)
+}
+
+async fn handle_set(
+    key: &[u8],
+    value: &[u8],
+    expiry: &Option<SetExpiry>,
+    condition: &Option<SetCondition>,
+    get: bool,
+    in_memory: &mut Arc<RwLock<Database>>,
+    config: &Arc<Config>,
+) -> RespType {
+    let (ttl, old_value) = {
+        let mut db = in_memory.write().unwrap();
+        let existing = db.get(key);
+        // With GET the old value is the reply whether or not the key is set.
+        let old_value = match existing.map(Item::as_string) {
+            Some(Ok(bytes)) if get => RespType::BulkBytes(bytes.clone()),
+            Some(Err(e)) if get => return RespType::Error(e),
+            _ => RespType::NullBulkString,
+        };
+
+        match condition {
+            Some(SetCondition::Nx) if existing.is_some() => return old_value,
+            Some(SetCondition::Xx) if existing.is_none() => return old_value,
+            _ => {}
+        }
+
+        let ttl = match expiry {
+            None => None,
+            Some(SetExpiry::KeepTtl) => existing.and_then(Item::ttl),
+            Some(SetExpiry::Ex(secs)) => Some(Duration::from_secs(*secs)),
+            Some(SetExpiry::Px(ms)) => Some(Duration::from_millis(*ms)),
+            Some(SetExpiry::ExAt(secs)) => Some(duration_until(*secs as u128 * 1000)),
+            Some(SetExpiry::PxAt(ms)) => Some(duration_until(*ms as u128)),
+        };
+
+        // An absolute expiry already in the past deletes the key.
+        if ttl == Some(Duration::ZERO) {
+            db.remove(key);
+        } else {
+            db.insert(key.to_vec(), Item::new(Value::String(value.to_vec()), ttl));
+        }
+        (ttl, old_value)
+    };
+
+    // Replicas get an absolute PXAT so both sides expire the key together.
+    match ttl {
+        Some(Duration::ZERO) => propagate_to_replicas(in_memory, &[b"DEL", key], config).await,
+        Some(ttl) => {
+            let expires_at = (unix_time_ms() + ttl.as_millis()).to_string();
+            let set: [&[u8]; 5] = [b"SET", key, value, b"PXAT", expires_at.as_bytes()];
+            propagate_to_replicas(in_memory, &set, config).await
+        }
+        None => propagate_to_replicas(in_memory, &[b"SET", key, value], config).await,
+    }
+    if get {
+        old_value
+    } else {
+        RespType::SimpleString("OK".to_string())
+    }
+}
+
+async fn handle_getdel(
+    key: &[u8],
+    protocol: u8,
+    in_memory: &mut Arc<RwLock<Database>>,
+    config: &Arc<Config>,
+) -> Option<Vec<u8>> {
+    let removed = {
+        let mut db = in_memory.write().unwrap();
+        match db.get_string(key) {
+            Ok(Some(value)) => {
+                let value = value.clone();
+                db.remove(key);
+                value
+            }
+            Ok(None) => return Some(RespType::NullBulkString.serialize_for(protocol)),
+            Err(e) => return Some(RespType::Error(e).serialize()),
+        }
+    };
+    propagate_to_replicas(in_memory, &[b"DEL", key], config).await;
+    Some(RespType::BulkBytes(removed).serialize())
+}
+
+// Redis's proto-max-bulk-len default: no string may grow past 512MB.
+const MAX_STRING_SIZE: usize = 512 * 1024 * 1024;
+
+// Overwrites the value from `offset` on, padding with zero bytes when it
+// starts past the end. Like APPEND it keeps the TTL and leaves the string
+// `raw`.
+async fn handle_setrange(
+    key: &[u8],
+    offset: usize,
+    value: &[u8],
+    in_memory: &mut Arc<RwLock<Database>>,
+    config: &Arc<Config>,
+) -> Option<Vec<u8>> {
+    let len = {
+        let mut db = in_memory.write().unwrap();
+        let existing = db.get(key).cloned();
+        let mut bytes = match existing.as_ref().map(Item::as_string).transpose() {
+            Ok(bytes) => bytes.cloned().unwrap_or_default(),
+            Err(e) => return Some(RespType::Error(e).serialize()),
+        };
+        // An empty value changes nothing, not even creating the key.
+        if value.is_empty() {
+            return Some(RespType::Integer(bytes.len() as i64).serialize());
+        }
+        if offset + value.len() > MAX_STRING_SIZE {
+            return Some(
+                RespType::Error(RedisError::Err(
+                    "string exceeds maximum allowed size (proto-max-bulk-len)".to_string(),
+                ))
+                .serialize(),
+            );
+        }
+
+        if bytes.len() < offset + value.len() {
+            bytes.resize(offset + value.len(), 0);
+        }
+        bytes[offset..offset + value.len()].copy_from_slice(value);
+        let len = bytes.len();
+        let item = existing.unwrap_or_else(|| Item::new(Value::String(Vec::new()), None));
+        db.insert(
+            key.to_vec(),
+            Item {
+                value: Value::String(bytes),
+                raw: true,
+                ..item
+            },
+        );
+        len
+    };
+
+    let offset = offset.to_string();
+    propagate_to_replicas(
+        in_memory,
+        &[b"SETRANGE", key, offset.as_bytes(), value],
+        config,
+    )
+    .await;
+    Some(RespType::Integer(len as i64).serialize())
+}
+
+// The bytes from `start` to `end` inclusive of a `len` byte value, where
+// negative indexes count from the end. Out of range indexes are clamped
+// rather than rejected; `None` means the range is empty.
+fn byte_range(start: i64, end: i64, len: usize) -> Option<RangeInclusive<usize>> {
+    let len = len as i64;
+    if start < 0 && end < 0 && start > end {
+        return None;
+    }
+    let start = if start < 0 { len + start } else { start }.max(0);
+    let end = if end < 0 { len + end } else { end }.max(0).min(len - 1);
+    (start <= end && len > 0).then_some(start as usize..=end as usize)
+}
+
+fn handle_getrange(
+    key: &[u8],
+    start: i64,
+    end: i64,
+    in_memory: &mut Arc<RwLock<Database>>,
+) -> Option<Vec<u8>> {
+    let db = in_memory.read().unwrap();
+    let value = match db.get_string(key) {
+        Ok(value) => value.map_or(&[][..], Vec::as_slice),
+        Err(e) => return Some(RespType::Error(e).serialize()),
+    };
+    let range = byte_range(start, end, value.len()).map_or(&[][..], |range| &value[range]);
+    Some(RespType::BulkBytes(range.to_vec()).serialize())
+}
+
+// Set bits in the value, or in the bytes from `start` to `end` as GETRANGE
+// picks them.
+fn handle_bitcount(
+    key: &[u8],
+    range: Option<(i64, i64)>,
+    in_memory: &mut Arc<RwLock<Database>>,
+) -> Option<Vec<u8>> {
+    let db = in_memory.read().unwrap();
+    let value = match db.get_string(key) {
+        Ok(value) => value.map_or(&[][..], Vec::as_slice),
+        Err(e) => return Some(RespType::Error(e).serialize()),
+    };
+    let bytes = match range {
+        None => value,
+        Some((start, end)) => {
+            byte_range(start, end, value.len()).map_or(&[][..], |range| &value[range])
+        }
+    };
+    let count: u32 = bytes.iter().map(|byte| byte.count_ones()).sum();
+    Some(RespType::Integer(count as i64).serialize())
+}
+
+// Sets or clears one bit, growing the value with zero bytes to reach it,
+// and replies with the bit's previous value.
+async fn handle_setbit(
+    key: &[u8],
+    offset: u64,
+    bit: bool,
+    in_memory: &mut Arc<RwLock<Database>>,
+    config: &Arc<Config>,
+) -> Option<Vec<u8>> {
+    let previous = {
+        let mut db = in_memory.write().unwrap();
+        let existing = db.get(key).cloned();
+        let mut bytes = match existing.as_ref().map(Item::as_string).transpose() {
+            Ok(bytes) => bytes.cloned().unwrap_or_default(),
+            Err(e) => return Some(RespType::Error(e).serialize()),
+        };
+        let previous = BIT.get(&bytes, offset);
+        BIT.set(&mut bytes, offset, bit as i64);
+        let item = existing.unwrap_or_else(|| Item::new(Value::String(Vec::new()), None));
+        db.insert(
+            key.to_vec(),
+            Item {
+                value: Value::String(bytes),
+                raw: true,
+                ..item
+            },
+        );
+        previous
+    };
+
+    let offset = offset.to_string();
+    let bit: &[u8] = if bit { b"1" } else { b"0" };
+    propagate_to_replicas(in_memory, &[b"SETBIT", key, offset.as_bytes(), bit], config).await;
+    Some(RespType::Integer(previous).serialize())
+}
+
+async fn handle_getex(
+    key: &[u8],
+    option: &Option<GetExOption>,
+    protocol: u8,
+    in_memory: &mut Arc<RwLock<Database>>,
+    config: &Arc<Config>,
+) -> Option<Vec<u8>> {
+    let (value, ttl) = {
+        let mut db = in_memory.write().unwrap();
+        let Some(item) = db.get(key).cloned() else {
+            return Some(RespType::NullBulkString.serialize_for(protocol));
+        };
+        let value = match item.as_string() {
+            Ok(value) => value.clone(),
+            Err(e) => return Some(RespType::Error(e).serialize()),
+        };
+        let ttl = match option {
+            None => return Some(RespType::BulkBytes(value).serialize()),
+            Some(GetExOption::Persist) => None,
+            Some(GetExOption::Expire(expiry)) => Some(match expiry {
+                SetExpiry::Ex(secs) => Duration::from_secs(*secs),
+                SetExpiry::Px(ms) => Duration::from_millis(*ms),
+                SetExpiry::ExAt(secs) => duration_until(*secs as u128 * 1000),
+                SetExpiry::PxAt(ms) => duration_until(*ms as u128),
+                SetExpiry::KeepTtl => return Some(RespType::BulkBytes(value).serialize()),
+            }),
+        };
+
+        // As with SET, an absolute expiry already in the past deletes the key.
+        if ttl == Some(Duration::ZERO) {
+            db.remove(key);
+        } else {
+            db.insert(
+                key.to_vec(),
+                Item {
+                    deadline: ttl.map(|ttl| Instant::now() + ttl),
+                    ..item
+                },
+            );
+        }
+        (value, ttl)
+    };
+
+    match ttl {
+        Some(Duration::ZERO) => propagate_to_replicas(in_memory, &[b"DEL", key], config).await,
+        Some(ttl) => {
+            let expires_at = (unix_time_ms() + ttl.as_millis()).to_string();
+            let getex: [&[u8]; 4] = [b"GETEX", key, b"PXAT", expires_at.as_bytes()];
+            propagate_to_replicas(in_memory, &getex, config).await
+        }
+        None => propagate_to_replicas(in_memory, &[b"GETEX", key, b"PERSIST"], config).await,
+    }
+    Some(RespType::BulkBytes(value).serialize())
+}
+
+fn duration_until(timestamp_ms: u128) -> Duration {
+    Duration::from_millis(timestamp_ms.saturating_sub(unix_time_ms()) as u64)
+}
+
+async fn handle_del(
+    keys: &[Vec<u8>],
+    in_memory: &mut Arc<RwLock<Database>>,
+    config: &Arc<Config>,
+) -> Option<Vec<u8>> {
+    let deleted = {
+        let mut db = in_memory.write().unwrap();
+        keys.iter()
+            .filter(|key| matches!(db.remove(key), Some(item) if !item.is_expired()))
+            .count()
+    };
+
+    if deleted > 0 {
+        let mut del: Vec<&[u8]> = vec![b"DEL"];
+        del.extend(keys.iter().map(Vec::as_slice));
+        propagate_to_replicas(in_memory, &del, config).await;
+    }
+    Some(RespType::Integer(deleted as i64).serialize())
+}
+
+// The copy shares the source's creation time and TTL, so both keys expire
+// at the same instant.
+async fn handle_copy(
+    src: &[u8],
+    dst: &[u8],
+    db: Option<usize>,
+    replace: bool,
+    in_memory: &mut Arc<RwLock<Database>>,
+    config: &Arc<Config>,
+) -> Option<Vec<u8>> {
+    let target = match db {
+        Some(index) => match config.databases.get(index) {
+            Some(target) => Arc::clone(target),
+            None => {
+                return Some(
+                    RespType::Error(RedisError::Err("DB index is out of range".to_string()))
+                        .serialize(),
+                )
+            }
+        },
+        None => Arc::clone(in_memory),
+    };
+    if src == dst && Arc::ptr_eq(&target, in_memory) {
+        return Some(
+            RespType::Error(RedisError::Err(
+                "source and destination objects are the same".to_string(),
+            ))
+            .serialize(),
+        );
+    }
+
+    let Some(item) = in_memory.read().unwrap().get(src).cloned() else {
+        return Some(RespType::Integer(0).serialize());
+    };
+    {
+        let mut target = target.write().unwrap();
+        if !replace && target.get(dst).is_some() {
+            return Some(RespType::Integer(0).serialize());
+        }
+        target.insert(dst.to_vec(), item);
+    }
+
+    let index = db.map(|index| index.to_string());
+    let mut copy: Vec<&[u8]> = vec![b"COPY", src, dst];
+    if let Some(index) = &index {
+        copy.extend([b"DB".as_slice(), index.as_bytes()]);
+    }
+    if replace {
+        copy.push(b"REPLACE");
+    }
+    propagate_to_replicas(in_memory, &copy, config).await;
+    Some(RespType::Integer(1).serialize())
+}
+
+// Sets a key's TTL, or deletes the key when `millis` isn't positive. Replicas
+// get the remaining time in milliseconds.
+async fn handle_expire(
+    key: &[u8],
+    millis: i64,
+    condition: Option<ExpireCondition>,
+    in_memory: &mut Arc<RwLock<Database>>,
+    config: &Arc<Config>,
+) -> Option<Vec<u8>> {
+    let updated = {
+        let mut db = in_memory.write().unwrap();
+        let Some(item) = db.get(key).cloned() else {
+            return Some(RespType::Integer(0).serialize());
+        };
+
+        // No TTL counts as an infinite one for GT and LT.
+        let current = item.ttl().map(|ttl| ttl.as_millis() as i64);
+        let allowed = match condition {
+            None => true,
+            Some(ExpireCondition::Nx) => current.is_none(),
+            Some(ExpireCondition::Xx) => current.is_some(),
+            Some(ExpireCondition::Gt) => current.is_some_and(|current| millis > current),
+            Some(ExpireCondition::Lt) => current.is_none_or(|current| millis < current),
+        };
+        if !allowed {
+            return Some(RespType::Integer(0).serialize());
+        }
+
+        if millis <= 0 {
+            db.remove(key);
+        } else {
+            db.insert(
+                key.to_vec(),
+                Item {
+                    deadline: Some(Instant::now() + Duration::from_millis(millis as u64)),
+                    ..item
+                },
+            );
+        }
+        millis > 0
+    };
+
+    // Replicas get an absolute PEXPIREAT so both sides expire the key together.
+    if updated {
+        let expires_at = (unix_time_ms() as i64 + millis).to_string();
+        let pexpireat: [&[u8]; 3] = [b"PEXPIREAT", key, expires_at.as_bytes()];
+        propagate_to_replicas(in_memory, &pexpireat, config).await;
+    } else {
+        propagate_to_replicas(in_memory, &[b"DEL", key], config).await;
+    }
+    Some(RespType::Integer(1).serialize())
+}
+
+// Keeps the key's TTL. Appending to an existing string makes it `raw`, as
+// in Redis; a new key is encoded like any SET value.
+async fn handle_append(
+    key: &[u8],
+    value: &[u8],
+    in_memory: &mut Arc<RwLock<Database>>,
+    config: &Arc<Config>,
+) -> Option<Vec<u8>> {
+    let len = {
+        let mut db = in_memory.write().unwrap();
+        let item = match db.get(key).cloned() {
+            Some(item) => {
+                let mut appended = match item.as_string() {
+                    Ok(existing) => existing.clone(),
+                    Err(e) => return Some(RespType::Error(e).serialize()),
+                };
+                appended.extend_from_slice(value);
+                Item {
+                    value: Value::String(appended),
+                    raw: true,
+                    ..item
+                }
+            }
+            None => Item::new(Value::String(value.to_vec()), None),
+        };
+        let len = item.as_string().map_or(0, Vec::len);
+        db.insert(key.to_vec(), item);
+        len
+    };
+
+    propagate_to_replicas(in_memory, &[b"APPEND", key, value], config).await;
+    Some(RespType::Integer(len as i64).serialize())
+}
+
+async fn handle_restore(
+    key: &[u8],
+    ttl: u64,
+    payload: &[u8],
+    replace: bool,
+    absttl: bool,
+    in_memory: &mut Arc<RwLock<Database>>,
+    config: &Arc<Config>,
+) -> Option<Vec<u8>> {
+    if !replace && in_memory.read().unwrap().get(key).is_some() {
+        return Some(RespType::Error(RedisError::BusyKey).serialize());
+    }
+    let value = match restore_value(payload) {
+        Ok(value) => value,
+        Err(e) => return Some(RespType::Error(e).serialize()),
+    };
+
+    let ttl = match (ttl, absttl) {
+        (0, _) => None,
+        (at, true) => Some((at as u128).saturating_sub(unix_time_ms()) as u64),
+        (ttl, false) => Some(ttl),
+    };
+    // An ABSTTL already in the past leaves no key behind, as if it had been
+    // restored and then expired.
+    if ttl == Some(0) {
+        if in_memory.write().unwrap().remove(key).is_some() {
+            propagate_to_replicas(in_memory, &[b"DEL", key], config).await;
+        }
+        return Some(RespType::SimpleString("OK".to_string()).serialize());
+    }
+
+    in_memory.write().unwrap().insert(
+        key.to_vec(),
+        Item::new(value, ttl.map(Duration::from_millis)),
+    );
+
+    let ttl = ttl.unwrap_or_default().to_string();
+    propagate_to_replicas(
+        in_memory,
+        &[b"RESTORE", key, ttl.as_bytes(), payload, b"REPLACE"],
+        config,
+    )
+    .await;
+    Some(RespType::SimpleString("OK".to_string()).serialize())
+}
+
+// Each element is pushed in turn, so LPUSH leaves them in reverse order.
+async fn handle_push(
+    key: &[u8],
+    elements: &[Vec<u8>],
+    left: bool,
+    in_memory: &mut Arc<RwLock<Database>>,
+    config: &Arc<Config>,
+) -> Option<Vec<u8>> {
+    let (db, len) = {
+        let mut db = in_memory.write().unwrap();
+        let list = match db.get_list_mut(key, true) {
+            Ok(list) => list.unwrap(),
+            Err(e) => return Some(RespType::Error(e).serialize()),
+        };
+        for element in elements {
+            if left {
+                list.push_front(element.clone());
+            } else {
+                list.push_back(element.clone());
+            }
+        }
+        let len = list.len();
+        (db.index, len)
+    };
+    config.blocked.notify(db, key);
+
+    let mut push: Vec<&[u8]> = vec![if left { b"LPUSH" } else { b"RPUSH" }, key];
+    push.extend(elements.iter().map(Vec::as_slice));
+    propagate_to_replicas(in_memory, &push, config).await;
+    Some(RespType::Integer(len as i64).serialize())
+}
+
+// Pops from the first of `keys` holding a list and replies [key, element],
+// or returns `None` when every key is empty. Replicas get a plain LPOP or
+// RPOP, since they must never block.
+async fn pop_first_list(
+    keys: &[Vec<u8>],
+    left: bool,
+    in_memory: &mut Arc<RwLock<Database>>,
+    config: &Arc<Config>,
+) -> Option<RespType> {
+    let (key, element) = {
+        let mut db = in_memory.write().unwrap();
+        let mut popped = None;
+        for key in keys {
+            let list = match db.get_list_mut(key, false) {
+                Ok(Some(list)) => list,
+                Ok(None) => continue,
+                Err(e) => return Some(RespType::Error(e)),
+            };
+            let element = if left {
+                list.pop_front()
+            } else {
+                list.pop_back()
+            };
+            db.remove_if_empty(key);
+            popped = element.map(|element| (key, element));
+            break;
+        }
+        popped?
+    };
+
+    let pop: [&[u8]; 2] = [if left { b"LPOP" } else { b"RPOP" }, key];
+    propagate_to_replicas(in_memory, &pop, config).await;
+    Some(RespType::Array(vec![
+        RespType::BulkBytes(key.clone()),
+        RespType::BulkBytes(element),
+    ]))
+}
+
+// BLPOP and BRPOP outside MULTI. `handle_client` calls this without the
+// shared transaction lock, which it only takes for each attempt, so a
+// connection parked here doesn't hold up EXEC on the others. Gives up
+// without a reply if the client disconnects or is killed while waiting.
+#[allow(clippy::too_many_arguments)]
+pub async fn handle_bpop<S: AsyncBufRead + Unpin>(
+    keys: &[Vec<u8>],
+    timeout: Duration,
+    left: bool,
+    stream: &mut S,
+    in_memory: &mut Arc<RwLock<Database>>,
+    config: &Arc<Config>,
+    client: &mut ClientState,
+) -> Option<Vec<u8>> {
+    let db = in_memory.read().unwrap().index;
+    let waiter = Arc::new(Notify::new());
+    let _registration = config.blocked.register(db, keys, &waiter);
+    let deadline = (!timeout.is_zero()).then(|| tokio::time::Instant::now() + timeout);
+    let mut watch_stream = true;
+
+    loop {
+        {
+            let _shared = config.transaction_lock.read().await;
+            if let Some(reply) = pop_first_list(keys, left, in_memory, config).await {
+                return Some(reply.serialize_for(client.protocol));
+            }
+        }
+
+        match wait_for_wakeup(&waiter, deadline, stream, &mut watch_stream, client).await {
+            Wakeup::Notified => {}
+            Wakeup::TimedOut => return Some(RespType::NullArray.serialize_for(client.protocol)),
+            Wakeup::Gone => return None,
+        }
+    }
+}
+
+// Why a blocked connection stopped waiting.
+enum Wakeup {
+    Notified,
+    TimedOut,
+    // The client disconnected or was killed.
+    Gone,
+}
+
+// Waits for a write to one of the blocked keys, the deadline, or the client
+// going away. Pipelined commands may arrive meanwhile; they stay buffered
+// until the blocking command finishes, so the socket only needs watching
+// until the first of them.
+async fn wait_for_wakeup<S: AsyncBufRead + Unpin>(
+    waiter: &Notify,
+    deadline: Option<tokio::time::Instant>,
+    stream: &mut S,
+    watch_stream: &mut bool,
+    client: &mut ClientState,
+) -> Wakeup {
+    loop {
+        tokio::select! {
+            _ = waiter.notified() => return Wakeup::Notified,
+            _ = tokio::time::sleep_until(deadline.unwrap_or_else(tokio::time::Instant::now)),
+                if deadline.is_some() => return Wakeup::TimedOut,
+            read = stream.fill_buf(), if *watch_stream => match read {
+                Ok([]) | Err(_) => return Wakeup::Gone,
+                Ok(_) => *watch_stream = false,
+            },
+            _ = client.killed.changed() => return Wakeup::Gone,
+        }
+    }
+}
+
+// Removes the key once its last element is popped, as Redis never keeps
+// empty lists.
+async fn handle_pop(
+    key: &[u8],
+    count: Option<usize>,
+    left: bool,
+    protocol: u8,
+    in_memory: &mut Arc<RwLock<Database>>,
+    config: &Arc<Config>,
+) -> Option<Vec<u8>> {
+    let popped = {
+        let mut db = in_memory.write().unwrap();
+        let list = match db.get_list_mut(key, false) {
+            Ok(Some(list)) => list,
+            Ok(None) if count.is_some() => {
+                return Some(RespType::NullArray.serialize_for(protocol))
+            }
+            Ok(None) => return Some(RespType::NullBulkString.serialize_for(protocol)),
+            Err(e) => return Some(RespType::Error(e).serialize()),
+        };
+        let popped = (0..count.unwrap_or(1))
+            .map_while(|_| {
+                if left {
+                    list.pop_front()
+                } else {
+                    list.pop_back()
+                }
+            })
+            .collect::<Vec<Vec<u8>>>();
+        db.remove_if_empty(key);
+        popped
+    };
+
+    if !popped.is_empty() {
+        let popped_count = popped.len().to_string();
+        let pop: [&[u8]; 3] = [
+            if left { b"LPOP" } else { b"RPOP" },
+            key,
+            popped_count.as_bytes(),
+        ];
+        propagate_to_replicas(in_memory, &pop, config).await;
+    }
+    match count {
+        Some(_) => {
+            Some(RespType::Array(popped.into_iter().map(RespType::BulkBytes).collect()).serialize())
+        }
+        None => popped
+            .into_iter()
+            .next()
+            .map(|element| RespType::BulkBytes(element).serialize()),
+    }
+}
+
+fn handle_lrange(
+    key: &[u8],
+    start: i64,
+    stop: i64,
+    in_memory: &mut Arc<RwLock<Database>>,
+) -> Option<Vec<u8>> {
+    let db = in_memory.read().unwrap();
+    let list = match db.get_list(key) {
+        Ok(Some(list)) => list,
+        Ok(None) => return Some(RespType::Array(Vec::new()).serialize()),
+        Err(e) => return Some(RespType::Error(e).serialize()),
+    };
+    let elements = index_range(start, stop, list.len()).map_or(Vec::new(), |range| {
+        list.range(range)
+            .map(|element| RespType::BulkBytes(element.clone()))
+            .collect()
+    });
+    Some(RespType::Array(elements).serialize())
+}
+
+// The elements from `start` to `stop` inclusive of a `len` element list or
+// sorted set, as LRANGE and ZRANGE count them: negative indexes from the
+// end, clamped to the elements that exist. `None` means the range is empty.
+fn index_range(start: i64, stop: i64, len: usize) -> Option<RangeInclusive<usize>> {
+    let len = len as i64;
+    let start = if start < 0 { len + start } else { start }.max(0);
+    let stop = if stop < 0 { len + stop } else { stop }.min(len - 1);
+    (start <= stop).then_some(start as usize..=stop as usize)
+}
+
+// Matches are searched for from the head, or from the tail for a negative
+// rank, skipping the first `|rank| - 1` of them. Indexes always count from
+// the head.
+fn handle_lpos(
+    key: &[u8],
+    element: &[u8],
+    rank: i64,
+    count: Option<usize>,
+    protocol: u8,
+    in_memory: &mut Arc<RwLock<Database>>,
+) -> Option<Vec<u8>> {
+    let db = in_memory.read().unwrap();
+    let empty = VecDeque::new();
+    let list = match db.get_list(key) {
+        Ok(list) => list.unwrap_or(&empty),
+        Err(e) => return Some(RespType::Error(e).serialize()),
+    };
+    let skip = (rank.unsigned_abs() - 1) as usize;
+    let limit = match count {
+        Some(0) => usize::MAX,
+        Some(count) => count,
+        None => 1,
+    };
+
+    let matches = list
+        .iter()
+        .enumerate()
+        .filter(|(_, candidate)| candidate.as_slice() == element)
+        .map(|(index, _)| RespType::Integer(index as i64));
+    let found = if rank > 0 {
+        matches.skip(skip).take(limit).collect::<Vec<RespType>>()
+    } else {
+        matches.rev().skip(skip).take(limit).collect()
+    };
+    match count {
+        Some(_) => Some(RespType::Array(found).serialize()),
+        None => Some(
+            found
+                .into_iter()
+                .next()
+                .unwrap_or(RespType::NullBulkString)
+                .serialize_for(protocol),
+        ),
+    }
+}
+
+// Replies with the new length, 0 when the key doesn't exist and -1 when
+// the pivot isn't in the list.
+async fn handle_linsert(
+    key: &[u8],
+    before: bool,
+    pivot: &[u8],
+    element: &[u8],
+    in_memory: &mut Arc<RwLock<Database>>,
+    config: &Arc<Config>,
+) -> Option<Vec<u8>> {
+    let len = {
+        let mut db = in_memory.write().unwrap();
+        let list = match db.get_list(key) {
+            Ok(Some(list)) => list,
+            Ok(None) => return Some(RespType::Integer(0).serialize()),
+            Err(e) => return Some(RespType::Error(e).serialize()),
+        };
+        let Some(position) = list.iter().position(|candidate| candidate == pivot) else {
+            return Some(RespType::Integer(-1).serialize());
+        };
+        let list = db.get_list_mut(key, false).unwrap().unwrap();
+        list.insert(
+            if before { position } else { position + 1 },
+            element.to_vec(),
+        );
+        list.len()
+    };
+
+    let place: &[u8] = if before { b"BEFORE" } else { b"AFTER" };
+    propagate_to_replicas(in_memory, &[b"LINSERT", key, place, pivot, element], config).await;
+    Some(RespType::Integer(len as i64).serialize())
+}
+
+// Overwrites the element at `index`, negative indexes counting from the
+// tail. The key keeps its TTL.
+async fn handle_lset(
+    key: &[u8],
+    index: i64,
+    element: &[u8],
+    in_memory: &mut Arc<RwLock<Database>>,
+    config: &Arc<Config>,
+) -> Option<Vec<u8>> {
+    {
+        let mut db = in_memory.write().unwrap();
+        let len = match db.get_list(key) {
+            Ok(Some(list)) => list.len() as i64,
+            Ok(None) => {
+                return Some(
+                    RespType::Error(RedisError::Err("no such key".to_string())).serialize(),
+                )
+            }
+            Err(e) => return Some(RespType::Error(e).serialize()),
+        };
+        let index = if index < 0 { len + index } else { index };
+        if !(0..len).contains(&index) {
+            return Some(
+                RespType::Error(RedisError::Err("index out of range".to_string())).serialize(),
+            );
+        }
+        let list = db.get_list_mut(key, false).unwrap().unwrap();
+        list[index as usize] = element.to_vec();
+    }
+
+    let index = index.to_string();
+    propagate_to_replicas(
+        in_memory,
+        &[b"LSET", key, index.as_bytes(), element],
+        config,
+    )
+    .await;
+    Some(RespType::SimpleString("OK".to_string()).serialize())
+}
+
+// Pops an element off `src` and pushes it onto `dst` under one lock, so no
+// other client sees it in neither or both lists. `src` and `dst` may be the
+// same list, which rotates it.
+async fn handle_lmove(
+    src: &[u8],
+    dst: &[u8],
+    from: ListEnd,
+    to: ListEnd,
+    protocol: u8,
+    in_memory: &mut Arc<RwLock<Database>>,
+    config: &Arc<Config>,
+) -> Option<Vec<u8>> {
+    let (db, element) = {
+        let mut db = in_memory.write().unwrap();
+        // Check the destination first so a WRONGTYPE leaves `src` untouched.
+        if let Err(e) = db.get_list(dst) {
+            return Some(RespType::Error(e).serialize());
+        }
+        let list = match db.get_list_mut(src, false) {
+            Ok(Some(list)) => list,
+            Ok(None) => return Some(RespType::NullBulkString.serialize_for(protocol)),
+            Err(e) => return Some(RespType::Error(e).serialize()),
+        };
+        let element = match from {
+            ListEnd::Left => list.pop_front(),
+            ListEnd::Right => list.pop_back(),
+        }
+        .unwrap();
+
+        let list = db.get_list_mut(dst, true).unwrap().unwrap();
+        match to {
+            ListEnd::Left => list.push_front(element.clone()),
+            ListEnd::Right => list.push_back(element.clone()),
+        }
+        // Only now, so rotating a one-element list keeps the key and its TTL.
+        db.remove_if_empty(src);
+        (db.index, element)
+    };
+    config.blocked.notify(db, dst);
+
+    let lmove: [&[u8]; 5] = [b"LMOVE", src, dst, from.as_bytes(), to.as_bytes()];
+    propagate_to_replicas(in_memory, &lmove, config).await;
+    Some(RespType::BulkBytes(element).serialize())
+}
+
+// Removes the first `count` matches, the last `|count|` for a negative
+// count, or every match for zero. Replies with how many went.
+async fn handle_lrem(
+    key: &[u8],
+    count: i64,
+    element: &[u8],
+    in_memory: &mut Arc<RwLock<Database>>,
+    config: &Arc<Config>,
+) -> Option<Vec<u8>> {
+    let removed = {
+        let mut db = in_memory.write().unwrap();
+        let list = match db.get_list(key) {
+            Ok(Some(list)) => list,
+            Ok(None) => return Some(RespType::Integer(0).serialize()),
+            Err(e) => return Some(RespType::Error(e).serialize()),
+        };
+        let limit = match count {
+            0 => usize::MAX,
+            count => count.unsigned_abs() as usize,
+        };
+        let matches = list
+            .iter()
+            .enumerate()
+            .filter(|(_, candidate)| candidate.as_slice() == element)
+            .map(|(index, _)| index);
+        // The indexes covering exactly the matches to remove.
+        let span = if count >= 0 {
+            matches.take(limit).last().map(|last| 0..=last)
+        } else {
+            matches
+                .rev()
+                .take(limit)
+                .last()
+                .map(|first| first..=usize::MAX)
+        };
+        let Some(span) = span else {
+            return Some(RespType::Integer(0).serialize());
+        };
+
+        let list = db.get_list_mut(key, false).unwrap().unwrap();
+        let len = list.len();
+        let mut index = 0;
+        list.retain(|candidate| {
+            let remove = span.contains(&index) && candidate == element;
+            index += 1;
+            !remove
+        });
+        let removed = len - list.len();
+        db.remove_if_empty(key);
+        removed
+    };
+
+    let count = count.to_string();
+    propagate_to_replicas(
+        in_memory,
+        &[b"LREM", key, count.as_bytes(), element],
+        config,
+    )
+    .await;
+    Some(RespType::Integer(removed as i64).serialize())
+}
+
+// Only the members that change are propagated, as a plain ZADD of their
+// new scores.
+async fn handle_zadd(
+    key: &[u8],
+    members: &[(Score, Vec<u8>)],
+    condition: &Option<SetCondition>,
+    score_condition: Option<ScoreCondition>,
+    ch: bool,
+    in_memory: &mut Arc<RwLock<Database>>,
+    config: &Arc<Config>,
+) -> Option<Vec<u8>> {
+    let (added, applied) = {
+        let mut db = in_memory.write().unwrap();
+        let create = *condition != Some(SetCondition::Xx);
+        let zset = match db.get_zset_mut(key, create) {
+            Ok(Some(zset)) => zset,
+            Ok(None) => return Some(RespType::Integer(0).serialize()),
+            Err(e) => return Some(RespType::Error(e).serialize()),
+        };
+
+        let mut added = 0;
+        let mut applied = Vec::new();
+        for (Score(score), member) in members {
+            let skip = match (zset.score(member), condition, score_condition) {
+                (None, Some(SetCondition::Xx), _) => true,
+                (None, _, _) => false,
+                (Some(_), Some(SetCondition::Nx), _) => true,
+                (Some(old), _, Some(ScoreCondition::Gt)) => *score <= old,
+                (Some(old), _, Some(ScoreCondition::Lt)) => *score >= old,
+                (Some(old), _, None) => *score == old,
+            };
+            if skip {
+                continue;
+            }
+            if zset.insert(member, *score).is_none() {
+                added += 1;
+            }
+            applied.push((format_double(*score), member));
+        }
+        db.remove_if_empty(key);
+        (added, applied)
+    };
+
+    if !applied.is_empty() {
+        let mut zadd: Vec<&[u8]> = vec![b"ZADD", key];
+        for (score, member) in &applied {
+            zadd.push(score.as_bytes());
+            zadd.push(member);
+        }
+        propagate_to_replicas(in_memory, &zadd, config).await;
+    }
+    let reply = if ch { applied.len() } else { added };
+    Some(RespType::Integer(reply as i64).serialize())
+}
+
+// ZRANGE and its BYSCORE and BYLEX forms, which ZRANGEBYSCORE and
+// ZRANGEBYLEX are too.
+fn handle_zrange(
+    key: &[u8],
+    range: &ZRange,
+    withscores: bool,
+    protocol: u8,
+    in_memory: &mut Arc<RwLock<Database>>,
+) -> Option<Vec<u8>> {
+    let db = in_memory.read().unwrap();
+    let members = match db.get_zset(key) {
+        Ok(zset) => zset.map_or_else(Vec::new, |zset| zrange_members(zset, range)),
+        Err(e) => return Some(RespType::Error(e).serialize()),
+    };
+    Some(scored_members(members, withscores, protocol).serialize_for(protocol))
+}
+
+// The members `range` selects, in the order they're returned.
+fn zrange_members<'a>(zset: &'a SortedSet, range: &'a ZRange) -> Vec<(&'a Vec<u8>, f64)> {
+    let members: Vec<(&Vec<u8>, f64)> = match &range.by {
+        ZRangeBy::Index(start, stop) => {
+            let Some(span) = index_range(*start, *stop, zset.len()) else {
+                return Vec::new();
+            };
+            let (skip, take) = (*span.start(), span.count());
+            return if range.rev {
+                zset.iter().rev().skip(skip).take(take).collect()
+            } else {
+                zset.iter().skip(skip).take(take).collect()
+            };
+        }
+        ZRangeBy::Score(min, max) => zset.range_by_score(*min, *max).collect(),
+        ZRangeBy::Lex(min, max) => zset.range_by_lex(min, max).collect(),
+    };
+
+    let (offset, count) = range.limit.unwrap_or((0, -1));
+    // A negative offset always gives an empty reply.
+    let Ok(offset) = usize::try_from(offset) else {
+        return Vec::new();
+    };
+    let take = usize::try_from(count).unwrap_or(usize::MAX);
+    if range.rev {
+        members.into_iter().rev().skip(offset).take(take).collect()
+    } else {
+        members.into_iter().skip(offset).take(take).collect()
+    }
+}
+
+// Replaces `dst` with the selected members of `src`, or deletes it when
+// there are none. Replicas get the result rather than the range, as a DEL
+// and a ZADD.
+async fn handle_zrangestore(
+    dst: &[u8],
+    src: &[u8],
+    range: &ZRange,
+    in_memory: &mut Arc<RwLock<Database>>,
+    config: &Arc<Config>,
+) -> Option<Vec<u8>> {
+    let (stored, existed) = {
+        let mut db = in_memory.write().unwrap();
+        let mut stored = SortedSet::default();
+        match db.get_zset(src) {
+            Ok(Some(zset)) => {
+                for (member, score) in zrange_members(zset, range) {
+                    stored.insert(member, score);
+                }
+            }
+            Ok(None) => {}
+            Err(e) => return Some(RespType::Error(e).serialize()),
+        }
+        let existed = db.store(dst, Value::ZSet(stored.clone()));
+        (stored, existed)
+    };
+
+    if existed {
+        propagate_to_replicas(in_memory, &[b"DEL", dst], config).await;
+    }
+    if !stored.is_empty() {
+        let scores = stored
+            .iter()
+            .map(|(_, score)| format_double(score))
+            .collect::<Vec<String>>();
+        let mut zadd: Vec<&[u8]> = vec![b"ZADD", dst];
+        for ((member, _), score) in stored.iter().zip(&scores) {
+            zadd.push(score.as_bytes());
+            zadd.push(member);
+        }
+        propagate_to_replicas(in_memory, &zadd, config).await;
+    }
+    Some(RespType::Integer(stored.len() as i64).serialize())
+}
+
+// The reply to ZRANGE, ZRANGEBYSCORE and ZRANDMEMBER. With scores, RESP3
+// clients get each member paired with its score and RESP2 clients one flat
+// array.
+fn scored_members(members: Vec<(&Vec<u8>, f64)>, withscores: bool, protocol: u8) -> RespType {
+    let reply = members
+        .into_iter()
+        .flat_map(|(member, score)| {
+            let member = RespType::BulkBytes(member.clone());
+            match (withscores, protocol >= 3) {
+                (false, _) => vec![member],
+                (true, false) => vec![member, RespType::Double(score)],
+                (true, true) => vec![RespType::Array(vec![member, RespType::Double(score)])],
+            }
+        })
+        .collect();
+    RespType::Array(reply)
+}
+
+// Removes the key along with its last member. Only members that were
+// there are propagated.
+async fn handle_zrem(
+    key: &[u8],
+    members: &[Vec<u8>],
+    in_memory: &mut Arc<RwLock<Database>>,
+    config: &Arc<Config>,
+) -> Option<Vec<u8>> {
+    let removed = {
+        let mut db = in_memory.write().unwrap();
+        let zset = match db.get_zset_mut(key, false) {
+            Ok(Some(zset)) => zset,
+            Ok(None) => return Some(RespType::Integer(0).serialize()),
+            Err(e) => return Some(RespType::Error(e).serialize()),
+        };
+        let removed = members
+            .iter()
+            .filter(|member| zset.remove(member).is_some())
+            .collect::<Vec<&Vec<u8>>>();
+        db.remove_if_empty(key);
+        removed
+    };
+
+    if !removed.is_empty() {
+        let mut zrem: Vec<&[u8]> = vec![b"ZREM", key];
+        zrem.extend(removed.iter().map(|member| member.as_slice()));
+        propagate_to_replicas(in_memory, &zrem, config).await;
+    }
+    Some(RespType::Integer(removed.len() as i64).serialize())
+}
+
+async fn handle_hset(
+    key: &[u8],
+    fields: &Fields,
+    in_memory: &mut Arc<RwLock<Database>>,
+    config: &Arc<Config>,
+) -> Option<Vec<u8>> {
+    let added = {
+        let mut db = in_memory.write().unwrap();
+        let hash = match db.get_hash_mut(key, true) {
+            Ok(hash) => hash.unwrap(),
+            Err(e) => return Some(RespType::Error(e).serialize()),
+        };
+        fields
+            .iter()
+            .filter(|(field, value)| hash.insert(field.clone(), value.clone()).is_none())
+            .count()
+    };
+
+    let mut hset: Vec<&[u8]> = vec![b"HSET", key];
+    for (field, value) in fields {
+        hset.push(field);
+        hset.push(value);
+    }
+    propagate_to_replicas(in_memory, &hset, config).await;
+    Some(RespType::Integer(added as i64).serialize())
+}
+
+// Removes the key along with its last field. Only fields that were there
+// are propagated.
+async fn handle_hdel(
+    key: &[u8],
+    fields: &[Vec<u8>],
+    in_memory: &mut Arc<RwLock<Database>>,
+    config: &Arc<Config>,
+) -> Option<Vec<u8>> {
+    let removed = {
+        let mut db = in_memory.write().unwrap();
+        let hash = match db.get_hash_mut(key, false) {
+            Ok(Some(hash)) => hash,
+            Ok(None) => return Some(RespType::Integer(0).serialize()),
+            Err(e) => return Some(RespType::Error(e).serialize()),
+        };
+        let removed = fields
+            .iter()
+            .filter(|field| hash.remove(*field).is_some())
+            .collect::<Vec<&Vec<u8>>>();
+        db.remove_if_empty(key);
+        removed
+    };
+
+    if !removed.is_empty() {
+        let mut hdel: Vec<&[u8]> = vec![b"HDEL", key];
+        hdel.extend(removed.iter().map(|field| field.as_slice()));
+        propagate_to_replicas(in_memory, &hdel, config).await;
+    }
+    Some(RespType::Integer(removed.len() as i64).serialize())
+}
+
+// A positive count picks that many distinct members (all of them if there
+// are fewer), a negative one picks `-count` members independently, so they
+// may repeat.
+fn handle_zrandmember(
+    key: &[u8],
+    count: Option<i64>,
+    withscores: bool,
+    protocol: u8,
+    in_memory: &mut Arc<RwLock<Database>>,
+    config: &Arc<Config>,
+) -> Option<Vec<u8>> {
+    let db = in_memory.read().unwrap();
+    let zset = match db.get_zset(key) {
+        Ok(zset) => zset,
+        Err(e) => return Some(RespType::Error(e).serialize()),
+    };
+    let members: Vec<(&Vec<u8>, f64)> = zset.map_or_else(Vec::new, |zset| zset.iter().collect());
+    let mut rng = config.rng.lock().unwrap();
+
+    let Some(count) = count else {
+        if members.is_empty() {
+            return Some(RespType::NullBulkString.serialize_for(protocol));
+        }
+        let (member, _) = members[rng.gen_range(members.len())];
+        return Some(RespType::BulkBytes(member.clone()).serialize());
+    };
+
+    let picked = if members.is_empty() {
+        Vec::new()
+    } else if count < 0 {
+        (0..count.unsigned_abs())
+            .map(|_| members[rng.gen_range(members.len())])
+            .collect()
+    } else {
+        // A partial Fisher-Yates shuffle: the first `count` slots end up a
+        // uniform sample in random order.
+        let mut members = members;
+        let count = (count as usize).min(members.len());
+        for i in 0..count {
+            let j = i + rng.gen_range(members.len() - i);
+            members.swap(i, j);
+        }
+        members.truncate(count);
+        members
+    };
+    Some(scored_members(picked, withscores, protocol).serialize_for(protocol))
+}
+
+// Replicas get the ID the entry was given, so they don't generate their
+// own.
+async fn handle_xadd(
+    key: &[u8],
+    id: XAddId,
+    fields: &Fields,
+    in_memory: &mut Arc<RwLock<Database>>,
+    config: &Arc<Config>,
+) -> Option<Vec<u8>> {
+    let (db, id) = {
+        let mut db = in_memory.write().unwrap();
+        let next_id = match db.get_stream(key) {
+            Ok(Some(stream)) => stream.next_id(id),
+            Ok(None) => Stream::default().next_id(id),
+            Err(e) => return Some(RespType::Error(e).serialize()),
+        };
+        let id = match next_id {
+            Ok(id) => id,
+            Err(e) => return Some(RespType::Error(e).serialize()),
+        };
+        let stream = db.get_stream_mut(key, true).unwrap().unwrap();
+        stream.insert(id, fields.clone());
+        (db.index, id.to_string())
+    };
+    config.blocked.notify(db, key);
+
+    let mut xadd: Vec<&[u8]> = vec![b"XADD", key, id.as_bytes()];
+    for (field, value) in fields {
+        xadd.push(field);
+        xadd.push(value);
+    }
+    propagate_to_replicas(in_memory, &xadd, config).await;
+    Some(RespType::BulkString(id).serialize())
+}
+
+fn handle_xrange(
+    key: &[u8],
+    start: StreamId,
+    end: StreamId,
+    count: Option<usize>,
+    in_memory: &mut Arc<RwLock<Database>>,
+) -> Option<Vec<u8>> {
+    let db = in_memory.read().unwrap();
+    let stream = match db.get_stream(key) {
+        Ok(stream) => stream,
+        Err(e) => return Some(RespType::Error(e).serialize()),
+    };
+    let entries = match stream {
+        Some(stream) if start <= end => stream
+            .entries
+            .range(start..=end)
+            .take(count.unwrap_or(usize::MAX))
+            .map(stream_entry)
+            .collect(),
+        _ => Vec::new(),
+    };
+    Some(RespType::Array(entries).serialize())
+}
+
+// Replies with the entries after each given ID, leaving out streams that
+// have none, or a null reply when none of them do. RESP3 clients get a map
+// from key to entries, RESP2 clients an array of [key, entries] pairs.
+// The entries after each stream's ID, or `None` when none has any.
+fn read_streams(
+    streams: &[(Vec<u8>, Option<StreamId>)],
+    count: Option<usize>,
+    protocol: u8,
+    in_memory: &Arc<RwLock<Database>>,
+) -> Option<RespType> {
+    let db = in_memory.read().unwrap();
+    let mut found = Vec::new();
+    for (key, after) in streams {
+        let stream = match db.get_stream(key) {
+            Ok(Some(stream)) => stream,
+            Ok(None) => continue,
+            Err(e) => return Some(RespType::Error(e)),
+        };
+        let after = after.unwrap_or(stream.last_id);
+        let entries: Vec<RespType> = stream
+            .entries
+            .range((Bound::Excluded(after), Bound::Unbounded))
+            .take(count.unwrap_or(usize::MAX))
+            .map(stream_entry)
+            .collect();
+        if !entries.is_empty() {
+            found.push((RespType::BulkBytes(key.clone()), RespType::Array(entries)));
+        }
+    }
+
+    if found.is_empty() {
+        None
+    } else if protocol >= 3 {
+        Some(RespType::Map(found))
+    } else {
+        Some(RespType::Array(
+            found
+                .into_iter()
+                .map(|(key, entries)| RespType::Array(vec![key, entries]))
+                .collect(),
+        ))
+    }
+}
+
+// XREAD BLOCK outside MULTI, waiting the way `handle_bpop` does. `$` is
+// pinned to each stream's last ID up front, so only entries added while
+// waiting count as new.
+#[allow(clippy::too_many_arguments)]
+pub async fn handle_xread_block<S: AsyncBufRead + Unpin>(
+    streams: &[(Vec<u8>, Option<StreamId>)],
+    count: Option<usize>,
+    timeout: Duration,
+    stream: &mut S,
+    in_memory: &mut Arc<RwLock<Database>>,
+    config: &Arc<Config>,
+    client: &mut ClientState,
+) -> Option<Vec<u8>> {
+    let (db, streams) = {
+        let db = in_memory.read().unwrap();
+        let streams: Vec<(Vec<u8>, Option<StreamId>)> = streams
+            .iter()
+            .map(|(key, after)| {
+                let last = || match db.get_stream(key) {
+                    Ok(Some(stream)) => stream.last_id,
+                    _ => StreamId::default(),
+                };
+                (key.clone(), Some(after.unwrap_or_else(last)))
+            })
+            .collect();
+        (db.index, streams)
+    };
+    let keys: Vec<Vec<u8>> = streams.iter().map(|(key, _)| key.clone()).collect();
+    let waiter = Arc::new(Notify::new());
+    let _registration = config.blocked.register(db, &keys, &waiter);
+    let deadline = (!timeout.is_zero()).then(|| tokio::time::Instant::now() + timeout);
+    let mut watch_stream = true;
+
+    loop {
+        {
+            let _shared = config.transaction_lock.read().await;
+            if let Some(reply) = read_streams(&streams, count, client.protocol, in_memory) {
+                return Some(reply.serialize_for(client.protocol));
+            }
+        }
+
+        match wait_for_wakeup(&waiter, deadline, stream, &mut watch_stream, client).await {
+            Wakeup::Notified => {}
+            Wakeup::TimedOut => return Some(RespType::NullArray.serialize_for(client.protocol)),
+            Wakeup::Gone => return None,
+        }
+    }
+}
+
+// An entry as stream commands reply with it: its ID, then its fields and
+// values in one flat array.
+fn stream_entry((id, fields): (&StreamId, &Fields)) -> RespType {
+    let fields = fields
+        .iter()
+        .flat_map(|(field, value)| {
+            [
+                RespType::BulkBytes(field.clone()),
+                RespType::BulkBytes(value.clone()),
+            ]
+        })
+        .collect();
+    RespType::Array(vec![
+        RespType::BulkString(id.to_string()),
+        RespType::Array(fields),
+    ])
+}
+
+fn handle_object(
+    subcommand: &str,
+    key: &[u8],
+    in_memory: &mut Arc<RwLock<Database>>,
+    config: &Arc<Config>,
+) -> Option<Vec<u8>> {
+    let db = in_memory.read().unwrap();
+    let Some(item) = db.get(key) else {
+        return Some(RespType::Error(RedisError::Err("no such key".to_string())).serialize());
+    };
+    match subcommand {
+        "encoding" => Some(RespType::BulkString(item.encoding(config).to_string()).serialize()),
+        "refcount" => Some(RespType::Integer(item.refcount()).serialize()),
+        _ => Some(
+            RespType::Error(RedisError::Err(format!(
+                "unknown subcommand '{}'. Try OBJECT HELP.",
+                subcommand
+            )))
+            .serialize(),
+        ),
+    }
+}
+
+// XINFO STREAM describes the stream; XINFO GROUPS lists its consumer
+// groups, of which there are never any since XGROUP isn't supported.
+fn handle_xinfo(
+    subcommand: &str,
+    key: &[u8],
+    protocol: u8,
+    in_memory: &mut Arc<RwLock<Database>>,
+) -> Option<Vec<u8>> {
+    let db = in_memory.read().unwrap();
+    let stream = match db.get_stream(key) {
+        Ok(Some(stream)) => stream,
+        Ok(None) => {
+            return Some(RespType::Error(RedisError::Err("no such key".to_string())).serialize())
+        }
+        Err(e) => return Some(RespType::Error(e).serialize()),
+    };
+    match subcommand {
+        "stream" => {
+            let entry =
+                |entry: Option<(&StreamId, &Fields)>| entry.map_or(RespType::Null, stream_entry);
+            let field = |name: &str| RespType::BulkString(name.to_string());
+            Some(
+                RespType::Map(vec![
+                    (
+                        field("length"),
+                        RespType::Integer(stream.entries.len() as i64),
+                    ),
+                    (
+                        field("last-generated-id"),
+                        RespType::BulkString(stream.last_id.to_string()),
+                    ),
+                    (field("groups"), RespType::Integer(0)),
+                    (
+                        field("first-entry"),
+                        entry(stream.entries.first_key_value()),
+                    ),
+                    (field("last-entry"), entry(stream.entries.last_key_value())),
+                ])
+                .serialize_for(protocol),
+            )
+        }
+        "groups" => Some(RespType::Array(Vec::new()).serialize()),
+        _ => Some(
+            RespType::Error(RedisError::Err(format!(
+                "unknown subcommand '{}'. Try XINFO HELP.",
+                subcommand
+            )))
+            .serialize(),
+        ),
+    }
+}
+
+// The line of `field:value` pairs Redis's DEBUG OBJECT gives and test
+// suites parse, plus `variant:` naming the `Value` the key holds. Access
+// times aren't tracked, so every key reads as just used.
+fn handle_debug_object(
+    key: &[u8],
+    in_memory: &mut Arc<RwLock<Database>>,
+    config: &Arc<Config>,
+) -> Option<Vec<u8>> {
+    let db = in_memory.read().unwrap();
+    let Some(item) = db.get(key) else {
+        return Some(RespType::Error(RedisError::Err("no such key".to_string())).serialize());
+    };
+    // Redis's LRU clock: Unix time in seconds, 24 bits wide.
+    let lru = (unix_time_ms() / 1000) as u64 & ((1 << 24) - 1);
+    let reply = format!(
+        "Value at:{:p} refcount:{} encoding:{} serializedlength:{} lru:{} lru_seconds_idle:0 \
+         variant:{}",
+        item,
+        item.refcount(),
+        item.encoding(config),
+        serialized_length(&item.value),
+        lru,
+        item.value.variant_name()
+    );
+    Some(RespType::BulkString(reply).serialize())
+}
+
+async fn handle_bitfield(
+    key: &[u8],
+    ops: &[BitFieldOp],
+    protocol: u8,
+    in_memory: &mut Arc<RwLock<Database>>,
+    config: &Arc<Config>,
+) -> Option<Vec<u8>> {
+    let (replies, changed) = {
+        let mut db = in_memory.write().unwrap();
+        let existing = db.get(key).cloned();
+        let mut bytes = match existing.as_ref().map(Item::as_string).transpose() {
+            Ok(bytes) => bytes.cloned().unwrap_or_default(),
+            Err(e) => return Some(RespType::Error(e).serialize()),
+        };
+
+        let mut changed = false;
+        let mut replies = Vec::with_capacity(ops.len());
+        for op in ops {
+            // The field to write, if any, and the op's reply. OVERFLOW FAIL
+            // leaves the field alone and replies nil.
+            let (write, reply) = match op {
+                BitFieldOp::Get { field, offset } => (None, Some(field.get(&bytes, *offset))),
+                BitFieldOp::Set {
+                    field,
+                    offset,
+                    value,
+                    overflow,
+                } => {
+                    let old = field.get(&bytes, *offset);
+                    let new = field.fit(*value as i128, *overflow);
+                    (new.map(|new| (field, offset, new)), new.map(|_| old))
+                }
+                BitFieldOp::IncrBy {
+                    field,
+                    offset,
+                    increment,
+                    overflow,
+                } => {
+                    let old = field.get(&bytes, *offset);
+                    let new = field.fit(old as i128 + *increment as i128, *overflow);
+                    (new.map(|new| (field, offset, new)), new)
+                }
+            };
+            if let Some((field, offset, value)) = write {
+                field.set(&mut bytes, *offset, value);
+                changed = true;
+            }
+            replies.push(reply.map_or(RespType::NullBulkString, RespType::Integer));
+        }
+
+        // Writing in place keeps the key's TTL.
+        if changed {
+            let item = match existing {
+                Some(item) => Item {
+                    value: Value::String(bytes),
+                    ..item
+                },
+                None => Item::new(Value::String(bytes), None),
+            };
+            db.insert(key.to_vec(), item);
+        }
+        (replies, changed)
+    };
+
+    if changed {
+        let args = ops
+            .iter()
+            .flat_map(BitFieldOp::to_args)
+            .collect::<Vec<String>>();
+        let mut bitfield: Vec<&[u8]> = vec![b"BITFIELD", key];
+        bitfield.extend(args.iter().map(String::as_bytes));
+        propagate_to_replicas(in_memory, &bitfield, config).await;
+    }
+    Some(RespType::Array(replies).serialize_for(protocol))
+}
+
+// Connections keep their database index, so those on either side of the
+// swap see the other's data from now on.
+async fn handle_swapdb(
+    first: usize,
+    second: usize,
+    in_memory: &mut Arc<RwLock<Database>>,
+    config: &Arc<Config>,
+) -> Option<Vec<u8>> {
+    if first.max(second) >= config.databases.len() {
+        return Some(
+            RespType::Error(RedisError::Err("DB index is out of range".to_string())).serialize(),
+        );
+    }
+    if first != second {
+        // Lock in index order so two SWAPDBs can't deadlock.
+        let (low, high) = (first.min(second), first.max(second));
+        let mut low = config.databases[low].write().unwrap();
+        let mut high = config.databases[high].write().unwrap();
+        low.swap(&mut high);
+    }
+
+    let (first, second) = (first.to_string(), second.to_string());
+    propagate_to_replicas(
+        in_memory,
+        &[b"SWAPDB", first.as_bytes(), second.as_bytes()],
+        config,
+    )
+    .await;
+    Some(RespType::SimpleString("OK".to_string()).serialize())
+}
+
+// FLUSHDB empties the selected database, FLUSHALL every database.
+async fn handle_flush(
+    all: bool,
+    in_memory: &mut Arc<RwLock<Database>>,
+    config: &Arc<Config>,
+) -> Option<Vec<u8>> {
+    if all {
+        for db in &config.databases {
+            db.write().unwrap().clear();
+        }
+        propagate_to_replicas(in_memory, &[b"FLUSHALL"], config).await;
+    } else {
+        in_memory.write().unwrap().clear();
+        propagate_to_replicas(in_memory, &[b"FLUSHDB"], config).await;
+    }
+    Some(RespType::SimpleString("OK".to_string()).serialize())
+}
+
+fn handle_client_setinfo(attr: &str, value: &str, client: &mut ClientState) -> Option<Vec<u8>> {
+    let attr = attr.to_lowercase();
+    if attr != "lib-name" && attr != "lib-ver" {
+        return Some(
+            RespType::Error(RedisError::Err(format!("Unrecognized option '{}'", attr))).serialize(),
+        );
+    }
+
+    if value.chars().any(|ch| !('!'..='~').contains(&ch)) {
+        return Some(
+            RespType::Error(RedisError::Err(format!(
+                "{} cannot contain spaces, newlines or special characters.",
+                attr
+            )))
+            .serialize(),
+        );
+    }
+
+    let value = (!value.is_empty()).then(|| value.to_string());
+    if attr == "lib-name" {
+        client.lib_name = value;
+    } else {
+        client.lib_ver = value;
+    }
+    Some(RespType::SimpleString("OK".to_string()).serialize())
+}
+
+// An empty name clears it.
+fn handle_client_setname(name: &str, client: &mut ClientState) -> Option<Vec<u8>> {
+    if name.chars().any(|ch| !('!'..='~').contains(&ch)) {
+        return Some(
+            RespType::Error(RedisError::Err(
+                "Client names cannot contain spaces, newlines or special characters.".to_string(),
+            ))
+            .serialize(),
+        );
+    }
+    client.name = (!name.is_empty()).then(|| name.to_string());
+    Some(RespType::SimpleString("OK".to_string()).serialize())
+}
+
+// Shard channels (SSUBSCRIBE) live in their own registry and are counted
+// separately from regular channels and patterns.
+async fn handle_subscribe(
+    names: &[String],
+    kind: SubscriptionKind,
+    config: &Arc<Config>,
+    client: &mut ClientState,
+) -> Option<Vec<u8>> {
+    let mut response = Vec::new();
+    for name in names {
+        set_subscribed(name, kind, true, config, &client.pubsub_sender).await;
+        client.subscriptions_mut(kind).insert(name.clone());
+        response.extend(subscription_frame(
+            subscription_frame_kind(kind, true),
+            Some(name),
+            client.subscription_count(kind),
+            client.protocol,
+        ));
+    }
+    Some(response)
+}
+
+// With no names given, UNSUBSCRIBE leaves every channel the client is in,
+// PUNSUBSCRIBE every pattern and SUNSUBSCRIBE every shard channel.
+async fn handle_unsubscribe(
+    names: &[String],
+    kind: SubscriptionKind,
+    config: &Arc<Config>,
+    client: &mut ClientState,
+) -> Option<Vec<u8>> {
+    let names = if names.is_empty() {
+        client.subscriptions_mut(kind).iter().cloned().collect()
+    } else {
+        names.to_vec()
+    };
+
+    let frame_kind = subscription_frame_kind(kind, false);
+    if names.is_empty() {
+        return Some(subscription_frame(
+            frame_kind,
+            None,
+            client.subscription_count(kind),
+            client.protocol,
+        ));
+    }
+
+    let mut response = Vec::new();
+    for name in names {
+        set_subscribed(&name, kind, false, config, &client.pubsub_sender).await;
+        client.subscriptions_mut(kind).remove(&name);
+        response.extend(subscription_frame(
+            frame_kind,
+            Some(&name),
+            client.subscription_count(kind),
+            client.protocol,
+        ));
+    }
+    Some(response)
+}
+
+// Adds `sender` to, or with `!on` removes it from, the registry for `kind`.
+async fn set_subscribed(
+    name: &str,
+    kind: SubscriptionKind,
+    on: bool,
+    config: &Config,
+    sender: &Subscriber,
+) {
+    match (kind, on) {
+        (SubscriptionKind::Channel, true) => config.pubsub.subscribe(name, sender).await,
+        (SubscriptionKind::Channel, false) => config.pubsub.unsubscribe(name, sender).await,
+        (SubscriptionKind::Pattern, true) => config.pubsub.psubscribe(name, sender).await,
+        (SubscriptionKind::Pattern, false) => config.pubsub.punsubscribe(name, sender).await,
+        (SubscriptionKind::Shard, true) => config.shard_pubsub.subscribe(name, sender).await,
+        (SubscriptionKind::Shard, false) => config.shard_pubsub.unsubscribe(name, sender).await,
+    }
+}
+
+// The first element of a (un)subscribe confirmation.
+fn subscription_frame_kind(kind: SubscriptionKind, on: bool) -> &'static str {
+    match (kind, on) {
+        (SubscriptionKind::Channel, true) => "subscribe",
+        (SubscriptionKind::Channel, false) => "unsubscribe",
+        (SubscriptionKind::Pattern, true) => "psubscribe",
+        (SubscriptionKind::Pattern, false) => "punsubscribe",
+        (SubscriptionKind::Shard, true) => "ssubscribe",
+        (SubscriptionKind::Shard, false) => "sunsubscribe",
+    }
+}
+
+// Pushed out of band to RESP3 connections, like the messages that follow.
+fn subscription_frame(kind: &str, channel: Option<&str>, count: usize, protocol: u8) -> Vec<u8> {
+    RespType::Push(vec![
+        RespType::BulkString(kind.to_string()),
+        channel.map_or(RespType::NullBulkString, |channel| {
+            RespType::BulkString(channel.to_string())
+        }),
+        RespType::Integer(count as i64),
+    ])
+    .serialize_for(protocol)
+}
+
+// Sends a write made in `in_memory` to every replica, preceded by a SELECT
+// when the stream was last switched to a different database.
+pub async fn propagate_to_replicas(
+    in_memory: &Arc<RwLock<Database>>,
+    args: &[&[u8]],
+    config: &Arc<Config>,
+) {
+    if config.role != Role::Master {
+        return;
+    }
+
+    let index = in_memory.read().unwrap().index;
+    let frame = |args: &[&[u8]]| {
+        RespType::Array(
+            args.iter()
+                .map(|arg| RespType::BulkBytes(arg.to_vec()))
+                .collect(),
+        )
+        .serialize()
+    };
+
+    // The offset moves while the replica list is still locked, so WAIT never
+    // sees a write on the wire that isn't counted yet, or the other way round.
+    let mut replicas = config.replication_manager.replicas.write().await;
+    let mut command = Vec::new();
+    {
+        let mut selected_db = config.replication_manager.selected_db.lock().unwrap();
+        if *selected_db != Some(index) {
+            command = frame(&[b"SELECT", index.to_string().as_bytes()]);
+            *selected_db = Some(index);
+        }
+    }
+    command.extend(frame(args));
+    for replica in replicas.iter_mut() {
+        if let Err(e) = replica.write_all(&command).await {
+            eprintln!("Error propagating command to replica: {}", e);
+        }
+    }
+    config.replication_manager.record(&command);
+}
+
+fn handle_get(key: &[u8], protocol: u8, in_memory: &mut Arc<RwLock<Database>>) -> Option<Vec<u8>> {
+    match in_memory.read().unwrap().storage.get(key) {
+        Some(item) if !item.is_expired() => match item.as_string() {
+            Ok(value) => Some(RespType::BulkBytes(value.clone()).serialize()),
+            Err(e) => Some(RespType::Error(e).serialize()),
+        },
+        _ => Some(RespType::NullBulkString.serialize_for(protocol)),
+    }
+}
+
+fn handle_hello(
+    protover: Option<u8>,
+    config: &Arc<Config>,
+    client: &mut ClientState,
+) -> Option<Vec<u8>> {
+    if let Some(protover) = protover {
+        client.protocol = protover;
+    }
+
+    let role = match config.role {
+        Role::Master => "master",
+        Role::Slave => "replica",
+    };
+    let fields = [
+        ("server", RespType::BulkString("redis".to_string())),
+        (
+            "version",
+            RespType::BulkString(env!("CARGO_PKG_VERSION").to_string()),
+        ),
+        ("proto", RespType::Integer(client.protocol as i64)),
+        ("mode", RespType::BulkString("standalone".to_string())),
+        ("role", RespType::BulkString(role.to_string())),
+        ("modules", RespType::Array(Vec::new())),
+    ];
+    let map = fields
+        .into_iter()
+        .map(|(name, value)| (RespType::BulkString(name.to_string()), value))
+        .collect();
+
+    Some(RespType::Map(map).serialize_for(client.protocol))
+}
+
+// Standalone answers for cluster-aware clients probing on connect: cluster
+// mode is off and this node serves no slots.
+fn handle_cluster(args: &[String], protocol: u8, config: &Arc<Config>) -> Option<Vec<u8>> {
+    let reply = match args[0].to_lowercase().as_str() {
+        "info" => RespType::VerbatimString("txt", "cluster_enabled:0\r\n".to_string()),
+        "myid" => RespType::BulkString(config.repl_id.clone()),
+        "slots" | "shards" => RespType::Array(Vec::new()),
+        _ => RespType::Error(RedisError::Err(format!(
+            "unknown subcommand '{}'. Try CLUSTER HELP.",
+            args[0]
+        ))),
+    };
+    Some(reply.serialize_for(protocol))
+}
+
+fn handle_config_get(patterns: &[String], protocol: u8, config: &Arc<Config>) -> Option<Vec<u8>> {
+    let matches = config
+        .parameters()
+        .into_iter()
+        .filter(|(name, _)| {
+            patterns
+                .iter()
+                .any(|pattern| glob_match(pattern.to_lowercase().as_bytes(), name.as_bytes()))
+        })
+        .map(|(name, value)| {
+            (
+                RespType::BulkString(name.to_string()),
+                RespType::BulkString(value),
+            )
+        })
+        .collect::<Vec<(RespType, RespType)>>();
+
+    Some(RespType::Map(matches).serialize_for(protocol))
+}
+
+fn handle_config_set(param: &str, value: &str, config: &Arc<Config>) -> Option<Vec<u8>> {
+    match param.to_lowercase().as_str() {
+        "dir" => {
+            if !Path::new(value).is_dir() {
+                return Some(
+                    RespType::Error(RedisError::Err(
+                        "CONFIG SET failed (possibly related to argument 'dir') - No such file or directory".to_string(),
+                    ))
+                    .serialize(),
+                );
+            }
+            config.set_dir(value.to_string());
+        }
+        "dbfilename" => {
+            if value.contains('/') {
+                return Some(
+                    RespType::Error(RedisError::Err(
+                        "CONFIG SET failed (possibly related to argument 'dbfilename') - dbfilename can't be a path, just a filename".to_string(),
+                    ))
+                    .serialize(),
+                );
+            }
+            config.set_dbfilename(value.to_string());
+        }
+        "activedefrag" => match value.to_lowercase().as_str() {
+            "yes" => config.set_activedefrag(true),
+            "no" => config.set_activedefrag(false),
+            _ => {
+                return Some(
+                    RespType::Error(RedisError::Err(
+                        "CONFIG SET failed (possibly related to argument 'activedefrag') - argument must be 'yes' or 'no'".to_string(),
+                    ))
+                    .serialize(),
+                )
+            }
+        },
+        "slowlog-log-slower-than" => match value.parse::<i64>() {
+            Ok(micros) => config.slowlog.set_log_slower_than(micros),
+            Err(_) => return Some(config_integer_error(param)),
+        },
+        "slowlog-max-len" => match value.parse::<usize>() {
+            Ok(max_len) => config.slowlog.set_max_len(max_len),
+            Err(_) => return Some(config_integer_error(param)),
+        },
+        "hash-max-listpack-entries" => match value.parse::<usize>() {
+            Ok(entries) => config.set_hash_max_listpack_entries(entries),
+            Err(_) => return Some(config_integer_error(param)),
+        },
+        "hash-max-listpack-value" => match value.parse::<usize>() {
+            Ok(bytes) => config.set_hash_max_listpack_value(bytes),
+            Err(_) => return Some(config_integer_error(param)),
+        },
+        _ => {
+            return Some(
+                RespType::Error(RedisError::Err(format!(
+                    "Unknown option or number of arguments for CONFIG SET - '{}'",
+                    param
+                )))
+                .serialize(),
+            )
+        }
+    }
+    Some(RespType::SimpleString("OK".to_string()).serialize())
+}
+
+fn config_integer_error(param: &str) -> Vec<u8> {
+    RespType::Error(RedisError::Err(format!(
+        "CONFIG SET failed (possibly related to argument '{}') - argument couldn't be parsed into an integer",
+        param.to_lowercase()
+    )))
+    .serialize()
+}
+
+// Picks uniformly among live keys by walking them to a random position,
+// which avoids copying every key out first.
+fn handle_randomkey(
+    protocol: u8,
+    in_memory: &mut Arc<RwLock<Database>>,
+    config: &Arc<Config>,
+) -> Option<Vec<u8>> {
+    let db = in_memory.read().unwrap();
+    let mut live = db.keys();
+
+    let count = live.clone().count();
+    if count == 0 {
+        return Some(RespType::NullBulkString.serialize_for(protocol));
+    }
+    let index = config.rng.lock().unwrap().gen_range(count);
+    let key = live.nth(index).cloned().unwrap_or_default();
+    Some(RespType::BulkBytes(key).serialize())
+}
+
+fn handle_keys(pattern: &[u8], in_memory: &mut Arc<RwLock<Database>>) -> Option<Vec<u8>> {
+    let db = in_memory.read().unwrap();
+    let db_keys = db
+        .storage
+        .keys()
+        .filter(|key| glob_match(pattern, key))
+        .map(|key| RespType::BulkBytes(key.clone()))
+        .collect::<Vec<RespType>>();
+    Some(RespType::Array(db_keys).serialize())
+}
+
+// The cursor is a position in the sorted key list, so iteration is stable
+// across calls even though `HashMap` ordering isn't.
+fn handle_scan(
+    cursor: usize,
+    pattern: &Option<Vec<u8>>,
+    count: usize,
+    in_memory: &mut Arc<RwLock<Database>>,
+) -> Option<Vec<u8>> {
+    let db = in_memory.read().unwrap();
+    let (next_cursor, keys) = db.scan(cursor, count);
+
+    let page = keys
+        .into_iter()
+        .filter(|key| db.get(key).is_some())
+        .filter(|key| match pattern {
+            Some(pattern) => glob_match(pattern, key),
+            None => true,
+        })
+        .map(|key| RespType::BulkBytes(key.to_vec()))
+        .collect::<Vec<RespType>>();
+
+    Some(
+        RespType::Array(vec![
+            RespType::BulkString(next_cursor.to_string()),
+            RespType::Array(page),
+        ])
+        .serialize(),
+    )
+}
+
+// Sections go in the order Redis prints them, each as a `# Name` header and
+// `field:value` lines. `all`, `everything` and `default` select every
+// section; names that match none give an empty reply.
+async fn handle_info(sections: &[String], protocol: u8, config: &Arc<Config>) -> Option<Vec<u8>> {
+    let wanted = |name: &str| {
+        sections.is_empty()
+            || sections.iter().any(|section| {
+                matches!(section.as_str(), "all" | "everything" | "default") || section == name
+            })
+    };
+    let mut output = Vec::new();
+
+    if wanted("server") {
+        output.push(format!(
+            "# Server\r\nredis_version:{}\r\nredis_mode:standalone\r\nos:{}\r\narch_bits:{}\r\nprocess_id:{}\r\ntcp_port:{}\r\nuptime_in_seconds:{}\r\nhz:{}\r\n",
+            REDIS_VERSION,
+            std::env::consts::OS,
+            usize::BITS,
+            std::process::id(),
+            config.port,
+            config.started.elapsed().as_secs(),
+            config.hz,
+        ));
+    }
+
+    if wanted("clients") {
+        output.push(format!(
+            "# Clients\r\nconnected_clients:{}\r\n",
+            config.clients.count()
+        ));
+    }
+
+    // Nothing tracks allocations, so used memory is estimated as the size
+    // of the keys and values in an RDB file.
+    if wanted("memory") {
+        let used: usize = config
+            .databases
+            .iter()
+            .map(|db| {
+                db.read()
+                    .unwrap()
+                    .storage
+                    .iter()
+                    .map(|(key, item)| key.len() + serialized_length(&item.value))
+                    .sum::<usize>()
+            })
+            .sum();
+        output.push(format!(
+            "# Memory\r\nused_memory:{}\r\nmaxmemory:0\r\nmaxmemory_policy:noeviction\r\n",
+            used
+        ));
+    }
+
+    if wanted("replication") {
+        let mut lines = match (&config.role, &config.replicaof) {
+            (Role::Slave, Some(master)) => {
+                let (host, port) = master.split_once(' ').unwrap_or((master, ""));
+                format!(
+                    "role:slave\r\nmaster_host:{}\r\nmaster_port:{}\r\n",
+                    host, port
+                )
+            }
+            _ => format!(
+                "role:master\r\nconnected_slaves:{}\r\n",
+                config.replication_manager.replicas.read().await.len()
+            ),
+        };
+        lines.push_str(&format!(
+            "master_replid:{}\r\nmaster_repl_offset:{}\r\n",
+            config.repl_id,
+            config.replication_manager.offset.load(Ordering::SeqCst)
+        ));
+        output.push(format!("# Replication\r\n{}", lines));
+    }
+
+    // Only databases holding keys are listed.
+    if wanted("keyspace") {
+        let mut lines = String::new();
+        for (index, db) in config.databases.iter().enumerate() {
+            let db = db.read().unwrap();
+            if db.storage.is_empty() {
+                continue;
+            }
+            let expires = db
+                .storage
+                .values()
+                .filter(|item| item.deadline.is_some())
+                .count();
+            lines.push_str(&format!(
+                "db{}:keys={},expires={},avg_ttl=0\r\n",
+                index,
+                db.storage.len(),
+                expires
+            ));
+        }
+        output.push(format!("# Keyspace\r\n{}", lines));
+    }
+
+    Some(RespType::VerbatimString("txt", output.join("\r\n")).serialize_for(protocol))
+}
+
+// Continues from the backlog when the replica asks for our replication ID
+// and an offset still in it, otherwise sends a full resync. Like Redis the
+// requested offset is one past the last byte the replica processed.
+async fn handle_psync<S: AsyncWrite + Unpin>(
+    replid: &str,
+    requested: i64,
+    stream: &mut S,
+    config: &Arc<Config>,
+    client: &mut ClientState,
+) -> Result<(), Box<dyn std::error::Error>> {
+    // Nothing is propagated while the replica is being synced; writes made
+    // before it joins the replica list are caught up from the backlog.
+    let _replicas = config.replication_manager.replicas.write().await;
+    // The replica starts counting from the current offset, so its ACKs line
+    // up with the bytes propagated after this point.
+    let offset = config.replication_manager.offset.load(Ordering::SeqCst);
+    client.replica_offset = Some(offset);
+
+    let missed = (replid == config.repl_id && requested > 0)
+        .then(|| {
+            config
+                .replication_manager
+                .backlog
+                .lock()
+                .unwrap()
+                .since(requested as usize - 1, offset)
+        })
+        .flatten();
+    if let Some(missed) = missed {
+        let continue_reply =
+            RespType::SimpleString(format!("CONTINUE {}", config.repl_id)).serialize();
+        stream.write_all(&continue_reply).await?;
+        stream.write_all(&missed).await?;
+        stream.flush().await?;
+        println!(
+            "Continued replication with {} bytes from the backlog",
+            missed.len()
+        );
+        return Ok(());
+    }
+
+    // A fully resynced replica starts on database 0, so the stream has to
+    // SELECT again before its next write.
+    *config.replication_manager.selected_db.lock().unwrap() = None;
+    let full_resync =
+        RespType::SimpleString(format!("FULLRESYNC {} {}", config.repl_id, offset)).serialize();
+    stream.write_all(&full_resync).await?;
+    stream.flush().await?;
+
+    let snapshot = encode_rdb(&config.databases);
+
+    let rdb_header = format!("${}\r\n", snapshot.len());
+    stream.write_all(rdb_header.as_bytes()).await?;
+    stream.flush().await?;
+    stream.write_all(&snapshot).await?;
+    stream.flush().await?;
+
+    println!("Successfully sent PSYNC response with RDB file");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::{HashMap, VecDeque},
+        sync::{atomic::Ordering, Arc, RwLock},
+    };
+
+    use super::{Command, COMMAND_TABLE, SUBCOMMAND_TABLE};
+    use crate::{
+        rdb::{encode_rdb, replace_with_rdb},
+        resp::RespType,
+        testing::{encode_command, test_config, TempDir, TestClient},
+        Database,
+    };
+
+    // Parses `args` as a client would send them.
+    fn parse(args: &[&str]) -> Command {
+        let args = args
+            .iter()
+            .map(|arg| RespType::BulkString(arg.to_string()))
+            .collect();
+        Command::from_resp(vec![RespType::Array(args)], &HashMap::new())
+    }
+
+    fn is_arity_error(command: &Command) -> bool {
+        matches!(command, Command::Error(e) if e.to_string().contains("wrong number of arguments"))
+    }
+
+    // `prefix` followed by enough placeholder arguments to make `len` in all.
+    fn padded<'a>(prefix: &[&'a str], len: usize) -> Vec<&'a str> {
+        let mut args = prefix.to_vec();
+        args.resize(len, "0");
+        args
+    }
+
+    // Each command's arity in the tables must match what its parser
+    // takes: one argument short is rejected as the wrong number, and the
+    // fewest it allows parse without the parser finding too few.
+    #[test]
+    fn command_tables_agree_with_the_parsers() {
+        let check = |prefix: &[&str], arity: i64, check_fewest: bool| {
+            let fewest = arity.unsigned_abs() as usize;
+            if fewest > prefix.len() {
+                let args = padded(prefix, fewest - 1);
+                assert!(is_arity_error(&parse(&args)), "{args:?} was accepted");
+            }
+            if check_fewest {
+                let args = padded(prefix, fewest);
+                let command = parse(&args);
+                assert!(
+                    !is_arity_error(&command) && !matches!(command, Command::Unknown { .. }),
+                    "{args:?} was rejected: {command:?}"
+                );
+            }
+        };
+
+        for &(name, arity) in COMMAND_TABLE {
+            // Container commands are checked per subcommand below.
+            let has_subcommands = SUBCOMMAND_TABLE
+                .iter()
+                .any(|(command, _, _)| *command == name);
+            check(&[name], arity, !has_subcommands);
+        }
+        for &(name, sub, arity) in SUBCOMMAND_TABLE {
+            check(&[name, sub], arity, true);
+        }
+    }
+
+    #[tokio::test]
+    async fn exec_fails_after_another_client_writes_a_watched_key() {
+        let config = test_config(&[]);
+        let mut watcher = TestClient::connect(&config);
+        let mut writer = TestClient::connect(&config);
+
+        assert_eq!(watcher.command(&["WATCH", "key"]).await, b"+OK\r\n");
+        assert_eq!(writer.command(&["SET", "key", "other"]).await, b"+OK\r\n");
+        assert_eq!(watcher.command(&["MULTI"]).await, b"+OK\r\n");
+        assert_eq!(
+            watcher.command(&["SET", "key", "mine"]).await,
+            b"+QUEUED\r\n"
+        );
+        assert_eq!(watcher.command(&["EXEC"]).await, b"*-1\r\n");
+        assert_eq!(watcher.command(&["GET", "key"]).await, b"$5\r\nother\r\n");
+    }
+
+    #[tokio::test]
+    async fn exec_runs_when_watched_keys_are_untouched() {
+        let config = test_config(&[]);
+        let mut client = TestClient::connect(&config);
+        let mut other = TestClient::connect(&config);
+
+        client.command(&["WATCH", "key"]).await;
+        other.command(&["SET", "unrelated", "v"]).await;
+        client.command(&["MULTI"]).await;
+        client.command(&["SET", "key", "mine"]).await;
+        assert_eq!(client.command(&["EXEC"]).await, b"*1\r\n+OK\r\n");
+    }
+
+    #[tokio::test]
+    async fn watch_inside_multi_aborts_exec() {
+        let config = test_config(&[]);
+        let mut client = TestClient::connect(&config);
+
+        client.command(&["MULTI"]).await;
+        assert!(client
+            .command(&["WATCH", "key"])
+            .await
+            .starts_with(b"-ERR WATCH inside MULTI"));
+        assert!(client.command(&["EXEC"]).await.starts_with(b"-EXECABORT"));
+    }
+
+    #[tokio::test]
+    async fn watches_are_dropped_when_the_connection_closes() {
+        let config = test_config(&[]);
+        let mut watcher = TestClient::connect(&config);
+        let mut writer = TestClient::connect(&config);
+
+        watcher.command(&["WATCH", "key"]).await;
+        writer.command(&["SET", "key", "v"]).await;
+        assert_eq!(config.databases[0].read().unwrap().version(b"key"), 1);
+
+        drop(watcher);
+        for _ in 0..100 {
+            if config.databases[0].read().unwrap().version(b"key") == 0 {
+                return;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        panic!("the closed connection's WATCH was never released");
+    }
+
+    // Splits a RESP2 array of bulk strings into its elements.
+    fn bulk_strings(reply: &[u8]) -> Vec<String> {
+        String::from_utf8_lossy(reply)
+            .split("\r\n")
+            .skip(1)
+            .filter(|line| !line.is_empty() && !line.starts_with('$'))
+            .map(str::to_string)
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn zrangebylex_excludes_a_parenthesized_upper_bound() {
+        let config = test_config(&[]);
+        let mut client = TestClient::connect(&config);
+        client
+            .command(&["ZADD", "zset", "0", "a", "0", "b", "0", "c", "0", "d"])
+            .await;
+
+        assert_eq!(
+            client.command(&["ZRANGEBYLEX", "zset", "[a", "(c"]).await,
+            b"*2\r\n$1\r\na\r\n$1\r\nb\r\n"
+        );
+        assert_eq!(
+            client
+                .command(&["ZRANGEBYLEX", "zset", "(a", "+", "LIMIT", "1", "5"])
+                .await,
+            b"*2\r\n$1\r\nc\r\n$1\r\nd\r\n"
+        );
+        assert_eq!(
+            client.command(&["ZLEXCOUNT", "zset", "-", "[c"]).await,
+            b":3\r\n"
+        );
+        assert!(client
+            .command(&["ZLEXCOUNT", "zset", "a", "+"])
+            .await
+            .starts_with(b"-ERR min or max not valid string range item"));
+    }
+
+    #[tokio::test]
+    async fn zrangestore_keeps_the_top_members_by_score() {
+        let config = test_config(&[]);
+        let mut client = TestClient::connect(&config);
+        client
+            .command(&["ZADD", "zset", "1", "a", "2", "b", "3", "c", "4", "d"])
+            .await;
+
+        assert_eq!(
+            client
+                .command(&[
+                    "ZRANGESTORE",
+                    "top",
+                    "zset",
+                    "+inf",
+                    "-inf",
+                    "BYSCORE",
+                    "REV",
+                    "LIMIT",
+                    "0",
+                    "2",
+                ])
+                .await,
+            b":2\r\n"
+        );
+        assert_eq!(
+            client
+                .command(&["ZRANGE", "top", "0", "-1", "WITHSCORES"])
+                .await,
+            b"*4\r\n$1\r\nc\r\n$1\r\n3\r\n$1\r\nd\r\n$1\r\n4\r\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn zrangestore_deletes_the_destination_for_an_empty_range() {
+        let config = test_config(&[]);
+        let mut client = TestClient::connect(&config);
+        client.command(&["ZADD", "zset", "1", "a", "2", "b"]).await;
+        client.command(&["SET", "dst", "old"]).await;
+
+        assert_eq!(
+            client
+                .command(&["ZRANGESTORE", "dst", "zset", "10", "20", "BYSCORE"])
+                .await,
+            b":0\r\n"
+        );
+        assert_eq!(client.command(&["TYPE", "dst"]).await, b"+none\r\n");
+    }
+
+    #[tokio::test]
+    async fn zrange_selects_by_lex_in_reverse() {
+        let config = test_config(&[]);
+        let mut client = TestClient::connect(&config);
+        client
+            .command(&["ZADD", "zset", "0", "a", "0", "b", "0", "c"])
+            .await;
+
+        assert_eq!(
+            client
+                .command(&["ZRANGE", "zset", "[c", "(a", "BYLEX", "REV"])
+                .await,
+            b"*2\r\n$1\r\nc\r\n$1\r\nb\r\n"
+        );
+        assert!(client
+            .command(&["ZRANGE", "zset", "0", "-1", "LIMIT", "0", "1"])
+            .await
+            .starts_with(b"-ERR syntax error, LIMIT"));
+    }
+
+    #[tokio::test]
+    async fn xinfo_stream_reports_length_and_end_entries() {
+        let config = test_config(&[]);
+        let mut client = TestClient::connect(&config);
+        client.command(&["XADD", "stream", "1-1", "a", "1"]).await;
+        client.command(&["XADD", "stream", "2-1", "b", "2"]).await;
+
+        assert_eq!(
+            client.command(&["XINFO", "STREAM", "stream"]).await,
+            b"*10\r\n\
+              $6\r\nlength\r\n:2\r\n\
+              $17\r\nlast-generated-id\r\n$3\r\n2-1\r\n\
+              $6\r\ngroups\r\n:0\r\n\
+              $11\r\nfirst-entry\r\n*2\r\n$3\r\n1-1\r\n*2\r\n$1\r\na\r\n$1\r\n1\r\n\
+              $10\r\nlast-entry\r\n*2\r\n$3\r\n2-1\r\n*2\r\n$1\r\nb\r\n$1\r\n2\r\n"
+        );
+        assert_eq!(
+            client.command(&["XINFO", "GROUPS", "stream"]).await,
+            b"*0\r\n"
+        );
+        assert!(client
+            .command(&["XINFO", "STREAM", "missing"])
+            .await
+            .starts_with(b"-ERR no such key"));
+    }
+
+    #[tokio::test]
+    async fn debug_object_names_the_value_variant() {
+        let config = test_config(&[]);
+        let mut client = TestClient::connect(&config);
+        client.command(&["SET", "string", "v"]).await;
+        client.command(&["RPUSH", "list", "v"]).await;
+        client.command(&["ZADD", "zset", "1", "v"]).await;
+        client.command(&["XADD", "stream", "1-1", "f", "v"]).await;
+
+        for (key, variant) in [
+            ("string", "String"),
+            ("list", "List"),
+            ("zset", "ZSet"),
+            ("stream", "Stream"),
+        ] {
+            let reply = client.command(&["DEBUG", "OBJECT", key]).await;
+            let reply = String::from_utf8(reply).unwrap();
+            assert!(
+                reply.trim_end().ends_with(&format!(" variant:{variant}")),
+                "{reply}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn resp3_connections_get_maps_from_hgetall_and_config_get() {
+        let config = test_config(&["--port", "6400"]);
+        let mut client = TestClient::connect(&config);
+        assert!(client.command(&["HELLO", "3"]).await.starts_with(b"%"));
+        assert_eq!(
+            client.command(&["HSET", "hash", "field", "value"]).await,
+            b":1\r\n"
+        );
+
+        assert_eq!(
+            client.command(&["HGETALL", "hash"]).await,
+            b"%1\r\n$5\r\nfield\r\n$5\r\nvalue\r\n"
+        );
+        assert_eq!(
+            client.command(&["CONFIG", "GET", "port"]).await,
+            b"%1\r\n$4\r\nport\r\n$4\r\n6400\r\n"
+        );
+        assert_eq!(client.command(&["HGET", "hash", "missing"]).await, b"_\r\n");
+    }
+
+    #[tokio::test]
+    async fn removing_the_last_element_deletes_the_key() {
+        let config = test_config(&[]);
+        let mut client = TestClient::connect(&config);
+        client.command(&["HSET", "hash", "a", "1", "b", "2"]).await;
+        client.command(&["RPUSH", "list", "a"]).await;
+        client.command(&["ZADD", "zset", "1", "a"]).await;
+
+        assert_eq!(client.command(&["HDEL", "hash", "a", "c"]).await, b":1\r\n");
+        assert_eq!(client.command(&["TYPE", "hash"]).await, b"+hash\r\n");
+        assert_eq!(client.command(&["HDEL", "hash", "b"]).await, b":1\r\n");
+        client.command(&["LPOP", "list"]).await;
+        client.command(&["ZREM", "zset", "a"]).await;
+
+        for key in ["hash", "list", "zset"] {
+            assert_eq!(client.command(&["TYPE", key]).await, b"+none\r\n");
+        }
+        assert_eq!(client.command(&["DBSIZE"]).await, b":0\r\n");
+    }
+
+    // DEBUG OBJECT's serializedlength must come from the encoder SAVE
+    // uses: with each key's own framing added, the lengths account for the
+    // whole snapshot.
+    #[tokio::test]
+    async fn serialized_lengths_add_up_to_the_snapshot() {
+        let config = test_config(&[]);
+        let mut client = TestClient::connect(&config);
+        client.command(&["SET", "string", "value"]).await;
+        client.command(&["SET", "number", "12345"]).await;
+        client.command(&["RPUSH", "list", "a", "bb", "ccc"]).await;
+        client
+            .command(&["HSET", "hash", "field", "value", "other", "42"])
+            .await;
+
+        // An empty snapshot, plus the database's SELECTDB and RESIZEDB
+        // opcodes with its index and two key counts.
+        let mut expected = encode_rdb(&test_config(&[]).databases).len() + 5;
+        for key in ["string", "number", "list", "hash"] {
+            let reply = client.command(&["DEBUG", "OBJECT", key]).await;
+            let length = String::from_utf8(reply)
+                .unwrap()
+                .split(' ')
+                .find_map(|field| field.strip_prefix("serializedlength:"))
+                .unwrap()
+                .parse::<usize>()
+                .unwrap();
+            // The type byte and the key with its one-byte length.
+            expected += 1 + 1 + key.len() + length;
+        }
+        assert_eq!(encode_rdb(&config.databases).len(), expected);
+    }
+
+    // RANDOMKEY replies from a fresh server seeded with `seed` and holding
+    // the same ten keys.
+    async fn random_keys(seed: &str) -> Vec<Vec<u8>> {
+        let config = test_config(&["--rng-seed", seed]);
+        let mut client = TestClient::connect(&config);
+        for i in 0..10 {
+            client.command(&["SET", &format!("key{i}"), "v"]).await;
+        }
+        let mut keys = Vec::new();
+        for _ in 0..20 {
+            keys.push(client.command(&["RANDOMKEY"]).await);
+        }
+        keys
+    }
+
+    #[tokio::test]
+    async fn a_seeded_randomkey_repeats_its_sequence() {
+        let keys = random_keys("42").await;
+        assert_eq!(random_keys("42").await, keys);
+        assert_ne!(random_keys("43").await, keys);
+        assert!(keys.iter().any(|key| *key != keys[0]));
+    }
+
+    #[tokio::test]
+    async fn zrandmember_pairs_members_with_their_scores() {
+        let config = test_config(&[]);
+        let mut client = TestClient::connect(&config);
+        client
+            .command(&["ZADD", "zset", "1", "a", "2", "b", "3", "c"])
+            .await;
+        let score_of = |member: &str| match member {
+            "a" => "1",
+            "b" => "2",
+            "c" => "3",
+            _ => panic!("unexpected member {member}"),
+        };
+
+        let picked = bulk_strings(
+            &client
+                .command(&["ZRANDMEMBER", "zset", "3", "WITHSCORES"])
+                .await,
+        );
+        assert_eq!(picked.len(), 6);
+        let mut members = Vec::new();
+        for pair in picked.chunks(2) {
+            assert_eq!(pair[1], score_of(&pair[0]));
+            members.push(pair[0].clone());
+        }
+        members.sort();
+        assert_eq!(members, ["a", "b", "c"]);
+
+        let repeated = bulk_strings(
+            &client
+                .command(&["ZRANDMEMBER", "zset", "-10", "WITHSCORES"])
+                .await,
+        );
+        assert_eq!(repeated.len(), 20);
+        for pair in repeated.chunks(2) {
+            assert_eq!(pair[1], score_of(&pair[0]));
+        }
+    }
+
+    #[tokio::test]
+    async fn zrandmember_caps_a_positive_count_at_the_set_size() {
+        let config = test_config(&[]);
+        let mut client = TestClient::connect(&config);
+        client.command(&["ZADD", "zset", "1", "a", "2", "b"]).await;
+
+        assert_eq!(
+            bulk_strings(&client.command(&["ZRANDMEMBER", "zset", "5"]).await).len(),
+            2
+        );
+        assert_eq!(
+            client.command(&["ZRANDMEMBER", "missing"]).await,
+            b"$-1\r\n"
+        );
+        assert_eq!(
+            client.command(&["ZRANDMEMBER", "missing", "-3"]).await,
+            b"*0\r\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn bitfield_incrby_saturates_an_unsigned_field() {
+        let config = test_config(&[]);
+        let mut client = TestClient::connect(&config);
+
+        assert_eq!(
+            client
+                .command(&["BITFIELD", "key", "SET", "u8", "0", "250"])
+                .await,
+            b"*1\r\n:0\r\n"
+        );
+        assert_eq!(
+            client
+                .command(&["BITFIELD", "key", "OVERFLOW", "SAT", "INCRBY", "u8", "0", "10"])
+                .await,
+            b"*1\r\n:255\r\n"
+        );
+        assert_eq!(
+            client
+                .command(&[
+                    "BITFIELD", "key", "OVERFLOW", "FAIL", "INCRBY", "u8", "0", "1", "GET", "u8",
+                    "0"
+                ])
+                .await,
+            b"*2\r\n$-1\r\n:255\r\n"
+        );
+    }
+
+    // A SCAN reply's cursor and keys.
+    fn scan_page(reply: &[u8]) -> (String, Vec<String>) {
+        let mut items = bulk_strings(reply)
+            .into_iter()
+            .filter(|item| !item.starts_with('*'));
+        (items.next().unwrap(), items.collect())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn scan_returns_every_key_present_throughout_while_others_write() {
+        let config = test_config(&[]);
+        let mut client = TestClient::connect(&config);
+        for i in 0..200 {
+            client.command(&["SET", &format!("stable{i}"), "v"]).await;
+        }
+
+        let mut writer = TestClient::connect(&config);
+        let writes = tokio::spawn(async move {
+            for i in 0..500 {
+                writer.command(&["SET", &format!("churn{i}"), "v"]).await;
+                if i >= 5 {
+                    writer.command(&["DEL", &format!("churn{}", i - 5)]).await;
+                }
+            }
+        });
+
+        let mut seen = Vec::new();
+        let mut cursor = "0".to_string();
+        loop {
+            let (next, keys) = scan_page(&client.command(&["SCAN", &cursor, "COUNT", "7"]).await);
+            seen.extend(keys);
+            if next == "0" {
+                break;
+            }
+            cursor = next;
+            tokio::task::yield_now().await;
+        }
+        writes.await.unwrap();
+
+        for i in 0..200 {
+            let key = format!("stable{i}");
+            assert_eq!(seen.iter().filter(|seen| **seen == key).count(), 1, "{key}");
+        }
+    }
+
+    #[tokio::test]
+    async fn keys_returns_only_keys_matching_the_pattern() {
+        let config = test_config(&[]);
+        let mut client = TestClient::connect(&config);
+        for key in ["user:1", "user:2", "session:1"] {
+            client.command(&["SET", key, "v"]).await;
+        }
+
+        let mut users = bulk_strings(&client.command(&["KEYS", "user:*"]).await);
+        users.sort();
+        assert_eq!(users, ["user:1", "user:2"]);
+        assert_eq!(
+            bulk_strings(&client.command(&["KEYS", "session:1"]).await),
+            ["session:1"]
+        );
+        assert_eq!(client.command(&["KEYS", "user:[3-9]"]).await, b"*0\r\n");
+    }
+
+    // The payload of a bulk string reply.
+    fn bulk_payload(reply: &[u8]) -> Vec<u8> {
+        let start = reply.windows(2).position(|w| w == b"\r\n").unwrap() + 2;
+        reply[start..reply.len() - 2].to_vec()
+    }
+
+    #[tokio::test]
+    async fn dump_and_restore_copy_every_type() {
+        let config = test_config(&[]);
+        let mut client = TestClient::connect(&config);
+        client.command(&["SET", "string", "value"]).await;
+        client.command(&["RPUSH", "list", "a", "b"]).await;
+        client
+            .command(&["ZADD", "zset", "1", "a", "2.5", "b"])
+            .await;
+        client.command(&["HSET", "hash", "field", "value"]).await;
+        client.command(&["XADD", "stream", "1-1", "f", "v"]).await;
+
+        for (key, read) in [
+            ("string", &["GET"][..]),
+            ("list", &["LRANGE", "0", "-1"]),
+            ("zset", &["ZRANGE", "0", "-1", "WITHSCORES"]),
+            ("hash", &["HGETALL"]),
+            ("stream", &["XRANGE", "-", "+"]),
+        ] {
+            let payload = bulk_payload(&client.command(&["DUMP", key]).await);
+            let copy = format!("{key}-copy");
+            let mut restore = format!(
+                "*4\r\n$7\r\nRESTORE\r\n${}\r\n{copy}\r\n$1\r\n0\r\n${}\r\n",
+                copy.len(),
+                payload.len()
+            )
+            .into_bytes();
+            restore.extend_from_slice(&payload);
+            restore.extend_from_slice(b"\r\n");
+            client.send(&restore).await;
+            assert_eq!(client.reply().await, b"+OK\r\n");
+
+            assert_eq!(
+                client.command(&["TYPE", &copy]).await,
+                client.command(&["TYPE", key]).await
+            );
+            let mut original = read.to_vec();
+            original.insert(1, key);
+            let mut copied = read.to_vec();
+            copied.insert(1, &copy);
+            assert_eq!(
+                client.command(&copied).await,
+                client.command(&original).await,
+                "{key}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn xadd_generates_increasing_ids_and_xrange_takes_open_bounds() {
+        let config = test_config(&[]);
+        let mut client = TestClient::connect(&config);
+
+        assert_eq!(
+            client.command(&["XADD", "s", "5-1", "f", "1"]).await,
+            b"$3\r\n5-1\r\n"
+        );
+        assert_eq!(
+            client.command(&["XADD", "s", "5-*", "f", "2"]).await,
+            b"$3\r\n5-2\r\n"
+        );
+        assert!(client
+            .command(&["XADD", "s", "5-2", "f", "3"])
+            .await
+            .starts_with(b"-ERR The ID specified in XADD is equal or smaller"));
+        let auto = bulk_payload(&client.command(&["XADD", "s", "*", "f", "3"]).await);
+        let auto = String::from_utf8(auto).unwrap();
+        let (ms, _) = auto.split_once('-').unwrap();
+        assert!(ms.parse::<u64>().unwrap() > 5);
+
+        let ids = |reply: Vec<u8>| -> Vec<String> {
+            bulk_strings(&reply)
+                .into_iter()
+                .filter(|item| item.contains('-'))
+                .collect()
+        };
+        assert_eq!(
+            ids(client.command(&["XRANGE", "s", "-", "+"]).await),
+            ["5-1", "5-2", auto.as_str()]
+        );
+        assert_eq!(
+            ids(client.command(&["XRANGE", "s", "5-2", "+"]).await),
+            ["5-2", auto.as_str()]
+        );
+        assert_eq!(
+            ids(client
+                .command(&["XRANGE", "s", "-", "5", "COUNT", "1"])
+                .await),
+            ["5-1"]
+        );
+    }
+
+    #[tokio::test]
+    async fn zrange_withscores_orders_by_score_and_zadd_nx_keeps_scores() {
+        let config = test_config(&[]);
+        let mut client = TestClient::connect(&config);
+        assert_eq!(
+            client
+                .command(&["ZADD", "z", "2", "b", "1", "c", "2", "a"])
+                .await,
+            b":3\r\n"
+        );
+        assert_eq!(
+            client
+                .command(&["ZADD", "z", "NX", "9", "a", "3", "d"])
+                .await,
+            b":1\r\n"
+        );
+
+        assert_eq!(
+            bulk_strings(
+                &client
+                    .command(&["ZRANGE", "z", "0", "-1", "WITHSCORES"])
+                    .await
+            ),
+            ["c", "1", "a", "2", "b", "2", "d", "3"]
+        );
+        assert_eq!(client.command(&["ZRANK", "z", "b"]).await, b":2\r\n");
+        assert_eq!(client.command(&["ZSCORE", "z", "a"]).await, b"$1\r\n2\r\n");
+        assert_eq!(client.command(&["ZCARD", "z"]).await, b":4\r\n");
+    }
+
+    #[tokio::test]
+    async fn zrangebyscore_takes_exclusive_and_infinite_bounds_and_limit() {
+        let config = test_config(&[]);
+        let mut client = TestClient::connect(&config);
+        client
+            .command(&["ZADD", "z", "1", "a", "2", "b", "3", "c", "4", "d"])
+            .await;
+
+        assert_eq!(
+            bulk_strings(&client.command(&["ZRANGEBYSCORE", "z", "(1", "3"]).await),
+            ["b", "c"]
+        );
+        assert_eq!(
+            bulk_strings(&client.command(&["ZRANGEBYSCORE", "z", "-inf", "(2"]).await),
+            ["a"]
+        );
+        assert_eq!(
+            bulk_strings(
+                &client
+                    .command(&["ZRANGEBYSCORE", "z", "-inf", "+inf", "LIMIT", "1", "2"])
+                    .await
+            ),
+            ["b", "c"]
+        );
+
+        assert_eq!(
+            client.command(&["ZREM", "z", "a", "b", "missing"]).await,
+            b":2\r\n"
+        );
+        client.command(&["ZREM", "z", "c", "d"]).await;
+        assert_eq!(client.command(&["TYPE", "z"]).await, b"+none\r\n");
+    }
+
+    #[tokio::test]
+    async fn pattern_subscriptions_are_in_the_command_table() {
+        for name in ["psubscribe", "punsubscribe"] {
+            assert!(super::is_command(name), "{name}");
+        }
+        assert!(is_arity_error(&parse(&["PSUBSCRIBE"])));
+
+        let config = test_config(&[]);
+        let mut client = TestClient::connect(&config);
+        assert_eq!(
+            client.command(&["PSUBSCRIBE"]).await,
+            b"-ERR wrong number of arguments for 'psubscribe' command\r\n"
+        );
+        assert_eq!(
+            client.command(&["PUNSUBSCRIBE"]).await,
+            b"*3\r\n$12\r\npunsubscribe\r\n$-1\r\n:0\r\n"
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn concurrent_readers_see_one_writer_s_values_in_order() {
+        let config = test_config(&[]);
+        let mut writer = TestClient::connect(&config);
+        writer.command(&["SET", "counter", "0"]).await;
+
+        let readers: Vec<_> = (0..3)
+            .map(|_| {
+                let mut reader = TestClient::connect(&config);
+                tokio::spawn(async move {
+                    let mut last = 0;
+                    while last < 300 {
+                        let reply = reader.command(&["GET", "counter"]).await;
+                        let value: u64 = String::from_utf8(bulk_payload(&reply))
+                            .unwrap()
+                            .parse()
+                            .unwrap();
+                        assert!(value >= last, "read {value} after {last}");
+                        last = value;
+                    }
+                })
+            })
+            .collect();
+        for i in 1..=300 {
+            assert_eq!(
+                writer.command(&["SET", "counter", &i.to_string()]).await,
+                b"+OK\r\n"
+            );
+        }
+        for reader in readers {
+            reader.await.unwrap();
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn gets_do_not_wait_for_another_reader() {
+        let config = test_config(&[]);
+        let mut client = TestClient::connect(&config);
+        client.command(&["SET", "key", "v"]).await;
+
+        // Another thread holds a read guard for the whole GET.
+        let (held, is_held) = std::sync::mpsc::channel();
+        let (release, released) = std::sync::mpsc::channel::<()>();
+        let database = std::sync::Arc::clone(&config.databases[0]);
+        let reader = std::thread::spawn(move || {
+            let _reading = database.read().unwrap();
+            held.send(()).unwrap();
+            let _ = released.recv();
+        });
+        is_held.recv().unwrap();
+
+        assert_eq!(client.command(&["GET", "key"]).await, b"$1\r\nv\r\n");
+        release.send(()).unwrap();
+        reader.join().unwrap();
+    }
+
+    #[tokio::test]
+    async fn hashes_turn_hashtable_past_the_configured_listpack_limits() {
+        let config = test_config(&[]);
+        let mut client = TestClient::connect(&config);
+        let long = "v".repeat(65);
+
+        client.command(&["HSET", "small", "field", "value"]).await;
+        client.command(&["HSET", "long", "field", &long]).await;
+        assert_eq!(
+            client.command(&["OBJECT", "ENCODING", "small"]).await,
+            b"$8\r\nlistpack\r\n"
+        );
+        assert_eq!(
+            client.command(&["OBJECT", "ENCODING", "long"]).await,
+            b"$9\r\nhashtable\r\n"
+        );
+
+        assert_eq!(
+            client
+                .command(&["CONFIG", "SET", "hash-max-listpack-value", "100"])
+                .await,
+            b"+OK\r\n"
+        );
+        assert_eq!(
+            client
+                .command(&["CONFIG", "GET", "hash-max-listpack-value"])
+                .await,
+            b"*2\r\n$23\r\nhash-max-listpack-value\r\n$3\r\n100\r\n"
+        );
+        client.command(&["HSET", "under", "field", &long]).await;
+        assert_eq!(
+            client.command(&["OBJECT", "ENCODING", "under"]).await,
+            b"$8\r\nlistpack\r\n"
+        );
+
+        client
+            .command(&["CONFIG", "SET", "hash-max-listpack-entries", "1"])
+            .await;
+        client.command(&["HSET", "small", "other", "value"]).await;
+        assert_eq!(
+            client.command(&["OBJECT", "ENCODING", "small"]).await,
+            b"$9\r\nhashtable\r\n"
+        );
+        assert!(client
+            .command(&["CONFIG", "SET", "hash-max-listpack-entries", "many"])
+            .await
+            .starts_with(b"-ERR CONFIG SET failed"));
+    }
+
+    #[tokio::test]
+    async fn set_px_1_expires_and_px_0_is_rejected() {
+        let config = test_config(&[]);
+        let mut client = TestClient::connect(&config);
+
+        assert_eq!(
+            client.command(&["SET", "key", "v", "PX", "1"]).await,
+            b"+OK\r\n"
+        );
+        tokio::time::sleep(std::time::Duration::from_millis(5)).await;
+        assert_eq!(client.command(&["GET", "key"]).await, b"$-1\r\n");
+
+        for ttl in ["0", "-5"] {
+            assert_eq!(
+                client.command(&["SET", "key", "v", "PX", ttl]).await,
+                b"-ERR invalid expire time in 'set' command\r\n"
+            );
+        }
+        assert_eq!(client.command(&["GET", "key"]).await, b"$-1\r\n");
+    }
+
+    #[tokio::test]
+    async fn psync_continues_from_the_backlog_or_falls_back_to_a_full_resync() {
+        let config = test_config(&["--repl-backlog-size", "100"]);
+        let mut writer = TestClient::connect(&config);
+        let offset = || {
+            config
+                .replication_manager
+                .offset
+                .load(std::sync::atomic::Ordering::SeqCst)
+        };
+        writer.command(&["SET", "a", "1"]).await;
+        let synced = offset();
+        writer.command(&["SET", "b", "2"]).await;
+
+        // A replica that had everything up to `synced` gets only the rest.
+        let mut replica = TestClient::connect(&config);
+        let next = (synced + 1).to_string();
+        assert_eq!(
+            replica.command(&["PSYNC", &config.repl_id, &next]).await,
+            format!("+CONTINUE {}\r\n", config.repl_id).as_bytes()
+        );
+        assert_eq!(
+            replica.reply().await,
+            b"*3\r\n$3\r\nSET\r\n$1\r\nb\r\n$1\r\n2\r\n"
+        );
+        // It is then streamed new writes like any other replica.
+        writer.command(&["SET", "c", "3"]).await;
+        assert_eq!(
+            replica.reply().await,
+            b"*3\r\n$3\r\nSET\r\n$1\r\nc\r\n$1\r\n3\r\n"
+        );
+
+        // Once that offset has left the 100-byte backlog, or under another
+        // replication ID, only a full resync will do.
+        writer.command(&["SET", "big", &"v".repeat(100)]).await;
+        for replid in [config.repl_id.as_str(), "another-id"] {
+            let mut late = TestClient::connect(&config);
+            let reply = late.command(&["PSYNC", replid, &next]).await;
+            assert!(reply.starts_with(b"+FULLRESYNC"), "{replid}");
+        }
+    }
+
+    #[tokio::test]
+    async fn fullresync_sends_the_current_dataset() {
+        let config = test_config(&[]);
+        let mut client = TestClient::connect(&config);
+        client.command(&["SET", "key", "value"]).await;
+        client.command(&["SELECT", "2"]).await;
+        client.command(&["RPUSH", "list", "a"]).await;
+
+        let mut replica = TestClient::connect(&config);
+        assert!(replica
+            .command(&["PSYNC", "?", "-1"])
+            .await
+            .starts_with(b"+FULLRESYNC"));
+        let snapshot = replica.snapshot().await;
+        assert_ne!(snapshot, encode_rdb(&[]));
+
+        let loaded: Vec<_> = (0..16)
+            .map(|index| Arc::new(RwLock::new(Database::new(index))))
+            .collect();
+        replace_with_rdb(&snapshot, &loaded).unwrap();
+        assert_eq!(
+            loaded[0].read().unwrap().get_string(b"key").unwrap(),
+            Some(&b"value".to_vec())
+        );
+        assert_eq!(
+            loaded[2]
+                .read()
+                .unwrap()
+                .get_list(b"list")
+                .unwrap()
+                .unwrap(),
+            &VecDeque::from([b"a".to_vec()])
+        );
+    }
+
+    #[tokio::test]
+    async fn scan_visits_each_of_100_keys_exactly_once() {
+        let config = test_config(&[]);
+        let mut client = TestClient::connect(&config);
+        for i in 0..100 {
+            client.command(&["SET", &format!("key{i}"), "v"]).await;
+        }
+
+        let mut seen = Vec::new();
+        let mut cursor = "0".to_string();
+        let mut calls = 0;
+        loop {
+            let (next, keys) = scan_page(&client.command(&["SCAN", &cursor, "COUNT", "9"]).await);
+            seen.extend(keys);
+            calls += 1;
+            if next == "0" {
+                break;
+            }
+            cursor = next;
+        }
+        assert!(calls > 1);
+        seen.sort();
+        let mut expected: Vec<String> = (0..100).map(|i| format!("key{i}")).collect();
+        expected.sort();
+        assert_eq!(seen, expected);
+
+        let (_, matched) = scan_page(
+            &client
+                .command(&["SCAN", "0", "MATCH", "key1?", "COUNT", "1000"])
+                .await,
+        );
+        assert_eq!(matched.len(), 10);
+    }
+
+    // The value of an integer reply.
+    fn integer(reply: &[u8]) -> i64 {
+        std::str::from_utf8(&reply[1..reply.len() - 2])
+            .unwrap()
+            .parse()
+            .unwrap()
+    }
+
+    fn unix_secs() -> u64 {
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+    }
+
+    #[tokio::test]
+    async fn getex_sets_or_clears_the_ttl_while_reading() {
+        let config = test_config(&[]);
+        let mut client = TestClient::connect(&config);
+        client.command(&["SET", "key", "v"]).await;
+
+        assert_eq!(
+            client.command(&["GETEX", "key", "EX", "100"]).await,
+            b"$1\r\nv\r\n"
+        );
+        assert!((99..=100).contains(&integer(&client.command(&["TTL", "key"]).await)));
+        client.command(&["GETEX", "key"]).await;
+        assert!((99..=100).contains(&integer(&client.command(&["TTL", "key"]).await)));
+
+        client.command(&["GETEX", "key", "PX", "50000"]).await;
+        assert!((49000..=50000).contains(&integer(&client.command(&["PTTL", "key"]).await)));
+
+        let at = (unix_secs() + 200).to_string();
+        client.command(&["GETEX", "key", "EXAT", &at]).await;
+        assert!((198..=200).contains(&integer(&client.command(&["TTL", "key"]).await)));
+
+        let at = ((unix_secs() + 300) * 1000).to_string();
+        client.command(&["GETEX", "key", "PXAT", &at]).await;
+        assert!((298..=300).contains(&integer(&client.command(&["TTL", "key"]).await)));
+
+        assert_eq!(
+            client.command(&["GETEX", "key", "PERSIST"]).await,
+            b"$1\r\nv\r\n"
+        );
+        assert_eq!(client.command(&["TTL", "key"]).await, b":-1\r\n");
+        assert_eq!(
+            client.command(&["GETEX", "missing", "EX", "1"]).await,
+            b"$-1\r\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn getex_rejects_invalid_expire_times() {
+        let config = test_config(&[]);
+        let mut client = TestClient::connect(&config);
+        client.command(&["SET", "key", "v"]).await;
+
+        for (option, time) in [
+            ("EX", "0"),
+            ("PX", "-1"),
+            ("EXAT", "0"),
+            ("EX", "9223372036854775807"),
+        ] {
+            assert_eq!(
+                client.command(&["GETEX", "key", option, time]).await,
+                b"-ERR invalid expire time in 'getex' command\r\n",
+                "{option} {time}"
+            );
+        }
+        assert_eq!(
+            client.command(&["GETEX", "key", "EX", "soon"]).await,
+            b"-ERR value is not an integer or out of range\r\n"
+        );
+        assert_eq!(
+            client.command(&["GETEX", "key", "LATER", "1"]).await,
+            b"-ERR syntax error\r\n"
+        );
+        assert_eq!(client.command(&["TTL", "key"]).await, b":-1\r\n");
+    }
+
+    #[tokio::test]
+    async fn set_with_get_returns_the_old_value() {
+        let config = test_config(&[]);
+        let mut client = TestClient::connect(&config);
+
+        assert_eq!(
+            client.command(&["SET", "key", "old", "GET"]).await,
+            b"$-1\r\n"
+        );
+        assert_eq!(
+            client.command(&["SET", "key", "new", "GET"]).await,
+            b"$3\r\nold\r\n"
+        );
+        assert_eq!(client.command(&["GET", "key"]).await, b"$3\r\nnew\r\n");
+
+        // With NX nothing is written over an existing key, but the old
+        // value is still returned.
+        assert_eq!(
+            client.command(&["SET", "key", "other", "NX", "GET"]).await,
+            b"$3\r\nnew\r\n"
+        );
+        assert_eq!(client.command(&["GET", "key"]).await, b"$3\r\nnew\r\n");
+
+        client.command(&["RPUSH", "list", "a"]).await;
+        assert_eq!(
+            client.command(&["SET", "list", "v", "GET"]).await,
+            b"-WRONGTYPE Operation against a key holding the wrong kind of value\r\n"
+        );
+        assert_eq!(client.command(&["TYPE", "list"]).await, b"+list\r\n");
+    }
+
+    #[tokio::test]
+    async fn getdel_returns_and_removes_a_string() {
+        let config = test_config(&[]);
+        let mut client = TestClient::connect(&config);
+
+        client.command(&["SET", "key", "value"]).await;
+        assert_eq!(client.command(&["GETDEL", "key"]).await, b"$5\r\nvalue\r\n");
+        assert_eq!(client.command(&["GET", "key"]).await, b"$-1\r\n");
+        assert_eq!(client.command(&["GETDEL", "key"]).await, b"$-1\r\n");
+
+        client.command(&["RPUSH", "list", "a"]).await;
+        assert_eq!(
+            client.command(&["GETDEL", "list"]).await,
+            b"-WRONGTYPE Operation against a key holding the wrong kind of value\r\n"
+        );
+        assert_eq!(client.command(&["TYPE", "list"]).await, b"+list\r\n");
+    }
+
+    #[tokio::test]
+    async fn setrange_pads_with_zeros_and_getrange_takes_negative_indexes() {
+        let config = test_config(&[]);
+        let mut client = TestClient::connect(&config);
+
+        assert_eq!(
+            client.command(&["SETRANGE", "key", "3", "abc"]).await,
+            b":6\r\n"
+        );
+        assert_eq!(
+            client.command(&["GET", "key"]).await,
+            b"$6\r\n\0\0\0abc\r\n"
+        );
+        assert_eq!(
+            client.command(&["SETRANGE", "key", "1", "xy"]).await,
+            b":6\r\n"
+        );
+        assert_eq!(client.command(&["GET", "key"]).await, b"$6\r\n\0xyabc\r\n");
+
+        assert_eq!(
+            client.command(&["GETRANGE", "key", "-3", "-1"]).await,
+            b"$3\r\nabc\r\n"
+        );
+        assert_eq!(
+            client.command(&["GETRANGE", "key", "-100", "1"]).await,
+            b"$2\r\n\0x\r\n"
+        );
+        assert_eq!(
+            client.command(&["GETRANGE", "key", "4", "2"]).await,
+            b"$0\r\n\r\n"
+        );
+        assert_eq!(
+            client.command(&["GETRANGE", "missing", "0", "-1"]).await,
+            b"$0\r\n\r\n"
+        );
+
+        assert_eq!(
+            client.command(&["SETRANGE", "key", "-1", "x"]).await,
+            b"-ERR offset is out of range\r\n"
+        );
+        assert_eq!(
+            client
+                .command(&["SETRANGE", "key", "536870911", "ab"])
+                .await,
+            b"-ERR string exceeds maximum allowed size (proto-max-bulk-len)\r\n"
+        );
+        assert_eq!(client.command(&["GET", "key"]).await, b"$6\r\n\0xyabc\r\n");
+    }
+
+    #[tokio::test]
+    async fn setbit_grows_the_string_and_getbit_reads_it_back() {
+        let config = test_config(&[]);
+        let mut client = TestClient::connect(&config);
+
+        assert_eq!(
+            client.command(&["SETBIT", "key", "100", "1"]).await,
+            b":0\r\n"
+        );
+        assert_eq!(
+            bulk_payload(&client.command(&["GET", "key"]).await).len(),
+            13
+        );
+        assert_eq!(client.command(&["GETBIT", "key", "100"]).await, b":1\r\n");
+        assert_eq!(client.command(&["GETBIT", "key", "99"]).await, b":0\r\n");
+        assert_eq!(client.command(&["GETBIT", "key", "1000"]).await, b":0\r\n");
+        assert_eq!(
+            client.command(&["SETBIT", "key", "100", "0"]).await,
+            b":1\r\n"
+        );
+        assert_eq!(client.command(&["GETBIT", "key", "100"]).await, b":0\r\n");
+
+        assert_eq!(
+            client.command(&["SETBIT", "key", "1", "2"]).await,
+            b"-ERR bit is not an integer or out of range\r\n"
+        );
+        assert_eq!(
+            client.command(&["SETBIT", "key", "-1", "1"]).await,
+            b"-ERR bit offset is not an integer or out of range\r\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn bitcount_counts_the_whole_string_or_a_byte_range() {
+        let config = test_config(&[]);
+        let mut client = TestClient::connect(&config);
+
+        // "foobar" has 26 set bits: f=4 o=6 o=6 b=3 a=3 r=4.
+        client.command(&["SET", "key", "foobar"]).await;
+        assert_eq!(client.command(&["BITCOUNT", "key"]).await, b":26\r\n");
+        assert_eq!(
+            client.command(&["BITCOUNT", "key", "0", "0"]).await,
+            b":4\r\n"
+        );
+        assert_eq!(
+            client.command(&["BITCOUNT", "key", "1", "1"]).await,
+            b":6\r\n"
+        );
+        assert_eq!(
+            client.command(&["BITCOUNT", "key", "-2", "-1"]).await,
+            b":7\r\n"
+        );
+        assert_eq!(
+            client.command(&["BITCOUNT", "key", "5", "2"]).await,
+            b":0\r\n"
+        );
+        assert_eq!(client.command(&["BITCOUNT", "missing"]).await, b":0\r\n");
+
+        assert_eq!(
+            client.command(&["BITCOUNT", "key", "0"]).await,
+            b"-ERR syntax error\r\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn set_with_a_past_pxat_leaves_the_key_absent() {
+        let config = test_config(&[]);
+        let mut client = TestClient::connect(&config);
+
+        client.command(&["SET", "key", "old"]).await;
+        assert_eq!(
+            client.command(&["SET", "key", "new", "PXAT", "1"]).await,
+            b"+OK\r\n"
+        );
+        assert_eq!(client.command(&["GET", "key"]).await, b"$-1\r\n");
+        assert_eq!(client.command(&["TYPE", "key"]).await, b"+none\r\n");
+
+        let at = ((unix_secs() + 100) * 1000).to_string();
+        client.command(&["SET", "key", "new", "PXAT", &at]).await;
+        let pttl = integer(&client.command(&["PTTL", "key"]).await);
+        assert!(pttl > 98_000 && pttl <= 100_000, "PTTL was {pttl}");
+    }
+
+    #[tokio::test]
+    async fn touch_counts_only_the_keys_that_exist() {
+        let config = test_config(&[]);
+        let mut client = TestClient::connect(&config);
+
+        client.command(&["SET", "a", "1"]).await;
+        client.command(&["RPUSH", "b", "x"]).await;
+        assert_eq!(
+            client.command(&["TOUCH", "a", "b", "missing"]).await,
+            b":2\r\n"
+        );
+        assert_eq!(client.command(&["TOUCH", "missing"]).await, b":0\r\n");
+        // A key named twice counts twice, as in Redis.
+        assert_eq!(client.command(&["TOUCH", "a", "a"]).await, b":2\r\n");
+    }
+
+    #[tokio::test]
+    async fn expiretime_reports_missing_persistent_and_expiring_keys() {
+        let config = test_config(&[]);
+        let mut client = TestClient::connect(&config);
+
+        assert_eq!(client.command(&["EXPIRETIME", "missing"]).await, b":-2\r\n");
+        assert_eq!(
+            client.command(&["PEXPIRETIME", "missing"]).await,
+            b":-2\r\n"
+        );
+
+        client.command(&["SET", "key", "value"]).await;
+        assert_eq!(client.command(&["EXPIRETIME", "key"]).await, b":-1\r\n");
+        assert_eq!(client.command(&["PEXPIRETIME", "key"]).await, b":-1\r\n");
+
+        let at = unix_secs() + 100;
+        client.command(&["EXPIREAT", "key", &at.to_string()]).await;
+        let secs = integer(&client.command(&["EXPIRETIME", "key"]).await);
+        assert!(
+            (secs - at as i64).abs() <= 1,
+            "EXPIRETIME was {secs}, set {at}"
+        );
+        let millis = integer(&client.command(&["PEXPIRETIME", "key"]).await);
+        assert!(
+            (millis - at as i64 * 1000).abs() <= 1000,
+            "PEXPIRETIME was {millis}, set {at}"
+        );
+    }
+
+    #[tokio::test]
+    async fn expireat_sets_future_deadlines_and_deletes_on_past_ones() {
+        let config = test_config(&[]);
+        let mut client = TestClient::connect(&config);
+        let at = |secs: u64| (unix_secs() + secs).to_string();
+
+        client.command(&["SET", "key", "value"]).await;
+        assert_eq!(
+            client.command(&["EXPIREAT", "key", &at(100)]).await,
+            b":1\r\n"
+        );
+        let ttl = integer(&client.command(&["TTL", "key"]).await);
+        assert!((99..=100).contains(&ttl), "TTL was {ttl}");
+        assert_eq!(
+            client.command(&["EXPIREAT", "missing", &at(100)]).await,
+            b":0\r\n"
+        );
+
+        assert_eq!(client.command(&["EXPIREAT", "key", "1"]).await, b":1\r\n");
+        assert_eq!(client.command(&["GET", "key"]).await, b"$-1\r\n");
+    }
+
+    #[tokio::test]
+    async fn expire_conditions_compare_against_the_current_ttl() {
+        let config = test_config(&[]);
+        let mut client = TestClient::connect(&config);
+        let ttl = |reply: Vec<u8>| integer(&reply);
+
+        client.command(&["SET", "key", "value"]).await;
+        // No TTL: XX and GT refuse, NX and LT (infinity is greater) apply.
+        assert_eq!(
+            client.command(&["EXPIRE", "key", "100", "XX"]).await,
+            b":0\r\n"
+        );
+        assert_eq!(
+            client.command(&["EXPIRE", "key", "100", "GT"]).await,
+            b":0\r\n"
+        );
+        assert_eq!(client.command(&["TTL", "key"]).await, b":-1\r\n");
+        assert_eq!(
+            client.command(&["EXPIRE", "key", "100", "NX"]).await,
+            b":1\r\n"
+        );
+        assert_eq!(
+            client.command(&["EXPIRE", "key", "200", "NX"]).await,
+            b":0\r\n"
+        );
+
+        assert_eq!(
+            client.command(&["EXPIRE", "key", "50", "GT"]).await,
+            b":0\r\n"
+        );
+        assert_eq!(
+            client.command(&["EXPIRE", "key", "200", "GT"]).await,
+            b":1\r\n"
+        );
+        assert!(ttl(client.command(&["TTL", "key"]).await) > 100);
+        assert_eq!(
+            client.command(&["EXPIRE", "key", "300", "LT"]).await,
+            b":0\r\n"
+        );
+        assert_eq!(
+            client.command(&["EXPIRE", "key", "50", "LT"]).await,
+            b":1\r\n"
+        );
+        assert!(ttl(client.command(&["TTL", "key"]).await) <= 50);
+        assert_eq!(
+            client.command(&["EXPIRE", "key", "80", "XX"]).await,
+            b":1\r\n"
+        );
+
+        client.command(&["SET", "other", "value"]).await;
+        assert_eq!(
+            client.command(&["EXPIREAT", "other", "1", "LT"]).await,
+            b":1\r\n"
+        );
+        assert_eq!(client.command(&["GET", "other"]).await, b"$-1\r\n");
+
+        assert_eq!(
+            client.command(&["EXPIRE", "key", "10", "GT", "LT"]).await,
+            b"-ERR GT and LT options at the same time are not compatible\r\n"
+        );
+        assert_eq!(
+            client.command(&["EXPIRE", "key", "10", "NX", "XX"]).await,
+            b"-ERR NX and XX, GT or LT options at the same time are not compatible\r\n"
+        );
+        assert_eq!(
+            client.command(&["EXPIRE", "key", "10", "FOO"]).await,
+            b"-ERR Unsupported option FOO\r\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn overflowing_expire_times_name_the_command_that_rejected_them() {
+        let config = test_config(&[]);
+        let mut client = TestClient::connect(&config);
+        let huge = "9223372036854775807";
+
+        client.command(&["SET", "key", "value"]).await;
+        for (args, command) in [
+            (vec!["SET", "key", "v", "EX", huge], "set"),
+            (vec!["SET", "key", "v", "PX", "0"], "set"),
+            (vec!["SETEX", "key", huge, "v"], "setex"),
+            (vec!["SETEX", "key", "0", "v"], "setex"),
+            (vec!["PSETEX", "key", huge, "v"], "psetex"),
+            (vec!["PSETEX", "key", "-5", "v"], "psetex"),
+            (vec!["GETEX", "key", "EX", huge], "getex"),
+            (vec!["EXPIRE", "key", huge], "expire"),
+            (vec!["PEXPIRE", "key", huge], "pexpire"),
+            (vec!["EXPIREAT", "key", huge], "expireat"),
+        ] {
+            assert_eq!(
+                client.command(&args).await,
+                format!("-ERR invalid expire time in '{command}' command\r\n").as_bytes(),
+                "{args:?}"
+            );
+        }
+        // None of them touched the key.
+        assert_eq!(client.command(&["GET", "key"]).await, b"$5\r\nvalue\r\n");
+        assert_eq!(client.command(&["TTL", "key"]).await, b":-1\r\n");
+    }
+
+    #[tokio::test]
+    async fn lpos_finds_matches_by_rank_from_either_end() {
+        let config = test_config(&[]);
+        let mut client = TestClient::connect(&config);
+        client
+            .command(&["RPUSH", "list", "a", "b", "c", "a", "b", "a"])
+            .await;
+
+        assert_eq!(client.command(&["LPOS", "list", "a"]).await, b":0\r\n");
+        assert_eq!(
+            client.command(&["LPOS", "list", "a", "RANK", "2"]).await,
+            b":3\r\n"
+        );
+        assert_eq!(
+            client.command(&["LPOS", "list", "a", "RANK", "-1"]).await,
+            b":5\r\n"
+        );
+        assert_eq!(
+            client.command(&["LPOS", "list", "b", "RANK", "-2"]).await,
+            b":1\r\n"
+        );
+        assert_eq!(
+            client.command(&["LPOS", "list", "a", "RANK", "4"]).await,
+            b"$-1\r\n"
+        );
+        assert_eq!(client.command(&["LPOS", "list", "z"]).await, b"$-1\r\n");
+
+        assert_eq!(
+            client.command(&["LPOS", "list", "a", "COUNT", "10"]).await,
+            b"*3\r\n:0\r\n:3\r\n:5\r\n"
+        );
+        assert_eq!(
+            client
+                .command(&["LPOS", "list", "a", "RANK", "-1", "COUNT", "2"])
+                .await,
+            b"*2\r\n:5\r\n:3\r\n"
+        );
+        assert_eq!(
+            client.command(&["LPOS", "list", "z", "COUNT", "2"]).await,
+            b"*0\r\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn linsert_lset_and_lrem_edit_a_list_in_place() {
+        let config = test_config(&[]);
+        let mut client = TestClient::connect(&config);
+        client.command(&["RPUSH", "list", "a", "c"]).await;
+
+        assert_eq!(
+            client
+                .command(&["LINSERT", "list", "BEFORE", "c", "b"])
+                .await,
+            b":3\r\n"
+        );
+        assert_eq!(
+            client
+                .command(&["LINSERT", "list", "AFTER", "c", "d"])
+                .await,
+            b":4\r\n"
+        );
+        assert_eq!(
+            client
+                .command(&["LINSERT", "list", "AFTER", "z", "x"])
+                .await,
+            b":-1\r\n"
+        );
+        assert_eq!(
+            client
+                .command(&["LINSERT", "missing", "AFTER", "a", "x"])
+                .await,
+            b":0\r\n"
+        );
+        assert_eq!(
+            bulk_strings(&client.command(&["LRANGE", "list", "0", "-1"]).await),
+            ["a", "b", "c", "d"]
+        );
+
+        assert_eq!(
+            client.command(&["LSET", "list", "-1", "D"]).await,
+            b"+OK\r\n"
+        );
+        assert_eq!(
+            client.command(&["LSET", "list", "4", "x"]).await,
+            b"-ERR index out of range\r\n"
+        );
+        assert_eq!(
+            client.command(&["LSET", "missing", "0", "x"]).await,
+            b"-ERR no such key\r\n"
+        );
+
+        client.command(&["RPUSH", "list", "a", "b", "a"]).await;
+        // A negative count removes from the tail: the last "a" goes first.
+        assert_eq!(
+            client.command(&["LREM", "list", "-1", "a"]).await,
+            b":1\r\n"
+        );
+        assert_eq!(
+            bulk_strings(&client.command(&["LRANGE", "list", "0", "-1"]).await),
+            ["a", "b", "c", "D", "a", "b"]
+        );
+        assert_eq!(client.command(&["LREM", "list", "1", "b"]).await, b":1\r\n");
+        assert_eq!(client.command(&["LREM", "list", "0", "a"]).await, b":2\r\n");
+        assert_eq!(
+            bulk_strings(&client.command(&["LRANGE", "list", "0", "-1"]).await),
+            ["c", "D", "b"]
+        );
+    }
+
+    #[tokio::test]
+    async fn rpoplpush_and_lmove_move_elements_between_lists() {
+        let config = test_config(&[]);
+        let mut client = TestClient::connect(&config);
+        client.command(&["RPUSH", "src", "a", "b", "c"]).await;
+
+        assert_eq!(
+            client.command(&["RPOPLPUSH", "src", "dst"]).await,
+            b"$1\r\nc\r\n"
+        );
+        assert_eq!(
+            client
+                .command(&["LMOVE", "src", "dst", "LEFT", "RIGHT"])
+                .await,
+            b"$1\r\na\r\n"
+        );
+        assert_eq!(
+            bulk_strings(&client.command(&["LRANGE", "dst", "0", "-1"]).await),
+            ["c", "a"]
+        );
+
+        // Rotating a list onto itself.
+        assert_eq!(
+            client
+                .command(&["LMOVE", "dst", "dst", "LEFT", "RIGHT"])
+                .await,
+            b"$1\r\nc\r\n"
+        );
+        assert_eq!(
+            bulk_strings(&client.command(&["LRANGE", "dst", "0", "-1"]).await),
+            ["a", "c"]
+        );
+
+        // Moving the last element deletes the source.
+        assert_eq!(
+            client
+                .command(&["LMOVE", "src", "dst", "RIGHT", "LEFT"])
+                .await,
+            b"$1\r\nb\r\n"
+        );
+        assert_eq!(client.command(&["TYPE", "src"]).await, b"+none\r\n");
+        assert_eq!(
+            client.command(&["RPOPLPUSH", "src", "dst"]).await,
+            b"$-1\r\n"
+        );
+        assert_eq!(client.command(&["LLEN", "dst"]).await, b":3\r\n");
+
+        client.command(&["SET", "string", "v"]).await;
+        assert_eq!(
+            client.command(&["RPOPLPUSH", "dst", "string"]).await,
+            b"-WRONGTYPE Operation against a key holding the wrong kind of value\r\n"
+        );
+        assert_eq!(client.command(&["LLEN", "dst"]).await, b":3\r\n");
+        assert_eq!(
+            client
+                .command(&["LMOVE", "dst", "other", "UP", "LEFT"])
+                .await,
+            b"-ERR syntax error\r\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn lpos_rejects_an_unnegatable_rank_and_counts_zero_as_all() {
+        let config = test_config(&[]);
+        let mut client = TestClient::connect(&config);
+        client.command(&["RPUSH", "list", "a", "b", "a", "a"]).await;
+
+        let min = i64::MIN.to_string();
+        assert_eq!(
+            client.command(&["LPOS", "list", "a", "RANK", &min]).await,
+            format!(
+                "-ERR value is out of range, value must between {} and {}\r\n",
+                -i64::MAX,
+                i64::MAX
+            )
+            .as_bytes()
+        );
+        let max = (-i64::MAX).to_string();
+        assert_eq!(
+            client.command(&["LPOS", "list", "a", "RANK", &max]).await,
+            b"$-1\r\n"
+        );
+        assert!(client
+            .command(&["LPOS", "list", "a", "RANK", "0"])
+            .await
+            .starts_with(b"-ERR RANK can't be zero"));
+
+        assert_eq!(
+            client.command(&["LPOS", "list", "a", "COUNT", "0"]).await,
+            b"*3\r\n:0\r\n:2\r\n:3\r\n"
+        );
+        assert_eq!(
+            client
+                .command(&["LPOS", "list", "a", "RANK", "-2", "COUNT", "0"])
+                .await,
+            b"*2\r\n:2\r\n:0\r\n"
+        );
+        assert_eq!(
+            client.command(&["LPOS", "list", "a", "COUNT", "-1"]).await,
+            b"-ERR COUNT can't be negative\r\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn blpop_waits_for_a_push_and_brpop_times_out() {
+        let config = test_config(&[]);
+        let mut waiter = TestClient::connect(&config);
+        let mut pusher = TestClient::connect(&config);
+
+        waiter
+            .send(&encode_command(&["BLPOP", "empty", "queue", "5"]))
+            .await;
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        assert_eq!(
+            pusher.command(&["RPUSH", "queue", "a", "b"]).await,
+            b":2\r\n"
+        );
+        assert_eq!(waiter.reply().await, b"*2\r\n$5\r\nqueue\r\n$1\r\na\r\n");
+        assert_eq!(pusher.command(&["LLEN", "queue"]).await, b":1\r\n");
+
+        // With an element already there BRPOP returns at once.
+        assert_eq!(
+            waiter.command(&["BRPOP", "queue", "0"]).await,
+            b"*2\r\n$5\r\nqueue\r\n$1\r\nb\r\n"
+        );
+
+        let started = std::time::Instant::now();
+        assert_eq!(waiter.command(&["BRPOP", "queue", "0.1"]).await, b"*-1\r\n");
+        assert!(started.elapsed() >= std::time::Duration::from_millis(100));
+    }
+
+    #[tokio::test]
+    async fn xread_returns_newer_entries_at_once_or_blocks_for_them() {
+        let config = test_config(&[]);
+        let mut reader = TestClient::connect(&config);
+        let mut writer = TestClient::connect(&config);
+        writer.command(&["XADD", "s", "1-1", "f", "a"]).await;
+        writer.command(&["XADD", "s", "1-2", "f", "b"]).await;
+
+        assert_eq!(
+            reader.command(&["XREAD", "STREAMS", "s", "1-1"]).await,
+            b"*1\r\n*2\r\n$1\r\ns\r\n*1\r\n*2\r\n$3\r\n1-2\r\n*2\r\n$1\r\nf\r\n$1\r\nb\r\n"
+        );
+        assert_eq!(
+            reader.command(&["XREAD", "STREAMS", "s", "$"]).await,
+            b"*-1\r\n"
+        );
+
+        // `$` means entries added after the read started.
+        reader
+            .send(&encode_command(&[
+                "XREAD", "BLOCK", "5000", "STREAMS", "s", "$",
+            ]))
+            .await;
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        writer.command(&["XADD", "s", "2-1", "f", "c"]).await;
+        assert_eq!(
+            reader.reply().await,
+            b"*1\r\n*2\r\n$1\r\ns\r\n*1\r\n*2\r\n$3\r\n2-1\r\n*2\r\n$1\r\nf\r\n$1\r\nc\r\n"
+        );
+
+        let started = std::time::Instant::now();
+        assert_eq!(
+            reader
+                .command(&["XREAD", "BLOCK", "100", "STREAMS", "s", "$"])
+                .await,
+            b"*-1\r\n"
+        );
+        assert!(started.elapsed() >= std::time::Duration::from_millis(100));
+
+        // An entry already past the ID is returned without waiting.
+        assert!(reader
+            .command(&["XREAD", "BLOCK", "0", "STREAMS", "s", "2-0"])
+            .await
+            .starts_with(b"*1\r\n"));
+        assert_eq!(
+            reader
+                .command(&["XREAD", "BLOCK", "-1", "STREAMS", "s", "$"])
+                .await,
+            b"-ERR timeout is negative\r\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn published_messages_reach_matching_pattern_subscribers() {
+        let config = test_config(&[]);
+        let mut subscriber = TestClient::connect(&config);
+        let mut publisher = TestClient::connect(&config);
+
+        assert_eq!(
+            subscriber.command(&["PSUBSCRIBE", "news.*"]).await,
+            b"*3\r\n$10\r\npsubscribe\r\n$6\r\nnews.*\r\n:1\r\n"
+        );
+        assert_eq!(
+            publisher.command(&["PUBLISH", "news.tech", "hi"]).await,
+            b":1\r\n"
+        );
+        assert_eq!(
+            subscriber.reply().await,
+            b"*4\r\n$8\r\npmessage\r\n$6\r\nnews.*\r\n$9\r\nnews.tech\r\n$2\r\nhi\r\n"
+        );
+        assert_eq!(
+            publisher.command(&["PUBLISH", "sports", "no"]).await,
+            b":0\r\n"
+        );
+
+        assert_eq!(
+            subscriber.command(&["PUNSUBSCRIBE", "news.*"]).await,
+            b"*3\r\n$12\r\npunsubscribe\r\n$6\r\nnews.*\r\n:0\r\n"
+        );
+        assert_eq!(
+            publisher.command(&["PUBLISH", "news.tech", "hi"]).await,
+            b":0\r\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn pubsub_reports_channels_subscribers_and_patterns() {
+        let config = test_config(&[]);
+        let mut first = TestClient::connect(&config);
+        let mut second = TestClient::connect(&config);
+        let mut observer = TestClient::connect(&config);
+
+        first.command(&["SUBSCRIBE", "news"]).await;
+        second.command(&["SUBSCRIBE", "news"]).await;
+        second.command(&["SUBSCRIBE", "sports", "weather"]).await;
+        second.reply().await;
+        first.command(&["PSUBSCRIBE", "n*"]).await;
+
+        let mut channels = bulk_strings(&observer.command(&["PUBSUB", "CHANNELS"]).await);
+        channels.sort();
+        assert_eq!(channels, ["news", "sports", "weather"]);
+        assert_eq!(
+            bulk_strings(&observer.command(&["PUBSUB", "CHANNELS", "s*"]).await),
+            ["sports"]
+        );
+        assert_eq!(
+            observer
+                .command(&["PUBSUB", "NUMSUB", "news", "missing"])
+                .await,
+            b"*4\r\n$4\r\nnews\r\n:2\r\n$7\r\nmissing\r\n:0\r\n"
+        );
+        assert_eq!(observer.command(&["PUBSUB", "NUMPAT"]).await, b":1\r\n");
+    }
+
+    #[tokio::test]
+    async fn subscribed_resp2_connections_only_take_pubsub_commands() {
+        let config = test_config(&[]);
+        let mut client = TestClient::connect(&config);
+        client.command(&["SUBSCRIBE", "news"]).await;
+
+        assert_eq!(
+            client.command(&["GET", "key"]).await,
+            b"-ERR Can't execute 'get': only (P|S)SUBSCRIBE / (P|S)UNSUBSCRIBE / PING / QUIT / RESET are allowed in this context\r\n"
+        );
+        assert_eq!(
+            client.command(&["PING"]).await,
+            b"*2\r\n$4\r\npong\r\n$0\r\n\r\n"
+        );
+        client.command(&["UNSUBSCRIBE"]).await;
+        assert_eq!(client.command(&["GET", "key"]).await, b"$-1\r\n");
+    }
+
+    #[tokio::test]
+    async fn resp3_subscribers_get_push_frames_and_keep_running_commands() {
+        let config = test_config(&[]);
+        let mut subscriber = TestClient::connect(&config);
+        let mut publisher = TestClient::connect(&config);
+        subscriber.command(&["HELLO", "3"]).await;
+
+        assert_eq!(
+            subscriber.command(&["SUBSCRIBE", "news"]).await,
+            b">3\r\n$9\r\nsubscribe\r\n$4\r\nnews\r\n:1\r\n"
+        );
+        publisher.command(&["PUBLISH", "news", "hi"]).await;
+        assert_eq!(
+            subscriber.reply().await,
+            b">3\r\n$7\r\nmessage\r\n$4\r\nnews\r\n$2\r\nhi\r\n"
+        );
+        assert_eq!(subscriber.command(&["GET", "key"]).await, b"_\r\n");
+        assert_eq!(subscriber.command(&["PING"]).await, b"+PONG\r\n");
+
+        // A RESP2 subscriber of the same channel still gets an array.
+        let mut resp2 = TestClient::connect(&config);
+        resp2.command(&["SUBSCRIBE", "news"]).await;
+        publisher.command(&["PUBLISH", "news", "hi"]).await;
+        assert_eq!(
+            resp2.reply().await,
+            b"*3\r\n$7\r\nmessage\r\n$4\r\nnews\r\n$2\r\nhi\r\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn a_zero_slowlog_threshold_logs_every_command() {
+        let config = test_config(&["--slowlog-log-slower-than", "0"]);
+        let mut client = TestClient::connect(&config);
+
+        client.command(&["SET", "key", "value"]).await;
+        assert_eq!(client.command(&["SLOWLOG", "LEN"]).await, b":1\r\n");
+
+        // Newest first: the SLOWLOG LEN above (id 1), then the SET (id 0).
+        let log = client.command(&["SLOWLOG", "GET", "2"]).await;
+        assert!(log.starts_with(b"*2\r\n*6\r\n:1\r\n"), "{log:?}");
+        let set_entry = b"*3\r\n$3\r\nSET\r\n$3\r\nkey\r\n$5\r\nvalue\r\n";
+        assert!(log
+            .windows(set_entry.len())
+            .any(|window| window == set_entry));
+        assert!(client
+            .command(&["SLOWLOG", "GET", "1"])
+            .await
+            .starts_with(b"*1\r\n*6\r\n:2\r\n"));
+
+        // RESET empties the log, then is logged itself once it has run.
+        assert_eq!(client.command(&["SLOWLOG", "RESET"]).await, b"+OK\r\n");
+        assert_eq!(client.command(&["SLOWLOG", "LEN"]).await, b":1\r\n");
+        assert_eq!(
+            client.command(&["SLOWLOG", "GET", "-2"]).await,
+            b"-ERR count should be greater than or equal to -1\r\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn monitor_echoes_another_client_s_commands() {
+        let config = test_config(&[]);
+        let mut monitor = TestClient::connect(&config);
+        let mut other = TestClient::connect(&config);
+
+        assert_eq!(monitor.command(&["MONITOR"]).await, b"+OK\r\n");
+        other.command(&["SET", "key", "a b"]).await;
+        other.command(&["SELECT", "1"]).await;
+        other.command(&["GET", "key"]).await;
+
+        let line = String::from_utf8(monitor.reply().await).unwrap();
+        assert!(line.starts_with('+'), "{line}");
+        assert!(line.contains(" [0 127.0.0.1:"), "{line}");
+        assert!(line.ends_with("] \"SET\" \"key\" \"a b\"\r\n"), "{line}");
+        assert!(String::from_utf8(monitor.reply().await)
+            .unwrap()
+            .ends_with("\"SELECT\" \"1\"\r\n"));
+        let line = String::from_utf8(monitor.reply().await).unwrap();
+        assert!(line.contains(" [1 127.0.0.1:"), "{line}");
+        assert!(line.ends_with("] \"GET\" \"key\"\r\n"), "{line}");
+    }
+
+    #[tokio::test]
+    async fn select_switches_between_isolated_databases() {
+        let config = test_config(&[]);
+        let mut client = TestClient::connect(&config);
+        let mut other = TestClient::connect(&config);
+
+        client.command(&["SET", "key", "zero"]).await;
+        assert_eq!(client.command(&["SELECT", "1"]).await, b"+OK\r\n");
+        assert_eq!(client.command(&["GET", "key"]).await, b"$-1\r\n");
+        client.command(&["SET", "key", "one"]).await;
+
+        // Each connection keeps its own selection.
+        assert_eq!(other.command(&["GET", "key"]).await, b"$4\r\nzero\r\n");
+        assert_eq!(client.command(&["SELECT", "0"]).await, b"+OK\r\n");
+        assert_eq!(client.command(&["GET", "key"]).await, b"$4\r\nzero\r\n");
+        assert_eq!(client.command(&["SELECT", "15"]).await, b"+OK\r\n");
+
+        for index in ["16", "-1"] {
+            assert!(client
+                .command(&["SELECT", index])
+                .await
+                .starts_with(b"-ERR "));
+        }
+        assert_eq!(
+            client.command(&["SELECT", "16"]).await,
+            b"-ERR DB index is out of range\r\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn swapdb_exchanges_two_databases_for_every_connection() {
+        let config = test_config(&[]);
+        let mut client = TestClient::connect(&config);
+        let mut in_db_1 = TestClient::connect(&config);
+        in_db_1.command(&["SELECT", "1"]).await;
+
+        client.command(&["SET", "a", "zero"]).await;
+        client.command(&["RPUSH", "list", "x"]).await;
+        in_db_1.command(&["SET", "b", "one"]).await;
+
+        assert_eq!(client.command(&["SWAPDB", "0", "1"]).await, b"+OK\r\n");
+        assert_eq!(client.command(&["GET", "a"]).await, b"$-1\r\n");
+        assert_eq!(client.command(&["GET", "b"]).await, b"$3\r\none\r\n");
+        assert_eq!(in_db_1.command(&["GET", "a"]).await, b"$4\r\nzero\r\n");
+        assert_eq!(in_db_1.command(&["LLEN", "list"]).await, b":1\r\n");
+
+        assert_eq!(
+            client.command(&["SWAPDB", "0", "16"]).await,
+            b"-ERR DB index is out of range\r\n"
+        );
+        assert_eq!(
+            client.command(&["SWAPDB", "x", "1"]).await,
+            b"-ERR invalid first DB index\r\n"
+        );
+        assert_eq!(
+            client.command(&["SWAPDB", "0", "y"]).await,
+            b"-ERR invalid second DB index\r\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn copy_takes_a_target_database_and_replace() {
+        let config = test_config(&[]);
+        let mut client = TestClient::connect(&config);
+
+        client.command(&["SET", "src", "value", "EX", "100"]).await;
+        client.command(&["SET", "dst", "old"]).await;
+        assert_eq!(client.command(&["COPY", "src", "dst"]).await, b":0\r\n");
+        assert_eq!(client.command(&["GET", "dst"]).await, b"$3\r\nold\r\n");
+        assert_eq!(
+            client.command(&["COPY", "src", "dst", "REPLACE"]).await,
+            b":1\r\n"
+        );
+        assert_eq!(client.command(&["GET", "dst"]).await, b"$5\r\nvalue\r\n");
+        let ttl = integer(&client.command(&["TTL", "dst"]).await);
+        assert!((99..=100).contains(&ttl), "TTL was {ttl}");
+        assert_eq!(
+            client.command(&["COPY", "missing", "dst", "REPLACE"]).await,
+            b":0\r\n"
+        );
+
+        assert_eq!(
+            client.command(&["COPY", "src", "src", "DB", "2"]).await,
+            b":1\r\n"
+        );
+        assert_eq!(client.command(&["GET", "src"]).await, b"$5\r\nvalue\r\n");
+        client.command(&["SELECT", "2"]).await;
+        assert_eq!(client.command(&["GET", "src"]).await, b"$5\r\nvalue\r\n");
+
+        // Only the same key in the same database is a copy onto itself.
+        assert_eq!(
+            client.command(&["COPY", "src", "src"]).await,
+            b"-ERR source and destination objects are the same\r\n"
+        );
+        assert_eq!(
+            client
+                .command(&["COPY", "src", "src", "DB", "2", "REPLACE"])
+                .await,
+            b"-ERR source and destination objects are the same\r\n"
+        );
+        assert_eq!(
+            client.command(&["COPY", "src", "other", "DB", "16"]).await,
+            b"-ERR DB index is out of range\r\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn append_keeps_bytes_that_are_not_utf8() {
+        let config = test_config(&[]);
+        let mut client = TestClient::connect(&config);
+
+        client.command(&["SET", "key", "ab"]).await;
+        assert_eq!(
+            client.command(&["OBJECT", "ENCODING", "key"]).await,
+            b"$6\r\nembstr\r\n"
+        );
+        client
+            .send(b"*3\r\n$6\r\nAPPEND\r\n$3\r\nkey\r\n$3\r\n\xff\x00\xfe\r\n")
+            .await;
+        assert_eq!(client.reply().await, b":5\r\n");
+        assert_eq!(
+            bulk_payload(&client.command(&["GET", "key"]).await),
+            b"ab\xff\x00\xfe"
+        );
+        assert_eq!(
+            client.command(&["OBJECT", "ENCODING", "key"]).await,
+            b"$3\r\nraw\r\n"
+        );
+
+        client
+            .send(b"*3\r\n$6\r\nAPPEND\r\n$3\r\nnew\r\n$1\r\n\x80\r\n")
+            .await;
+        assert_eq!(client.reply().await, b":1\r\n");
+        assert_eq!(
+            bulk_payload(&client.command(&["GET", "new"]).await),
+            b"\x80"
+        );
+    }
+
+    #[tokio::test]
+    async fn keys_that_are_not_utf8_are_stored_and_matched_byte_for_byte() {
+        let config = test_config(&[]);
+        let mut client = TestClient::connect(&config);
+
+        for key in [b"\xff\x01".as_slice(), b"\xfe\x01", b"plain"] {
+            let mut frame = format!("*3\r\n$3\r\nSET\r\n${}\r\n", key.len()).into_bytes();
+            frame.extend(key);
+            frame.extend(b"\r\n$1\r\nv\r\n");
+            client.send(&frame).await;
+            assert_eq!(client.reply().await, b"+OK\r\n");
+        }
+
+        client.send(b"*2\r\n$3\r\nGET\r\n$2\r\n\xff\x01\r\n").await;
+        assert_eq!(client.reply().await, b"$1\r\nv\r\n");
+        // Lossy decoding would turn both keys into the same replacement
+        // character, and the pattern would match both.
+        client
+            .send(b"*6\r\n$4\r\nSCAN\r\n$1\r\n0\r\n$5\r\nMATCH\r\n$2\r\n\xff*\r\n$5\r\nCOUNT\r\n$3\r\n100\r\n")
+            .await;
+        assert_eq!(
+            client.reply().await,
+            b"*2\r\n$1\r\n0\r\n*1\r\n$2\r\n\xff\x01\r\n"
+        );
+        client.send(b"*2\r\n$4\r\nKEYS\r\n$3\r\n?\x01*\r\n").await;
+        assert!(client.reply().await.starts_with(b"*2\r\n"));
+    }
+
+    #[tokio::test]
+    async fn dbsize_counts_expired_keys_until_they_are_removed() {
+        let config = test_config(&[]);
+        let mut client = TestClient::connect(&config);
+        assert_eq!(client.command(&["DBSIZE"]).await, b":0\r\n");
+
+        client.command(&["SET", "a", "1"]).await;
+        client.command(&["RPUSH", "b", "x"]).await;
+        client.command(&["SET", "c", "1", "PX", "1"]).await;
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        assert_eq!(client.command(&["DBSIZE"]).await, b":3\r\n");
+
+        // Reads skip it, but only the sweeper removes it.
+        assert_eq!(client.command(&["GET", "c"]).await, b"$-1\r\n");
+        assert_eq!(client.command(&["DBSIZE"]).await, b":3\r\n");
+        config.databases[0].write().unwrap().remove_expired();
+        assert_eq!(client.command(&["DBSIZE"]).await, b":2\r\n");
+
+        client.command(&["SELECT", "1"]).await;
+        assert_eq!(client.command(&["DBSIZE"]).await, b":0\r\n");
+    }
+
+    #[tokio::test]
+    async fn randomkey_returns_a_live_key_or_null() {
+        let config = test_config(&[]);
+        let mut client = TestClient::connect(&config);
+        assert_eq!(client.command(&["RANDOMKEY"]).await, b"$-1\r\n");
+
+        client.command(&["SET", "expired", "v", "PX", "1"]).await;
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        assert_eq!(client.command(&["RANDOMKEY"]).await, b"$-1\r\n");
+
+        for key in ["a", "b", "c"] {
+            client.command(&["SET", key, "v"]).await;
+        }
+        let mut seen = std::collections::HashSet::new();
+        for _ in 0..100 {
+            let key = String::from_utf8(bulk_payload(&client.command(&["RANDOMKEY"]).await));
+            let key = key.unwrap();
+            assert!(["a", "b", "c"].contains(&key.as_str()), "{key}");
+            seen.insert(key);
+        }
+        assert_eq!(seen.len(), 3);
+    }
+
+    #[tokio::test]
+    async fn the_replication_offset_counts_only_propagated_bytes() {
+        let config = test_config(&[]);
+        let mut client = TestClient::connect(&config);
+        let offset = || config.replication_manager.offset.load(Ordering::SeqCst);
+
+        client.command(&["SET", "key", "value"]).await;
+        // The first write selects the database on the replication stream.
+        let select_0 = encode_command(&["SELECT", "0"]).len();
+        let set = encode_command(&["SET", "key", "value"]).len();
+        assert_eq!(offset(), select_0 + set);
+
+        let before = offset();
+        for _ in 0..3 {
+            client.command(&["GET", "key"]).await;
+        }
+        client.command(&["SET", "key", "other", "NX"]).await;
+        client.command(&["DEL", "missing"]).await;
+        assert_eq!(offset(), before);
+
+        client.command(&["SET", "key", "value"]).await;
+        assert_eq!(offset(), before + set);
+
+        client.command(&["SELECT", "1"]).await;
+        client.command(&["SET", "key", "value"]).await;
+        let select_1 = encode_command(&["SELECT", "1"]).len();
+        assert_eq!(offset(), before + set + select_1 + set);
+    }
+
+    #[tokio::test]
+    async fn object_encoding_tells_int_embstr_and_raw_strings_apart() {
+        let config = test_config(&[]);
+        let mut client = TestClient::connect(&config);
+        let encoding = |reply: Vec<u8>| String::from_utf8(bulk_payload(&reply)).unwrap();
+
+        let embstr = "x".repeat(44);
+        let raw = "x".repeat(45);
+        for (value, expected) in [
+            ("12345", "int"),
+            ("-7", "int"),
+            ("007", "embstr"),
+            ("hello", "embstr"),
+            (embstr.as_str(), "embstr"),
+            (raw.as_str(), "raw"),
+        ] {
+            client.command(&["SET", "key", value]).await;
+            assert_eq!(
+                encoding(client.command(&["OBJECT", "ENCODING", "key"]).await),
+                expected,
+                "{value}"
+            );
+        }
+
+        client.command(&["RPUSH", "list", "a"]).await;
+        assert_eq!(
+            encoding(client.command(&["OBJECT", "ENCODING", "list"]).await),
+            "listpack"
+        );
+        assert_eq!(
+            client.command(&["OBJECT", "ENCODING", "missing"]).await,
+            b"-ERR no such key\r\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn commands_on_the_wrong_type_reply_wrongtype() {
+        let config = test_config(&[]);
+        let mut client = TestClient::connect(&config);
+        client.command(&["SET", "string", "v"]).await;
+        client.command(&["RPUSH", "list", "a"]).await;
+
+        let wrongtype: &[u8] =
+            b"-WRONGTYPE Operation against a key holding the wrong kind of value\r\n";
+        for args in [
+            &["GET", "list"][..],
+            &["APPEND", "list", "x"],
+            &["LPUSH", "string", "x"],
+            &["LRANGE", "string", "0", "-1"],
+            &["ZADD", "string", "1", "m"],
+            &["HSET", "list", "f", "v"],
+            &["XADD", "string", "*", "f", "v"],
+            &["GETRANGE", "list", "0", "1"],
+        ] {
+            assert_eq!(client.command(args).await, wrongtype, "{args:?}");
+        }
+
+        // RESP3 keeps the same error code.
+        client.command(&["HELLO", "3"]).await;
+        assert_eq!(client.command(&["GET", "list"]).await, wrongtype);
+    }
+
+    #[tokio::test]
+    async fn client_list_shows_each_connection_s_last_command() {
+        let config = test_config(&[]);
+        let mut reader = TestClient::connect(&config);
+        let mut observer = TestClient::connect(&config);
+
+        let id = integer(&reader.command(&["CLIENT", "ID"]).await);
+        reader.command(&["GET", "key"]).await;
+        observer.command(&["SET", "key", "v"]).await;
+
+        let list = String::from_utf8(bulk_payload(&observer.command(&["CLIENT", "LIST"]).await));
+        let list = list.unwrap();
+        let line = |id: i64| {
+            list.lines()
+                .find(|line| line.starts_with(&format!("id={id} ")))
+                .unwrap_or_else(|| panic!("no client {id} in {list}"))
+                .to_string()
+        };
+        assert!(line(id).contains(" cmd=get "), "{}", line(id));
+        // The observer's own line shows the CLIENT LIST it is running.
+        let own = integer(&observer.command(&["CLIENT", "ID"]).await);
+        assert!(line(own).contains(" cmd=client"), "{}", line(own));
+    }
+
+    #[tokio::test]
+    async fn debug_sleep_waits_and_other_debug_subcommands_are_accepted() {
+        let config = test_config(&[]);
+        let mut client = TestClient::connect(&config);
+
+        let started = std::time::Instant::now();
+        assert_eq!(client.command(&["DEBUG", "SLEEP", "0.1"]).await, b"+OK\r\n");
+        assert!(started.elapsed() >= std::time::Duration::from_millis(100));
+        assert_eq!(client.command(&["DEBUG", "SLEEP", "0"]).await, b"+OK\r\n");
+
+        assert_eq!(client.command(&["DEBUG", "JMAP"]).await, b"+OK\r\n");
+        assert_eq!(
+            client.command(&["DEBUG", "SLEEP", "soon"]).await,
+            b"-ERR value is not a valid float\r\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn ping_echoes_its_argument_and_changes_shape_when_subscribed() {
+        let config = test_config(&[]);
+        let mut client = TestClient::connect(&config);
+
+        assert_eq!(client.command(&["PING"]).await, b"+PONG\r\n");
+        assert_eq!(
+            client.command(&["PING", "hello world"]).await,
+            b"$11\r\nhello world\r\n"
+        );
+        assert_eq!(
+            client.command(&["PING", "a", "b"]).await,
+            b"-ERR wrong number of arguments for 'ping' command\r\n"
+        );
+
+        client.command(&["SUBSCRIBE", "news"]).await;
+        assert_eq!(
+            client.command(&["PING"]).await,
+            b"*2\r\n$4\r\npong\r\n$0\r\n\r\n"
+        );
+        assert_eq!(
+            client.command(&["PING", "hi"]).await,
+            b"*2\r\n$4\r\npong\r\n$2\r\nhi\r\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn debug_object_reports_the_fields_tooling_parses() {
+        let config = test_config(&[]);
+        let mut client = TestClient::connect(&config);
+        client.command(&["SET", "key", "12345"]).await;
+
+        let reply = bulk_payload(&client.command(&["DEBUG", "OBJECT", "key"]).await);
+        let reply = String::from_utf8(reply).unwrap();
+        assert!(reply.starts_with("Value at:"), "{reply}");
+        let fields: HashMap<&str, &str> = reply
+            .split(' ')
+            .filter_map(|field| field.split_once(':'))
+            .collect();
+        assert_eq!(fields["encoding"], "int");
+        assert!(fields["refcount"].parse::<i64>().is_ok(), "{reply}");
+        assert!(
+            fields["serializedlength"].parse::<u64>().unwrap() > 0,
+            "{reply}"
+        );
+        assert_eq!(fields["lru_seconds_idle"], "0");
+
+        assert_eq!(
+            client.command(&["DEBUG", "OBJECT", "missing"]).await,
+            b"-ERR no such key\r\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn info_sections_report_clients_replication_and_keyspace() {
+        let config = test_config(&[]);
+        let mut client = TestClient::connect(&config);
+        let _other = TestClient::connect(&config);
+        let info = |reply: Vec<u8>| String::from_utf8(bulk_payload(&reply)).unwrap();
+
+        let replication = info(client.command(&["INFO", "replication"]).await);
+        assert!(
+            replication.starts_with("# Replication\r\n"),
+            "{replication}"
+        );
+        assert!(replication.contains("role:master\r\n"), "{replication}");
+        assert!(!replication.contains("# Clients"), "{replication}");
+
+        let clients = info(client.command(&["INFO", "clients"]).await);
+        assert!(clients.contains("connected_clients:2\r\n"), "{clients}");
+
+        client.command(&["SET", "a", "1"]).await;
+        client.command(&["SET", "b", "1", "EX", "100"]).await;
+        let keyspace = info(client.command(&["INFO", "keyspace"]).await);
+        assert!(keyspace.contains("db0:keys=2,expires=1,"), "{keyspace}");
+        assert!(!keyspace.contains("db1:"), "{keyspace}");
+
+        let all = info(client.command(&["INFO"]).await);
+        for section in [
+            "# Server",
+            "# Clients",
+            "# Memory",
+            "# Replication",
+            "# Keyspace",
+        ] {
+            assert!(all.contains(section), "{section} missing from {all}");
+        }
+    }
+
+    #[tokio::test]
+    async fn client_setname_getname_and_id_are_per_connection() {
+        let config = test_config(&[]);
+        let mut client = TestClient::connect(&config);
+        let mut other = TestClient::connect(&config);
+
+        assert_eq!(client.command(&["CLIENT", "GETNAME"]).await, b"$-1\r\n");
+        assert_eq!(
+            client.command(&["CLIENT", "SETNAME", "worker"]).await,
+            b"+OK\r\n"
+        );
+        assert_eq!(
+            client.command(&["CLIENT", "GETNAME"]).await,
+            b"$6\r\nworker\r\n"
+        );
+        assert_eq!(other.command(&["CLIENT", "GETNAME"]).await, b"$-1\r\n");
+        assert_eq!(
+            client.command(&["CLIENT", "SETNAME", "two words"]).await,
+            b"-ERR Client names cannot contain spaces, newlines or special characters.\r\n"
+        );
+        assert_eq!(
+            client.command(&["CLIENT", "GETNAME"]).await,
+            b"$6\r\nworker\r\n"
+        );
+
+        let id = integer(&client.command(&["CLIENT", "ID"]).await);
+        assert_eq!(integer(&client.command(&["CLIENT", "ID"]).await), id);
+        assert_ne!(integer(&other.command(&["CLIENT", "ID"]).await), id);
+    }
+
+    #[tokio::test]
+    async fn client_kill_by_id_closes_that_connection_only() {
+        let config = test_config(&[]);
+        let mut admin = TestClient::connect(&config);
+        let mut victim = TestClient::connect(&config);
+        let victim_id = integer(&victim.command(&["CLIENT", "ID"]).await);
+        let admin_id = integer(&admin.command(&["CLIENT", "ID"]).await);
+
+        let ids = |list: Vec<u8>| -> Vec<i64> {
+            String::from_utf8(bulk_payload(&list))
+                .unwrap()
+                .lines()
+                .map(|line| line["id=".len()..line.find(' ').unwrap()].parse().unwrap())
+                .collect()
+        };
+        let mut listed = ids(admin.command(&["CLIENT", "LIST"]).await);
+        listed.sort();
+        assert_eq!(listed, [victim_id.min(admin_id), victim_id.max(admin_id)]);
+
+        let kill = victim_id.to_string();
+        assert_eq!(
+            admin.command(&["CLIENT", "KILL", "ID", &kill]).await,
+            b":1\r\n"
+        );
+        assert!(victim.is_closed().await);
+        assert_eq!(ids(admin.command(&["CLIENT", "LIST"]).await), [admin_id]);
+        assert_eq!(
+            admin.command(&["CLIENT", "KILL", "ID", &kill]).await,
+            b":0\r\n"
+        );
+        assert_eq!(admin.command(&["PING"]).await, b"+PONG\r\n");
+    }
+
+    #[tokio::test]
+    async fn flushdb_clears_one_database_and_flushall_every_one() {
+        let config = test_config(&[]);
+        let mut client = TestClient::connect(&config);
+
+        for key in ["a", "b", "c"] {
+            client.command(&["SET", key, "v"]).await;
+        }
+        client.command(&["SELECT", "1"]).await;
+        client.command(&["SET", "other", "v"]).await;
+        client.command(&["SELECT", "0"]).await;
+
+        assert_eq!(client.command(&["FLUSHDB"]).await, b"+OK\r\n");
+        assert_eq!(client.command(&["KEYS", "*"]).await, b"*0\r\n");
+        client.command(&["SELECT", "1"]).await;
+        assert_eq!(
+            bulk_strings(&client.command(&["KEYS", "*"]).await),
+            ["other"]
+        );
+
+        assert_eq!(client.command(&["FLUSHALL", "ASYNC"]).await, b"+OK\r\n");
+        assert_eq!(client.command(&["KEYS", "*"]).await, b"*0\r\n");
+        assert_eq!(client.command(&["FLUSHDB", "SYNC"]).await, b"+OK\r\n");
+    }
+
+    #[tokio::test]
+    async fn client_setinfo_shows_up_in_client_info() {
+        let config = test_config(&[]);
+        let mut client = TestClient::connect(&config);
+        let info = |reply: Vec<u8>| String::from_utf8(bulk_payload(&reply)).unwrap();
+
+        assert_eq!(
+            client
+                .command(&["CLIENT", "SETINFO", "LIB-NAME", "redis-py"])
+                .await,
+            b"+OK\r\n"
+        );
+        assert_eq!(
+            client
+                .command(&["CLIENT", "SETINFO", "lib-ver", "5.0.1"])
+                .await,
+            b"+OK\r\n"
+        );
+        let line = info(client.command(&["CLIENT", "INFO"]).await);
+        assert!(line.contains(" lib-name=redis-py lib-ver=5.0.1 "), "{line}");
+
+        assert_eq!(
+            client
+                .command(&["CLIENT", "SETINFO", "lib-color", "blue"])
+                .await,
+            b"-ERR Unrecognized option 'lib-color'\r\n"
+        );
+        assert_eq!(
+            client
+                .command(&["CLIENT", "SETINFO", "lib-name", "two words"])
+                .await,
+            b"-ERR lib-name cannot contain spaces, newlines or special characters.\r\n"
+        );
+        let line = info(client.command(&["CLIENT", "INFO"]).await);
+        assert!(line.contains(" lib-name=redis-py "), "{line}");
+    }
+
+    #[tokio::test]
+    async fn config_set_changes_dir_and_dbfilename_for_config_get() {
+        let dir = TempDir::new();
+        let config = test_config(&[]);
+        let mut client = TestClient::connect(&config);
+
+        assert_eq!(
+            client.command(&["CONFIG", "SET", "dir", dir.path()]).await,
+            b"+OK\r\n"
+        );
+        assert_eq!(
+            bulk_strings(&client.command(&["CONFIG", "GET", "dir"]).await),
+            ["dir", dir.path()]
+        );
+        assert_eq!(
+            client
+                .command(&["CONFIG", "SET", "DBFILENAME", "backup.rdb"])
+                .await,
+            b"+OK\r\n"
+        );
+        assert_eq!(
+            bulk_strings(&client.command(&["CONFIG", "GET", "dbfilename"]).await),
+            ["dbfilename", "backup.rdb"]
+        );
+
+        let missing = format!("{}/missing", dir.path());
+        assert!(client
+            .command(&["CONFIG", "SET", "dir", &missing])
+            .await
+            .starts_with(b"-ERR CONFIG SET failed (possibly related to argument 'dir')"));
+        assert!(client
+            .command(&["CONFIG", "SET", "dbfilename", "a/b.rdb"])
+            .await
+            .starts_with(b"-ERR CONFIG SET failed (possibly related to argument 'dbfilename')"));
+        assert_eq!(
+            client.command(&["CONFIG", "SET", "colour", "blue"]).await,
+            b"-ERR Unknown option or number of arguments for CONFIG SET - 'colour'\r\n"
+        );
+        assert_eq!(
+            bulk_strings(&client.command(&["CONFIG", "GET", "dbfilename"]).await),
+            ["dbfilename", "backup.rdb"]
+        );
+    }
+
+    #[tokio::test]
+    async fn set_options_set_relative_absolute_and_kept_ttls_and_conditions() {
+        let config = test_config(&[]);
+        let mut client = TestClient::connect(&config);
+        let ttl = |reply: Vec<u8>| integer(&reply);
+
+        client.command(&["SET", "key", "v", "EX", "100"]).await;
+        assert!((99..=100).contains(&ttl(client.command(&["TTL", "key"]).await)));
+        client.command(&["SET", "key", "v", "PX", "50000"]).await;
+        assert!((49..=50).contains(&ttl(client.command(&["TTL", "key"]).await)));
+        let at = (unix_secs() + 200).to_string();
+        client.command(&["SET", "key", "v", "EXAT", &at]).await;
+        assert!((199..=200).contains(&ttl(client.command(&["TTL", "key"]).await)));
+        let at = ((unix_secs() + 300) * 1000).to_string();
+        client.command(&["SET", "key", "v", "PXAT", &at]).await;
+        assert!((299..=300).contains(&ttl(client.command(&["TTL", "key"]).await)));
+
+        client.command(&["SET", "key", "w", "KEEPTTL"]).await;
+        assert!((299..=300).contains(&ttl(client.command(&["TTL", "key"]).await)));
+        assert_eq!(client.command(&["GET", "key"]).await, b"$1\r\nw\r\n");
+        client.command(&["SET", "key", "w"]).await;
+        assert_eq!(client.command(&["TTL", "key"]).await, b":-1\r\n");
+
+        assert_eq!(client.command(&["SET", "key", "x", "NX"]).await, b"$-1\r\n");
+        assert_eq!(client.command(&["SET", "new", "x", "XX"]).await, b"$-1\r\n");
+        assert_eq!(client.command(&["GET", "new"]).await, b"$-1\r\n");
+        assert_eq!(
+            client.command(&["SET", "new", "x", "NX", "EX", "10"]).await,
+            b"+OK\r\n"
+        );
+        assert_eq!(
+            client.command(&["SET", "new", "y", "XX", "KEEPTTL"]).await,
+            b"+OK\r\n"
+        );
+        assert!((9..=10).contains(&ttl(client.command(&["TTL", "new"]).await)));
+
+        for args in [
+            &["SET", "key", "v", "NX", "XX"][..],
+            &["SET", "key", "v", "EX", "10", "PX", "10"],
+            &["SET", "key", "v", "EX", "10", "KEEPTTL"],
+            &["SET", "key", "v", "EX"],
+        ] {
+            assert_eq!(
+                client.command(args).await,
+                b"-ERR syntax error\r\n",
+                "{args:?}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn command_count_matches_the_command_table_and_docs_is_empty() {
+        let config = test_config(&[]);
+        let mut client = TestClient::connect(&config);
+
+        let count = integer(&client.command(&["COMMAND", "COUNT"]).await);
+        assert!(count > 0);
+        assert_eq!(count, COMMAND_TABLE.len() as i64);
+        assert_eq!(client.command(&["COMMAND", "DOCS"]).await, b"*0\r\n");
+        assert_eq!(client.command(&["COMMAND", "DOCS", "get"]).await, b"*0\r\n");
+        assert_eq!(client.command(&["COMMAND"]).await, b"*0\r\n");
+        assert_eq!(
+            client.command(&["COMMAND", "COUNT", "extra"]).await,
+            b"-ERR wrong number of arguments for 'command|count' command\r\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn hello_negotiates_resp2_or_resp3_for_the_connection() {
+        let config = test_config(&[]);
+        let mut client = TestClient::connect(&config);
+        let version = env!("CARGO_PKG_VERSION");
+        let handshake = |proto: u8| {
+            format!(
+                "$6\r\nserver\r\n$5\r\nredis\r\n$7\r\nversion\r\n${}\r\n{version}\r\n\
+                 $5\r\nproto\r\n:{proto}\r\n$4\r\nmode\r\n$10\r\nstandalone\r\n\
+                 $4\r\nrole\r\n$6\r\nmaster\r\n$7\r\nmodules\r\n*0\r\n",
+                version.len()
+            )
+        };
+
+        let reply = client.command(&["HELLO", "2"]).await;
+        assert_eq!(reply, format!("*12\r\n{}", handshake(2)).as_bytes());
+        assert_eq!(client.command(&["GET", "missing"]).await, b"$-1\r\n");
+
+        let reply = client.command(&["HELLO", "3"]).await;
+        assert_eq!(reply, format!("%6\r\n{}", handshake(3)).as_bytes());
+        assert_eq!(client.command(&["GET", "missing"]).await, b"_\r\n");
+
+        // Without a version HELLO keeps the current protocol.
+        assert!(client.command(&["HELLO"]).await.starts_with(b"%6\r\n"));
+        assert_eq!(
+            client.command(&["HELLO", "4"]).await,
+            b"-NOPROTO unsupported protocol version\r\n"
+        );
+        assert_eq!(client.command(&["GET", "missing"]).await, b"_\r\n");
+    }
+
+    #[tokio::test]
+    async fn cluster_subcommands_answer_as_a_standalone_server() {
+        let config = test_config(&[]);
+        let mut client = TestClient::connect(&config);
+
+        let info = String::from_utf8(bulk_payload(&client.command(&["CLUSTER", "INFO"]).await));
+        assert!(info.unwrap().contains("cluster_enabled:0"));
+        assert_eq!(
+            bulk_payload(&client.command(&["CLUSTER", "MYID"]).await),
+            config.repl_id.as_bytes()
+        );
+        assert_eq!(client.command(&["CLUSTER", "SLOTS"]).await, b"*0\r\n");
+        assert_eq!(client.command(&["CLUSTER", "SHARDS"]).await, b"*0\r\n");
+
+        client.command(&["HELLO", "3"]).await;
+        assert_eq!(
+            client.command(&["CLUSTER", "INFO"]).await,
+            b"=23\r\ntxt:cluster_enabled:0\r\n\r\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn reset_and_quit_work_while_subscribed() {
+        let config = test_config(&[]);
+        let mut client = TestClient::connect(&config);
+        let mut publisher = TestClient::connect(&config);
+
+        client.command(&["SUBSCRIBE", "news"]).await;
+        client.command(&["PSUBSCRIBE", "n*"]).await;
+        assert_eq!(client.command(&["RESET"]).await, b"+RESET\r\n");
+        assert_eq!(client.command(&["GET", "key"]).await, b"$-1\r\n");
+        assert_eq!(
+            publisher.command(&["PUBLISH", "news", "hi"]).await,
+            b":0\r\n"
+        );
+
+        client.command(&["SUBSCRIBE", "news"]).await;
+        assert_eq!(client.command(&["QUIT"]).await, b"+OK\r\n");
+        assert!(client.is_closed().await);
+    }
+
+    #[tokio::test]
+    async fn activedefrag_round_trips_through_config_and_memory_purge_is_ok() {
+        let config = test_config(&[]);
+        let mut client = TestClient::connect(&config);
+
+        assert_eq!(
+            bulk_strings(&client.command(&["CONFIG", "GET", "activedefrag"]).await),
+            ["activedefrag", "no"]
+        );
+        assert_eq!(
+            client
+                .command(&["CONFIG", "SET", "activedefrag", "YES"])
+                .await,
+            b"+OK\r\n"
+        );
+        assert_eq!(
+            bulk_strings(&client.command(&["CONFIG", "GET", "activedefrag"]).await),
+            ["activedefrag", "yes"]
+        );
+        assert!(client
+            .command(&["CONFIG", "SET", "activedefrag", "maybe"])
+            .await
+            .starts_with(b"-ERR CONFIG SET failed (possibly related to argument 'activedefrag')"));
+        assert_eq!(
+            bulk_strings(&client.command(&["CONFIG", "GET", "active*"]).await),
+            ["activedefrag", "yes"]
+        );
+
+        assert_eq!(client.command(&["MEMORY", "PURGE"]).await, b"+OK\r\n");
+    }
+
+    #[tokio::test]
+    async fn unknown_commands_are_named_with_their_first_arguments() {
+        let config = test_config(&[]);
+        let mut client = TestClient::connect(&config);
+
+        assert_eq!(
+            client.command(&["FOOBAR", "a", "b"]).await,
+            b"-ERR unknown command 'FOOBAR', with args beginning with: 'a' 'b' \r\n"
+        );
+        assert_eq!(
+            client.command(&["nope"]).await,
+            b"-ERR unknown command 'nope', with args beginning with: \r\n"
+        );
+
+        // The quoted arguments stop at 128 characters.
+        let long = "x".repeat(200);
+        let reply = String::from_utf8(client.command(&["FOOBAR", &long, "b"]).await).unwrap();
+        assert_eq!(
+            reply,
+            format!(
+                "-ERR unknown command 'FOOBAR', with args beginning with: '{}' \r\n",
+                "x".repeat(128)
+            )
+        );
+
+        // An unknown subcommand points at the container command's HELP.
+        assert_eq!(
+            client.command(&["CLIENT", "FLY"]).await,
+            b"-ERR unknown subcommand 'FLY'. Try CLIENT HELP.\r\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn wrong_argument_counts_are_rejected_before_running() {
+        let config = test_config(&[]);
+        let mut client = TestClient::connect(&config);
+
+        for (args, name) in [
+            (&["SET", "key"][..], "set"),
+            (&["SET"], "set"),
+            (&["GET"], "get"),
+            (&["GET", "a", "b"], "get"),
+            (&["DEL"], "del"),
+        ] {
+            assert_eq!(
+                client.command(args).await,
+                format!("-ERR wrong number of arguments for '{name}' command\r\n").as_bytes(),
+                "{args:?}"
+            );
+        }
+        // The one-argument SET stored nothing, not even an empty value.
+        assert_eq!(client.command(&["GET", "key"]).await, b"$-1\r\n");
+        assert_eq!(client.command(&["DBSIZE"]).await, b":0\r\n");
+    }
+
+    #[tokio::test]
+    async fn set_ex_counts_seconds_and_px_counts_milliseconds() {
+        let config = test_config(&[]);
+        let mut client = TestClient::connect(&config);
+
+        client.command(&["SET", "seconds", "v", "EX", "1"]).await;
+        client.command(&["SET", "millis", "v", "PX", "1"]).await;
+        tokio::time::sleep(std::time::Duration::from_millis(500)).await;
+
+        assert_eq!(client.command(&["GET", "seconds"]).await, b"$1\r\nv\r\n");
+        assert_eq!(client.command(&["GET", "millis"]).await, b"$-1\r\n");
+    }
+
+    #[tokio::test]
+    async fn reset_aborts_multi_and_restores_connection_defaults() {
+        let config = test_config(&[]);
+        let mut client = TestClient::connect(&config);
+        let mut other = TestClient::connect(&config);
+
+        client.command(&["HELLO", "3"]).await;
+        client.command(&["SELECT", "1"]).await;
+        client.command(&["WATCH", "key"]).await;
+        client.command(&["MULTI"]).await;
+        assert_eq!(client.command(&["SET", "key", "v"]).await, b"+QUEUED\r\n");
+
+        assert_eq!(client.command(&["RESET"]).await, b"+RESET\r\n");
+        assert_eq!(
+            client.command(&["EXEC"]).await,
+            b"-ERR EXEC without MULTI\r\n"
+        );
+        // Back on database 0, speaking RESP2, with nothing queued or watched.
+        assert_eq!(client.command(&["GET", "key"]).await, b"$-1\r\n");
+        client.command(&["MULTI"]).await;
+        client.command(&["SET", "key", "v"]).await;
+        other.command(&["SET", "key", "changed"]).await;
+        assert_eq!(client.command(&["EXEC"]).await, b"*1\r\n+OK\r\n");
+    }
+
+    #[tokio::test]
+    async fn pattern_subscribers_cannot_write_until_they_leave_resp2_subscribed_mode() {
+        let config = test_config(&[]);
+        let mut client = TestClient::connect(&config);
+        client.command(&["PSUBSCRIBE", "n*"]).await;
+
+        assert_eq!(
+            client.command(&["SET", "key", "v"]).await,
+            b"-ERR Can't execute 'set': only (P|S)SUBSCRIBE / (P|S)UNSUBSCRIBE / PING / QUIT / RESET are allowed in this context\r\n"
+        );
+        client.command(&["PUNSUBSCRIBE"]).await;
+        assert_eq!(client.command(&["GET", "key"]).await, b"$-1\r\n");
+
+        client.command(&["HELLO", "3"]).await;
+        client.command(&["SUBSCRIBE", "news"]).await;
+        assert_eq!(client.command(&["GET", "key"]).await, b"_\r\n");
+    }
+
+    #[tokio::test]
+    async fn quit_replies_ok_then_closes_before_running_pipelined_commands() {
+        let config = test_config(&[]);
+        let mut client = TestClient::connect(&config);
+        let mut observer = TestClient::connect(&config);
+
+        let mut pipeline = encode_command(&["QUIT"]);
+        pipeline.extend(encode_command(&["SET", "key", "v"]));
+        client.send(&pipeline).await;
+
+        assert_eq!(client.reply().await, b"+OK\r\n");
+        assert!(client.is_closed().await);
+        assert_eq!(observer.command(&["GET", "key"]).await, b"$-1\r\n");
+    }
 }
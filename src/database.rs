@@ -1,47 +1,704 @@
 use std::{
-    collections::HashMap,
+    collections::{BTreeMap, BTreeSet, HashMap, VecDeque},
+    fmt,
     sync::Arc,
-    time::{Duration, Instant},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
-use crate::Config;
+use crate::{
+    command::propagate_to_replicas, error::RedisError, stream::Stream, zset::SortedSet, Config,
+};
+
+// A hash's fields and their values.
+pub type Hash = HashMap<Vec<u8>, Vec<u8>>;
+
+// What a key holds. Commands for one type answer WRONGTYPE on the others.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Value {
+    String(Vec<u8>),
+    List(VecDeque<Vec<u8>>),
+    ZSet(SortedSet),
+    Hash(Hash),
+    Stream(Stream),
+}
+
+impl Value {
+    // The name TYPE reports.
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::String(_) => "string",
+            Value::List(_) => "list",
+            Value::ZSet(_) => "zset",
+            Value::Hash(_) => "hash",
+            Value::Stream(_) => "stream",
+        }
+    }
+
+    // The variant's own name, which DEBUG OBJECT reports so tests can check
+    // how a value is held rather than only how Redis would encode it.
+    pub fn variant_name(&self) -> &'static str {
+        match self {
+            Value::String(_) => "String",
+            Value::List(_) => "List",
+            Value::ZSet(_) => "ZSet",
+            Value::Hash(_) => "Hash",
+            Value::Stream(_) => "Stream",
+        }
+    }
+
+    // Whether this is a list, sorted set or hash with nothing left in it,
+    // which Redis deletes rather than keeps. Streams may stay empty.
+    pub fn is_empty_collection(&self) -> bool {
+        match self {
+            Value::List(list) => list.is_empty(),
+            Value::ZSet(zset) => zset.is_empty(),
+            Value::Hash(hash) => hash.is_empty(),
+            Value::String(_) | Value::Stream(_) => false,
+        }
+    }
+}
 
 #[derive(Debug, Clone)]
 pub struct Item {
-    pub value: String,
-    pub expires: Option<Duration>,
-    pub created: Instant,
+    pub value: Value,
+    // When the key expires, if it has a TTL. Kept absolute so every command
+    // that reads or changes it agrees on the same moment.
+    pub deadline: Option<Instant>,
+    // Set once APPEND modifies the value; Redis keeps such strings `raw`
+    // whatever their length.
+    pub raw: bool,
 }
 
 impl Item {
-    pub fn new(value: String, expires: Option<Duration>) -> Self {
-        let time_now = Instant::now();
+    pub fn new(value: Value, ttl: Option<Duration>) -> Self {
         Self {
             value,
-            expires,
-            created: time_now,
+            deadline: ttl.map(|ttl| Instant::now() + ttl),
+            raw: false,
         }
     }
+
     pub fn is_expired(&self) -> bool {
-        if let Some(duration) = self.expires {
-            self.created.elapsed() >= duration
+        // Like Redis (`now > when`), a key is still alive at the exact instant it expires.
+        self.deadline
+            .is_some_and(|deadline| Instant::now() > deadline)
+    }
+
+    pub fn as_string(&self) -> Result<&Vec<u8>, RedisError> {
+        match &self.value {
+            Value::String(bytes) => Ok(bytes),
+            _ => Err(RedisError::WrongType),
+        }
+    }
+
+    pub fn as_list(&self) -> Result<&VecDeque<Vec<u8>>, RedisError> {
+        match &self.value {
+            Value::List(list) => Ok(list),
+            _ => Err(RedisError::WrongType),
+        }
+    }
+
+    pub fn as_zset(&self) -> Result<&SortedSet, RedisError> {
+        match &self.value {
+            Value::ZSet(zset) => Ok(zset),
+            _ => Err(RedisError::WrongType),
+        }
+    }
+
+    pub fn as_hash(&self) -> Result<&Hash, RedisError> {
+        match &self.value {
+            Value::Hash(hash) => Ok(hash),
+            _ => Err(RedisError::WrongType),
+        }
+    }
+
+    pub fn as_stream(&self) -> Result<&Stream, RedisError> {
+        match &self.value {
+            Value::Stream(stream) => Ok(stream),
+            _ => Err(RedisError::WrongType),
+        }
+    }
+
+    // The name OBJECT ENCODING reports. Strings are `int` for integers in
+    // canonical form, `embstr` up to 44 bytes and `raw` otherwise; lists,
+    // sorted sets and hashes are a `listpack` while small, then a
+    // `quicklist`, `skiplist` or `hashtable` once they grow. A hash stops
+    // being small past `hash-max-listpack-entries` fields or once any field
+    // or value is longer than `hash-max-listpack-value` bytes. Streams are
+    // always `stream`.
+    pub fn encoding(&self, config: &Config) -> &'static str {
+        let bytes = match &self.value {
+            Value::String(bytes) => bytes,
+            Value::List(elements) => {
+                let small = elements.len() <= 128 && elements.iter().all(|e| e.len() <= 64);
+                return if small { "listpack" } else { "quicklist" };
+            }
+            Value::ZSet(zset) => {
+                let small = zset.len() <= 128 && zset.iter().all(|(m, _)| m.len() <= 64);
+                return if small { "listpack" } else { "skiplist" };
+            }
+            Value::Hash(hash) => {
+                let max_value = config.hash_max_listpack_value();
+                let small = hash.len() <= config.hash_max_listpack_entries()
+                    && hash
+                        .iter()
+                        .all(|(f, v)| f.len() <= max_value && v.len() <= max_value);
+                return if small { "listpack" } else { "hashtable" };
+            }
+            Value::Stream(_) => return "stream",
+        };
+        if self.raw {
+            "raw"
+        } else if self.canonical_int().is_some() {
+            "int"
+        } else if bytes.len() <= 44 {
+            "embstr"
         } else {
-            false
+            "raw"
         }
     }
+
+    // What OBJECT REFCOUNT reports. Redis shares one object per small
+    // integer and reports those with INT_MAX; nothing else is shared here.
+    pub fn refcount(&self) -> i64 {
+        let shared = !self.raw
+            && self
+                .canonical_int()
+                .is_some_and(|n| (0..10000).contains(&n));
+        if shared {
+            i32::MAX as i64
+        } else {
+            1
+        }
+    }
+
+    // The integer a string holds, if it's written the way Redis would print
+    // it, so it can be stored as an `int`.
+    fn canonical_int(&self) -> Option<i64> {
+        let Value::String(bytes) = &self.value else {
+            return None;
+        };
+        std::str::from_utf8(bytes)
+            .ok()
+            .and_then(|text| text.parse::<i64>().ok())
+            .filter(|n| n.to_string().as_bytes() == bytes.as_slice())
+    }
+
+    // Time left before the key expires, if it has a TTL.
+    pub fn ttl(&self) -> Option<Duration> {
+        self.deadline
+            .map(|deadline| deadline.saturating_duration_since(Instant::now()))
+    }
+}
+
+pub fn unix_time_ms() -> u128 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards")
+        .as_millis()
 }
 
-#[derive(Debug)]
 pub struct Database {
-    pub storage: HashMap<String, Item>,
-    pub config: Arc<Config>,
+    // Which of the numbered databases this is, for SELECT in the
+    // replication stream.
+    pub index: usize,
+    pub storage: HashMap<Vec<u8>, Item>,
+    // Keys some connection is WATCHing, each with a version bumped on every
+    // write to it and how many watches are on it. Keys nobody watches
+    // aren't tracked, and a key's entry goes with its last watch.
+    watched: HashMap<Vec<u8>, (u64, usize)>,
+    // Keys with a TTL ordered by deadline, so the sweeper only visits keys
+    // that are due instead of scanning the whole keyspace.
+    expires: BTreeSet<(Instant, Vec<u8>)>,
+    // Every key gets a position when created and keeps it until removed, so
+    // a SCAN cursor (the next position to visit) survives other keys being
+    // added or deleted between calls.
+    positions: HashMap<Vec<u8>, usize>,
+    scan_order: BTreeMap<usize, Vec<u8>>,
+    next_position: usize,
 }
 
 impl Database {
-    pub fn new(config: Arc<Config>) -> Self {
+    pub fn new(index: usize) -> Self {
         Self {
+            index,
             storage: HashMap::new(),
-            config,
+            watched: HashMap::new(),
+            expires: BTreeSet::new(),
+            positions: HashMap::new(),
+            scan_order: BTreeMap::new(),
+            // Cursor 0 starts a scan, so positions start at 1.
+            next_position: 1,
+        }
+    }
+
+    // Looks up a key, treating expired entries as missing.
+    pub fn get(&self, key: &[u8]) -> Option<&Item> {
+        self.storage.get(key).filter(|item| !item.is_expired())
+    }
+
+    // Looks up a string key: `Ok(None)` when it's missing and WRONGTYPE when
+    // it holds another type.
+    pub fn get_string(&self, key: &[u8]) -> Result<Option<&Vec<u8>>, RedisError> {
+        self.get(key).map(Item::as_string).transpose()
+    }
+
+    // Looks up a list key, the same way as `get_string`.
+    pub fn get_list(&self, key: &[u8]) -> Result<Option<&VecDeque<Vec<u8>>>, RedisError> {
+        self.get(key).map(Item::as_list).transpose()
+    }
+
+    // Looks up a list key to modify it in place, bumping its version for
+    // WATCH. With `create`, a missing key starts out as an empty list; the
+    // caller must remove the key again if it leaves the list empty.
+    pub fn get_list_mut(
+        &mut self,
+        key: &[u8],
+        create: bool,
+    ) -> Result<Option<&mut VecDeque<Vec<u8>>>, RedisError> {
+        self.get_list(key)?;
+        let empty = create.then(|| Value::List(VecDeque::new()));
+        Ok(self.value_mut(key, empty).and_then(|value| match value {
+            Value::List(list) => Some(list),
+            _ => None,
+        }))
+    }
+
+    pub fn get_zset(&self, key: &[u8]) -> Result<Option<&SortedSet>, RedisError> {
+        self.get(key).map(Item::as_zset).transpose()
+    }
+
+    // Like `get_list_mut`, for sorted sets.
+    pub fn get_zset_mut(
+        &mut self,
+        key: &[u8],
+        create: bool,
+    ) -> Result<Option<&mut SortedSet>, RedisError> {
+        self.get_zset(key)?;
+        let empty = create.then(|| Value::ZSet(SortedSet::default()));
+        Ok(self.value_mut(key, empty).and_then(|value| match value {
+            Value::ZSet(zset) => Some(zset),
+            _ => None,
+        }))
+    }
+
+    pub fn get_hash(&self, key: &[u8]) -> Result<Option<&Hash>, RedisError> {
+        self.get(key).map(Item::as_hash).transpose()
+    }
+
+    // Like `get_list_mut`, for hashes.
+    pub fn get_hash_mut(
+        &mut self,
+        key: &[u8],
+        create: bool,
+    ) -> Result<Option<&mut Hash>, RedisError> {
+        self.get_hash(key)?;
+        let empty = create.then(|| Value::Hash(Hash::new()));
+        Ok(self.value_mut(key, empty).and_then(|value| match value {
+            Value::Hash(hash) => Some(hash),
+            _ => None,
+        }))
+    }
+
+    pub fn get_stream(&self, key: &[u8]) -> Result<Option<&Stream>, RedisError> {
+        self.get(key).map(Item::as_stream).transpose()
+    }
+
+    // Like `get_list_mut`, for streams. Unlike the other types, a stream
+    // stays when its last entry goes.
+    pub fn get_stream_mut(
+        &mut self,
+        key: &[u8],
+        create: bool,
+    ) -> Result<Option<&mut Stream>, RedisError> {
+        self.get_stream(key)?;
+        let empty = create.then(|| Value::Stream(Stream::default()));
+        Ok(self.value_mut(key, empty).and_then(|value| match value {
+            Value::Stream(stream) => Some(stream),
+            _ => None,
+        }))
+    }
+
+    // A key's value for modifying in place, bumping its version. A missing
+    // key is created holding `empty` when given.
+    fn value_mut(&mut self, key: &[u8], empty: Option<Value>) -> Option<&mut Value> {
+        if self.get(key).is_none() {
+            self.insert(key.to_vec(), Item::new(empty?, None));
+        }
+        self.touch(key);
+        self.storage.get_mut(key).map(|item| &mut item.value)
+    }
+
+    pub fn insert(&mut self, key: Vec<u8>, item: Item) {
+        self.touch(&key);
+        if let Some(deadline) = item.deadline {
+            self.expires.insert((deadline, key.clone()));
+        }
+        if let Some(old) = self.storage.insert(key.clone(), item) {
+            self.unindex(&key, &old);
+        } else {
+            self.positions.insert(key.clone(), self.next_position);
+            self.scan_order.insert(self.next_position, key);
+            self.next_position += 1;
+        }
+    }
+
+    // Replaces `key` with what a *STORE command computed, deleting it
+    // instead when that's an empty collection. Returns whether the key held
+    // a value before, so the caller can tell replicas to drop it.
+    pub fn store(&mut self, key: &[u8], value: Value) -> bool {
+        let existed = self.remove(key).is_some();
+        if !value.is_empty_collection() {
+            self.insert(key.to_vec(), Item::new(value, None));
+        }
+        existed
+    }
+
+    // Deletes `key` if a command just took the last element out of the
+    // collection it holds, since Redis never keeps an empty one around.
+    // Every command that removes elements calls this once it's done.
+    pub fn remove_if_empty(&mut self, key: &[u8]) {
+        if self
+            .storage
+            .get(key)
+            .is_some_and(|item| item.value.is_empty_collection())
+        {
+            self.remove(key);
+        }
+    }
+
+    pub fn remove(&mut self, key: &[u8]) -> Option<Item> {
+        let item = self.storage.remove(key)?;
+        self.unindex(key, &item);
+        if let Some(position) = self.positions.remove(key) {
+            self.scan_order.remove(&position);
+        }
+        self.touch(key);
+        Some(item)
+    }
+
+    // Drops every key whose TTL has elapsed, soonest deadline first, and
+    // returns their names.
+    pub fn remove_expired(&mut self) -> Vec<Vec<u8>> {
+        let now = Instant::now();
+        let mut expired = Vec::new();
+        while let Some((deadline, _)) = self.expires.first() {
+            if *deadline >= now {
+                break;
+            }
+            let (_, key) = self.expires.pop_first().unwrap();
+            self.remove(&key);
+            expired.push(key);
+        }
+        expired
+    }
+
+    pub fn clear(&mut self) {
+        for (key, (version, _)) in self.watched.iter_mut() {
+            if self.storage.contains_key(key) {
+                *version += 1;
+            }
+        }
+        self.storage.clear();
+        self.expires.clear();
+        self.positions.clear();
+        self.scan_order.clear();
+    }
+
+    // Exchanges keyspaces with `other` for SWAPDB. Each side keeps its
+    // index, and watched keys present in either one are bumped so WATCHes
+    // on them fail.
+    pub fn swap(&mut self, other: &mut Database) {
+        std::mem::swap(&mut self.storage, &mut other.storage);
+        std::mem::swap(&mut self.expires, &mut other.expires);
+        std::mem::swap(&mut self.positions, &mut other.positions);
+        std::mem::swap(&mut self.scan_order, &mut other.scan_order);
+        std::mem::swap(&mut self.next_position, &mut other.next_position);
+
+        let keys = self
+            .watched
+            .keys()
+            .chain(other.watched.keys())
+            .filter(|key| self.storage.contains_key(*key) || other.storage.contains_key(*key))
+            .cloned()
+            .collect::<Vec<Vec<u8>>>();
+        for key in keys {
+            self.touch(&key);
+            other.touch(&key);
+        }
+    }
+
+    // Live keys in the order they were created. Unlike the map's own order
+    // this is the same on every run, so a seeded RANDOMKEY repeats itself.
+    pub fn keys(&self) -> impl Iterator<Item = &Vec<u8>> + Clone {
+        self.scan_order
+            .values()
+            .filter(|key| self.get(key).is_some())
+    }
+
+    // Up to `count` keys from position `cursor` on, with the cursor to
+    // continue from (0 once every key has been visited). Keys present for
+    // the whole scan are returned exactly once.
+    pub fn scan(&self, cursor: usize, count: usize) -> (usize, Vec<&Vec<u8>>) {
+        let mut page = self.scan_order.range(cursor..);
+        let keys = page
+            .by_ref()
+            .take(count.max(1))
+            .map(|(_, key)| key)
+            .collect();
+        let next_cursor = page.next().map_or(0, |(&position, _)| position);
+        (next_cursor, keys)
+    }
+
+    // Removes `item`'s deadline from the expiry index unless `key` has since
+    // been stored again with the same deadline.
+    fn unindex(&mut self, key: &[u8], item: &Item) {
+        let Some(deadline) = item.deadline else {
+            return;
+        };
+        let still_current = self
+            .storage
+            .get(key)
+            .is_some_and(|current| current.deadline == Some(deadline));
+        if !still_current {
+            self.expires.remove(&(deadline, key.to_vec()));
+        }
+    }
+
+    // Starts tracking writes to `key` for WATCH, returning its version now.
+    // Each call must be matched by an `unwatch`.
+    pub fn watch(&mut self, key: &[u8]) -> u64 {
+        let (version, watches) = self.watched.entry(key.to_vec()).or_default();
+        *watches += 1;
+        *version
+    }
+
+    pub fn unwatch(&mut self, key: &[u8]) {
+        if let Some((_, watches)) = self.watched.get_mut(key) {
+            *watches -= 1;
+            if *watches == 0 {
+                self.watched.remove(key);
+            }
+        }
+    }
+
+    // The version of a watched key.
+    pub fn version(&self, key: &[u8]) -> u64 {
+        self.watched.get(key).map_or(0, |(version, _)| *version)
+    }
+
+    fn touch(&mut self, key: &[u8]) {
+        if let Some((version, _)) = self.watched.get_mut(key) {
+            *version += 1;
+        }
+    }
+}
+
+// The key count stands in for the contents, which would flood the logs.
+impl fmt::Debug for Database {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Database")
+            .field("index", &self.index)
+            .field("keys", &self.storage.len())
+            .finish()
+    }
+}
+
+// Background task that deletes expired keys nobody reads, `hz` times per
+// second, and tells replicas with a DEL since they never expire keys on
+// their own.
+pub async fn run_active_expiry(config: Arc<Config>) {
+    let mut interval = tokio::time::interval(Duration::from_millis(1000 / config.hz.max(1)));
+    loop {
+        interval.tick().await;
+
+        for in_memory in &config.databases {
+            let expired = in_memory.write().unwrap().remove_expired();
+            if expired.is_empty() {
+                continue;
+            }
+
+            let mut del: Vec<&[u8]> = vec![b"DEL"];
+            del.extend(expired.iter().map(Vec::as_slice));
+            propagate_to_replicas(in_memory, &del, &config).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn string_item() -> Item {
+        Item::new(Value::String(b"v".to_vec()), None)
+    }
+
+    #[test]
+    fn writes_to_unwatched_keys_are_not_tracked() {
+        let mut db = Database::new(0);
+        for i in 0..100 {
+            db.insert(format!("key{i}").into_bytes(), string_item());
+        }
+        db.remove(b"key0");
+        assert!(db.watched.is_empty());
+    }
+
+    #[test]
+    fn watched_key_is_tracked_until_its_last_unwatch() {
+        let mut db = Database::new(0);
+        assert_eq!(db.watch(b"key"), 0);
+        assert_eq!(db.watch(b"key"), 0);
+        db.insert(b"key".to_vec(), string_item());
+        assert_eq!(db.version(b"key"), 1);
+
+        db.unwatch(b"key");
+        assert_eq!(db.version(b"key"), 1);
+        db.unwatch(b"key");
+        assert!(db.watched.is_empty());
+    }
+
+    #[test]
+    fn flushing_bumps_only_watched_keys_that_exist() {
+        let mut db = Database::new(0);
+        db.insert(b"present".to_vec(), string_item());
+        db.watch(b"present");
+        db.watch(b"missing");
+        db.clear();
+        assert_eq!(db.version(b"present"), 1);
+        assert_eq!(db.version(b"missing"), 0);
+    }
+
+    #[test]
+    fn expired_keys_read_as_missing_until_swept() {
+        let mut db = Database::new(0);
+        db.insert(
+            b"expired".to_vec(),
+            Item::new(Value::String(b"v".to_vec()), Some(Duration::ZERO)),
+        );
+        db.insert(
+            b"later".to_vec(),
+            Item::new(Value::String(b"v".to_vec()), Some(Duration::from_secs(100))),
+        );
+        db.insert(b"forever".to_vec(), string_item());
+        std::thread::sleep(Duration::from_millis(2));
+
+        assert!(db.get(b"expired").is_none());
+        assert_eq!(db.keys().count(), 2);
+        assert_eq!(db.remove_expired(), [b"expired".to_vec()]);
+        assert_eq!(db.storage.len(), 2);
+        assert!(db.remove_expired().is_empty());
+    }
+
+    #[test]
+    fn sweeps_expired_keys_in_deadline_order_and_leaves_live_ones() {
+        let mut db = Database::new(0);
+        let start = Instant::now();
+        // Inserted out of deadline order: key7 expires first, key0 last.
+        for i in 0..8 {
+            let mut item = string_item();
+            item.deadline = Some(start + Duration::from_millis(8 - i));
+            db.insert(format!("key{i}").into_bytes(), item);
+        }
+        for i in 0..100 {
+            let ttl = Some(Duration::from_secs(100 + i));
+            db.insert(
+                format!("live{i}").into_bytes(),
+                Item::new(Value::String(b"v".to_vec()), ttl),
+            );
+        }
+        std::thread::sleep(Duration::from_millis(20));
+
+        let expected: Vec<Vec<u8>> = (0..8)
+            .rev()
+            .map(|i| format!("key{i}").into_bytes())
+            .collect();
+        assert_eq!(db.remove_expired(), expected);
+        assert_eq!(db.storage.len(), 100);
+        // Only keys with a TTL are indexed, soonest first, so the sweep
+        // stops at the first live deadline.
+        assert_eq!(db.expires.len(), 100);
+        assert_eq!(db.expires.first().unwrap().1, b"live0");
+    }
+
+    #[test]
+    fn scan_visits_surviving_keys_once_despite_changes() {
+        let mut db = Database::new(0);
+        for i in 0..10 {
+            db.insert(format!("key{i}").into_bytes(), string_item());
+        }
+
+        let mut seen = Vec::new();
+        let mut cursor = 0;
+        loop {
+            let (next, keys) = db.scan(cursor, 3);
+            seen.extend(keys.into_iter().cloned());
+            if next == 0 {
+                break;
+            }
+            // Changes between calls must not make the scan skip or repeat
+            // keys that were there all along.
+            db.remove(b"key9");
+            db.insert(format!("new{next}").into_bytes(), string_item());
+            cursor = next;
+        }
+
+        for i in 0..9 {
+            let key = format!("key{i}").into_bytes();
+            assert_eq!(seen.iter().filter(|seen| **seen == key).count(), 1);
+        }
+    }
+
+    // GETs per second that `threads` threads complete through `get`.
+    fn gets_per_second(threads: usize, get: impl Fn(&[u8]) -> bool + Sync) -> f64 {
+        let keys: Vec<Vec<u8>> = (0..1000).map(|i| format!("key{i}").into_bytes()).collect();
+        let period = Duration::from_millis(500);
+        let deadline = Instant::now() + period;
+        let gets = std::sync::atomic::AtomicUsize::new(0);
+        std::thread::scope(|scope| {
+            for _ in 0..threads {
+                scope.spawn(|| {
+                    let mut done = 0;
+                    while Instant::now() < deadline {
+                        for key in &keys {
+                            assert!(std::hint::black_box(get(key)));
+                        }
+                        done += keys.len();
+                    }
+                    gets.fetch_add(done, std::sync::atomic::Ordering::Relaxed);
+                });
+            }
+        });
+        gets.into_inner() as f64 / period.as_secs_f64()
+    }
+
+    // Compares GET throughput with the database behind the std RwLock the
+    // server uses, a tokio RwLock, and the Mutex it replaced. Run with
+    // `cargo test --release benchmark -- --ignored --nocapture`.
+    #[test]
+    #[ignore = "benchmark"]
+    fn benchmark_concurrent_gets() {
+        let filled = || {
+            let mut db = Database::new(0);
+            for i in 0..1000 {
+                db.insert(format!("key{i}").into_bytes(), string_item());
+            }
+            db
+        };
+        let std_rwlock = std::sync::RwLock::new(filled());
+        let tokio_rwlock = tokio::sync::RwLock::new(filled());
+        let mutex = std::sync::Mutex::new(filled());
+
+        for threads in [1, 2, 4, 8] {
+            let std_rwlock =
+                gets_per_second(threads, |key| std_rwlock.read().unwrap().get(key).is_some());
+            let tokio_rwlock = gets_per_second(threads, |key| {
+                tokio_rwlock.blocking_read().get(key).is_some()
+            });
+            let mutex = gets_per_second(threads, |key| mutex.lock().unwrap().get(key).is_some());
+            println!(
+                "{threads} threads: std RwLock {std_rwlock:.0}/s, \
+                 tokio RwLock {tokio_rwlock:.0}/s, Mutex {mutex:.0}/s"
+            );
         }
     }
 }
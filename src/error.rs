@@ -0,0 +1,27 @@
+use thiserror::Error;
+
+// Error replies. Each displays as the code clients match on (`ERR`,
+// `WRONGTYPE`, ...) followed by Redis's message.
+// Some codes have no command producing them yet.
+#[allow(dead_code)]
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum RedisError {
+    #[error("ERR {0}")]
+    Err(String),
+    #[error("WRONGTYPE Operation against a key holding the wrong kind of value")]
+    WrongType,
+    #[error("NOAUTH Authentication required.")]
+    NoAuth,
+    #[error("READONLY You can't write against a read only replica.")]
+    ReadOnly,
+    #[error("OOM command not allowed when used memory > 'maxmemory'.")]
+    Oom,
+    #[error("NOSCRIPT No matching script. Please use EVAL.")]
+    NoScript,
+    #[error("EXECABORT Transaction discarded because of previous errors.")]
+    ExecAbort,
+    #[error("BUSYKEY Target key name already exists.")]
+    BusyKey,
+    #[error("NOPROTO unsupported protocol version")]
+    NoProto,
+}
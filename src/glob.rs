@@ -0,0 +1,111 @@
+// Redis-style glob matching used by KEYS: `*`, `?`, `[abc]`, `[^abc]`,
+// `[a-z]` and `\` to escape the next character. Works on raw bytes.
+pub fn glob_match(pattern: &[u8], string: &[u8]) -> bool {
+    let (mut p, mut s) = (0, 0);
+    // Position to resume from when a later match fails after a `*`.
+    let mut backtrack: Option<(usize, usize)> = None;
+
+    while s < string.len() {
+        let matched = match pattern.get(p) {
+            Some(b'*') => {
+                backtrack = Some((p, s));
+                p += 1;
+                continue;
+            }
+            Some(b'?') => Some(p + 1),
+            Some(b'[') => match_class(pattern, p, string[s]),
+            Some(b'\\') if p + 1 < pattern.len() => (pattern[p + 1] == string[s]).then_some(p + 2),
+            Some(&ch) => (ch == string[s]).then_some(p + 1),
+            None => None,
+        };
+
+        match (matched, backtrack) {
+            (Some(next), _) => {
+                p = next;
+                s += 1;
+            }
+            (None, Some((star_p, star_s))) => {
+                p = star_p + 1;
+                s = star_s + 1;
+                backtrack = Some((star_p, star_s + 1));
+            }
+            (None, None) => return false,
+        }
+    }
+
+    pattern[p..].iter().all(|&ch| ch == b'*')
+}
+
+// Matches `ch` against the class opening at `pattern[start]`, returning the
+// pattern index just past the closing `]` on success.
+fn match_class(pattern: &[u8], start: usize, ch: u8) -> Option<usize> {
+    let mut p = start + 1;
+    let negate = pattern.get(p) == Some(&b'^');
+    if negate {
+        p += 1;
+    }
+
+    let mut found = false;
+    while p < pattern.len() && pattern[p] != b']' {
+        if pattern[p] == b'\\' && p + 1 < pattern.len() {
+            found |= pattern[p + 1] == ch;
+            p += 2;
+        } else if p + 2 < pattern.len() && pattern[p + 1] == b'-' && pattern[p + 2] != b']' {
+            let (low, high) = if pattern[p] <= pattern[p + 2] {
+                (pattern[p], pattern[p + 2])
+            } else {
+                (pattern[p + 2], pattern[p])
+            };
+            found |= (low..=high).contains(&ch);
+            p += 3;
+        } else {
+            found |= pattern[p] == ch;
+            p += 1;
+        }
+    }
+
+    // An unterminated class matches up to the end of the pattern, like Redis.
+    let next = (p + 1).min(pattern.len());
+    (found != negate).then_some(next)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::glob_match;
+
+    #[test]
+    fn wildcards_match_any_run_or_one_byte() {
+        assert!(glob_match(b"*", b""));
+        assert!(glob_match(b"h*llo", b"heeeello"));
+        assert!(glob_match(b"h*llo", b"hllo"));
+        assert!(glob_match(b"h?llo", b"hello"));
+        assert!(!glob_match(b"h?llo", b"hllo"));
+        assert!(glob_match(b"*a*b", b"xxaxxab"));
+        assert!(!glob_match(b"*a*b", b"xxaxxa"));
+    }
+
+    #[test]
+    fn classes_take_sets_ranges_and_negation() {
+        assert!(glob_match(b"h[ae]llo", b"hallo"));
+        assert!(!glob_match(b"h[ae]llo", b"hillo"));
+        assert!(glob_match(b"h[^e]llo", b"hallo"));
+        assert!(!glob_match(b"h[^e]llo", b"hello"));
+        assert!(glob_match(b"h[a-c]llo", b"hbllo"));
+        assert!(glob_match(b"h[c-a]llo", b"hbllo"));
+        assert!(!glob_match(b"h[a-c]llo", b"hdllo"));
+    }
+
+    #[test]
+    fn backslash_escapes_special_characters() {
+        assert!(glob_match(br"h\*llo", b"h*llo"));
+        assert!(!glob_match(br"h\*llo", b"hello"));
+        assert!(glob_match(br"[\]]", b"]"));
+    }
+
+    #[test]
+    fn a_pattern_without_wildcards_matches_only_itself() {
+        assert!(glob_match(b"user:1", b"user:1"));
+        assert!(!glob_match(b"user:1", b"user:10"));
+        assert!(!glob_match(b"user:1", b"user:"));
+    }
+}
@@ -0,0 +1,171 @@
+// Redis's listpack: a flat, serialized list of strings and integers. RDB
+// files store stream entries in listpacks.
+//
+// Layout: total byte count (u32) and element count (u16), both
+// little-endian, then each element as an encoding byte, its data and a
+// "backlen" giving the element's size so it can be walked backwards, then
+// 0xFF.
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Element {
+    Int(i64),
+    Str(Vec<u8>),
+}
+
+impl Element {
+    pub fn as_int(&self) -> Option<i64> {
+        match self {
+            Element::Int(n) => Some(*n),
+            Element::Str(bytes) => std::str::from_utf8(bytes).ok()?.parse().ok(),
+        }
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        match self {
+            Element::Int(n) => n.to_string().into_bytes(),
+            Element::Str(bytes) => bytes,
+        }
+    }
+}
+
+pub fn encode(elements: &[Element]) -> Vec<u8> {
+    let mut out = vec![0; 6];
+    for element in elements {
+        let mut entry = Vec::new();
+        match element {
+            Element::Int(n @ 0..=127) => entry.push(*n as u8),
+            Element::Int(n @ -4096..=4095) => {
+                let n = *n as u16 & 0x1FFF;
+                entry.extend([0xC0 | (n >> 8) as u8, n as u8]);
+            }
+            Element::Int(n) => {
+                entry.push(0xF4);
+                entry.extend(n.to_le_bytes());
+            }
+            Element::Str(bytes) if bytes.len() < 64 => entry.push(0x80 | bytes.len() as u8),
+            Element::Str(bytes) if bytes.len() < 4096 => {
+                entry.extend([0xE0 | (bytes.len() >> 8) as u8, bytes.len() as u8]);
+            }
+            Element::Str(bytes) => {
+                entry.push(0xF0);
+                entry.extend((bytes.len() as u32).to_le_bytes());
+            }
+        }
+        if let Element::Str(bytes) = element {
+            entry.extend(bytes);
+        }
+        let backlen = encode_backlen(entry.len());
+        out.extend(entry);
+        out.extend(backlen);
+    }
+    out.push(0xFF);
+
+    let total = out.len() as u32;
+    out[..4].copy_from_slice(&total.to_le_bytes());
+    // Counts past u16::MAX are stored as u16::MAX, meaning "walk to count".
+    let count = elements.len().min(u16::MAX as usize) as u16;
+    out[4..6].copy_from_slice(&count.to_le_bytes());
+    out
+}
+
+// The size of an element's encoding and data, 7 bits per byte with the
+// most significant first and the high bit set on all but the first, so it
+// can be read from its last byte backwards. Redis picks the width with
+// these exact cut-offs and expects them when skipping forwards.
+fn encode_backlen(len: usize) -> Vec<u8> {
+    let width = match len {
+        0..=127 => 1,
+        128..=16382 => 2,
+        16383..=2097150 => 3,
+        2097151..=268435454 => 4,
+        _ => 5,
+    };
+    (0..width)
+        .rev()
+        .map(|group| {
+            let byte = (len >> (7 * group)) as u8 & 0x7F;
+            if group == width - 1 {
+                byte
+            } else {
+                byte | 0x80
+            }
+        })
+        .collect()
+}
+
+// Reads every element, or `None` if the listpack is malformed.
+pub fn decode(bytes: &[u8]) -> Option<Vec<Element>> {
+    let mut rest = bytes.get(6..)?;
+    let mut elements = Vec::new();
+    loop {
+        let first = *rest.first()?;
+        if first == 0xFF {
+            return Some(elements);
+        }
+        let (element, size) = match first {
+            0x00..=0x7F => (Element::Int(first as i64), 1),
+            0x80..=0xBF => {
+                let len = (first & 0x3F) as usize;
+                (Element::Str(rest.get(1..1 + len)?.to_vec()), 1 + len)
+            }
+            0xC0..=0xDF => {
+                let raw = ((first & 0x1F) as u16) << 8 | *rest.get(1)? as u16;
+                // Sign-extend from 13 bits.
+                let n = ((raw << 3) as i16 >> 3) as i64;
+                (Element::Int(n), 2)
+            }
+            0xE0..=0xEF => {
+                let len = ((first & 0x0F) as usize) << 8 | *rest.get(1)? as usize;
+                (Element::Str(rest.get(2..2 + len)?.to_vec()), 2 + len)
+            }
+            0xF0 => {
+                let len = u32::from_le_bytes(rest.get(1..5)?.try_into().ok()?) as usize;
+                (Element::Str(rest.get(5..5 + len)?.to_vec()), 5 + len)
+            }
+            0xF1..=0xF4 => {
+                let width = match first {
+                    0xF1 => 2,
+                    0xF2 => 3,
+                    0xF3 => 4,
+                    _ => 8,
+                };
+                let data = rest.get(1..1 + width)?;
+                let mut le = [0; 8];
+                le[..width].copy_from_slice(data);
+                // Sign-extend from the stored width.
+                let shift = 64 - 8 * width as u32;
+                let n = (i64::from_le_bytes(le) << shift) >> shift;
+                (Element::Int(n), 1 + width)
+            }
+            _ => return None,
+        };
+        elements.push(element);
+        rest = rest.get(size + encode_backlen(size).len()..)?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_what_it_encodes() {
+        let elements = vec![
+            Element::Int(0),
+            Element::Int(127),
+            Element::Int(-4096),
+            Element::Int(40_000),
+            Element::Int(i64::MIN),
+            Element::Str(Vec::new()),
+            Element::Str(b"field".to_vec()),
+            Element::Str(vec![b'x'; 5000]),
+        ];
+        assert_eq!(decode(&encode(&elements)), Some(elements));
+    }
+
+    #[test]
+    fn rejects_a_truncated_listpack() {
+        let bytes = encode(&[Element::Str(b"value".to_vec())]);
+        assert_eq!(decode(&bytes[..bytes.len() - 3]), None);
+    }
+}
@@ -1,27 +1,56 @@
-use std::sync::{Arc, Mutex};
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{
+    atomic::{AtomicBool, AtomicUsize, Ordering},
+    Arc, Mutex, RwLock as StdRwLock,
+};
+use std::time::Instant;
 
 use clap::Parser;
 
-use rdb::load_rdb_to_database;
+use rdb::{load_rdb_to_database, save_rdb};
 use replication::handle_replica;
-use resp::parse_messages;
+use resp::{parse_messages, RespType};
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
-    net::{TcpListener, TcpStream},
-    sync::RwLock,
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpSocket, UnixListener, UnixStream},
+    sync::{mpsc, RwLock},
+    task::JoinError,
+    time::Duration,
 };
 
-use crate::command::handle_command;
+use crate::command::{handle_bpop, handle_command, handle_xread_block};
 use crate::{
+    blocking::BlockingRegistry,
+    client::{ClientRegistry, ClientState},
     command::Command,
-    database::{Database, Item},
+    database::{run_active_expiry, Database, Item},
+    monitor::Monitors,
+    pubsub::PubSub,
+    replication::Backlog,
+    rng::Rng,
+    slowlog::SlowLog,
 };
 
+mod bitfield;
+mod blocking;
+mod client;
 mod command;
 mod database;
+mod error;
+mod glob;
+mod listpack;
+mod monitor;
+mod pubsub;
 mod rdb;
 mod replication;
 mod resp;
+mod rng;
+mod slowlog;
+mod stream;
+#[cfg(test)]
+mod testing;
+mod zset;
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
@@ -37,8 +66,55 @@ struct Args {
 
     #[arg(long)]
     replicaof: Option<String>,
+
+    // Also serve clients on a Unix socket at this path.
+    #[arg(long)]
+    unixsocket: Option<String>,
+
+    #[arg(long)]
+    rng_seed: Option<u64>,
+
+    #[arg(long, default_value_t = 10)]
+    hz: u64,
+
+    // Most commands run from one read before yielding to other connections.
+    #[arg(long, default_value_t = 1000)]
+    pipeline_depth: usize,
+
+    #[arg(long, default_value_t = 16)]
+    databases: usize,
+
+    #[arg(long, default_value_t = 1024 * 1024)]
+    repl_backlog_size: usize,
+
+    // Connections the kernel queues before they're accepted.
+    #[arg(long, default_value_t = 511)]
+    tcp_backlog: u32,
+
+    // Repeatable; an empty new name disables the command.
+    #[arg(long, num_args = 2, value_names = ["COMMAND", "NEWNAME"])]
+    rename_command: Vec<String>,
+
+    // Microseconds a command must take to enter the slow log; negative
+    // disables it.
+    #[arg(long, default_value_t = 10000, allow_negative_numbers = true)]
+    slowlog_log_slower_than: i64,
+
+    #[arg(long, default_value_t = 128)]
+    slowlog_max_len: usize,
+
+    // Past this many fields, or with a field or value longer than this many
+    // bytes, a hash is encoded as a hashtable instead of a listpack.
+    #[arg(long, default_value_t = 128)]
+    hash_max_listpack_entries: usize,
+
+    #[arg(long, default_value_t = 64)]
+    hash_max_listpack_value: usize,
 }
 
+// The Redis version reported in INFO and stamped on RDB files.
+const REDIS_VERSION: &str = "7.2.0";
+
 #[derive(Debug, PartialEq, Eq, Clone)]
 enum Role {
     Slave,
@@ -47,177 +123,903 @@ enum Role {
 
 #[derive(Debug, Clone)]
 struct Config {
-    dir: Option<String>,
-    dbfilename: Option<String>,
+    dir: Arc<StdRwLock<Option<String>>>,
+    dbfilename: Arc<StdRwLock<Option<String>>>,
     role: Role,
     port: u32,
+    tcp_backlog: u32,
+    unixsocket: Option<String>,
     repl_id: String,
     replicaof: Option<String>,
+    hz: u64,
+    pipeline_depth: usize,
+    // Accepted for compatibility; there is no allocator to defragment.
+    activedefrag: Arc<AtomicBool>,
+    hash_max_listpack_entries: Arc<AtomicUsize>,
+    hash_max_listpack_value: Arc<AtomicUsize>,
     replication_manager: ReplicationManager,
+    pubsub: PubSub,
+    shard_pubsub: PubSub,
+    // Every command runs holding a read guard; EXEC takes the write guard so
+    // no other connection's command interleaves with a transaction.
+    transaction_lock: Arc<RwLock<()>>,
+    rng: Arc<Mutex<Rng>>,
+    // From --rename-command: lowercase names mapped to the command they run,
+    // or to `None` for original names that were renamed away.
+    renamed_commands: HashMap<String, Option<String>>,
+    // The numbered databases SELECT switches between.
+    databases: Vec<Arc<StdRwLock<Database>>>,
+    clients: Arc<ClientRegistry>,
+    // Connections waiting in BLPOP/BRPOP for a push.
+    blocked: Arc<BlockingRegistry>,
+    // When the server started, for INFO's uptime.
+    started: Instant,
+    slowlog: Arc<SlowLog>,
+    // Connections streaming every command with MONITOR.
+    monitors: Arc<Monitors>,
 }
 
 impl Config {
-    fn new(
-        dir: Option<String>,
-        dbfilename: Option<String>,
-        role: Role,
-        port: u32,
-        replicaof: Option<String>,
-    ) -> Self {
+    fn new(args: &Args) -> Self {
+        let mut rng = Rng::new(args.rng_seed);
+        let repl_id = generate_repl_id(&mut rng);
+        let role = if args.replicaof.is_some() {
+            Role::Slave
+        } else {
+            Role::Master
+        };
         Self {
-            dir,
-            dbfilename,
+            dir: Arc::new(StdRwLock::new(args.dir.clone())),
+            dbfilename: Arc::new(StdRwLock::new(args.dbfilename.clone())),
             role,
-            port,
-            repl_id: "8371b4fb1155b71f4a04d3e1bc3e18c4a990aeeb".to_string(),
-            replicaof,
-            replication_manager: ReplicationManager::new(),
+            port: args.port.unwrap_or(6379),
+            tcp_backlog: args.tcp_backlog,
+            unixsocket: args.unixsocket.clone(),
+            repl_id,
+            replicaof: args.replicaof.clone(),
+            hz: args.hz,
+            pipeline_depth: args.pipeline_depth,
+            activedefrag: Arc::new(AtomicBool::new(false)),
+            hash_max_listpack_entries: Arc::new(AtomicUsize::new(args.hash_max_listpack_entries)),
+            hash_max_listpack_value: Arc::new(AtomicUsize::new(args.hash_max_listpack_value)),
+            replication_manager: ReplicationManager::new(args.repl_backlog_size),
+            pubsub: PubSub::new("message"),
+            shard_pubsub: PubSub::new("smessage"),
+            transaction_lock: Arc::new(RwLock::new(())),
+            rng: Arc::new(Mutex::new(rng)),
+            renamed_commands: renamed_commands(&args.rename_command),
+            databases: (0..args.databases.max(1))
+                .map(|index| Arc::new(StdRwLock::new(Database::new(index))))
+                .collect(),
+            clients: Arc::new(ClientRegistry::default()),
+            blocked: Arc::new(BlockingRegistry::default()),
+            started: Instant::now(),
+            slowlog: Arc::new(SlowLog::new(
+                args.slowlog_log_slower_than,
+                args.slowlog_max_len,
+            )),
+            monitors: Arc::new(Monitors::default()),
         }
     }
 }
 
+impl Config {
+    fn dir(&self) -> Option<String> {
+        self.dir.read().unwrap().clone()
+    }
+
+    fn dbfilename(&self) -> Option<String> {
+        self.dbfilename.read().unwrap().clone()
+    }
+
+    fn set_dir(&self, dir: String) {
+        *self.dir.write().unwrap() = Some(dir);
+    }
+
+    fn set_dbfilename(&self, dbfilename: String) {
+        *self.dbfilename.write().unwrap() = Some(dbfilename);
+    }
+
+    fn set_activedefrag(&self, enabled: bool) {
+        self.activedefrag.store(enabled, Ordering::SeqCst);
+    }
+
+    fn hash_max_listpack_entries(&self) -> usize {
+        self.hash_max_listpack_entries.load(Ordering::SeqCst)
+    }
+
+    fn hash_max_listpack_value(&self) -> usize {
+        self.hash_max_listpack_value.load(Ordering::SeqCst)
+    }
+
+    fn set_hash_max_listpack_entries(&self, entries: usize) {
+        self.hash_max_listpack_entries
+            .store(entries, Ordering::SeqCst);
+    }
+
+    fn set_hash_max_listpack_value(&self, bytes: usize) {
+        self.hash_max_listpack_value.store(bytes, Ordering::SeqCst);
+    }
+
+    // Every parameter visible to CONFIG GET with its current value. Only dir,
+    // dbfilename, activedefrag, the hash encoding thresholds and the slowlog
+    // settings can change; the rest are reported for compatibility.
+    fn parameters(&self) -> Vec<(&'static str, String)> {
+        vec![
+            ("dir", self.dir().unwrap_or_default()),
+            ("dbfilename", self.dbfilename().unwrap_or_default()),
+            ("port", self.port.to_string()),
+            ("tcp-backlog", self.tcp_backlog.to_string()),
+            ("unixsocket", self.unixsocket.clone().unwrap_or_default()),
+            ("hz", self.hz.to_string()),
+            ("pipeline-depth", self.pipeline_depth.to_string()),
+            ("databases", self.databases.len().to_string()),
+            (
+                "repl-backlog-size",
+                self.replication_manager
+                    .backlog
+                    .lock()
+                    .unwrap()
+                    .capacity()
+                    .to_string(),
+            ),
+            ("maxmemory", "0".to_string()),
+            ("save", "3600 1 300 100 60 10000".to_string()),
+            ("appendonly", "no".to_string()),
+            (
+                "activedefrag",
+                if self.activedefrag.load(Ordering::SeqCst) {
+                    "yes"
+                } else {
+                    "no"
+                }
+                .to_string(),
+            ),
+            (
+                "slowlog-log-slower-than",
+                self.slowlog.log_slower_than().to_string(),
+            ),
+            ("slowlog-max-len", self.slowlog.max_len().to_string()),
+            (
+                "hash-max-listpack-entries",
+                self.hash_max_listpack_entries().to_string(),
+            ),
+            (
+                "hash-max-listpack-value",
+                self.hash_max_listpack_value().to_string(),
+            ),
+        ]
+    }
+}
+
+fn renamed_commands(renames: &[String]) -> HashMap<String, Option<String>> {
+    let mut renamed = HashMap::new();
+    for pair in renames.chunks(2) {
+        renamed.insert(pair[0].to_lowercase(), None);
+    }
+    // Inserted second so swapping two names keeps both reachable.
+    for pair in renames.chunks(2) {
+        if !pair[1].is_empty() {
+            renamed.insert(pair[1].to_lowercase(), Some(pair[0].to_lowercase()));
+        }
+    }
+    renamed
+}
+
+fn generate_repl_id(rng: &mut Rng) -> String {
+    const HEX: &[u8] = b"0123456789abcdef";
+    (0..40)
+        .map(|_| HEX[rng.gen_range(HEX.len())] as char)
+        .collect()
+}
+
+// Anything a client can connect over. Connections are generic over it, and
+// a replica's is kept boxed as one once PSYNC hands it to the replication
+// stream.
+trait Connection: AsyncRead + AsyncWrite + Unpin + Send + Sync + std::fmt::Debug {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send + Sync + std::fmt::Debug> Connection for T {}
+
 #[derive(Debug, Clone)]
 struct ReplicationManager {
-    replicas: Arc<RwLock<Vec<TcpStream>>>,
+    replicas: Arc<RwLock<Vec<Box<dyn Connection>>>>,
+    // master_repl_offset: bytes written to the replication stream so far.
+    offset: Arc<AtomicUsize>,
+    // The database the replication stream last switched to. `None` makes the
+    // next propagated write start with a SELECT, as after a replica joins.
+    selected_db: Arc<Mutex<Option<usize>>>,
+    backlog: Arc<Mutex<Backlog>>,
 }
 
 impl ReplicationManager {
-    fn new() -> Self {
+    fn new(backlog_size: usize) -> Self {
         Self {
             replicas: Arc::new(RwLock::new(Vec::new())),
+            offset: Arc::new(AtomicUsize::new(0)),
+            selected_db: Arc::new(Mutex::new(None)),
+            backlog: Arc::new(Mutex::new(Backlog::new(backlog_size))),
         }
     }
+
+    // Counts bytes written to the replication stream and keeps them in the
+    // backlog. Callers hold the replicas lock so both stay in step with
+    // what replicas were sent.
+    fn record(&self, bytes: &[u8]) {
+        self.backlog.lock().unwrap().push(bytes);
+        self.offset.fetch_add(bytes.len(), Ordering::SeqCst);
+    }
 }
 
-async fn handle_client(
-    mut stream: TcpStream,
-    in_memory: &mut Arc<Mutex<Database>>,
+// Serves one client, whose address and local address are `addr` and `laddr`.
+async fn handle_client<S: Connection + 'static>(
+    stream: S,
+    addr: String,
+    laddr: String,
     config: Arc<Config>,
 ) {
     println!("Connection created successfully");
-    let mut last_command = Command::Unknown;
+    // Buffered so a blocked pop can tell whether the client hung up without
+    // consuming what it sent meanwhile.
+    let mut stream = BufReader::new(stream);
+    // The connection's selected database; SELECT points it elsewhere.
+    let mut in_memory = Arc::clone(&config.databases[0]);
+    let mut last_command = None;
+    let (pubsub_sender, mut pubsub_receiver) = mpsc::unbounded_channel::<RespType>();
+    let mut client = ClientState::new(config.clients.next_id(), addr, laddr, pubsub_sender);
+    config.clients.update(&client);
+
+    // Bytes read but not run yet: commands past `pipeline_depth` in one
+    // read, or the start of a command still arriving.
+    let mut input = Vec::new();
+    // Set when `input` holds whole commands the depth limit held back.
+    let mut held_back = false;
 
     loop {
-        let mut buffer = [0; 1024];
-        match stream.read(&mut buffer).await {
-            Ok(bytes_read) => {
-                if bytes_read == 0 {
+        if held_back {
+            // Let other connections run before working through the rest of a
+            // long pipeline.
+            tokio::task::yield_now().await;
+        } else {
+            let mut buffer = [0; 16 * 1024];
+            let read_result = tokio::select! {
+                result = stream.read(&mut buffer) => result,
+                _ = client.killed.changed() => break,
+                Some(message) = pubsub_receiver.recv() => {
+                    let message = message.serialize_for(client.protocol);
+                    if let Err(e) = stream.write_all(&message).await {
+                        eprintln!("Error sending pub/sub message: {}", e);
+                        break;
+                    }
+                    continue;
+                }
+            };
+
+            match read_result {
+                Ok(0) => {
                     println!("The connection has been closed");
                     break;
                 }
-                println!("Bytes read: {bytes_read:?}");
-                let filtered_buffer = buffer
-                    .iter()
-                    .take(bytes_read)
-                    .filter(|&&ch| ch != 0_u8)
-                    .copied()
-                    .collect::<Vec<u8>>();
-
-                let data = String::from_utf8(filtered_buffer).expect("Expected utf-8 string");
-                let commands = parse_messages(&data);
-                println!("{:?}", commands);
-
-                for command in commands {
-                    last_command = command.clone();
-
-                    if let Some(response) =
-                        handle_command(&command, &mut stream, in_memory, &config).await
-                    {
-                        if let Err(e) = stream.write_all(response.as_bytes()).await {
-                            eprintln!("Error sending response: {}", e);
-                            break;
-                        }
-                    }
+                Ok(bytes_read) => input.extend_from_slice(&buffer[..bytes_read]),
+                Err(e) => {
+                    eprintln!("Error reading stream: {}", e);
+                    break;
+                }
+            }
+        }
 
-                    if command == Command::PSync {
-                        println!("Inserting stream onto replication manager.");
-                        break;
-                    }
+        let (commands, rest, protocol_error) =
+            parse_messages(&input, config.pipeline_depth, &config.renamed_commands);
+        held_back = commands.len() >= config.pipeline_depth.max(1) && !rest.is_empty();
+        let consumed = input.len() - rest.len();
+        input.drain(..consumed);
+
+        for (command, args) in commands {
+            last_command = Some(command.clone());
+            if !matches!(command, Command::Error(_) | Command::Unknown { .. }) {
+                client.last_command = command.name();
+                config.monitors.feed(client.db, &client.addr, &args);
+            }
+
+            // Timed from when the command holds its lock. Blocking commands
+            // aren't timed at all: most of their time is spent waiting, not
+            // running.
+            let mut started = None;
+            // PSYNC runs alone too, so its snapshot can't catch a write that
+            // hasn't reached the replication stream yet.
+            let response = match &command {
+                Command::Exec | Command::PSync { .. } => {
+                    let _exclusive = config.transaction_lock.write().await;
+                    started = Some(Instant::now());
+                    handle_command(&command, &mut stream, &mut in_memory, &config, &mut client)
+                        .await
+                }
+                // A blocking command that may wait takes the shared lock itself.
+                Command::BPop {
+                    keys,
+                    timeout,
+                    left,
+                } if client.transaction.is_none() && !client.in_pubsub_mode() => {
+                    handle_bpop(
+                        keys,
+                        *timeout,
+                        *left,
+                        &mut stream,
+                        &mut in_memory,
+                        &config,
+                        &mut client,
+                    )
+                    .await
+                }
+                Command::XRead {
+                    streams,
+                    count,
+                    block: Some(timeout),
+                } if client.transaction.is_none() && !client.in_pubsub_mode() => {
+                    handle_xread_block(
+                        streams,
+                        *count,
+                        *timeout,
+                        &mut stream,
+                        &mut in_memory,
+                        &config,
+                        &mut client,
+                    )
+                    .await
                 }
+                _ => {
+                    let _shared = config.transaction_lock.read().await;
+                    started = Some(Instant::now());
+                    handle_command(&command, &mut stream, &mut in_memory, &config, &mut client)
+                        .await
+                }
+            };
+
+            if let (Some(started), false) = (
+                started,
+                matches!(command, Command::Error(_) | Command::Unknown { .. }),
+            ) {
+                config.slowlog.record(
+                    &args,
+                    started.elapsed(),
+                    &client.addr,
+                    client.name.as_deref(),
+                );
+            }
 
-                if last_command == Command::PSync {
+            config.clients.update(&client);
+
+            if let Some(response) = response {
+                if let Err(e) = stream.write_all(&response).await {
+                    eprintln!("Error sending response: {}", e);
                     break;
                 }
             }
-            Err(e) => {
-                eprintln!("Error reading stream: {}", e);
+
+            if matches!(command, Command::PSync { .. }) {
+                println!("Inserting stream onto replication manager.");
+                break;
+            }
+
+            if command == Command::Quit || client.is_killed() {
                 break;
             }
         }
+
+        if matches!(last_command, Some(Command::PSync { .. } | Command::Quit)) || client.is_killed()
+        {
+            break;
+        }
+
+        if let Some(error) = protocol_error {
+            if let Err(e) = stream.write_all(&RespType::Error(error).serialize()).await {
+                eprintln!("Error sending response: {}", e);
+            }
+            break;
+        }
     }
 
-    if last_command == Command::PSync {
-        config
+    client.unsubscribe_all(&config).await;
+    client.unwatch_all(&config);
+    config.monitors.remove(client.id);
+    config.clients.remove(client.id);
+
+    if let Some(synced) = client.replica_offset {
+        let mut replicas = config.replication_manager.replicas.write().await;
+        // Catch the replica up on writes propagated since PSYNC answered.
+        let end = config.replication_manager.offset.load(Ordering::SeqCst);
+        let missed = config
             .replication_manager
-            .replicas
-            .write()
-            .await
-            .push(stream);
-        println!("{config:?}");
+            .backlog
+            .lock()
+            .unwrap()
+            .since(synced, end);
+        match missed {
+            Some(missed) => match stream.write_all(&missed).await {
+                Ok(()) => replicas.push(Box::new(stream)),
+                Err(e) => eprintln!("Error catching up new replica: {}", e),
+            },
+            None => eprintln!("New replica fell behind the backlog; dropping it"),
+        }
+        drop(replicas);
+    }
+}
+
+// Saves the dataset if an RDB file is configured and closes the replica
+// streams. Client connections end when the runtime stops after this returns.
+async fn shutdown(config: &Config) {
+    if let Err(e) = save_rdb(config) {
+        eprintln!("Error saving RDB on shutdown: {}", e);
+    }
+
+    for replica in config.replication_manager.replicas.write().await.iter_mut() {
+        if let Err(e) = replica.shutdown().await {
+            eprintln!("Error closing replica connection: {}", e);
+        }
+    }
+
+    if let Some(path) = &config.unixsocket {
+        if let Err(e) = std::fs::remove_file(path) {
+            eprintln!("Error removing Unix socket {}: {}", path, e);
+        }
     }
 }
 
+// Serves connections on `listener`, and on `unix_listener` if given, until
+// `shutdown_signal` completes, then saves and returns. Kept apart from
+// argument parsing so a server can be started on any listener, such as one
+// bound to port 0. Fails when a replica can't complete the handshake with its
+// master.
+async fn run_server(
+    config: Arc<Config>,
+    listener: TcpListener,
+    unix_listener: Option<UnixListener>,
+    shutdown_signal: impl Future<Output = ()>,
+) -> Result<(), anyhow::Error> {
+    // A replica loads its own file first; the master's snapshot replaces it.
+    load_rdb_to_database(&config);
+
+    if config.role == Role::Slave {
+        handle_replica(Arc::clone(&config)).await?;
+    }
+
+    if config.role == Role::Master {
+        tokio::spawn(run_active_expiry(Arc::clone(&config)));
+    }
+
+    println!("Listening on {:?}", listener.local_addr()?.port());
+
+    tokio::pin!(shutdown_signal);
+    // Grows while accept keeps failing, e.g. when out of file descriptors,
+    // so the loop waits for connections to close instead of spinning.
+    let mut accept_backoff = Duration::ZERO;
+    loop {
+        let accepted = tokio::select! {
+            accepted = listener.accept() => accepted.map(|(stream, addr)| {
+                let laddr = stream
+                    .local_addr()
+                    .map(|addr| addr.to_string())
+                    .unwrap_or_default();
+                spawn_client(stream, addr.to_string(), laddr, &config);
+            }),
+            accepted = accept_unix(unix_listener.as_ref()) => accepted.map(|stream| {
+                // Like Redis, Unix clients show up as `<path>:0`.
+                let path = format!("{}:0", config.unixsocket.as_deref().unwrap_or_default());
+                spawn_client(stream, path.clone(), path, &config);
+            }),
+            _ = &mut shutdown_signal => {
+                shutdown(&config).await;
+                return Ok(());
+            }
+        };
+        match accepted {
+            Ok(()) => accept_backoff = Duration::ZERO,
+            Err(e) => {
+                accept_backoff = next_accept_backoff(accept_backoff);
+                eprintln!(
+                    "Error accepting connection: {}; retrying in {:?}",
+                    e, accept_backoff
+                );
+                tokio::time::sleep(accept_backoff).await;
+            }
+        };
+    }
+}
+
+// Waits for the next Unix socket client, or forever without a listener.
+async fn accept_unix(listener: Option<&UnixListener>) -> std::io::Result<UnixStream> {
+    match listener {
+        Some(listener) => listener.accept().await.map(|(stream, _)| stream),
+        None => std::future::pending().await,
+    }
+}
+
+// Runs `handle_client` in its own task, reporting it if it panics.
+fn spawn_client<S: Connection + 'static>(
+    stream: S,
+    addr: String,
+    laddr: String,
+    config: &Arc<Config>,
+) {
+    let config = Arc::clone(config);
+    tokio::spawn(async move {
+        let connection = tokio::spawn(handle_client(stream, addr, laddr, config));
+        if let Err(e) = connection.await {
+            if e.is_panic() {
+                eprintln!("Connection task panicked: {}", panic_message(e));
+            }
+        }
+    });
+}
+
+const ACCEPT_BACKOFF_MIN: Duration = Duration::from_millis(5);
+const ACCEPT_BACKOFF_MAX: Duration = Duration::from_secs(1);
+
+// How long to wait after another failed accept: twice the last wait, from
+// `ACCEPT_BACKOFF_MIN` after a success up to `ACCEPT_BACKOFF_MAX`.
+fn next_accept_backoff(backoff: Duration) -> Duration {
+    (backoff * 2).clamp(ACCEPT_BACKOFF_MIN, ACCEPT_BACKOFF_MAX)
+}
+
+fn panic_message(error: JoinError) -> String {
+    let payload = error.into_panic();
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "unknown cause".to_string()
+    }
+}
+
+// Binds like `TcpListener::bind` but with the listen backlog from
+// --tcp-backlog instead of the default.
+fn bind_listener(port: u32, backlog: u32) -> std::io::Result<TcpListener> {
+    let address = format!("127.0.0.1:{}", port)
+        .parse()
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+    let socket = TcpSocket::new_v4()?;
+    socket.set_reuseaddr(true)?;
+    socket.bind(address)?;
+    socket.listen(backlog)
+}
+
 #[tokio::main]
 async fn main() {
     let args = Args::parse();
 
-    let role = if args.replicaof.is_some() {
-        Role::Slave
-    } else {
-        Role::Master
-    };
+    if args.dir.is_some() != args.dbfilename.is_some() {
+        eprintln!("Error: Both --dir and --dbfilename must be provided together.");
+        std::process::exit(1);
+    }
 
-    let port = args.port.unwrap_or(6379);
+    if let Some(name) = args
+        .rename_command
+        .chunks(2)
+        .map(|pair| &pair[0])
+        .find(|name| !command::is_command(name))
+    {
+        eprintln!("Error: No such command in rename-command: {}", name);
+        std::process::exit(1);
+    }
 
-    let config: Config = match (&args.dir, &args.dbfilename) {
-        (Some(dir), Some(db_filename)) => Config::new(
-            Some(dir.clone()),
-            Some(db_filename.clone()),
-            role,
-            port,
-            args.replicaof.clone(),
-        ),
-        (Some(_), None) | (None, Some(_)) => {
-            eprintln!("Error: Both --dir and --dbfilename must be provided together.");
+    let config = Arc::new(Config::new(&args));
+    let listener = match bind_listener(config.port, config.tcp_backlog) {
+        Ok(listener) => listener,
+        Err(e) => {
+            eprintln!("Error: Could not listen on port {}: {}", config.port, e);
             std::process::exit(1);
         }
-        (None, None) => Config::new(None, None, role, port, args.replicaof.clone()),
     };
 
-    let config = Arc::new(config);
-    let in_memory: Arc<Mutex<Database>> = Arc::new(Mutex::new(Database::new(Arc::clone(&config))));
+    // A socket file left behind by an earlier run would make binding fail.
+    let unix_listener = match &config.unixsocket {
+        Some(path) => {
+            let _ = std::fs::remove_file(path);
+            match UnixListener::bind(path) {
+                Ok(listener) => Some(listener),
+                Err(e) => {
+                    eprintln!("Error: Could not listen on Unix socket {}: {}", path, e);
+                    std::process::exit(1);
+                }
+            }
+        }
+        None => None,
+    };
 
-    if config.role == Role::Slave && args.replicaof.is_some() {
-        let mut in_memory_cloned = Arc::clone(&in_memory);
-        let config_cloned = Arc::clone(&config);
-        if let Err(e) = handle_replica(&mut in_memory_cloned, config_cloned, &args).await {
-            eprintln!("Failed to establish replication connection: {}", e);
-            std::process::exit(1);
+    let shutdown_signal = async {
+        let _ = tokio::signal::ctrl_c().await;
+        println!("Received Ctrl-C, shutting down");
+    };
+    if let Err(e) = run_server(config, listener, unix_listener, shutdown_signal).await {
+        eprintln!("Failed to start server: {}", e);
+        std::process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::Arc,
+        time::{Duration, Instant},
+    };
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, UnixListener, UnixStream},
+        sync::oneshot,
+    };
+
+    use crate::{
+        load_rdb_to_database, next_accept_backoff, run_server,
+        testing::{encode_command, test_config, ReplicaPair, TempDir, TestClient},
+        Config,
+    };
+
+    #[tokio::test]
+    async fn answers_ping_over_any_stream() {
+        let config = test_config(&[]);
+        let mut client = TestClient::connect(&config);
+        assert_eq!(client.command(&["PING"]).await, b"+PONG\r\n");
+        client.send(b"PING hello\r\n").await;
+        assert_eq!(client.reply().await, b"$5\r\nhello\r\n");
+    }
+
+    #[tokio::test]
+    async fn command_split_across_reads_runs_once_complete() {
+        let config = test_config(&[]);
+        let mut client = TestClient::connect(&config);
+        let value = "v".repeat(100_000);
+        let frame = encode_command(&["SET", "key", &value]);
+        let (head, tail) = frame.split_at(frame.len() / 2);
+
+        client.send(head).await;
+        tokio::task::yield_now().await;
+        client.send(tail).await;
+        assert_eq!(client.reply().await, b"+OK\r\n");
+        assert_eq!(
+            client.command(&["GETRANGE", "key", "99998", "-1"]).await,
+            b"$2\r\nvv\r\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn malformed_request_replies_protocol_error_and_closes() {
+        let config = test_config(&[]);
+        let mut client = TestClient::connect(&config);
+        client.send(b"PING\r\n*1\r\n$x\r\nPING\r\n").await;
+        assert_eq!(client.reply().await, b"+PONG\r\n");
+        assert_eq!(
+            client.reply().await,
+            b"-ERR Protocol error: invalid bulk length\r\n"
+        );
+        assert!(client.is_closed().await);
+    }
+
+    #[tokio::test]
+    async fn long_pipeline_yields_to_other_connections() {
+        // Few enough to arrive in one read and to stay inside tokio's
+        // cooperative budget, so only the depth limit makes the connection
+        // yield.
+        const COMMANDS: usize = 100;
+        let config = test_config(&["--pipeline-depth", "10"]);
+        let mut pipeliner = TestClient::connect(&config);
+        let mut other = TestClient::connect(&config);
+
+        pipeliner
+            .send(&encode_command(&["APPEND", "counter", "x"]).repeat(COMMANDS))
+            .await;
+        assert_eq!(pipeliner.reply().await, b":1\r\n");
+
+        // The other connection gets a turn while the pipeline is still
+        // being worked through.
+        let seen = other.command(&["GET", "counter"]).await;
+        let appended = seen.iter().filter(|&&byte| byte == b'x').count();
+        assert!(appended < COMMANDS, "pipeline ran to completion first");
+
+        for count in 2..=COMMANDS {
+            assert_eq!(
+                pipeliner.reply().await,
+                format!(":{}\r\n", count).as_bytes()
+            );
         }
     }
 
-    load_rdb_to_database(Arc::clone(&in_memory));
+    #[tokio::test]
+    async fn writes_on_the_master_reach_the_replica() {
+        let pair = ReplicaPair::start().await;
 
-    let listener = TcpListener::bind(format!("127.0.0.1:{}", port))
-        .await
-        .unwrap();
+        assert_eq!(pair.on_master(&["SET", "key", "value"]).await, b"+OK\r\n");
+        pair.assert_on_replica(&["GET", "key"], b"$5\r\nvalue\r\n")
+            .await;
 
-    println!("Listening on {:?}", port);
+        pair.on_master(&["ZADD", "zset", "1", "a", "2", "b"]).await;
+        pair.on_master(&["ZRANGESTORE", "top", "zset", "1", "1"])
+            .await;
+        pair.assert_on_replica(&["ZRANGE", "top", "0", "-1"], b"*1\r\n$1\r\nb\r\n")
+            .await;
+    }
 
-    loop {
-        let stream = listener.accept().await;
-        match stream {
-            Ok((stream, _)) => {
-                let mut in_memory_cloned = Arc::clone(&in_memory);
-                let config_cloned = Arc::clone(&config);
-                tokio::spawn(async move {
-                    println!("Current config: {:?}", config_cloned.clone());
-                    handle_client(stream, &mut in_memory_cloned, config_cloned).await;
-                });
-            }
-            Err(e) => {
-                println!("error: {}", e);
-            }
+    #[tokio::test]
+    async fn wait_times_out_with_fewer_acknowledging_replicas() {
+        let pair = ReplicaPair::start().await;
+        let mut client = TestClient::connect(&pair.master);
+        client.command(&["SET", "key", "value"]).await;
+
+        let started = Instant::now();
+        assert_eq!(client.command(&["WAIT", "2", "200"]).await, b":1\r\n");
+        assert!(started.elapsed() >= Duration::from_millis(200));
+
+        // A master no replica ever connected to waits out the timeout too.
+        let config = test_config(&[]);
+        let mut lone = TestClient::connect(&config);
+        let started = Instant::now();
+        assert_eq!(lone.command(&["WAIT", "2", "200"]).await, b":0\r\n");
+        assert!(started.elapsed() >= Duration::from_millis(200));
+    }
+
+    #[tokio::test]
+    async fn shutdown_saves_a_snapshot_that_loads_back() {
+        let dir = TempDir::new();
+        let args = ["--dir", dir.path(), "--dbfilename", "dump.rdb"];
+        let config = test_config(&args);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let (stop, stopped) = oneshot::channel::<()>();
+        let server = tokio::spawn(run_server(Arc::clone(&config), listener, None, async {
+            let _ = stopped.await;
+        }));
+
+        let mut client = TestClient::connect(&config);
+        client.command(&["SET", "key", "value"]).await;
+        client.command(&["SET", "volatile", "v", "EX", "100"]).await;
+        client.command(&["RPUSH", "list", "a", "b"]).await;
+        client.command(&["SELECT", "3"]).await;
+        client.command(&["SET", "other", "db"]).await;
+
+        stop.send(()).unwrap();
+        server.await.unwrap().unwrap();
+        assert!(std::path::Path::new(dir.path()).join("dump.rdb").exists());
+
+        let reloaded = test_config(&args);
+        load_rdb_to_database(&reloaded);
+        let mut client = TestClient::connect(&reloaded);
+        assert_eq!(client.command(&["GET", "key"]).await, b"$5\r\nvalue\r\n");
+        let ttl = client.command(&["TTL", "volatile"]).await;
+        assert!(ttl == b":100\r\n" || ttl == b":99\r\n", "{ttl:?}");
+        assert_eq!(
+            client.command(&["LRANGE", "list", "0", "-1"]).await,
+            b"*2\r\n$1\r\na\r\n$1\r\nb\r\n"
+        );
+        client.command(&["SELECT", "3"]).await;
+        assert_eq!(client.command(&["GET", "other"]).await, b"$2\r\ndb\r\n");
+    }
+
+    #[tokio::test]
+    async fn a_new_replica_loads_the_master_s_keys_from_the_snapshot() {
+        let pair = ReplicaPair::start_with(&[
+            &["SET", "key", "value"],
+            &["RPUSH", "list", "a", "b"],
+            &["ZADD", "zset", "1", "member"],
+        ])
+        .await;
+
+        // These were written before the replica connected, so they can only
+        // have reached it through the snapshot.
+        pair.assert_on_replica(&["GET", "key"], b"$5\r\nvalue\r\n")
+            .await;
+        pair.assert_on_replica(
+            &["LRANGE", "list", "0", "-1"],
+            b"*2\r\n$1\r\na\r\n$1\r\nb\r\n",
+        )
+        .await;
+        pair.assert_on_replica(&["ZSCORE", "zset", "member"], b"$1\r\n1\r\n")
+            .await;
+    }
+
+    #[tokio::test]
+    async fn expired_keys_nobody_reads_are_swept_and_deleted_on_replicas() {
+        let pair = ReplicaPair::start().await;
+        pair.on_master(&["SET", "short", "v", "PX", "30"]).await;
+        pair.on_master(&["SET", "long", "v", "EX", "100"]).await;
+        // Applied in order, so the replica has seen the short-lived key too.
+        pair.assert_on_replica(&["GET", "long"], b"$1\r\nv\r\n")
+            .await;
+        let stored = |config: &Config, key: &[u8]| {
+            config.databases[0]
+                .read()
+                .unwrap()
+                .storage
+                .contains_key(key)
         };
+        // Replicas never expire keys themselves, so the key only leaves
+        // the replica's storage once the master's DEL arrives.
+        for _ in 0..500 {
+            if !stored(&pair.master, b"short") && !stored(&pair.replica, b"short") {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert!(!stored(&pair.master, b"short"), "never swept");
+        assert!(!stored(&pair.replica, b"short"), "DEL never propagated");
+        assert!(stored(&pair.master, b"long") && stored(&pair.replica, b"long"));
+    }
+
+    #[tokio::test]
+    async fn serves_clients_over_the_unix_socket() {
+        let dir = TempDir::new();
+        let path = format!("{}/redis.sock", dir.path());
+        let config = test_config(&["--unixsocket", &path]);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let unix_listener = UnixListener::bind(&path).unwrap();
+        let (stop, stopped) = oneshot::channel::<()>();
+        let server = tokio::spawn(run_server(
+            Arc::clone(&config),
+            listener,
+            Some(unix_listener),
+            async {
+                let _ = stopped.await;
+            },
+        ));
+
+        let mut stream = UnixStream::connect(&path).await.unwrap();
+        let mut buffer = [0; 1024];
+        stream
+            .write_all(&encode_command(&["SET", "key", "value"]))
+            .await
+            .unwrap();
+        let n = stream.read(&mut buffer).await.unwrap();
+        assert_eq!(&buffer[..n], b"+OK\r\n");
+
+        // Unix clients are listed under the socket path.
+        stream
+            .write_all(&encode_command(&["CLIENT", "INFO"]))
+            .await
+            .unwrap();
+        let n = stream.read(&mut buffer).await.unwrap();
+        let info = String::from_utf8_lossy(&buffer[..n]);
+        assert!(info.contains(&format!("addr={path}:0")), "{info}");
+
+        let mut client = TestClient::connect(&config);
+        assert_eq!(client.command(&["GET", "key"]).await, b"$5\r\nvalue\r\n");
+
+        stop.send(()).unwrap();
+        server.await.unwrap().unwrap();
+        assert!(!std::path::Path::new(&path).exists());
+    }
+
+    #[test]
+    fn accept_backoff_doubles_from_the_minimum_up_to_the_cap() {
+        let mut backoff = Duration::ZERO;
+        let mut waits = Vec::new();
+        for _ in 0..10 {
+            backoff = next_accept_backoff(backoff);
+            waits.push(backoff.as_millis());
+        }
+        assert_eq!(waits, [5, 10, 20, 40, 80, 160, 320, 640, 1000, 1000]);
+    }
+
+    #[tokio::test]
+    async fn renamed_commands_answer_only_to_their_new_names() {
+        let config = test_config(&[
+            "--rename-command",
+            "get",
+            "fetch",
+            "--rename-command",
+            "flushall",
+            "",
+        ]);
+        let mut client = TestClient::connect(&config);
+
+        client.command(&["SET", "key", "value"]).await;
+        assert_eq!(client.command(&["FETCH", "key"]).await, b"$5\r\nvalue\r\n");
+        assert_eq!(client.command(&["fetch", "key"]).await, b"$5\r\nvalue\r\n");
+        assert_eq!(
+            client.command(&["GET", "key"]).await,
+            b"-ERR unknown command 'GET', with args beginning with: 'key' \r\n"
+        );
+        assert_eq!(
+            client.command(&["FLUSHALL"]).await,
+            b"-ERR unknown command 'FLUSHALL', with args beginning with: \r\n"
+        );
+        assert_eq!(client.command(&["FETCH", "key"]).await, b"$5\r\nvalue\r\n");
     }
 }
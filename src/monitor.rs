@@ -0,0 +1,76 @@
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::{pubsub::Subscriber, resp::RespType};
+
+// Connections that ran MONITOR, by client ID. Each is sent a line for every
+// command any connection runs, through the same queue pub/sub messages
+// reach it by.
+#[derive(Debug, Default)]
+pub struct Monitors {
+    senders: Mutex<HashMap<u64, Subscriber>>,
+}
+
+impl Monitors {
+    pub fn add(&self, id: u64, sender: &Subscriber) {
+        self.senders.lock().unwrap().insert(id, sender.clone());
+    }
+
+    pub fn remove(&self, id: u64) {
+        self.senders.lock().unwrap().remove(&id);
+    }
+
+    // Sends `+<unix time> [<db> <addr>] "arg" "arg" ...` to every monitor.
+    pub fn feed(&self, db: usize, addr: &str, args: &[Vec<u8>]) {
+        let senders = self.senders.lock().unwrap();
+        if senders.is_empty() {
+            return;
+        }
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("Time went backwards");
+        let mut line = format!(
+            "{}.{:06} [{} {}]",
+            now.as_secs(),
+            now.subsec_micros(),
+            db,
+            addr
+        );
+        for arg in args {
+            line.push(' ');
+            line.push_str(&quote(arg));
+        }
+
+        for sender in senders.values() {
+            // A monitor that went away is removed when its connection ends.
+            let _ = sender.send(RespType::SimpleString(line.clone()));
+        }
+    }
+}
+
+// Quotes an argument like Redis's `sdscatrepr`, escaping quotes, backslashes
+// and anything unprintable so the line stays on one line.
+fn quote(arg: &[u8]) -> String {
+    let mut quoted = String::from("\"");
+    for &byte in arg {
+        match byte {
+            b'\\' | b'"' => {
+                quoted.push('\\');
+                quoted.push(byte as char);
+            }
+            b'\n' => quoted.push_str("\\n"),
+            b'\r' => quoted.push_str("\\r"),
+            b'\t' => quoted.push_str("\\t"),
+            7 => quoted.push_str("\\a"),
+            8 => quoted.push_str("\\b"),
+            b' '..=b'~' => quoted.push(byte as char),
+            _ => quoted.push_str(&format!("\\x{:02x}", byte)),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
@@ -0,0 +1,129 @@
+use std::{collections::HashMap, sync::Arc};
+
+use tokio::sync::{mpsc::UnboundedSender, RwLock};
+
+use crate::{glob::glob_match, resp::RespType};
+
+// Sending half of a connection's queue of frames to deliver. The connection
+// serializes each for the protocol it negotiated.
+pub type Subscriber = UnboundedSender<RespType>;
+
+type Subscribers = HashMap<String, Vec<Subscriber>>;
+
+// Channel name -> senders of every connection subscribed to it, and the same
+// for glob patterns (PSUBSCRIBE). Each connection owns the receiving half and
+// writes what it gets to its socket. `message_kind` is the first element of
+// delivered frames: `message` for regular channels, `smessage` for shard
+// channels, which have no patterns.
+#[derive(Debug, Clone)]
+pub struct PubSub {
+    channels: Arc<RwLock<Subscribers>>,
+    patterns: Arc<RwLock<Subscribers>>,
+    message_kind: &'static str,
+}
+
+impl PubSub {
+    pub fn new(message_kind: &'static str) -> Self {
+        Self {
+            channels: Arc::new(RwLock::new(HashMap::new())),
+            patterns: Arc::new(RwLock::new(HashMap::new())),
+            message_kind,
+        }
+    }
+
+    pub async fn subscribe(&self, channel: &str, sender: &Subscriber) {
+        add_subscriber(&mut *self.channels.write().await, channel, sender);
+    }
+
+    pub async fn unsubscribe(&self, channel: &str, sender: &Subscriber) {
+        remove_subscriber(&mut *self.channels.write().await, channel, sender);
+    }
+
+    pub async fn psubscribe(&self, pattern: &str, sender: &Subscriber) {
+        add_subscriber(&mut *self.patterns.write().await, pattern, sender);
+    }
+
+    pub async fn punsubscribe(&self, pattern: &str, sender: &Subscriber) {
+        remove_subscriber(&mut *self.patterns.write().await, pattern, sender);
+    }
+
+    // Channels with at least one subscriber, optionally only those matching
+    // a glob pattern, for PUBSUB CHANNELS.
+    pub async fn channels(&self, pattern: Option<&str>) -> Vec<String> {
+        self.channels
+            .read()
+            .await
+            .keys()
+            .filter(|channel| {
+                pattern.is_none_or(|pattern| glob_match(pattern.as_bytes(), channel.as_bytes()))
+            })
+            .cloned()
+            .collect()
+    }
+
+    // How many connections subscribe to `channel`, not counting patterns.
+    pub async fn subscriber_count(&self, channel: &str) -> usize {
+        self.channels.read().await.get(channel).map_or(0, Vec::len)
+    }
+
+    // How many distinct patterns have subscribers.
+    pub async fn pattern_count(&self) -> usize {
+        self.patterns.read().await.len()
+    }
+
+    // Delivers `message` to every subscriber of `channel`, then as a
+    // `pmessage` to every subscriber of a pattern matching it, and returns
+    // how many deliveries were made: a connection subscribed both ways
+    // counts twice. Subscribers whose connection is gone are dropped.
+    pub async fn publish(&self, channel: &str, message: &str) -> usize {
+        let mut received = 0;
+
+        let mut channels = self.channels.write().await;
+        if let Some(subscribers) = channels.get_mut(channel) {
+            let frame = RespType::Push(vec![
+                RespType::BulkString(self.message_kind.to_string()),
+                RespType::BulkString(channel.to_string()),
+                RespType::BulkString(message.to_string()),
+            ]);
+            subscribers.retain(|s| s.send(frame.clone()).is_ok());
+            received += subscribers.len();
+            if subscribers.is_empty() {
+                channels.remove(channel);
+            }
+        }
+        drop(channels);
+
+        let mut patterns = self.patterns.write().await;
+        patterns.retain(|pattern, subscribers| {
+            if !glob_match(pattern.as_bytes(), channel.as_bytes()) {
+                return true;
+            }
+            let frame = RespType::Push(vec![
+                RespType::BulkString("pmessage".to_string()),
+                RespType::BulkString(pattern.clone()),
+                RespType::BulkString(channel.to_string()),
+                RespType::BulkString(message.to_string()),
+            ]);
+            subscribers.retain(|s| s.send(frame.clone()).is_ok());
+            received += subscribers.len();
+            !subscribers.is_empty()
+        });
+        received
+    }
+}
+
+fn add_subscriber(subscribers: &mut Subscribers, name: &str, sender: &Subscriber) {
+    let senders = subscribers.entry(name.to_string()).or_default();
+    if !senders.iter().any(|s| s.same_channel(sender)) {
+        senders.push(sender.clone());
+    }
+}
+
+fn remove_subscriber(subscribers: &mut Subscribers, name: &str, sender: &Subscriber) {
+    if let Some(senders) = subscribers.get_mut(name) {
+        senders.retain(|s| !s.same_channel(sender));
+        if senders.is_empty() {
+            subscribers.remove(name);
+        }
+    }
+}
@@ -1,161 +1,620 @@
-use std::fs::File;
-use std::io::{BufReader, Cursor, Read};
-use std::sync::{Arc, Mutex};
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::fs::{self, File};
+use std::io::{self, BufReader, Cursor, Read};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
 
-use byteorder::{LittleEndian, ReadBytesExt};
+use byteorder::{BigEndian, LittleEndian, ReadBytesExt, WriteBytesExt};
 
+use crate::database::{unix_time_ms, Value};
+use crate::error::RedisError;
+use crate::listpack::{self, Element};
+use crate::stream::{Fields, Stream, StreamId};
+use crate::zset::SortedSet;
+use crate::Config;
 use crate::Database;
 use crate::Item;
+use crate::REDIS_VERSION;
 
-pub fn load_rdb_to_database(in_memory: Arc<Mutex<Database>>) {
-    let mut db = in_memory.lock().unwrap();
-
-    if db.config.dir == None || db.config.dbfilename == None {
+pub fn load_rdb_to_database(config: &Config) {
+    let (Some(mut path), Some(file_name)) = (config.dir(), config.dbfilename()) else {
         return;
-    }
+    };
 
-    let mut path = db
-        .config
-        .dir
-        .clone()
-        .expect("Couldn't retrieve path of file");
-    let file_name = db.config.dbfilename.clone().unwrap();
-
-    path.push_str("/");
+    path.push('/');
     path.push_str(&file_name);
 
     let file = File::open(path);
     match file {
         Ok(file) => {
-            let mut file_buffer: [u8; 1024] = [0; 1024];
+            let mut file_buffer: Vec<u8> = Vec::new();
             let mut reader = BufReader::new(file);
-            reader.read(&mut file_buffer).unwrap();
-
-            let mut hash_table_size;
-            let mut _expire_table_size;
-            let mut buffer_iterator = file_buffer.iter();
-
-            while let Some(&control) = buffer_iterator.next() {
-                match control {
-                    0xFB => {
-                        let (table_size, expiry_size) = process_hash_table(&mut buffer_iterator);
-                        hash_table_size = table_size;
-                        _expire_table_size = expiry_size;
-                        println!(
-                            "Hash table size: {:?}, Hash expiry size: {:?}",
-                            hash_table_size, _expire_table_size
-                        );
-
-                        parse_hash_table(&mut buffer_iterator, hash_table_size, &mut db);
-                    }
-                    _ => {}
-                }
-            }
+            reader.read_to_end(&mut file_buffer).unwrap();
 
-            //let size_hash_table = *buffer_iterator.next().unwrap() as usize;
-            //let _size_expire_hash_table = buffer_iterator.next();
-            //for _ in 0..size_hash_table {
-            //    let _value_type = buffer_iterator.next();
-            //    let key_len = *buffer_iterator.next().unwrap() as usize;
-            //    let mut key_chars = Vec::with_capacity(key_len);
-            //    for _ in 0..key_len {
-            //        if let Some(&byte) = buffer_iterator.next() {
-            //            key_chars.push(byte)
-            //        } else {
-            //            break;
-            //        }
-            //    }
-            //
-            //    let value_len = *buffer_iterator.next().unwrap() as usize;
-            //    let mut value_chars = Vec::with_capacity(value_len);
-            //    for _ in 0..value_len {
-            //        if let Some(&byte) = buffer_iterator.next() {
-            //            value_chars.push(byte)
-            //        } else {
-            //            break;
-            //        }
-            //    }
-            //
-            //    let key_string = String::from_utf8_lossy(&key_chars).to_string();
-            //    let value_string = String::from_utf8_lossy(&value_chars).to_string();
-            //
-            //    let new_item = Item::new(value_string.clone(), None);
-            //
-            //    db.storage.insert(key_string.clone(), new_item);
+            if apply_rdb(&file_buffer, &config.databases).is_none() {
+                eprintln!("RDB file is truncated or uses an unsupported encoding.");
+            }
         }
         Err(_) => eprintln!("Couldn't find rdb file."),
     }
 }
 
-fn process_hash_table<'a, I>(buffer_iterator: &mut I) -> (usize, usize)
+// Loads string, list, hash, sorted set and stream keys from an RDB file into
+// the numbered databases, walking its opcodes in order. Returns `None` on anything it
+// can't read, keeping whatever was loaded before that point.
+fn apply_rdb(bytes: &[u8], databases: &[Arc<RwLock<Database>>]) -> Option<()> {
+    let mut buffer_iterator = bytes.strip_prefix(b"REDIS")?.get(4..)?.iter();
+    let mut db_index = 0;
+    let mut expiry: Option<u128> = None;
+
+    loop {
+        match *buffer_iterator.next()? {
+            // Auxiliary field such as redis-ver: nothing to keep.
+            0xFA => {
+                read_string(&mut buffer_iterator)?;
+                read_string(&mut buffer_iterator)?;
+            }
+            0xFE => db_index = read_length(&mut buffer_iterator)?,
+            // Hash table size hints.
+            0xFB => {
+                read_length(&mut buffer_iterator)?;
+                read_length(&mut buffer_iterator)?;
+            }
+            0xFC => {
+                let expiry_bytes: Vec<u8> = buffer_iterator.by_ref().take(8).copied().collect();
+                let mut cursor = Cursor::new(expiry_bytes);
+                expiry = Some(cursor.read_u64::<LittleEndian>().ok()? as u128);
+            }
+            0xFD => {
+                let expiry_bytes: Vec<u8> = buffer_iterator.by_ref().take(4).copied().collect();
+                let mut cursor = Cursor::new(expiry_bytes);
+                expiry = Some(cursor.read_u32::<LittleEndian>().ok()? as u128 * 1000);
+            }
+            0xFF => return Some(()),
+            // A string, list, hash, sorted set or stream value; other types
+            // aren't supported.
+            value_type @ (0x00 | 0x01 | 0x04 | 0x05 | 0x0F | 0x13 | 0x15) => {
+                let key = read_string(&mut buffer_iterator)?;
+                let value = read_value(value_type, &mut buffer_iterator)?;
+                let ttl = match expiry.take().map(remaining_ttl) {
+                    Some(None) => continue,
+                    Some(ttl) => ttl,
+                    None => None,
+                };
+                if let Some(in_memory) = databases.get(db_index) {
+                    in_memory
+                        .write()
+                        .unwrap()
+                        .insert(key, Item::new(value, ttl));
+                }
+            }
+            _ => return None,
+        }
+    }
+}
+
+// Replaces the whole dataset with an RDB snapshot, as a replica does with
+// the one its master sends on a full resync.
+pub fn replace_with_rdb(bytes: &[u8], databases: &[Arc<RwLock<Database>>]) -> Option<()> {
+    for in_memory in databases {
+        in_memory.write().unwrap().clear();
+    }
+    apply_rdb(bytes, databases)
+}
+
+// Converts an absolute Unix expiry into the TTL left from now, or `None`
+// when the key already expired and shouldn't be loaded at all.
+fn remaining_ttl(expiry_timestamp_ms: u128) -> Option<Duration> {
+    let now_ms = unix_time_ms();
+    if expiry_timestamp_ms <= now_ms {
+        return None;
+    }
+    Some(Duration::from_millis((expiry_timestamp_ms - now_ms) as u64))
+}
+
+// Reads a length in RDB's variable-size encoding: 6 bits, 14 bits, or a
+// 32 or 64-bit big-endian number, picked by the first byte.
+fn read_length<'a, I>(buffer_iterator: &mut I) -> Option<usize>
 where
     I: Iterator<Item = &'a u8>,
 {
-    let size_hash_table = *buffer_iterator.next().unwrap() as usize;
-    let size_expire_hash_table = *buffer_iterator.next().unwrap() as usize;
+    let first = *buffer_iterator.next()?;
+    let length = match first >> 6 {
+        0 => (first & 0x3F) as usize,
+        1 => ((first & 0x3F) as usize) << 8 | *buffer_iterator.next()? as usize,
+        _ => {
+            let size = match first {
+                0x80 => 4,
+                0x81 => 8,
+                _ => return None,
+            };
+            let bytes: Vec<u8> = buffer_iterator.take(size).copied().collect();
+            let mut cursor = Cursor::new(bytes);
+            if size == 4 {
+                cursor.read_u32::<BigEndian>().ok()? as usize
+            } else {
+                cursor.read_u64::<BigEndian>().ok()? as usize
+            }
+        }
+    };
+    Some(length)
+}
 
-    (size_hash_table, size_expire_hash_table)
+// Reads a string, which Redis may have stored as an 8, 16 or 32-bit integer.
+// LZF-compressed strings aren't supported.
+fn read_string<'a, I>(buffer_iterator: &mut I) -> Option<Vec<u8>>
+where
+    I: Iterator<Item = &'a u8>,
+{
+    let mut peek = buffer_iterator.by_ref().peekable();
+    let integer_size = match peek.peek() {
+        Some(0xC0) => Some(1),
+        Some(0xC1) => Some(2),
+        Some(0xC2) => Some(4),
+        _ => None,
+    };
+    let Some(size) = integer_size else {
+        let len = read_length(&mut peek)?;
+        let bytes: Vec<u8> = peek.take(len).copied().collect();
+        return (bytes.len() == len).then_some(bytes);
+    };
+    peek.next();
+    let bytes: Vec<u8> = peek.take(size).copied().collect();
+    let mut cursor = Cursor::new(bytes);
+    let value = match size {
+        1 => cursor.read_i8().ok()? as i64,
+        2 => cursor.read_i16::<LittleEndian>().ok()? as i64,
+        _ => cursor.read_i32::<LittleEndian>().ok()? as i64,
+    };
+    Some(value.to_string().into_bytes())
 }
 
-fn parse_hash_table<'a, I>(buffer_iterator: &mut I, keys_size: usize, db: &mut Database)
+// Reads a value of the given RDB type: a string (0), a list stored as its
+// length followed by each element (1), a hash stored as its length followed
+// by each field and value (4), a sorted set stored as its length followed by
+// each member and its score as a little-endian double (5), or a stream (15,
+// or 19 and 21 from newer Redis versions).
+fn read_value<'a, I>(value_type: u8, buffer_iterator: &mut I) -> Option<Value>
 where
     I: Iterator<Item = &'a u8>,
 {
-    for _ in 0..keys_size {
-        let value_type = buffer_iterator.next().unwrap();
-        let expiry: Option<u128>;
-        match value_type {
-            0xFC => {
-                let expiry_bytes: Vec<u8> = buffer_iterator.take(8).copied().collect();
-                println!("{expiry_bytes:2x?}");
-                let mut cursor = Cursor::new(expiry_bytes);
-                expiry = Some(cursor.read_u64::<LittleEndian>().ok().unwrap() as u128);
-                let _ = buffer_iterator.next();
-            }
-            _ => {
-                expiry = None;
+    match value_type {
+        0x00 => read_string(buffer_iterator).map(Value::String),
+        0x01 => {
+            let len = read_length(buffer_iterator)?;
+            let list = (0..len)
+                .map(|_| read_string(buffer_iterator))
+                .collect::<Option<_>>()?;
+            Some(Value::List(list))
+        }
+        0x04 => {
+            let len = read_length(buffer_iterator)?;
+            let hash = (0..len)
+                .map(|_| Some((read_string(buffer_iterator)?, read_string(buffer_iterator)?)))
+                .collect::<Option<_>>()?;
+            Some(Value::Hash(hash))
+        }
+        0x05 => {
+            let len = read_length(buffer_iterator)?;
+            let mut zset = SortedSet::default();
+            for _ in 0..len {
+                let member = read_string(buffer_iterator)?;
+                let score_bytes: Vec<u8> = buffer_iterator.by_ref().take(8).copied().collect();
+                let score = Cursor::new(score_bytes).read_f64::<LittleEndian>().ok()?;
+                if score.is_nan() {
+                    return None;
+                }
+                zset.insert(&member, score);
             }
+            Some(Value::ZSet(zset))
         }
+        0x0F | 0x13 | 0x15 => read_stream(value_type, buffer_iterator).map(Value::Stream),
+        _ => None,
+    }
+}
 
-        let key_len = *buffer_iterator.next().unwrap() as usize;
-        let mut key_chars = Vec::with_capacity(key_len);
+// Reads a stream: its listpack nodes, each keyed by the 16-byte big-endian
+// ID its entries are stored relative to, then the stream's length, last ID
+// and consumer groups. Types 19 and 21 add a few counters before the groups.
+// Consumer groups aren't supported.
+fn read_stream<'a, I>(value_type: u8, buffer_iterator: &mut I) -> Option<Stream>
+where
+    I: Iterator<Item = &'a u8>,
+{
+    let mut stream = Stream::default();
+    let nodes = read_length(buffer_iterator)?;
+    for _ in 0..nodes {
+        let master = read_string(buffer_iterator)?;
+        let mut master = Cursor::new(master);
+        let master = StreamId {
+            ms: master.read_u64::<BigEndian>().ok()?,
+            seq: master.read_u64::<BigEndian>().ok()?,
+        };
+        let elements = listpack::decode(&read_string(buffer_iterator)?)?;
+        read_stream_node(&mut stream, master, elements)?;
+    }
 
-        for _ in 0..key_len {
-            if let Some(&byte) = buffer_iterator.next() {
-                key_chars.push(byte)
-            } else {
-                break;
+    read_length(buffer_iterator)?;
+    stream.last_id = StreamId {
+        ms: read_length(buffer_iterator)? as u64,
+        seq: read_length(buffer_iterator)? as u64,
+    };
+    if value_type >= 0x13 {
+        // First ID, maximal deleted ID and entries ever added.
+        for _ in 0..5 {
+            read_length(buffer_iterator)?;
+        }
+    }
+    let groups = read_length(buffer_iterator)?;
+    (groups == 0).then_some(stream)
+}
+
+// Entry flags in a stream listpack.
+const STREAM_ITEM_DELETED: i64 = 1;
+const STREAM_ITEM_SAME_FIELDS: i64 = 2;
+
+// Reads one listpack node: a master entry (live and deleted counts, then
+// the field names entries can share), then each entry as its flags, ID
+// relative to `master`, fields and values, and an element count used for
+// walking backwards.
+fn read_stream_node(stream: &mut Stream, master: StreamId, elements: Vec<Element>) -> Option<()> {
+    let mut elements = elements.into_iter();
+    let mut next_int = || elements.next()?.as_int();
+    let count = next_int()? + next_int()?;
+    let master_fields = (0..next_int()?)
+        .map(|_| Some(elements.next()?.into_bytes()))
+        .collect::<Option<Vec<_>>>()?;
+    elements.next()?;
+
+    for _ in 0..count {
+        let mut next_int = || elements.next()?.as_int();
+        let flags = next_int()?;
+        let id = StreamId {
+            ms: master.ms.checked_add(next_int()? as u64)?,
+            seq: master.seq.wrapping_add(next_int()? as u64),
+        };
+        let fields: Fields = if flags & STREAM_ITEM_SAME_FIELDS != 0 {
+            master_fields
+                .iter()
+                .map(|field| Some((field.clone(), elements.next()?.into_bytes())))
+                .collect::<Option<_>>()?
+        } else {
+            (0..next_int()?)
+                .map(|_| Some((elements.next()?.into_bytes(), elements.next()?.into_bytes())))
+                .collect::<Option<_>>()?
+        };
+        elements.next()?;
+        if flags & STREAM_ITEM_DELETED == 0 {
+            stream.insert(id, fields);
+        }
+    }
+    Some(())
+}
+
+fn write_length(out: &mut Vec<u8>, len: usize) {
+    if len < 1 << 6 {
+        out.push(len as u8);
+    } else if len < 1 << 14 {
+        out.push(0x40 | (len >> 8) as u8);
+        out.push(len as u8);
+    } else if len <= u32::MAX as usize {
+        out.push(0x80);
+        out.write_u32::<BigEndian>(len as u32).unwrap();
+    } else {
+        out.push(0x81);
+        out.write_u64::<BigEndian>(len as u64).unwrap();
+    }
+}
+
+// Like Redis, strings holding a small canonical integer are stored as that
+// integer rather than as text.
+fn write_string(out: &mut Vec<u8>, bytes: &[u8]) {
+    let integer = std::str::from_utf8(bytes)
+        .ok()
+        .filter(|text| text.len() <= 11)
+        .and_then(|text| text.parse::<i32>().ok().filter(|n| n.to_string() == text));
+    match integer {
+        Some(n) if i8::try_from(n).is_ok() => out.extend([0xC0, n as i8 as u8]),
+        Some(n) if i16::try_from(n).is_ok() => {
+            out.push(0xC1);
+            out.write_i16::<LittleEndian>(n as i16).unwrap();
+        }
+        Some(n) => {
+            out.push(0xC2);
+            out.write_i32::<LittleEndian>(n).unwrap();
+        }
+        None => {
+            write_length(out, bytes.len());
+            out.extend_from_slice(bytes);
+        }
+    }
+}
+
+// The RDB type byte written before a value's key.
+fn value_type(value: &Value) -> u8 {
+    match value {
+        Value::String(_) => 0x00,
+        Value::List(_) => 0x01,
+        Value::Hash(_) => 0x04,
+        Value::ZSet(_) => 0x05,
+        Value::Stream(_) => 0x0F,
+    }
+}
+
+// Writes a value's contents in the layout `read_value` expects.
+fn write_value(out: &mut Vec<u8>, value: &Value) {
+    match value {
+        Value::String(bytes) => write_string(out, bytes),
+        Value::List(list) => {
+            write_length(out, list.len());
+            for element in list {
+                write_string(out, element);
+            }
+        }
+        Value::ZSet(zset) => {
+            write_length(out, zset.len());
+            for (member, score) in zset.iter() {
+                write_string(out, member);
+                out.write_f64::<LittleEndian>(score).unwrap();
             }
         }
+        Value::Hash(hash) => {
+            write_length(out, hash.len());
+            for (field, value) in hash {
+                write_string(out, field);
+                write_string(out, value);
+            }
+        }
+        Value::Stream(stream) => write_stream(out, stream),
+    }
+}
 
-        let value_len = *buffer_iterator.next().unwrap() as usize;
-        let mut value_chars = Vec::with_capacity(value_len);
-        for _ in 0..value_len {
-            if let Some(&byte) = buffer_iterator.next() {
-                value_chars.push(byte)
-            } else {
-                break;
+// Entries per listpack node, Redis's default `stream-node-max-entries`.
+const STREAM_NODE_ENTRIES: usize = 100;
+
+// Writes a stream in the layout `read_stream` expects. Each node's master
+// entry takes the field names of its first entry, but entries always list
+// their own fields rather than sharing them.
+fn write_stream(out: &mut Vec<u8>, stream: &Stream) {
+    let entries = stream.entries.iter().collect::<Vec<_>>();
+    let nodes = entries.chunks(STREAM_NODE_ENTRIES).collect::<Vec<_>>();
+    write_length(out, nodes.len());
+    for node in nodes {
+        let (master, master_fields) = node[0];
+        let mut key = Vec::new();
+        key.write_u64::<BigEndian>(master.ms).unwrap();
+        key.write_u64::<BigEndian>(master.seq).unwrap();
+        write_string(out, &key);
+
+        let mut elements = vec![
+            Element::Int(node.len() as i64),
+            Element::Int(0),
+            Element::Int(master_fields.len() as i64),
+        ];
+        elements.extend(
+            master_fields
+                .iter()
+                .map(|(field, _)| Element::Str(field.clone())),
+        );
+        elements.push(Element::Int(0));
+        for (id, fields) in node {
+            elements.extend([
+                Element::Int(0),
+                Element::Int((id.ms - master.ms) as i64),
+                Element::Int(id.seq.wrapping_sub(master.seq) as i64),
+                Element::Int(fields.len() as i64),
+            ]);
+            for (field, value) in fields.iter() {
+                elements.extend([Element::Str(field.clone()), Element::Str(value.clone())]);
             }
+            elements.push(Element::Int(2 * fields.len() as i64 + 4));
         }
+        write_string(out, &listpack::encode(&elements));
+    }
+
+    write_length(out, stream.entries.len());
+    write_length(out, stream.last_id.ms as usize);
+    write_length(out, stream.last_id.seq as usize);
+    // No consumer groups.
+    write_length(out, 0);
+}
 
-        let key_string = String::from_utf8_lossy(&key_chars).to_string();
-        let value_string = String::from_utf8_lossy(&value_chars).to_string();
+// How many bytes a value takes in an RDB file, not counting its type and key.
+pub fn serialized_length(value: &Value) -> usize {
+    let mut out = Vec::new();
+    write_value(&mut out, value);
+    out.len()
+}
+
+// Serializes every live key into an RDB file, one section per non-empty
+// database. The checksum is left as zero, which Redis treats as "not
+// computed".
+pub fn encode_rdb(databases: &[Arc<RwLock<Database>>]) -> Vec<u8> {
+    let now = Instant::now();
+    let now_ms = unix_time_ms();
 
-        let new_item = Item::new(value_string.clone(), None);
+    let mut out = b"REDIS0011".to_vec();
+    for (key, value) in [("redis-ver", REDIS_VERSION), ("redis-bits", "64")] {
+        out.push(0xFA);
+        write_string(&mut out, key.as_bytes());
+        write_string(&mut out, value.as_bytes());
+    }
 
-        if is_expired(expiry) && !expiry.is_none() {
+    for (index, in_memory) in databases.iter().enumerate() {
+        let db = in_memory.read().unwrap();
+        let live = db
+            .storage
+            .iter()
+            .filter(|(_, item)| !item.is_expired())
+            .collect::<Vec<_>>();
+        if live.is_empty() {
             continue;
         }
+        let expiring = live
+            .iter()
+            .filter(|(_, item)| item.deadline.is_some())
+            .count();
 
-        db.storage.insert(key_string.clone(), new_item);
+        out.push(0xFE);
+        write_length(&mut out, index);
+        out.push(0xFB);
+        write_length(&mut out, live.len());
+        write_length(&mut out, expiring);
+        for (key, item) in live {
+            if let Some(deadline) = item.deadline {
+                let expires_at = now_ms + deadline.saturating_duration_since(now).as_millis();
+                out.push(0xFC);
+                out.write_u64::<LittleEndian>(expires_at as u64).unwrap();
+            }
+            out.push(value_type(&item.value));
+            write_string(&mut out, key);
+            write_value(&mut out, &item.value);
+        }
     }
+
+    out.push(0xFF);
+    out.extend([0; 8]);
+    out
 }
 
-fn is_expired(expiry_timestamp_ms: Option<u128>) -> bool {
-    let now = SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .expect("Time went backwards");
-    let current_timestamp_ms = now.as_millis() as u128;
-    expiry_timestamp_ms < Some(current_timestamp_ms)
+// Writes the dataset to `dir/dbfilename`, going through a temporary file so
+// a crash mid-save never leaves a truncated RDB behind. Does nothing when no
+// file is configured.
+pub fn save_rdb(config: &Config) -> io::Result<()> {
+    let (Some(dir), Some(file_name)) = (config.dir(), config.dbfilename()) else {
+        return Ok(());
+    };
+
+    let contents = encode_rdb(&config.databases);
+    let path = format!("{}/{}", dir, file_name);
+    let temp_path = format!("{}/temp-{}.rdb", dir, std::process::id());
+    fs::write(&temp_path, contents)?;
+    fs::rename(temp_path, path)
+}
+
+// The RDB version stamped on DUMP payloads. RESTORE refuses payloads from a
+// newer version.
+const DUMP_RDB_VERSION: u16 = 11;
+
+// Serializes a value as DUMP does: its RDB type and encoding, followed by the
+// RDB version and a CRC64 of everything before the checksum.
+pub fn dump_value(item: &Item) -> Vec<u8> {
+    let mut out = vec![value_type(&item.value)];
+    write_value(&mut out, &item.value);
+    out.write_u16::<LittleEndian>(DUMP_RDB_VERSION).unwrap();
+    let checksum = crc64(&out);
+    out.write_u64::<LittleEndian>(checksum).unwrap();
+    out
+}
+
+// Reads back the value in a DUMP payload, checking its version and checksum.
+pub fn restore_value(payload: &[u8]) -> Result<Value, RedisError> {
+    let wrong = || RedisError::Err("DUMP payload version or checksum are wrong".to_string());
+    let split = payload.len().checked_sub(10).ok_or_else(wrong)?;
+    let (body, footer) = payload.split_at(split);
+    let mut footer = Cursor::new(footer);
+    let version = footer.read_u16::<LittleEndian>().map_err(|_| wrong())?;
+    let checksum = footer.read_u64::<LittleEndian>().map_err(|_| wrong())?;
+    if version > DUMP_RDB_VERSION || crc64(&payload[..split + 2]) != checksum {
+        return Err(wrong());
+    }
+
+    let bad_format = || RedisError::Err("Bad data format".to_string());
+    let mut buffer_iterator = body.iter();
+    let value_type = *buffer_iterator.next().ok_or_else(bad_format)?;
+    let value = read_value(value_type, &mut buffer_iterator).ok_or_else(bad_format)?;
+    match buffer_iterator.next() {
+        None => Ok(value),
+        Some(_) => Err(bad_format()),
+    }
+}
+
+// CRC-64/Jones, the checksum Redis puts on DUMP payloads and RDB files,
+// computed bit by bit in its reflected form.
+fn crc64(bytes: &[u8]) -> u64 {
+    const POLY: u64 = 0x95AC_9329_AC4B_C9B5;
+    let mut crc = 0u64;
+    for &byte in bytes {
+        crc ^= byte as u64;
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ POLY
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample_values() -> Vec<Value> {
+        let mut zset = SortedSet::default();
+        zset.insert(b"member", 1.5);
+        zset.insert(b"other", f64::NEG_INFINITY);
+        let mut stream = Stream::default();
+        stream.insert(
+            StreamId { ms: 1, seq: 1 },
+            vec![(b"f".to_vec(), b"v".to_vec())],
+        );
+        stream.insert(
+            StreamId { ms: 2, seq: 0 },
+            vec![(b"g".to_vec(), b"12".to_vec()), (b"h".to_vec(), Vec::new())],
+        );
+        vec![
+            Value::String(b"value".to_vec()),
+            Value::String(b"-12345".to_vec()),
+            Value::String(vec![0; 100]),
+            Value::List([b"a".to_vec(), b"12".to_vec()].into()),
+            Value::ZSet(zset),
+            Value::Hash([(b"field".to_vec(), b"value".to_vec())].into()),
+            Value::Stream(stream),
+        ]
+    }
+
+    #[test]
+    fn dump_payloads_restore_every_type() {
+        for value in sample_values() {
+            let payload = dump_value(&Item::new(value.clone(), None));
+            assert_eq!(restore_value(&payload), Ok(value));
+        }
+    }
+
+    #[test]
+    fn restore_rejects_a_corrupted_payload() {
+        let mut payload = dump_value(&Item::new(Value::String(b"value".to_vec()), None));
+        payload[1] ^= 1;
+        assert!(restore_value(&payload).is_err());
+    }
+
+    #[test]
+    fn snapshots_load_back_into_the_same_databases() {
+        let databases = || {
+            (0..2)
+                .map(|index| Arc::new(RwLock::new(Database::new(index))))
+                .collect::<Vec<_>>()
+        };
+        let saved = databases();
+        for (i, value) in sample_values().into_iter().enumerate() {
+            let ttl = (i % 2 == 0).then_some(Duration::from_secs(100));
+            saved[i % 2]
+                .write()
+                .unwrap()
+                .insert(format!("key{i}").into_bytes(), Item::new(value, ttl));
+        }
+
+        let loaded = databases();
+        replace_with_rdb(&encode_rdb(&saved), &loaded).unwrap();
+        for (saved, loaded) in saved.iter().zip(&loaded) {
+            let (saved, loaded) = (saved.read().unwrap(), loaded.read().unwrap());
+            assert_eq!(saved.storage.len(), loaded.storage.len());
+            for (key, item) in &saved.storage {
+                let copy = loaded.get(key).unwrap();
+                assert_eq!(copy.value, item.value);
+                assert_eq!(copy.deadline.is_some(), item.deadline.is_some());
+            }
+        }
+    }
 }
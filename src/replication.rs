@@ -1,19 +1,26 @@
-use std::sync::{Arc, Mutex};
+use std::{
+    collections::VecDeque,
+    sync::{atomic::Ordering, Arc},
+};
 
 use anyhow::Error;
 use tokio::{
-    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader},
     net::TcpStream,
+    sync::mpsc,
+    time::{sleep, timeout as time_limit, Duration, Instant},
 };
 
-use crate::{database::Database, handle_client, resp::RespType, Args, Config};
+use crate::{
+    client::ClientState,
+    command::{handle_command, Command},
+    rdb::replace_with_rdb,
+    resp::{parse_single_message, ParseError, RespType},
+    Config, ReplicationManager,
+};
 
-pub async fn handle_replica(
-    in_memory: &mut Arc<Mutex<Database>>,
-    config: Arc<Config>,
-    args: &Args,
-) -> Result<(), Error> {
-    let host = args
+pub async fn handle_replica(config: Arc<Config>) -> Result<(), Error> {
+    let host = config
         .replicaof
         .clone()
         .expect("Expected host and port to be passed")
@@ -30,22 +37,96 @@ pub async fn handle_replica(
     send_replconf_capa_psync2(&mut stream).await?;
     println!("Master acknowledged REPLCONF capa psync2");
 
-    send_psync(&mut stream, &config.repl_id).await?;
+    let (offset, leftover) = send_psync(&mut stream, &config).await?;
+    config
+        .replication_manager
+        .offset
+        .store(offset, Ordering::SeqCst);
     println!("Replication handshake completed successfully!");
 
-    let mut in_memory_cloned = Arc::clone(&in_memory);
-    let config_cloned = Arc::clone(&config);
-
-    tokio::spawn(async move {
-        println!("Current config: {:?}", config_cloned.clone());
-        handle_client(stream, &mut in_memory_cloned, config_cloned).await;
-    });
+    let addr = stream
+        .peer_addr()
+        .map(|addr| addr.to_string())
+        .unwrap_or_default();
+    let laddr = stream
+        .local_addr()
+        .map(|addr| addr.to_string())
+        .unwrap_or_default();
+    tokio::spawn(handle_master_stream(stream, addr, laddr, leftover, config));
     Ok(())
 }
 
-async fn send_ping(stream: &mut TcpStream) -> Result<(), Error> {
+// Applies the writes the master streams to this replica. Unlike a client
+// connection nothing is answered except REPLCONF GETACK, and every command's
+// bytes count towards the replication offset reported in those ACKs.
+// `pending` holds whatever the master sent right behind its snapshot, and
+// bytes of a command split across reads wait there for the rest.
+async fn handle_master_stream<S: AsyncRead + AsyncWrite + Unpin>(
+    mut stream: S,
+    addr: String,
+    laddr: String,
+    mut pending: Vec<u8>,
+    config: Arc<Config>,
+) {
+    let mut in_memory = Arc::clone(&config.databases[0]);
+    let (pubsub_sender, _pubsub_receiver) = mpsc::unbounded_channel::<RespType>();
+    let mut client = ClientState::new(config.clients.next_id(), addr, laddr, pubsub_sender);
+
+    loop {
+        loop {
+            let (command, consumed) = match parse_single_message(&pending, &config.renamed_commands)
+            {
+                Ok((command, _, rest)) => (command, pending.len() - rest.len()),
+                Err(ParseError::Incomplete) => break,
+                // Nothing after bytes that can't be a command can be trusted,
+                // so stop applying the stream rather than guess where the
+                // next command starts.
+                Err(ParseError::Protocol(message)) => {
+                    eprintln!("Protocol error in replication stream: {}", message);
+                    return;
+                }
+            };
+
+            let response = if command == Command::Exec {
+                let _exclusive = config.transaction_lock.write().await;
+                handle_command(&command, &mut stream, &mut in_memory, &config, &mut client).await
+            } else {
+                let _shared = config.transaction_lock.read().await;
+                handle_command(&command, &mut stream, &mut in_memory, &config, &mut client).await
+            };
+            if command == Command::ReplConf("getack".to_string()) {
+                if let Some(response) = response {
+                    if let Err(e) = stream.write_all(&response).await {
+                        eprintln!("Error sending ACK to master: {}", e);
+                    }
+                }
+            }
+
+            config
+                .replication_manager
+                .offset
+                .fetch_add(consumed, Ordering::SeqCst);
+            pending.drain(..consumed);
+        }
+
+        let mut buffer = [0; 1024];
+        match stream.read(&mut buffer).await {
+            Ok(0) => {
+                println!("Master closed the replication connection");
+                break;
+            }
+            Ok(bytes_read) => pending.extend_from_slice(&buffer[..bytes_read]),
+            Err(e) => {
+                eprintln!("Error reading replication stream: {}", e);
+                break;
+            }
+        }
+    }
+}
+
+async fn send_ping<S: AsyncRead + AsyncWrite + Unpin>(stream: &mut S) -> Result<(), Error> {
     let ping = RespType::Array(vec![RespType::BulkString("PING".to_string())]).serialize();
-    stream.write_all(ping.as_bytes()).await?;
+    stream.write_all(&ping).await?;
     stream.flush().await?;
 
     let mut buff = vec![0; 1024];
@@ -57,7 +138,10 @@ async fn send_ping(stream: &mut TcpStream) -> Result<(), Error> {
     Ok(())
 }
 
-async fn send_replconf_listening_port(stream: &mut TcpStream, port: u32) -> Result<(), Error> {
+async fn send_replconf_listening_port<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    port: u32,
+) -> Result<(), Error> {
     let repl_conf_port = RespType::Array(vec![
         RespType::BulkString("REPLCONF".to_string()),
         RespType::BulkString("listening-port".to_string()),
@@ -65,7 +149,7 @@ async fn send_replconf_listening_port(stream: &mut TcpStream, port: u32) -> Resu
     ])
     .serialize();
 
-    stream.write_all(repl_conf_port.as_bytes()).await?;
+    stream.write_all(&repl_conf_port).await?;
     stream.flush().await?;
 
     let mut buff = vec![0; 1024];
@@ -80,7 +164,9 @@ async fn send_replconf_listening_port(stream: &mut TcpStream, port: u32) -> Resu
     Ok(())
 }
 
-async fn send_replconf_capa_psync2(stream: &mut TcpStream) -> Result<(), Error> {
+async fn send_replconf_capa_psync2<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+) -> Result<(), Error> {
     let repl_conf_capa = RespType::Array(vec![
         RespType::BulkString("REPLCONF".to_string()),
         RespType::BulkString("capa".to_string()),
@@ -88,7 +174,7 @@ async fn send_replconf_capa_psync2(stream: &mut TcpStream) -> Result<(), Error>
     ])
     .serialize();
 
-    stream.write_all(repl_conf_capa.as_bytes()).await?;
+    stream.write_all(&repl_conf_capa).await?;
     stream.flush().await?;
 
     let mut buff = vec![0; 1024];
@@ -101,7 +187,13 @@ async fn send_replconf_capa_psync2(stream: &mut TcpStream) -> Result<(), Error>
     Ok(())
 }
 
-async fn send_psync(stream: &mut TcpStream, repl_id: &str) -> Result<(), Error> {
+// Loads the master's snapshot in place of the current dataset and returns
+// the replication offset the master starts streaming from, along with any
+// of the stream that was read past the snapshot.
+async fn send_psync<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    config: &Config,
+) -> Result<(usize, Vec<u8>), Error> {
     let psync = RespType::Array(vec![
         RespType::BulkString("PSYNC".to_string()),
         RespType::BulkString("?".to_string()),
@@ -109,7 +201,7 @@ async fn send_psync(stream: &mut TcpStream, repl_id: &str) -> Result<(), Error>
     ])
     .serialize();
 
-    stream.write_all(psync.as_bytes()).await?;
+    stream.write_all(&psync).await?;
     stream.flush().await?;
 
     let mut reader = BufReader::new(stream);
@@ -120,6 +212,11 @@ async fn send_psync(stream: &mut TcpStream, repl_id: &str) -> Result<(), Error>
     if !line.starts_with("+FULLRESYNC") {
         return Err(Error::msg(format!("Unexpected response: {}", line)));
     }
+    let offset = line
+        .split_whitespace()
+        .nth(2)
+        .and_then(|offset| offset.parse::<usize>().ok())
+        .ok_or_else(|| Error::msg("Invalid FULLRESYNC offset"))?;
 
     line.clear();
     reader.read_line(&mut line).await?;
@@ -133,10 +230,234 @@ async fn send_psync(stream: &mut TcpStream, repl_id: &str) -> Result<(), Error>
     let mut rdb_data = vec![0u8; size];
     reader.read_exact(&mut rdb_data).await?;
 
-    println!(
-        "First few RDB bytes: {:?}",
-        &rdb_data[..rdb_data.len().min(32)]
-    );
+    if replace_with_rdb(&rdb_data, &config.databases).is_none() {
+        eprintln!("RDB from master is truncated or uses an unsupported encoding.");
+    }
 
-    Ok(())
+    Ok((offset, reader.buffer().to_vec()))
+}
+
+// Implements WAIT: returns how many replicas acknowledged every write made so
+// far, giving up after `timeout` (zero means no limit). With no replicas the
+// answer is always 0, so it only sleeps out the timeout instead of blocking.
+pub async fn wait_for_replicas(
+    manager: &ReplicationManager,
+    numreplicas: usize,
+    timeout: Duration,
+) -> usize {
+    let replica_count = manager.replicas.read().await.len();
+    if replica_count == 0 {
+        if numreplicas > 0 && !timeout.is_zero() {
+            sleep(timeout).await;
+        }
+        return 0;
+    }
+
+    let target_offset = manager.offset.load(Ordering::SeqCst);
+    if target_offset == 0 {
+        return replica_count;
+    }
+
+    let getack = RespType::Array(vec![
+        RespType::BulkString("REPLCONF".to_string()),
+        RespType::BulkString("GETACK".to_string()),
+        RespType::BulkString("*".to_string()),
+    ])
+    .serialize();
+    // GETACK goes through the replication stream too, so it counts towards
+    // the offset like any propagated command.
+    let mut replicas = manager.replicas.write().await;
+    for replica in replicas.iter_mut() {
+        if let Err(e) = replica.write_all(&getack).await {
+            eprintln!("Error sending GETACK to replica: {}", e);
+        }
+    }
+    manager.record(&getack);
+    drop(replicas);
+
+    let deadline = (!timeout.is_zero()).then(|| Instant::now() + timeout);
+    let mut acked = vec![false; replica_count];
+    loop {
+        // Only hold the lock while polling so propagation isn't blocked. A
+        // zero time limit reads only what has already arrived.
+        let mut replicas = manager.replicas.write().await;
+        for (replica, acked) in replicas.iter_mut().zip(acked.iter_mut()) {
+            let mut buffer = [0; 1024];
+            if let Ok(Ok(bytes_read)) = time_limit(Duration::ZERO, replica.read(&mut buffer)).await
+            {
+                let data = String::from_utf8_lossy(&buffer[..bytes_read]);
+                if parse_ack_offset(&data).is_some_and(|offset| offset >= target_offset) {
+                    *acked = true;
+                }
+            }
+        }
+        drop(replicas);
+
+        let acked_count = acked.iter().filter(|&&acked| acked).count();
+        if acked_count >= numreplicas || deadline.is_some_and(|d| Instant::now() >= d) {
+            return acked_count;
+        }
+        sleep(Duration::from_millis(10)).await;
+    }
+}
+
+// Finds the offset in the last `REPLCONF ACK <offset>` frame of `data`.
+fn parse_ack_offset(data: &str) -> Option<usize> {
+    let start = data.rfind("ACK\r\n")? + "ACK\r\n".len();
+    data[start..].split("\r\n").nth(1)?.parse().ok()
+}
+
+// The most recent bytes of the replication stream, so a replica that
+// reconnects can be sent only what it missed instead of a full resync.
+#[derive(Debug)]
+pub struct Backlog {
+    data: VecDeque<u8>,
+    capacity: usize,
+}
+
+impl Backlog {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            data: VecDeque::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn push(&mut self, bytes: &[u8]) {
+        self.data.extend(bytes);
+        let excess = self.data.len().saturating_sub(self.capacity);
+        self.data.drain(..excess);
+    }
+
+    // The bytes from `offset` up to `end`, the current offset, or `None` if
+    // they're no longer all in the backlog.
+    pub fn since(&self, offset: usize, end: usize) -> Option<Vec<u8>> {
+        let start = end - self.data.len();
+        if !(start..=end).contains(&offset) {
+            return None;
+        }
+        Some(self.data.range(offset - start..).copied().collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::duplex;
+
+    use super::*;
+    use crate::{
+        database::Value,
+        rdb::encode_rdb,
+        testing::{encode_command, test_config},
+        Database, Item,
+    };
+
+    #[tokio::test]
+    async fn a_malformed_replication_stream_stops_being_applied() {
+        let config = test_config(&["--replicaof", "127.0.0.1 6379"]);
+        let (mut master, replica) = duplex(1024);
+        let applying = tokio::spawn(handle_master_stream(
+            replica,
+            String::new(),
+            String::new(),
+            Vec::new(),
+            Arc::clone(&config),
+        ));
+
+        let before = encode_command(&["SET", "before", "v"]);
+        let mut stream = before.clone();
+        stream.extend_from_slice(b"*1\r\n$-3\r\n");
+        stream.extend(encode_command(&["SET", "after", "v"]));
+        master.write_all(&stream).await.unwrap();
+
+        time_limit(Duration::from_secs(5), applying)
+            .await
+            .expect("kept reading after a protocol error")
+            .unwrap();
+        let db = config.databases[0].read().unwrap();
+        assert!(db.get(b"before").is_some());
+        assert!(db.get(b"after").is_none());
+        assert_eq!(
+            config.replication_manager.offset.load(Ordering::SeqCst),
+            before.len()
+        );
+    }
+
+    #[test]
+    fn the_backlog_returns_what_followed_an_offset_it_still_holds() {
+        let mut backlog = Backlog::new(8);
+        backlog.push(b"abcde");
+        // Offsets 0..5 were propagated.
+        assert_eq!(backlog.since(0, 5).unwrap(), b"abcde");
+        assert_eq!(backlog.since(3, 5).unwrap(), b"de");
+        assert_eq!(backlog.since(5, 5).unwrap(), b"");
+        assert_eq!(backlog.since(6, 5), None);
+
+        // Past its capacity the oldest bytes are dropped: offsets 3..11
+        // are kept, so 0..3 can't be continued from any more.
+        backlog.push(b"fghijk");
+        assert_eq!(backlog.since(3, 11).unwrap(), b"defghijk");
+        assert_eq!(backlog.since(9, 11).unwrap(), b"jk");
+        assert_eq!(backlog.since(2, 11), None);
+        assert_eq!(backlog.since(12, 11), None);
+    }
+
+    #[tokio::test]
+    async fn writes_sent_with_the_snapshot_are_applied() {
+        let config = test_config(&["--replicaof", "127.0.0.1 6379"]);
+        let (mut master, mut replica) = duplex(64 * 1024);
+        let replicating = {
+            let config = Arc::clone(&config);
+            tokio::spawn(async move {
+                let (_, leftover) = send_psync(&mut replica, &config).await.unwrap();
+                handle_master_stream(replica, String::new(), String::new(), leftover, config).await;
+            })
+        };
+
+        let psync = encode_command(&["PSYNC", "?", "-1"]);
+        let mut request = vec![0; psync.len()];
+        master.read_exact(&mut request).await.unwrap();
+        assert_eq!(request, psync);
+
+        let snapshot = {
+            let databases = [Arc::new(std::sync::RwLock::new(Database::new(0)))];
+            databases[0].write().unwrap().insert(
+                b"snapshot".to_vec(),
+                Item::new(Value::String(b"v".to_vec()), None),
+            );
+            encode_rdb(&databases)
+        };
+        // The snapshot and the next write arrive in the same read.
+        let mut reply = format!(
+            "+FULLRESYNC {} 0\r\n${}\r\n",
+            "x".repeat(40),
+            snapshot.len()
+        )
+        .into_bytes();
+        reply.extend(snapshot);
+        reply.extend(encode_command(&["SET", "after", "v"]));
+        master.write_all(&reply).await.unwrap();
+
+        for _ in 0..500 {
+            if config.databases[0].read().unwrap().get(b"after").is_some() {
+                break;
+            }
+            sleep(Duration::from_millis(10)).await;
+        }
+        {
+            let db = config.databases[0].read().unwrap();
+            assert!(db.get(b"snapshot").is_some());
+            assert!(
+                db.get(b"after").is_some(),
+                "the write behind the snapshot was lost"
+            );
+        }
+
+        drop(master);
+        replicating.await.unwrap();
+    }
 }
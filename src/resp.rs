@@ -1,87 +1,539 @@
+use std::collections::HashMap;
+
 use crate::command::Command;
+use crate::error::RedisError;
+
+// A command's arguments as the client sent them, command name included.
+pub type Args = Vec<Vec<u8>>;
 
-#[derive(Debug)]
+// Some RESP3 types have no command producing them yet.
+#[allow(dead_code)]
+#[derive(Debug, Clone)]
 pub enum RespType {
     SimpleString(String),
+    Error(RedisError),
+    Integer(i64),
     BulkString(String),
-    Rdb(String),
+    // Binary-safe bulk string, for values that may not be valid UTF-8.
+    BulkBytes(Vec<u8>),
     NullBulkString,
+    NullArray,
     Array(Vec<RespType>),
+    Map(Vec<(RespType, RespType)>),
+    Set(Vec<RespType>),
+    // Out-of-band data such as pub/sub messages.
+    Push(Vec<RespType>),
+    Null,
+    Double(f64),
+    Boolean(bool),
+    BigNumber(String),
+    // Text tagged with a three-letter format such as `txt` or `mkd`.
+    VerbatimString(&'static str, String),
 }
 
 impl RespType {
-    pub fn serialize(self) -> String {
+    // RESP2 encoding, used unless a connection negotiated RESP3 with HELLO.
+    pub fn serialize(self) -> Vec<u8> {
+        self.serialize_for(2)
+    }
+
+    // Encodes for the connection's protocol version. RESP2 clients get the
+    // closest RESP2 shape: maps flatten into arrays, sets and pushes become
+    // arrays, booleans become 1/0, and doubles, big numbers and verbatim strings
+    // become bulk strings.
+    pub fn serialize_for(self, protocol: u8) -> Vec<u8> {
         match self {
-            RespType::SimpleString(s) => format!("+{}\r\n", s),
-            RespType::BulkString(s) => format!("${}\r\n{}\r\n", s.chars().count(), s),
-            RespType::Rdb(s) => s,
-            RespType::NullBulkString => "$-1\r\n".to_string(),
+            RespType::SimpleString(s) => format!("+{}\r\n", s).into_bytes(),
+            // A line break would end the error early and desync the client.
+            RespType::Error(e) => {
+                format!("-{}\r\n", e.to_string().replace(['\r', '\n'], " ")).into_bytes()
+            }
+            RespType::Integer(n) => format!(":{}\r\n", n).into_bytes(),
+            RespType::BulkString(s) => RespType::BulkBytes(s.into_bytes()).serialize_for(protocol),
+            RespType::BulkBytes(bytes) => {
+                let mut serialized = format!("${}\r\n", bytes.len()).into_bytes();
+                serialized.extend(bytes);
+                serialized.extend(b"\r\n");
+                serialized
+            }
+            RespType::NullBulkString | RespType::NullArray | RespType::Null if protocol >= 3 => {
+                b"_\r\n".to_vec()
+            }
+            RespType::NullBulkString | RespType::Null => b"$-1\r\n".to_vec(),
+            RespType::NullArray => b"*-1\r\n".to_vec(),
             RespType::Array(items) => {
-                let mut serialized = format!("*{}\r\n", items.len());
+                let mut serialized = format!("*{}\r\n", items.len()).into_bytes();
+                for item in items {
+                    serialized.extend(item.serialize_for(protocol));
+                }
+                serialized
+            }
+            RespType::Map(entries) => {
+                let mut serialized = if protocol >= 3 {
+                    format!("%{}\r\n", entries.len())
+                } else {
+                    format!("*{}\r\n", entries.len() * 2)
+                }
+                .into_bytes();
+                for (key, value) in entries {
+                    serialized.extend(key.serialize_for(protocol));
+                    serialized.extend(value.serialize_for(protocol));
+                }
+                serialized
+            }
+            RespType::Set(items) => {
+                let mut serialized = if protocol >= 3 {
+                    format!("~{}\r\n", items.len())
+                } else {
+                    format!("*{}\r\n", items.len())
+                }
+                .into_bytes();
                 for item in items {
-                    serialized.push_str(&item.serialize());
+                    serialized.extend(item.serialize_for(protocol));
                 }
                 serialized
             }
+            RespType::Push(items) if protocol >= 3 => {
+                let mut serialized = format!(">{}\r\n", items.len()).into_bytes();
+                for item in items {
+                    serialized.extend(item.serialize_for(protocol));
+                }
+                serialized
+            }
+            RespType::Push(items) => RespType::Array(items).serialize_for(protocol),
+            RespType::Double(n) if protocol >= 3 => {
+                format!(",{}\r\n", format_double(n)).into_bytes()
+            }
+            RespType::Double(n) => RespType::BulkString(format_double(n)).serialize_for(protocol),
+            RespType::Boolean(b) if protocol >= 3 => {
+                format!("#{}\r\n", if b { 't' } else { 'f' }).into_bytes()
+            }
+            RespType::Boolean(b) => format!(":{}\r\n", b as i64).into_bytes(),
+            RespType::BigNumber(n) if protocol >= 3 => format!("({}\r\n", n).into_bytes(),
+            RespType::BigNumber(n) => RespType::BulkString(n).serialize_for(protocol),
+            RespType::VerbatimString(format, text) if protocol >= 3 => {
+                format!("={}\r\n{}:{}\r\n", text.len() + 4, format, text).into_bytes()
+            }
+            RespType::VerbatimString(_, text) => RespType::BulkString(text).serialize_for(protocol),
         }
     }
 }
 
-pub fn parse_messages(buffer: &str) -> Vec<Command> {
+// Redis spells infinities `inf`/`-inf` and NaN `nan`; finite values use the
+// shortest representation that round-trips.
+pub fn format_double(n: f64) -> String {
+    if n.is_nan() {
+        "nan".to_string()
+    } else if n.is_infinite() {
+        if n > 0.0 { "inf" } else { "-inf" }.to_string()
+    } else {
+        n.to_string()
+    }
+}
+
+// The most a single request may claim, as Redis's defaults: arguments per
+// command, bytes per argument, and bytes in an inline command or in the
+// length line of a multibulk one.
+const MAX_MULTIBULK_LEN: usize = 1024 * 1024;
+const MAX_BULK_LEN: usize = 512 * 1024 * 1024;
+const MAX_INLINE_LEN: usize = 64 * 1024;
+
+// Why no command could be parsed off the front of a buffer.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParseError {
+    // The buffer ends partway through a command; more bytes will finish it.
+    Incomplete,
+    // The bytes can't be a command. Nothing after them can be trusted, so
+    // like Redis the connection replies with this and closes.
+    Protocol(&'static str),
+}
+
+// Parses at most `limit` commands from `buffer`, each with the arguments it
+// was sent with, and returns them with the bytes after them: the rest of a
+// pipeline the limit cut short, for the caller to come back to after letting
+// other connections run, or the start of a command still arriving. A
+// malformed command ends parsing with the error to reply with.
+pub fn parse_messages<'a>(
+    buffer: &'a [u8],
+    limit: usize,
+    renamed: &HashMap<String, Option<String>>,
+) -> (Vec<(Command, Args)>, &'a [u8], Option<RedisError>) {
     let mut commands = Vec::new();
     let mut remaining = buffer;
 
-    while !remaining.trim().is_empty() {
-        match parse_single_message(remaining) {
-            Ok((command, rest)) => {
-                commands.push(command);
+    while !remaining.trim_ascii().is_empty() {
+        if commands.len() >= limit.max(1) {
+            return (commands, remaining, None);
+        }
+        match parse_single_message(remaining, renamed) {
+            Ok((command, args, rest)) => {
+                commands.push((command, args));
                 remaining = rest;
             }
-            Err(_) => break,
+            Err(ParseError::Incomplete) => return (commands, remaining, None),
+            Err(ParseError::Protocol(message)) => {
+                let error = RedisError::Err(format!("Protocol error: {}", message));
+                return (commands, &[], Some(error));
+            }
         }
     }
 
-    commands
+    (commands, &[], None)
 }
 
-fn parse_single_message(buffer: &str) -> Result<(Command, &str), &'static str> {
-    let mut lines = buffer.split("\r\n").peekable();
-    let mut bytes_consumed = 0;
-
-    let first_line = lines.next().ok_or("Empty buffer")?;
-    bytes_consumed += first_line.len() + 2;
+// Arguments arrive as raw bytes. Text is kept as a `BulkString` so commands
+// can match on it; anything that isn't UTF-8 stays a `BulkBytes`.
+fn bulk_arg(bytes: Vec<u8>) -> RespType {
+    match String::from_utf8(bytes) {
+        Ok(text) => RespType::BulkString(text),
+        Err(e) => RespType::BulkBytes(e.into_bytes()),
+    }
+}
 
-    if !first_line.starts_with('*') {
-        return Err("Expected array");
+// Splits off the next `\r\n`-terminated line, failing with `too_big` if
+// there's no line end within `MAX_INLINE_LEN` bytes.
+fn read_line<'a>(
+    buffer: &'a [u8],
+    too_big: &'static str,
+) -> Result<(&'a [u8], &'a [u8]), ParseError> {
+    match buffer.windows(2).position(|window| window == b"\r\n") {
+        Some(end) => Ok((&buffer[..end], &buffer[end + 2..])),
+        None if buffer.len() > MAX_INLINE_LEN => Err(ParseError::Protocol(too_big)),
+        None => Err(ParseError::Incomplete),
     }
-    let count = first_line[1..]
+}
+
+// Parses the digits of a length line, accepting nothing above `max`.
+fn parse_length(digits: &[u8], max: usize) -> Option<usize> {
+    std::str::from_utf8(digits)
+        .ok()?
         .parse::<usize>()
-        .map_err(|_| "Invalid array length")?;
+        .ok()
+        .filter(|&len| len <= max)
+}
 
-    let mut elements = Vec::with_capacity(count);
+// Parses one command off the front of `buffer`, returning it with its raw
+// arguments and the bytes after it.
+pub fn parse_single_message<'a>(
+    buffer: &'a [u8],
+    renamed: &HashMap<String, Option<String>>,
+) -> Result<(Command, Args, &'a [u8]), ParseError> {
+    if !buffer.starts_with(b"*") {
+        return parse_inline_message(buffer, renamed);
+    }
 
+    let (header, mut rest) = read_line(buffer, "too big mbulk count string")?;
+    let count = parse_length(&header[1..], MAX_MULTIBULK_LEN)
+        .ok_or(ParseError::Protocol("invalid multibulk length"))?;
+
+    let mut args = Vec::with_capacity(count);
     for _ in 0..count {
-        let size_line = lines.next().ok_or("Unexpected end of input (size line)")?;
-        bytes_consumed += size_line.len() + 2;
+        let (size_line, after_size) = read_line(rest, "too big bulk count string")?;
+        let len = match size_line.split_first() {
+            Some((b'$', digits)) => parse_length(digits, MAX_BULK_LEN)
+                .ok_or(ParseError::Protocol("invalid bulk length"))?,
+            _ => return Err(ParseError::Protocol("expected '$'")),
+        };
+
+        // Bulk strings are length-prefixed, so the data may itself contain \r\n.
+        if after_size.len() < len + 2 {
+            return Err(ParseError::Incomplete);
+        }
+        if &after_size[len..len + 2] != b"\r\n" {
+            return Err(ParseError::Protocol("invalid bulk length"));
+        }
+        args.push(after_size[..len].to_vec());
+        rest = &after_size[len + 2..];
+    }
 
-        if !size_line.starts_with('$') {
-            return Err("Expected bulk string");
+    let elements = args.iter().cloned().map(bulk_arg).collect();
+    let command = Command::from_resp(vec![RespType::Array(elements)], renamed);
+    Ok((command, args, rest))
+}
+
+// Inline commands are a single line of space-separated words, as sent by
+// health checks and telnet (`PING\r\n`). Blank lines are skipped.
+fn parse_inline_message<'a>(
+    buffer: &'a [u8],
+    renamed: &HashMap<String, Option<String>>,
+) -> Result<(Command, Args, &'a [u8]), ParseError> {
+    let start = buffer
+        .iter()
+        .position(|byte| !byte.is_ascii_whitespace())
+        .ok_or(ParseError::Incomplete)?;
+    let buffer = &buffer[start..];
+    let (line, rest) = match buffer.iter().position(|&byte| byte == b'\n') {
+        Some(end) => (&buffer[..end], &buffer[end + 1..]),
+        None if buffer.len() > MAX_INLINE_LEN => {
+            return Err(ParseError::Protocol("too big inline request"))
+        }
+        None => return Err(ParseError::Incomplete),
+    };
+
+    let words =
+        split_inline_args(line).ok_or(ParseError::Protocol("unbalanced quotes in request"))?;
+
+    let elements = words.iter().cloned().map(bulk_arg).collect();
+    let command = Command::from_resp(vec![RespType::Array(elements)], renamed);
+    Ok((command, words, rest))
+}
+
+// Splits an inline command line like Redis's `sdssplitargs`: words are
+// separated by whitespace and may be "double quoted" (with \n, \t, \xHH and
+// similar escapes) or 'single quoted' (where only \' is an escape). Returns
+// `None` for unbalanced quotes or a closing quote not followed by a space.
+fn split_inline_args(line: &[u8]) -> Option<Vec<Vec<u8>>> {
+    let line = line.strip_suffix(b"\r").unwrap_or(line);
+    let mut words = Vec::new();
+    let mut bytes = line.iter().copied().peekable();
+
+    loop {
+        while bytes.next_if(u8::is_ascii_whitespace).is_some() {}
+        let Some(&first) = bytes.peek() else {
+            return Some(words);
+        };
+
+        let mut word = Vec::new();
+        match first {
+            b'"' => {
+                bytes.next();
+                loop {
+                    match bytes.next()? {
+                        b'"' => break,
+                        b'\\' => match bytes.next()? {
+                            b'n' => word.push(b'\n'),
+                            b'r' => word.push(b'\r'),
+                            b't' => word.push(b'\t'),
+                            b'b' => word.push(8),
+                            b'a' => word.push(7),
+                            b'x' => {
+                                let hex = [bytes.next()?, bytes.next()?];
+                                match std::str::from_utf8(&hex)
+                                    .ok()
+                                    .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+                                {
+                                    Some(byte) => word.push(byte),
+                                    None => {
+                                        word.push(b'x');
+                                        word.extend(hex);
+                                    }
+                                }
+                            }
+                            other => word.push(other),
+                        },
+                        other => word.push(other),
+                    }
+                }
+                if bytes.peek().is_some_and(|c| !c.is_ascii_whitespace()) {
+                    return None;
+                }
+            }
+            b'\'' => {
+                bytes.next();
+                loop {
+                    match bytes.next()? {
+                        b'\'' => break,
+                        b'\\' if bytes.peek() == Some(&b'\'') => {
+                            bytes.next();
+                            word.push(b'\'');
+                        }
+                        other => word.push(other),
+                    }
+                }
+                if bytes.peek().is_some_and(|c| !c.is_ascii_whitespace()) {
+                    return None;
+                }
+            }
+            _ => {
+                while let Some(c) = bytes.next_if(|c| !c.is_ascii_whitespace()) {
+                    word.push(c);
+                }
+            }
+        }
+        words.push(word);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(buffer: &[u8], limit: usize) -> (Vec<Command>, Vec<u8>, Option<RedisError>) {
+        let (commands, rest, error) = parse_messages(buffer, limit, &HashMap::new());
+        let commands = commands.into_iter().map(|(command, _)| command).collect();
+        (commands, rest.to_vec(), error)
+    }
+
+    #[test]
+    fn parses_a_pipeline_of_array_and_inline_commands() {
+        let (commands, rest, error) = parse(b"*1\r\n$4\r\nPING\r\n\r\nECHO hi\r\n", 10);
+        assert_eq!(
+            commands,
+            vec![Command::Ping(None), Command::Echo(b"hi".to_vec())]
+        );
+        assert!(rest.is_empty());
+        assert_eq!(error, None);
+    }
+
+    #[test]
+    fn bare_inline_ping_and_quit_parse_like_health_check_probes_send_them() {
+        for probe in [&b"PING\r\n"[..], b"ping\n", b"\r\nPING\r\n"] {
+            let (commands, rest, error) = parse(probe, 10);
+            assert_eq!(commands, vec![Command::Ping(None)], "{probe:?}");
+            assert!(rest.is_empty());
+            assert_eq!(error, None);
         }
 
-        let len = size_line[1..]
-            .parse::<usize>()
-            .map_err(|_| "Invalid bulk string length")?;
+        let (commands, rest, error) = parse(b"PING\r\nQUIT\r\n", 10);
+        assert_eq!(commands, vec![Command::Ping(None), Command::Quit]);
+        assert!(rest.is_empty());
+        assert_eq!(error, None);
+    }
 
-        let data_line = lines.next().ok_or("Unexpected end of input (data line)")?;
-        bytes_consumed += data_line.len() + 2;
+    #[test]
+    fn keeps_an_incomplete_command_for_the_next_read() {
+        for buffer in [
+            &b"*2\r\n$4\r\nECHO\r\n$5\r\nhel"[..],
+            b"*2\r\n$4\r\nEC",
+            b"*2",
+            b"PIN",
+        ] {
+            let mut pipeline = b"PING\r\n".to_vec();
+            pipeline.extend_from_slice(buffer);
+            let (commands, rest, error) = parse(&pipeline, 10);
+            assert_eq!(commands, vec![Command::Ping(None)]);
+            assert_eq!(rest, buffer);
+            assert_eq!(error, None);
+        }
+    }
+
+    #[test]
+    fn stops_at_the_limit_and_returns_the_rest() {
+        let (commands, rest, _) = parse(b"PING\r\nPING\r\nPING\r\n", 2);
+        assert_eq!(commands.len(), 2);
+        assert_eq!(rest, b"PING\r\n");
+    }
 
-        if data_line.len() != len {
-            return Err("Bulk string length mismatch");
+    #[test]
+    fn malformed_commands_are_protocol_errors() {
+        for (buffer, message) in [
+            (&b"*x\r\n"[..], "invalid multibulk length"),
+            (b"*1\r\n$-3\r\n", "invalid bulk length"),
+            (b"*1\r\n$3\r\nPINGPONG\r\n", "invalid bulk length"),
+            (b"*1\r\n+PING\r\n", "expected '$'"),
+            (b"*1\r\n$999999999999\r\n", "invalid bulk length"),
+            (b"ECHO \"unterminated\r\n", "unbalanced quotes in request"),
+        ] {
+            let mut pipeline = b"PING\r\n".to_vec();
+            pipeline.extend_from_slice(buffer);
+            let (commands, rest, error) = parse(&pipeline, 10);
+            assert_eq!(commands, vec![Command::Ping(None)]);
+            assert!(rest.is_empty());
+            assert_eq!(
+                error,
+                Some(RedisError::Err(format!("Protocol error: {}", message)))
+            );
         }
+    }
+
+    #[test]
+    fn an_inline_request_without_a_line_end_has_a_size_limit() {
+        let (_, rest, error) = parse(&vec![b'a'; MAX_INLINE_LEN], 10);
+        assert_eq!((rest.len(), error), (MAX_INLINE_LEN, None));
 
-        elements.push(RespType::BulkString(data_line.to_string()));
+        let (_, _, error) = parse(&vec![b'a'; MAX_INLINE_LEN + 1], 10);
+        assert_eq!(
+            error,
+            Some(RedisError::Err(
+                "Protocol error: too big inline request".to_string()
+            ))
+        );
     }
 
-    let command = Command::from_resp(vec![RespType::Array(elements)]);
-    Ok((command, &buffer[bytes_consumed..]))
+    // Checks how `value` serializes for a RESP2 and for a RESP3 client.
+    fn assert_serializes(value: impl Fn() -> RespType, resp2: &[u8], resp3: &[u8]) {
+        assert_eq!(value().serialize_for(2), resp2, "{:?}", value());
+        assert_eq!(value().serialize_for(3), resp3, "{:?}", value());
+    }
+
+    #[test]
+    fn resp3_only_types_fall_back_to_resp2_shapes() {
+        assert_serializes(|| RespType::NullBulkString, b"$-1\r\n", b"_\r\n");
+        assert_serializes(|| RespType::NullArray, b"*-1\r\n", b"_\r\n");
+        assert_serializes(|| RespType::Double(1.5), b"$3\r\n1.5\r\n", b",1.5\r\n");
+        assert_serializes(
+            || RespType::Double(f64::NEG_INFINITY),
+            b"$4\r\n-inf\r\n",
+            b",-inf\r\n",
+        );
+        assert_serializes(|| RespType::Boolean(true), b":1\r\n", b"#t\r\n");
+        assert_serializes(|| RespType::Boolean(false), b":0\r\n", b"#f\r\n");
+        assert_serializes(|| RespType::Null, b"$-1\r\n", b"_\r\n");
+        assert_serializes(
+            || RespType::BigNumber("12345678901234567890".to_string()),
+            b"$20\r\n12345678901234567890\r\n",
+            b"(12345678901234567890\r\n",
+        );
+        assert_serializes(
+            || RespType::VerbatimString("txt", "hi".to_string()),
+            b"$2\r\nhi\r\n",
+            b"=6\r\ntxt:hi\r\n",
+        );
+        assert_serializes(
+            || RespType::Map(vec![(RespType::Integer(1), RespType::Integer(2))]),
+            b"*2\r\n:1\r\n:2\r\n",
+            b"%1\r\n:1\r\n:2\r\n",
+        );
+        assert_serializes(
+            || RespType::Set(vec![RespType::Integer(1)]),
+            b"*1\r\n:1\r\n",
+            b"~1\r\n:1\r\n",
+        );
+        assert_serializes(
+            || RespType::Push(vec![RespType::Integer(1)]),
+            b"*1\r\n:1\r\n",
+            b">1\r\n:1\r\n",
+        );
+    }
+
+    #[test]
+    fn nested_values_serialize_for_the_same_protocol() {
+        assert_serializes(
+            || {
+                RespType::Array(vec![
+                    RespType::BulkBytes(b"a\r\nb".to_vec()),
+                    RespType::Array(vec![RespType::Null]),
+                ])
+            },
+            b"*2\r\n$4\r\na\r\nb\r\n*1\r\n$-1\r\n",
+            b"*2\r\n$4\r\na\r\nb\r\n*1\r\n_\r\n",
+        );
+    }
+
+    #[test]
+    fn errors_stay_on_one_line() {
+        let error = RespType::Error(RedisError::Err("bad\r\nthing".to_string()));
+        assert_eq!(error.serialize(), b"-ERR bad  thing\r\n");
+    }
+
+    #[test]
+    fn unknown_commands_serialize_as_error_replies() {
+        let (commands, _, _) = parse(b"FOOBAR a\r\n", 10);
+        assert_eq!(
+            commands,
+            vec![Command::Unknown {
+                name: "FOOBAR".to_string(),
+                args: vec!["a".to_string()],
+            }]
+        );
+
+        // An error reply starts with `-`, never `+-ERR`, in either protocol.
+        let message = "unknown command 'FOOBAR', with args beginning with: 'a' ";
+        for protocol in [2, 3] {
+            assert_eq!(
+                RespType::Error(RedisError::Err(message.to_string())).serialize_for(protocol),
+                format!("-ERR {message}\r\n").as_bytes()
+            );
+        }
+    }
 }
@@ -0,0 +1,141 @@
+use std::{
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicI64, AtomicU64, AtomicUsize, Ordering},
+        Mutex,
+    },
+    time::Duration,
+};
+
+use crate::{database::unix_time_ms, resp::RespType};
+
+// Arguments past this many are replaced by a note saying how many were left
+// out, and each argument is cut after `MAX_ARG_LEN` bytes, as Redis does.
+const MAX_ARGS: usize = 32;
+const MAX_ARG_LEN: usize = 128;
+
+#[derive(Debug)]
+struct Entry {
+    id: u64,
+    // Unix time in seconds when the command ran.
+    timestamp: u64,
+    micros: u64,
+    args: Vec<Vec<u8>>,
+    addr: String,
+    name: String,
+}
+
+// Commands that took at least `log_slower_than` microseconds, newest first,
+// keeping at most `max_len` of them. A negative threshold logs nothing.
+#[derive(Debug)]
+pub struct SlowLog {
+    entries: Mutex<VecDeque<Entry>>,
+    next_id: AtomicU64,
+    log_slower_than: AtomicI64,
+    max_len: AtomicUsize,
+}
+
+impl SlowLog {
+    pub fn new(log_slower_than: i64, max_len: usize) -> Self {
+        Self {
+            entries: Mutex::new(VecDeque::new()),
+            next_id: AtomicU64::new(0),
+            log_slower_than: AtomicI64::new(log_slower_than),
+            max_len: AtomicUsize::new(max_len),
+        }
+    }
+
+    pub fn log_slower_than(&self) -> i64 {
+        self.log_slower_than.load(Ordering::SeqCst)
+    }
+
+    pub fn max_len(&self) -> usize {
+        self.max_len.load(Ordering::SeqCst)
+    }
+
+    pub fn set_log_slower_than(&self, micros: i64) {
+        self.log_slower_than.store(micros, Ordering::SeqCst);
+    }
+
+    pub fn set_max_len(&self, max_len: usize) {
+        self.max_len.store(max_len, Ordering::SeqCst);
+        self.entries.lock().unwrap().truncate(max_len);
+    }
+
+    // Logs the command if it ran for at least the threshold.
+    pub fn record(&self, args: &[Vec<u8>], elapsed: Duration, addr: &str, name: Option<&str>) {
+        let threshold = self.log_slower_than();
+        let micros = elapsed.as_micros() as u64;
+        if threshold < 0 || micros < threshold as u64 {
+            return;
+        }
+
+        let mut logged: Vec<Vec<u8>> = args
+            .iter()
+            .take(if args.len() > MAX_ARGS {
+                MAX_ARGS - 1
+            } else {
+                MAX_ARGS
+            })
+            .map(|arg| match arg.get(MAX_ARG_LEN..) {
+                Some(rest) if !rest.is_empty() => {
+                    let mut cut = arg[..MAX_ARG_LEN].to_vec();
+                    cut.extend(format!("... ({} more bytes)", rest.len()).into_bytes());
+                    cut
+                }
+                _ => arg.clone(),
+            })
+            .collect();
+        if args.len() > MAX_ARGS {
+            logged.push(format!("... ({} more arguments)", args.len() - MAX_ARGS + 1).into_bytes());
+        }
+
+        let entry = Entry {
+            id: self.next_id.fetch_add(1, Ordering::SeqCst),
+            timestamp: (unix_time_ms() / 1000) as u64,
+            micros,
+            args: logged,
+            addr: addr.to_string(),
+            name: name.unwrap_or_default().to_string(),
+        };
+        let mut entries = self.entries.lock().unwrap();
+        entries.push_front(entry);
+        entries.truncate(self.max_len());
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.lock().unwrap().len()
+    }
+
+    pub fn reset(&self) {
+        self.entries.lock().unwrap().clear();
+    }
+
+    // The newest `count` entries as SLOWLOG GET replies with them: id,
+    // timestamp, duration in microseconds, arguments, client address and
+    // client name.
+    pub fn newest(&self, count: usize) -> Vec<RespType> {
+        self.entries
+            .lock()
+            .unwrap()
+            .iter()
+            .take(count)
+            .map(|entry| {
+                RespType::Array(vec![
+                    RespType::Integer(entry.id as i64),
+                    RespType::Integer(entry.timestamp as i64),
+                    RespType::Integer(entry.micros as i64),
+                    RespType::Array(
+                        entry
+                            .args
+                            .iter()
+                            .map(|arg| RespType::BulkBytes(arg.clone()))
+                            .collect(),
+                    ),
+                    RespType::BulkString(entry.addr.clone()),
+                    RespType::BulkString(entry.name.clone()),
+                ])
+            })
+            .collect()
+    }
+}
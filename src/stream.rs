@@ -0,0 +1,151 @@
+use std::{collections::BTreeMap, fmt};
+
+use crate::{database::unix_time_ms, error::RedisError};
+
+// An entry's field-value pairs, in the order XADD was given them.
+pub type Fields = Vec<(Vec<u8>, Vec<u8>)>;
+
+// `<ms>-<seq>`: a Unix time in milliseconds and a sequence number telling
+// apart entries added in the same millisecond.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub struct StreamId {
+    pub ms: u64,
+    pub seq: u64,
+}
+
+impl StreamId {
+    pub const MIN: StreamId = StreamId { ms: 0, seq: 0 };
+    pub const MAX: StreamId = StreamId {
+        ms: u64::MAX,
+        seq: u64::MAX,
+    };
+
+    // Parses `<ms>-<seq>`, or a bare `<ms>` taking `missing_seq` as the
+    // sequence number.
+    pub fn parse(text: &str, missing_seq: u64) -> Option<StreamId> {
+        let (ms, seq) = match text.split_once('-') {
+            Some((ms, seq)) => (ms, seq.parse().ok()?),
+            None => (text, missing_seq),
+        };
+        Some(StreamId {
+            ms: ms.parse().ok()?,
+            seq,
+        })
+    }
+}
+
+impl fmt::Display for StreamId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}-{}", self.ms, self.seq)
+    }
+}
+
+// The ID given to XADD: `*` to generate one, `<ms>-*` to generate just the
+// sequence number, or an explicit ID.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum XAddId {
+    Auto,
+    AutoSeq(u64),
+    Explicit(StreamId),
+}
+
+impl XAddId {
+    pub fn parse(text: &str) -> Option<XAddId> {
+        if text == "*" {
+            return Some(XAddId::Auto);
+        }
+        match text.strip_suffix("-*") {
+            Some(ms) => ms.parse().ok().map(XAddId::AutoSeq),
+            None => StreamId::parse(text, 0).map(XAddId::Explicit),
+        }
+    }
+}
+
+// Entries ordered by ID. `last_id` is the highest ID ever added, which new
+// IDs must stay above.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Stream {
+    pub entries: BTreeMap<StreamId, Fields>,
+    pub last_id: StreamId,
+}
+
+impl Stream {
+    // Picks the ID for a new entry, rejecting IDs that don't go past
+    // `last_id`.
+    pub fn next_id(&self, id: XAddId) -> Result<StreamId, RedisError> {
+        let last = self.last_id;
+        let next_seq = |ms: u64| match ms.cmp(&last.ms) {
+            std::cmp::Ordering::Greater => Some(0),
+            std::cmp::Ordering::Equal => last.seq.checked_add(1),
+            std::cmp::Ordering::Less => None,
+        };
+        let id = match id {
+            XAddId::Auto => {
+                let ms = (unix_time_ms() as u64).max(last.ms);
+                let seq = next_seq(ms).ok_or_else(|| {
+                    RedisError::Err(
+                        "The stream has exhausted the last possible ID, unable to add more items"
+                            .to_string(),
+                    )
+                })?;
+                StreamId { ms, seq }
+            }
+            // An `ms` behind the last ID gets rejected below.
+            XAddId::AutoSeq(ms) => StreamId {
+                ms,
+                seq: next_seq(ms).unwrap_or(0),
+            },
+            XAddId::Explicit(id) => id,
+        };
+
+        if id == StreamId::MIN {
+            return Err(RedisError::Err(
+                "The ID specified in XADD must be greater than 0-0".to_string(),
+            ));
+        }
+        if id <= last {
+            return Err(RedisError::Err(
+                "The ID specified in XADD is equal or smaller than the target stream top item"
+                    .to_string(),
+            ));
+        }
+        Ok(id)
+    }
+
+    pub fn insert(&mut self, id: StreamId, fields: Fields) {
+        self.entries.insert(id, fields);
+        self.last_id = self.last_id.max(id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn id(ms: u64, seq: u64) -> StreamId {
+        StreamId { ms, seq }
+    }
+
+    #[test]
+    fn parses_ids_with_and_without_a_sequence() {
+        assert_eq!(StreamId::parse("5-3", 0), Some(id(5, 3)));
+        assert_eq!(StreamId::parse("5", u64::MAX), Some(id(5, u64::MAX)));
+        assert_eq!(StreamId::parse("5-x", 0), None);
+        assert_eq!(XAddId::parse("*"), Some(XAddId::Auto));
+        assert_eq!(XAddId::parse("7-*"), Some(XAddId::AutoSeq(7)));
+        assert_eq!(XAddId::parse("7-1"), Some(XAddId::Explicit(id(7, 1))));
+    }
+
+    #[test]
+    fn new_ids_must_pass_the_last_one() {
+        let mut stream = Stream::default();
+        assert!(stream.next_id(XAddId::Explicit(StreamId::MIN)).is_err());
+        stream.insert(id(5, 1), Vec::new());
+
+        assert_eq!(stream.next_id(XAddId::AutoSeq(5)).unwrap(), id(5, 2));
+        assert_eq!(stream.next_id(XAddId::AutoSeq(6)).unwrap(), id(6, 0));
+        assert!(stream.next_id(XAddId::AutoSeq(4)).is_err());
+        assert!(stream.next_id(XAddId::Explicit(id(5, 1))).is_err());
+        assert!(stream.next_id(XAddId::Auto).unwrap() > id(5, 1));
+    }
+}
@@ -0,0 +1,252 @@
+// Helpers for tests that drive the server the way a client would, over an
+// in-memory pipe instead of a socket.
+
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+};
+
+use clap::Parser;
+use tokio::{
+    io::{duplex, AsyncReadExt, AsyncWriteExt, DuplexStream},
+    net::TcpListener,
+    sync::oneshot,
+    time::{sleep, timeout, Duration},
+};
+
+use crate::{handle_client, run_server, Args, Config};
+
+// How long a test waits for a reply before failing instead of hanging.
+const REPLY_TIMEOUT: Duration = Duration::from_secs(5);
+
+// A config as the server would build from these command-line arguments.
+pub fn test_config(args: &[&str]) -> Arc<Config> {
+    let argv = std::iter::once("redis-starter-rust").chain(args.iter().copied());
+    Arc::new(Config::new(&Args::parse_from(argv)))
+}
+
+// One connection served by `handle_client`.
+pub struct TestClient {
+    stream: DuplexStream,
+    // Bytes read past the last reply returned.
+    unread: Vec<u8>,
+}
+
+impl TestClient {
+    pub fn connect(config: &Arc<Config>) -> Self {
+        static NEXT_PORT: AtomicU64 = AtomicU64::new(50000);
+        let (stream, server) = duplex(1024 * 1024);
+        let addr = format!("127.0.0.1:{}", NEXT_PORT.fetch_add(1, Ordering::SeqCst));
+        tokio::spawn(handle_client(
+            server,
+            addr,
+            "127.0.0.1:6379".to_string(),
+            Arc::clone(config),
+        ));
+        Self {
+            stream,
+            unread: Vec::new(),
+        }
+    }
+
+    pub async fn send(&mut self, bytes: &[u8]) {
+        self.stream.write_all(bytes).await.unwrap();
+    }
+
+    // Sends `args` as a RESP array and returns the reply's bytes.
+    pub async fn command(&mut self, args: &[&str]) -> Vec<u8> {
+        self.send(&encode_command(args)).await;
+        self.reply().await
+    }
+
+    // The next complete reply, or a panic if none arrives in time.
+    pub async fn reply(&mut self) -> Vec<u8> {
+        loop {
+            if let Some(len) = frame_len(&self.unread) {
+                return self.unread.drain(..len).collect();
+            }
+            self.read_more().await;
+        }
+    }
+
+    // The RDB payload a master sends after FULLRESYNC, which is framed like
+    // a bulk string but without the trailing CRLF.
+    pub async fn snapshot(&mut self) -> Vec<u8> {
+        loop {
+            if let Some(line_end) = self.unread.windows(2).position(|w| w == b"\r\n") {
+                let len: usize = std::str::from_utf8(&self.unread[1..line_end])
+                    .unwrap()
+                    .parse()
+                    .unwrap();
+                let end = line_end + 2 + len;
+                if self.unread.len() >= end {
+                    return self.unread.drain(..end).skip(line_end + 2).collect();
+                }
+            }
+            self.read_more().await;
+        }
+    }
+
+    async fn read_more(&mut self) {
+        let mut buffer = [0; 16 * 1024];
+        let read = timeout(REPLY_TIMEOUT, self.stream.read(&mut buffer))
+            .await
+            .expect("timed out waiting for a reply")
+            .unwrap();
+        assert!(read > 0, "connection closed waiting for a reply");
+        self.unread.extend_from_slice(&buffer[..read]);
+    }
+
+    // Whether the server closed the connection, once everything it sent
+    // before that has been read.
+    pub async fn is_closed(&mut self) -> bool {
+        let mut buffer = [0; 1024];
+        matches!(
+            timeout(REPLY_TIMEOUT, self.stream.read(&mut buffer)).await,
+            Ok(Ok(0))
+        )
+    }
+}
+
+// A master and a replica of it, each run by `run_server` on its own
+// loopback port, so replication goes over real sockets as between two
+// processes. Both servers shut down when this is dropped.
+pub struct ReplicaPair {
+    pub master: Arc<Config>,
+    pub replica: Arc<Config>,
+    _shutdown: [oneshot::Sender<()>; 2],
+}
+
+impl ReplicaPair {
+    // Returns once the replica has completed its handshake with the master.
+    pub async fn start() -> Self {
+        Self::start_with(&[]).await
+    }
+
+    // Like `start`, but runs `commands` on the master before the replica
+    // connects, so they reach it through the snapshot.
+    pub async fn start_with(commands: &[&[&str]]) -> Self {
+        let (master, master_shutdown) = start_server(&[]).await;
+        let mut client = TestClient::connect(&master);
+        for command in commands {
+            client.command(command).await;
+        }
+        let replicaof = format!("127.0.0.1 {}", master.port);
+        let (replica, replica_shutdown) = start_server(&["--replicaof", &replicaof]).await;
+        while master.replication_manager.replicas.read().await.is_empty() {
+            sleep(Duration::from_millis(5)).await;
+        }
+        Self {
+            master,
+            replica,
+            _shutdown: [master_shutdown, replica_shutdown],
+        }
+    }
+
+    // Sends `args` to the master and returns its reply.
+    pub async fn on_master(&self, args: &[&str]) -> Vec<u8> {
+        TestClient::connect(&self.master).command(args).await
+    }
+
+    // Waits for the replica to reply `expected` to `args`, failing if it
+    // hasn't caught up in time.
+    pub async fn assert_on_replica(&self, args: &[&str], expected: &[u8]) {
+        let mut client = TestClient::connect(&self.replica);
+        let mut reply = Vec::new();
+        for _ in 0..(REPLY_TIMEOUT.as_millis() / 10) {
+            reply = client.command(args).await;
+            if reply == expected {
+                return;
+            }
+            sleep(Duration::from_millis(10)).await;
+        }
+        panic!(
+            "replica replied {:?} to {:?}, expected {:?}",
+            String::from_utf8_lossy(&reply),
+            args,
+            String::from_utf8_lossy(expected)
+        );
+    }
+}
+
+// Runs a server with these arguments on a free port until the returned
+// sender is dropped.
+async fn start_server(args: &[&str]) -> (Arc<Config>, oneshot::Sender<()>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port().to_string();
+    let mut args = args.to_vec();
+    args.extend(["--port", &port]);
+    let config = test_config(&args);
+
+    let (shutdown, shutdown_signal) = oneshot::channel::<()>();
+    let server = run_server(Arc::clone(&config), listener, None, async {
+        let _ = shutdown_signal.await;
+    });
+    tokio::spawn(async move { server.await.unwrap() });
+    (config, shutdown)
+}
+
+// A fresh directory under the system temp dir, removed with everything in
+// it when this is dropped.
+pub struct TempDir(String);
+
+impl TempDir {
+    pub fn new() -> Self {
+        static NEXT: AtomicU64 = AtomicU64::new(0);
+        let path = std::env::temp_dir().join(format!(
+            "redis-rust-test-{}-{}",
+            std::process::id(),
+            NEXT.fetch_add(1, Ordering::SeqCst)
+        ));
+        std::fs::create_dir_all(&path).unwrap();
+        Self(path.to_string_lossy().into_owned())
+    }
+
+    pub fn path(&self) -> &str {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+pub fn encode_command(args: &[&str]) -> Vec<u8> {
+    let mut frame = format!("*{}\r\n", args.len()).into_bytes();
+    for arg in args {
+        frame.extend(format!("${}\r\n{}\r\n", arg.len(), arg).into_bytes());
+    }
+    frame
+}
+
+// The length of the RESP2 or RESP3 frame at the start of `buffer`, or `None`
+// until all of it has arrived.
+pub fn frame_len(buffer: &[u8]) -> Option<usize> {
+    let line_end = buffer.windows(2).position(|window| window == b"\r\n")?;
+    let header = std::str::from_utf8(&buffer[1..line_end]).ok()?;
+    let after_header = line_end + 2;
+    match buffer.first()? {
+        b'$' | b'=' | b'!' => match header.parse::<i64>().ok()? {
+            -1 => Some(after_header),
+            len => {
+                let end = after_header + len as usize + 2;
+                (buffer.len() >= end).then_some(end)
+            }
+        },
+        b'*' | b'~' | b'>' | b'%' | b'|' => {
+            let count = header.parse::<i64>().ok()?;
+            let items = match buffer[0] {
+                b'%' | b'|' => count * 2,
+                _ => count.max(0),
+            };
+            let mut end = after_header;
+            for _ in 0..items {
+                end += frame_len(&buffer[end..])?;
+            }
+            Some(end)
+        }
+        _ => Some(after_header),
+    }
+}
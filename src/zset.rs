@@ -0,0 +1,260 @@
+use std::{
+    cmp::Ordering,
+    collections::{BTreeSet, HashMap},
+};
+
+// A score that can be ordered. ZADD never lets NaN in, so comparing with
+// `total_cmp` agrees with comparing the numbers.
+#[derive(Debug, Clone, Copy)]
+pub struct Score(pub f64);
+
+impl PartialEq for Score {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Score {}
+
+impl PartialOrd for Score {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Score {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.total_cmp(&other.0)
+    }
+}
+
+// Members ordered by score, ties broken by comparing the members' bytes,
+// plus a member -> score map so lookups don't walk the order.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SortedSet {
+    scores: HashMap<Vec<u8>, Score>,
+    order: BTreeSet<(Score, Vec<u8>)>,
+}
+
+// One end of a score range as ZRANGEBYSCORE takes it: a number, `-inf` or
+// `+inf`, excluding the number itself when prefixed with `(`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ScoreBound {
+    pub score: Score,
+    pub exclusive: bool,
+}
+
+impl ScoreBound {
+    pub fn parse(text: &str) -> Option<ScoreBound> {
+        let (text, exclusive) = match text.strip_prefix('(') {
+            Some(text) => (text, true),
+            None => (text, false),
+        };
+        Some(ScoreBound {
+            score: Score(parse_score(text)? + 0.0),
+            exclusive,
+        })
+    }
+}
+
+// One end of a member range as ZRANGEBYLEX takes it: `[member` includes the
+// member, `(member` excludes it, and `-` and `+` stand for before the first
+// and after the last member.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LexBound {
+    Min,
+    Max,
+    Inclusive(Vec<u8>),
+    Exclusive(Vec<u8>),
+}
+
+impl LexBound {
+    pub fn parse(text: &[u8]) -> Option<LexBound> {
+        match text {
+            b"-" => Some(LexBound::Min),
+            b"+" => Some(LexBound::Max),
+            [b'[', member @ ..] => Some(LexBound::Inclusive(member.to_vec())),
+            [b'(', member @ ..] => Some(LexBound::Exclusive(member.to_vec())),
+            _ => None,
+        }
+    }
+
+    // Whether `member` is at or past this bound as the lower end of a range.
+    fn admits_from_below(&self, member: &[u8]) -> bool {
+        match self {
+            LexBound::Min => true,
+            LexBound::Max => false,
+            LexBound::Inclusive(bound) => member >= bound.as_slice(),
+            LexBound::Exclusive(bound) => member > bound.as_slice(),
+        }
+    }
+
+    // Whether `member` is at or before this bound as the upper end of a range.
+    fn admits_from_above(&self, member: &[u8]) -> bool {
+        match self {
+            LexBound::Min => false,
+            LexBound::Max => true,
+            LexBound::Inclusive(bound) => member <= bound.as_slice(),
+            LexBound::Exclusive(bound) => member < bound.as_slice(),
+        }
+    }
+}
+
+// Parses a score as Redis does, accepting `inf`, `+inf` and `-inf` but not
+// NaN.
+pub fn parse_score(text: &str) -> Option<f64> {
+    text.parse::<f64>().ok().filter(|score| !score.is_nan())
+}
+
+impl SortedSet {
+    pub fn len(&self) -> usize {
+        self.scores.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.scores.is_empty()
+    }
+
+    pub fn score(&self, member: &[u8]) -> Option<f64> {
+        self.scores.get(member).map(|score| score.0)
+    }
+
+    // Adds `member` or moves it to `score`, returning its previous score.
+    pub fn insert(&mut self, member: &[u8], score: f64) -> Option<f64> {
+        // -0 and 0 are the same score; keep only one of them in the order.
+        let score = Score(score + 0.0);
+        let old = self.scores.insert(member.to_vec(), score);
+        if let Some(old) = old {
+            self.order.remove(&(old, member.to_vec()));
+        }
+        self.order.insert((score, member.to_vec()));
+        old.map(|old| old.0)
+    }
+
+    // Drops `member`, returning the score it had.
+    pub fn remove(&mut self, member: &[u8]) -> Option<f64> {
+        let score = self.scores.remove(member)?;
+        self.order.remove(&(score, member.to_vec()));
+        Some(score.0)
+    }
+
+    // The member's position counting from the lowest score.
+    pub fn rank(&self, member: &[u8]) -> Option<usize> {
+        let score = *self.scores.get(member)?;
+        Some(self.order.range(..(score, member.to_vec())).count())
+    }
+
+    // Members from the lowest score to the highest.
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = (&Vec<u8>, f64)> {
+        self.order.iter().map(|(score, member)| (member, score.0))
+    }
+
+    // Members scored between `min` and `max`, lowest first. Starts from the
+    // first member at `min` rather than walking the lower scores.
+    pub fn range_by_score(
+        &self,
+        min: ScoreBound,
+        max: ScoreBound,
+    ) -> impl Iterator<Item = (&Vec<u8>, f64)> {
+        self.order
+            .range((min.score, Vec::new())..)
+            .map(|(score, member)| (member, score.0))
+            .skip_while(move |(_, score)| min.exclusive && *score == min.score.0)
+            .take_while(move |(_, score)| {
+                *score < max.score.0 || (!max.exclusive && *score == max.score.0)
+            })
+    }
+
+    // Members between `min` and `max` compared as bytes, in order. Like
+    // Redis this is only meaningful when every member has the same score:
+    // it walks the order from the first member past `min` and stops at the
+    // first one past `max`.
+    pub fn range_by_lex<'a>(
+        &'a self,
+        min: &'a LexBound,
+        max: &'a LexBound,
+    ) -> impl Iterator<Item = (&'a Vec<u8>, f64)> {
+        self.iter()
+            .skip_while(move |(member, _)| !min.admits_from_below(member))
+            .take_while(move |(member, _)| max.admits_from_above(member))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn zset(members: &[(&str, f64)]) -> SortedSet {
+        let mut zset = SortedSet::default();
+        for (member, score) in members {
+            zset.insert(member.as_bytes(), *score);
+        }
+        zset
+    }
+
+    fn members<'a>(iter: impl Iterator<Item = (&'a Vec<u8>, f64)>) -> Vec<&'a str> {
+        iter.map(|(member, _)| std::str::from_utf8(member).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn orders_by_score_then_member() {
+        let mut zset = zset(&[("b", 1.0), ("a", 1.0), ("c", 0.5)]);
+        assert_eq!(members(zset.iter()), ["c", "a", "b"]);
+        assert_eq!(zset.rank(b"b"), Some(2));
+
+        assert_eq!(zset.insert(b"c", 2.0), Some(0.5));
+        assert_eq!(members(zset.iter()), ["a", "b", "c"]);
+        assert_eq!(zset.remove(b"a"), Some(1.0));
+        assert_eq!(zset.remove(b"a"), None);
+        assert_eq!(zset.len(), 2);
+    }
+
+    #[test]
+    fn negative_zero_is_the_same_score_as_zero() {
+        let mut zset = zset(&[("a", -0.0)]);
+        zset.insert(b"a", 0.0);
+        assert_eq!(zset.iter().count(), 1);
+    }
+
+    #[test]
+    fn score_ranges_honour_exclusive_bounds() {
+        let zset = zset(&[("a", 1.0), ("b", 2.0), ("c", 3.0)]);
+        let bound = |text: &str| ScoreBound::parse(text).unwrap();
+
+        assert_eq!(
+            members(zset.range_by_score(bound("-inf"), bound("+inf"))),
+            ["a", "b", "c"]
+        );
+        assert_eq!(
+            members(zset.range_by_score(bound("(1"), bound("3"))),
+            ["b", "c"]
+        );
+        assert_eq!(
+            members(zset.range_by_score(bound("1"), bound("(3"))),
+            ["a", "b"]
+        );
+        assert!(ScoreBound::parse("nan").is_none());
+        assert!(ScoreBound::parse("(x").is_none());
+    }
+
+    #[test]
+    fn lex_ranges_take_brackets_parentheses_and_infinities() {
+        let zset = zset(&[("a", 0.0), ("b", 0.0), ("c", 0.0)]);
+        let bound = |text: &str| LexBound::parse(text.as_bytes()).unwrap();
+
+        assert_eq!(
+            members(zset.range_by_lex(&bound("-"), &bound("+"))),
+            ["a", "b", "c"]
+        );
+        assert_eq!(
+            members(zset.range_by_lex(&bound("(a"), &bound("[c"))),
+            ["b", "c"]
+        );
+        assert_eq!(
+            members(zset.range_by_lex(&bound("[b"), &bound("(b"))),
+            [] as [&str; 0]
+        );
+        assert!(LexBound::parse(b"a").is_none());
+    }
+}